@@ -1,18 +1,70 @@
-use btclib::types::Block;
+use btclib::crypto::PublicKey;
+use btclib::types::{Block, BlockChain, Transaction};
 use btclib::util::Savable;
+use serde::Serialize;
 use std::env;
+use std::fmt::Debug;
 use std::fs::File;
+use std::io;
+use std::process::exit;
 
-fn main() {
-    let path = if let Some(arg) = env::args().nth(1) {
-        arg
+const USAGE: &str = "Usage: block_print [--json] [--type block|tx|chain|pubkey] <file>";
+
+fn print<T: Savable + Serialize + Debug>(file: File, json: bool) {
+    let value = T::load(file).unwrap_or_else(|e| {
+        eprintln!("Failed to load file: {}", e);
+        exit(1);
+    });
+
+    if json {
+        value.save_json(io::stdout()).unwrap_or_else(|e| {
+            eprintln!("Failed to print as JSON: {}", e);
+            exit(1);
+        });
+        println!();
     } else {
-        eprintln!("Usage: block_print <block_file>");
-        std::process::exit(1);
+        println!("{:#?}", value);
+    }
+}
+
+fn main() {
+    let mut json = false;
+    // block by default, as before the other types were supported
+    let mut kind = "block".to_string();
+    let mut path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--type" => {
+                kind = args.next().unwrap_or_else(|| {
+                    eprintln!("{}", USAGE);
+                    exit(1);
+                })
+            }
+            _ => path = Some(arg),
+        }
+    }
+
+    let Some(path) = path else {
+        eprintln!("{}", USAGE);
+        exit(1);
     };
 
-    if let Ok(file) = File::open(path) {
-        let block = Block::load(file).expect("Failed to load block");
-        println!("{:#?}", block);
+    let file = File::open(&path).unwrap_or_else(|e| {
+        eprintln!("Failed to open {}: {}", path, e);
+        exit(1);
+    });
+
+    match kind.as_str() {
+        "block" => print::<Block>(file, json),
+        "tx" => print::<Transaction>(file, json),
+        "chain" => print::<BlockChain>(file, json),
+        "pubkey" => print::<PublicKey>(file, json),
+        _ => {
+            eprintln!("Unknown type {}\n{}", kind, USAGE);
+            exit(1);
+        }
     }
 }
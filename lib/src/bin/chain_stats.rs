@@ -0,0 +1,63 @@
+use btclib::types::BlockChain;
+use btclib::util::Savable;
+use std::env;
+use std::process::exit;
+
+fn main() {
+    let Some(path) = env::args().nth(1) else {
+        eprintln!("Usage: chain_stats <blockchain_file>");
+        exit(1);
+    };
+
+    let blockchain = BlockChain::load_from_file(&path).unwrap_or_else(|e| {
+        eprintln!("Failed to load {}: {}", path, e);
+        exit(1);
+    });
+
+    let params = blockchain.params();
+    let height = blockchain.block_height();
+
+    // the block reward only; fees move existing coins around
+    let issued = (0..height)
+        .map(|height| params.reward_at_height(height))
+        .fold(0u64, u64::saturating_add);
+
+    println!("network: {}", params.network);
+    println!("blocks: {}", height);
+
+    let transactions = blockchain.transactions().count();
+    if blockchain.pruned_height() > 0 {
+        println!(
+            "transactions: {} (bodies below height {} are pruned)",
+            transactions,
+            blockchain.pruned_height()
+        );
+    } else {
+        println!("transactions: {}", transactions);
+    }
+
+    println!(
+        "coins issued: {}.{:08}",
+        issued / 100_000_000,
+        issued % 100_000_000
+    );
+
+    let first = blockchain.blocks().next();
+    let last = blockchain.blocks().last();
+
+    match (first, last) {
+        (Some(first), Some(last)) if height > 1 => {
+            let span = last.header.timestamp - first.header.timestamp;
+            println!(
+                "average block interval: {:.1}s (ideal {}s)",
+                span.num_milliseconds() as f64 / 1000.0 / (height - 1) as f64,
+                params.ideal_block_time
+            );
+        }
+        _ => println!("average block interval: n/a"),
+    }
+
+    println!("difficulty: {:.4}", blockchain.difficulty());
+    println!("target: {:x}", blockchain.target());
+    println!("total work: {}", blockchain.total_work());
+}
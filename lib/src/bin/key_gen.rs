@@ -1,15 +1,56 @@
 use std::env;
+use std::process::exit;
 
-use btclib::{crypto::PrivateKey, util::Savable};
+use btclib::{
+    crypto::{PrivateKey, PublicKey},
+    util::Savable,
+};
+
+fn usage() -> ! {
+    eprintln!("Usage: key_gen [--out-prefix] <name>");
+    eprintln!("       key_gen --show <key_file>");
+    exit(1);
+}
 
 fn main() {
-    let name = env::args().nth(1).expect("Please provide a name");
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["--show", key_file] => show(key_file),
+        ["--out-prefix", name] | [name] if !name.starts_with("--") => generate(name),
+        _ => usage(),
+    }
+}
+
+fn generate(name: &str) {
     let private_key = PrivateKey::new_key();
     let public_key = private_key.public_key();
 
-    let public_key_file = name.clone() + ".pub.pem";
-    let private_key_file = name.clone() + ".priv.cbor";
+    let public_key_file = name.to_string() + ".pub.pem";
+    let private_key_file = name.to_string() + ".priv.cbor";
 
     private_key.save_to_file(&private_key_file).unwrap();
     public_key.save_to_file(&public_key_file).unwrap();
+
+    println!("wrote {} and {}", private_key_file, public_key_file);
+    println!("address: {}", public_key.to_address());
+}
+
+// accepts either half of a keypair; a private key is reduced to its public key
+fn show(key_file: &str) {
+    let public_key = match PrivateKey::load_from_file(key_file) {
+        Ok(private_key) => private_key.public_key(),
+        Err(_) => PublicKey::load_from_file(key_file).unwrap_or_else(|e| {
+            eprintln!("Failed to load key from {}: {}", key_file, e);
+            exit(1);
+        }),
+    };
+
+    let mut pem = vec![];
+    public_key
+        .save(&mut pem)
+        .expect("Failed to encode public key");
+
+    println!("address: {}", public_key.to_address());
+    print!("{}", String::from_utf8_lossy(&pem));
 }
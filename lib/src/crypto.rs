@@ -1,35 +1,157 @@
+use chacha20poly1305::{
+    ChaCha20Poly1305,
+    aead::{Aead, KeyInit},
+};
 use ecdsa::{
     Signature as EcdsaSignature, SigningKey, VerifyingKey,
-    signature::{Signer, Verifier, rand_core::OsRng},
+    signature::{
+        Signer, Verifier,
+        rand_core::{OsRng, RngCore},
+    },
 };
 use k256::Secp256k1;
 use serde::{Deserialize, Serialize};
 
-use crate::{sha256::Hash, util::Savable};
+use crate::{
+    error::{BtcError, Result},
+    sha256::Hash,
+    types::Transaction,
+    util::Savable,
+};
 use spki::EncodePublicKey;
+use std::hash::{Hash as StdHash, Hasher};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Signature(pub EcdsaSignature<Secp256k1>);
 
 impl Signature {
-    pub fn sign_output(output_hash: &Hash, private_key: &PrivateKey) -> Self {
+    pub fn sign_input(
+        tx: &Transaction,
+        input_index: usize,
+        prev_hash: &Hash,
+        private_key: &PrivateKey,
+    ) -> Self {
+        let sighash = tx.sighash(input_index, prev_hash);
         let signing_key = &private_key.0;
-        let signature = signing_key.sign(&output_hash.as_bytes());
-        Self(signature)
+        let signature: EcdsaSignature<Secp256k1> = signing_key.sign(&sighash.as_bytes());
+        Self(signature.normalize_s().unwrap_or(signature))
+    }
+
+    pub fn verify_input(
+        &self,
+        tx: &Transaction,
+        input_index: usize,
+        prev_hash: &Hash,
+        public_key: &PublicKey,
+    ) -> bool {
+        self.verify(&tx.sighash(input_index, prev_hash), public_key)
     }
 
-    pub fn verify(&self, output_hash: &Hash, public_key: &PublicKey) -> bool {
-        public_key
-            .0
-            .verify(&output_hash.as_bytes(), &self.0)
-            .is_ok()
+    // (r, s) and (r, n - s) are both valid signatures of the same message,
+    // so anyone relaying a transaction could flip s and change its hash.
+    // Only the low form is accepted, as in BIP-62
+    pub fn verify(&self, message: &Hash, public_key: &PublicKey) -> bool {
+        self.is_low_s() && public_key.0.verify(&message.as_bytes(), &self.0).is_ok()
+    }
+
+    pub fn is_low_s(&self) -> bool {
+        // normalize_s only has something to return for a high s
+        self.0.normalize_s().is_none()
+    }
+
+    // ECDSA has no true batch verification, so this spreads the checks over
+    // threads and stops everyone at the first failure; it only says whether
+    // all of them hold, not which one doesn't
+    pub fn verify_batch(items: &[(&Hash, &Signature, &PublicKey)]) -> bool {
+        let threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        if items.len() < BATCH_MIN_PER_THREAD * 2 || threads == 1 {
+            return items
+                .iter()
+                .all(|(message, signature, public_key)| signature.verify(message, public_key));
+        }
+
+        let chunk_size = items.len().div_ceil(threads).max(BATCH_MIN_PER_THREAD);
+        let failed = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            for chunk in items.chunks(chunk_size) {
+                let failed = &failed;
+
+                scope.spawn(move || {
+                    for (message, signature, public_key) in chunk {
+                        if failed.load(Ordering::Relaxed) {
+                            return;
+                        }
+
+                        if !signature.verify(message, public_key) {
+                            failed.store(true, Ordering::Relaxed);
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        !failed.load(Ordering::Relaxed)
     }
 }
 
+// below this many signatures per thread, spawning costs more than it saves
+const BATCH_MIN_PER_THREAD: usize = 16;
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PublicKey(pub VerifyingKey<Secp256k1>);
 
+impl PublicKey {
+    pub fn to_address(&self) -> String {
+        let mut payload = vec![crate::ADDRESS_VERSION];
+        payload.extend_from_slice(&self.address_hash());
+
+        bs58::encode(payload).with_check().into_string()
+    }
+
+    // checks the version byte and checksum and returns the key hash the
+    // address carries. The key itself can't be had back from a hash, so
+    // this is as much of it as an address identifies
+    pub fn from_address(address: &str) -> Result<[u8; 20]> {
+        let payload = bs58::decode(address)
+            .with_check(Some(crate::ADDRESS_VERSION))
+            .into_vec()
+            .map_err(|_| BtcError::InvalidAddress)?;
+
+        payload[1..]
+            .try_into()
+            .map_err(|_| BtcError::InvalidAddress)
+    }
+
+    pub fn matches_address(&self, address: &str) -> bool {
+        Self::from_address(address).is_ok_and(|hash| hash == self.address_hash())
+    }
+
+    pub fn address_hash(&self) -> [u8; 20] {
+        let sec1 = self.0.to_encoded_point(true);
+        let hash = Hash::hash(&sec1.as_bytes()).as_bytes();
+
+        let mut address_hash = [0u8; 20];
+        address_hash.copy_from_slice(&hash[..20]);
+        address_hash
+    }
+}
+
+// over the compressed SEC1 encoding, which is one to one with the point, so
+// keys that are equal hash alike
+impl StdHash for PublicKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_encoded_point(true).as_bytes().hash(state);
+    }
+}
+
 impl Savable for PublicKey {
     fn load<I: Read>(mut reader: I) -> IoResult<Self> {
         let mut buf = String::new();
@@ -61,6 +183,93 @@ impl PrivateKey {
     pub fn public_key(&self) -> PublicKey {
         PublicKey(*self.0.verifying_key())
     }
+
+    // version byte, 32 byte secret, then the compressed-pubkey flag
+    pub fn to_wif(&self) -> String {
+        let mut payload = vec![crate::WIF_VERSION];
+        payload.extend_from_slice(&self.0.to_bytes());
+        payload.push(0x01);
+
+        bs58::encode(payload).with_check().into_string()
+    }
+
+    pub fn from_wif(wif: &str) -> Result<Self> {
+        let payload = bs58::decode(wif.trim())
+            .with_check(Some(crate::WIF_VERSION))
+            .into_vec()
+            .map_err(|_| BtcError::InvalidPrivateKey)?;
+
+        let secret = match payload.len() {
+            33 => &payload[1..],
+            34 if payload[33] == 0x01 => &payload[1..33],
+            _ => return Err(BtcError::InvalidPrivateKey),
+        };
+
+        SigningKey::from_slice(secret)
+            .map(Self)
+            .map_err(|_| BtcError::InvalidPrivateKey)
+    }
+
+    pub fn save_encrypted<O: Write>(&self, writer: O, passphrase: &str) -> IoResult<()> {
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+
+        let cipher = ChaCha20Poly1305::new(&derive_encryption_key(passphrase, &salt)?.into());
+        let ciphertext = cipher
+            .encrypt(&nonce.into(), self.0.to_bytes().as_slice())
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to encrypt private key"))?;
+
+        let encrypted = EncryptedPrivateKey {
+            salt,
+            nonce,
+            ciphertext,
+        };
+
+        ciborium::ser::into_writer(&encrypted, writer).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to serialize encrypted private key",
+            )
+        })
+    }
+
+    pub fn load_encrypted<I: Read>(reader: I, passphrase: &str) -> IoResult<Self> {
+        let encrypted: EncryptedPrivateKey = ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize encrypted private key",
+            )
+        })?;
+
+        let cipher =
+            ChaCha20Poly1305::new(&derive_encryption_key(passphrase, &encrypted.salt)?.into());
+        let bytes = cipher
+            .decrypt(&encrypted.nonce.into(), encrypted.ciphertext.as_slice())
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to decrypt private key"))?;
+
+        SigningKey::from_slice(&bytes)
+            .map(Self)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Invalid private key"))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncryptedPrivateKey {
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+fn derive_encryption_key(passphrase: &str, salt: &[u8]) -> IoResult<[u8; 32]> {
+    let params = scrypt::Params::new(15, 8, 1, 32)
+        .map_err(|_| IoError::other("Invalid scrypt parameters"))?;
+
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+        .map_err(|_| IoError::other("Failed to derive encryption key"))?;
+    Ok(key)
 }
 
 impl Savable for PrivateKey {
@@ -80,7 +289,7 @@ impl Savable for PrivateKey {
 }
 
 mod signkey_serde {
-    use serde::Deserialize;
+    use serde::{Deserialize, de::Error};
 
     pub fn serialize<S>(
         key: &super::SigningKey<super::Secp256k1>,
@@ -99,6 +308,204 @@ mod signkey_serde {
         D: serde::Deserializer<'de>,
     {
         let bytes: Vec<u8> = Vec::<u8>::deserialize(deserializer)?;
-        Ok(super::SigningKey::from_slice(&bytes).unwrap())
+        super::SigningKey::from_slice(&bytes)
+            .map_err(|_| D::Error::custom("invalid signing key bytes"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_round_trips() {
+        let key = PrivateKey::new_key().public_key();
+        let address = key.to_address();
+
+        assert_eq!(
+            PublicKey::from_address(&address).unwrap(),
+            key.address_hash()
+        );
+        assert!(key.matches_address(&address));
+        assert!(!PrivateKey::new_key().public_key().matches_address(&address));
+    }
+
+    #[test]
+    fn corrupted_addresses_are_rejected() {
+        let key = PrivateKey::new_key().public_key();
+        let mut payload = bs58::decode(key.to_address()).into_vec().unwrap();
+
+        // flip a bit of the checksum, then of the key hash
+        for index in [payload.len() - 1, 5] {
+            payload[index] ^= 1;
+            let corrupted = bs58::encode(&payload).into_string();
+            assert!(matches!(
+                PublicKey::from_address(&corrupted),
+                Err(BtcError::InvalidAddress)
+            ));
+            assert!(!key.matches_address(&corrupted));
+            payload[index] ^= 1;
+        }
+
+        let mut wrong_version = vec![crate::WIF_VERSION];
+        wrong_version.extend_from_slice(&key.address_hash());
+        let wrong_version = bs58::encode(wrong_version).with_check().into_string();
+        assert!(PublicKey::from_address(&wrong_version).is_err());
+
+        assert!(PublicKey::from_address("not an address").is_err());
+    }
+
+    #[test]
+    fn high_s_signatures_are_rejected() {
+        let key = PrivateKey::new_key();
+        let prev = crate::test_util::output(1000, &key.public_key());
+        let tx = crate::test_util::spend(&prev, &key, 900);
+        let signature = &tx.inputs[0].signature;
+
+        assert!(signature.is_low_s());
+        assert!(signature.verify_input(&tx, 0, &prev.hash(), &key.public_key()));
+
+        // (r, n - s) signs the same message
+        let (r, s) = signature.0.split_scalars();
+        let high = Signature(EcdsaSignature::from_scalars(r, -s).unwrap());
+
+        assert_eq!(high.0.normalize_s(), Some(signature.0));
+        assert!(!high.is_low_s());
+        assert!(!high.verify_input(&tx, 0, &prev.hash(), &key.public_key()));
+
+        // and would give the transaction another hash
+        let mut malleated = tx.clone();
+        malleated.inputs[0].signature = high;
+        assert_ne!(malleated.hash(), tx.hash());
+    }
+
+    #[test]
+    fn an_encrypted_key_needs_its_passphrase() {
+        let key = PrivateKey::new_key();
+        let mut encrypted = vec![];
+        key.save_encrypted(&mut encrypted, "correct horse").unwrap();
+
+        // the secret is not stored in the clear
+        let secret = key.0.to_bytes();
+        assert!(
+            !encrypted
+                .windows(secret.len())
+                .any(|w| w == secret.as_slice())
+        );
+
+        let loaded = PrivateKey::load_encrypted(encrypted.as_slice(), "correct horse").unwrap();
+        assert_eq!(loaded.public_key(), key.public_key());
+
+        let error = PrivateKey::load_encrypted(encrypted.as_slice(), "battery staple")
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), IoErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn malformed_key_bytes_are_an_error_not_a_panic() {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&PrivateKey::new_key(), &mut bytes).unwrap();
+
+        // too short, all zeros (not a valid scalar), and cut off mid-way
+        let mut short = vec![];
+        ciborium::ser::into_writer(&ciborium::Value::Bytes(vec![1; 16]), &mut short).unwrap();
+        let mut zero = vec![];
+        ciborium::ser::into_writer(&ciborium::Value::Bytes(vec![0; 32]), &mut zero).unwrap();
+        let truncated = &bytes[..bytes.len() - 4];
+
+        for malformed in [short.as_slice(), zero.as_slice(), truncated] {
+            assert!(ciborium::de::from_reader::<PrivateKey, _>(malformed).is_err());
+            assert!(PrivateKey::load(malformed).is_err());
+        }
+    }
+
+    #[test]
+    fn wif_round_trips() {
+        let key = PrivateKey::new_key();
+        let wif = key.to_wif();
+
+        let imported = PrivateKey::from_wif(&wif).unwrap();
+        assert_eq!(imported.public_key(), key.public_key());
+        assert_eq!(imported.to_wif(), wif);
+
+        // surrounding whitespace from a paste is ignored
+        let padded = format!("  {}\n", wif);
+        assert_eq!(
+            PrivateKey::from_wif(&padded).unwrap().public_key(),
+            key.public_key()
+        );
+
+        // the uncompressed layout, without the trailing flag, loads too
+        let mut payload = vec![crate::WIF_VERSION];
+        payload.extend_from_slice(&key.0.to_bytes());
+        let uncompressed = bs58::encode(payload).with_check().into_string();
+        assert_eq!(
+            PrivateKey::from_wif(&uncompressed).unwrap().public_key(),
+            key.public_key()
+        );
+    }
+
+    #[test]
+    fn corrupted_wifs_are_rejected() {
+        let key = PrivateKey::new_key();
+        let mut payload = bs58::decode(key.to_wif()).into_vec().unwrap();
+
+        // flip a bit of the checksum, then of the secret
+        for index in [payload.len() - 1, 10] {
+            payload[index] ^= 1;
+            let corrupted = bs58::encode(&payload).into_string();
+            assert!(matches!(
+                PrivateKey::from_wif(&corrupted),
+                Err(BtcError::InvalidPrivateKey)
+            ));
+            payload[index] ^= 1;
+        }
+
+        let with_check = |payload: Vec<u8>| bs58::encode(payload).with_check().into_string();
+        let secret = key.0.to_bytes().to_vec();
+
+        // a wrong version, a short secret, and a bad compression flag
+        let wrong_version = with_check([vec![0x00], secret.clone(), vec![0x01]].concat());
+        let short = with_check([vec![crate::WIF_VERSION], secret[..31].to_vec()].concat());
+        let bad_flag = with_check([vec![crate::WIF_VERSION], secret, vec![0x02]].concat());
+
+        for wif in [wrong_version, short, bad_flag, "not a key".to_string()] {
+            assert!(PrivateKey::from_wif(&wif).is_err(), "{} was accepted", wif);
+        }
+    }
+
+    #[test]
+    fn one_bad_signature_fails_the_whole_batch() {
+        let key = PrivateKey::new_key();
+
+        // enough to be split over threads wherever there are several
+        let signed = (0..BATCH_MIN_PER_THREAD as u64 * 8)
+            .map(|i| {
+                let prev = crate::test_util::output(1000 + i, &key.public_key());
+                let tx = crate::test_util::spend(&prev, &key, 900);
+                (tx.sighash(0, &prev.hash()), tx.inputs[0].signature.clone())
+            })
+            .collect::<Vec<_>>();
+        let public_key = key.public_key();
+
+        let batch = |signed: &[(Hash, Signature)]| {
+            let items = signed
+                .iter()
+                .map(|(message, signature)| (message, signature, &public_key))
+                .collect::<Vec<_>>();
+            Signature::verify_batch(&items)
+        };
+
+        assert!(batch(&signed));
+        assert!(batch(&signed[..3]));
+
+        // a valid signature, but of another message, wherever it sits
+        for position in [0, signed.len() / 2, signed.len() - 1] {
+            let mut forged = signed.clone();
+            forged[position].1 = signed[(position + 1) % signed.len()].1.clone();
+            assert!(!batch(&forged));
+            assert!(!batch(&forged[position.saturating_sub(1)..=position]));
+        }
     }
 }
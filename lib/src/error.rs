@@ -30,6 +30,109 @@ pub enum BtcError {
 
     #[error("Invalid private key")]
     InvalidPrivateKey,
+
+    #[error("Invalid address")]
+    InvalidAddress,
+
+    #[error("Block does not extend the expected previous block")]
+    BadPrevHash,
+
+    #[error("Block hash does not meet its target")]
+    TargetNotMet,
+
+    #[error("Block target is not the one the chain requires")]
+    UnexpectedTarget,
+
+    #[error("Block is not this network's genesis block")]
+    InvalidGenesis,
+
+    #[error("Block timestamp is not after the median time past")]
+    StaleTimestamp,
+
+    #[error("Block timestamp is too far in the future")]
+    FutureTimestamp,
+
+    #[error("Block exceeds the maximum block size")]
+    BlockTooLarge,
+
+    #[error("Block is already known")]
+    DuplicateBlock,
+
+    #[error("Block has no transactions")]
+    EmptyBlock,
+
+    #[error("Blockchain has no blocks")]
+    EmptyChain,
+
+    #[error("Malformed coinbase transaction")]
+    InvalidCoinbase,
+
+    #[error("Coinbase does not pay the block reward plus fees")]
+    CoinbaseMismatch,
+
+    #[error("Transaction has no inputs")]
+    MissingInputs,
+
+    #[error("Transaction spends an unknown output")]
+    UnknownInput,
+
+    #[error("Transaction spends an output that was already spent on chain")]
+    SpentInput,
+
+    #[error("Output is spent more than once")]
+    DoubleSpend,
+
+    #[error("Duplicate transaction output")]
+    DuplicateOutput,
+
+    #[error("Transaction values overflow")]
+    ValueOverflow,
+
+    #[error("Transaction output is below the dust threshold")]
+    DustOutput,
+
+    #[error("Transaction outputs exceed its inputs")]
+    InsufficientInputValue,
+
+    #[error("Replacement does not pay a higher fee than what it replaces")]
+    ReplacementFeeTooLow,
+
+    #[error("Mempool is full and the transaction pays too little")]
+    MempoolFull,
+
+    #[error("Transaction spends unknown outputs and is held as an orphan")]
+    OrphanTransaction,
+
+    #[error("Transaction spends a coinbase output before it matured")]
+    ImmatureCoinbase,
+
+    #[error("Transaction is locked until a later block height")]
+    TransactionLocked,
+
+    #[error("Unknown network: {0}")]
+    UnknownNetwork(String),
+
+    #[error("Blockchain belongs to a different network")]
+    NetworkMismatch,
+
+    #[error("Block {height} failed verification: {reason}")]
+    VerificationFailed { height: u64, reason: Box<BtcError> },
+
+    #[error("Invalid chain parameters")]
+    InvalidChainParams,
+
+    #[error("Block body has been pruned")]
+    BlockUnavailable,
+
+    #[error("Invalid UTXO snapshot")]
+    InvalidSnapshot,
+
+    #[error("UTXO snapshot was not taken at this chain's tip")]
+    SnapshotMismatch,
+
+    // the block was added all the same; only the journal has fallen behind
+    #[error("Block was added but could not be journaled: {0}")]
+    JournalFailed(#[source] std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, BtcError>;
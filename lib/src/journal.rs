@@ -0,0 +1,291 @@
+use std::fs::{File, OpenOptions};
+use std::io::{
+    BufReader, Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write,
+};
+use std::path::{Path, PathBuf};
+
+use crate::types::Block;
+
+// an append-only file of blocks, each stored as its CBOR encoding behind
+// its length as four big-endian bytes
+#[derive(Clone, Debug)]
+pub struct BlockJournal {
+    path: PathBuf,
+}
+
+impl BlockJournal {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // synced before returning, so a block that was journaled survives a crash
+    pub fn append(&self, block: &Block) -> IoResult<()> {
+        let mut frame = vec![0u8; 4];
+        ciborium::ser::into_writer(block, &mut frame).map_err(|_| IoErrorKind::InvalidData)?;
+
+        let len = u32::try_from(frame.len() - 4).map_err(|_| IoErrorKind::InvalidData)?;
+        frame[..4].copy_from_slice(&len.to_be_bytes());
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&frame)?;
+        file.sync_data()
+    }
+
+    // the blocks in the order they were appended. A crash mid-append leaves a
+    // frame cut short at the end; it is cut off the file as well, so later
+    // appends follow the last good frame. A complete frame that doesn't
+    // decode is corruption rather than a torn write, and is an error that
+    // leaves the file alone. A missing file is an empty journal
+    pub fn read(&self) -> IoResult<Vec<Block>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == IoErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut blocks = vec![];
+        let mut good_len = 0u64;
+
+        loop {
+            let mut len_bytes = [0u8; 4];
+            if reader.read_exact(&mut len_bytes).is_err() {
+                break;
+            }
+
+            let len = u32::from_be_bytes(len_bytes) as u64;
+            if good_len + 4 + len > file_len {
+                break;
+            }
+
+            let mut frame = vec![0u8; len as usize];
+            reader.read_exact(&mut frame)?;
+
+            let block = ciborium::de::from_reader(frame.as_slice()).map_err(|_| {
+                IoError::new(
+                    IoErrorKind::InvalidData,
+                    format!("corrupt block at offset {} of the journal", good_len),
+                )
+            })?;
+
+            blocks.push(block);
+            good_len += 4 + len;
+        }
+
+        if good_len < file_len {
+            OpenOptions::new()
+                .write(true)
+                .open(&self.path)?
+                .set_len(good_len)?;
+        }
+
+        Ok(blocks)
+    }
+
+    // for once the blocks are saved some other way
+    pub fn clear(&self) -> IoResult<()> {
+        File::create(&self.path)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::error::BtcError;
+    use crate::test_util::*;
+    use crate::util::Savable;
+
+    fn temp_journal() -> BlockJournal {
+        BlockJournal::new(
+            std::env::temp_dir().join(format!("rsbtc-journal-{}.log", uuid::Uuid::new_v4())),
+        )
+    }
+
+    #[test]
+    fn replaying_the_journal_rebuilds_the_chain() {
+        let key = PrivateKey::new_key();
+        let snapshot = regtest_chain(&key);
+        let journal = temp_journal();
+
+        let mut chain = snapshot.clone();
+        chain.enable_journal(journal.clone());
+        extend(&mut chain, &key, 3);
+
+        // a longer side branch, so the replay has to reorganize too
+        let fork = chain.blocks().nth(2).unwrap().clone();
+        let side_1 = block_on(&chain, &fork, 3, &key, vec![]);
+        let side_2 = block_on(&chain, &side_1, 4, &key, vec![]);
+        let side_3 = block_on(&chain, &side_2, 5, &key, vec![]);
+        for block in [side_1, side_2, side_3.clone()] {
+            chain.add_block(block).unwrap();
+        }
+        assert_eq!(chain.blocks().last().unwrap().hash(), side_3.hash());
+
+        let mut replayed = snapshot.clone();
+        assert_eq!(replayed.replay_journal(&journal).unwrap(), (6, 0));
+
+        let hashes = |chain: &crate::types::BlockChain| {
+            chain.blocks().map(|block| block.hash()).collect::<Vec<_>>()
+        };
+        assert_eq!(hashes(&replayed), hashes(&chain));
+        assert_eq!(replayed.utxos().len(), chain.utxos().len());
+        assert!(
+            chain
+                .utxos()
+                .keys()
+                .all(|hash| replayed.utxos().contains_key(hash))
+        );
+        assert!(replayed.journal().is_none());
+
+        // replaying again only finds blocks it already has
+        assert_eq!(replayed.replay_journal(&journal).unwrap(), (0, 6));
+
+        std::fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn a_snapshot_and_the_journal_after_it_restore_the_utxos() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, 2);
+        let journal = temp_journal();
+
+        // what a compaction writes: the chain, its utxos, an empty journal
+        let mut saved = vec![];
+        chain.save(&mut saved).unwrap();
+        let mut snapshot = vec![];
+        chain.export_utxo_snapshot(&mut snapshot).unwrap();
+        journal.clear().unwrap();
+
+        chain.enable_journal(journal.clone());
+        extend(&mut chain, &key, 2);
+
+        let mut loaded = crate::types::BlockChain::load(saved.as_slice()).unwrap();
+        loaded.import_utxo_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(loaded.replay_journal(&journal).unwrap(), (2, 0));
+
+        assert_eq!(loaded.block_height(), chain.block_height());
+        assert_eq!(loaded.utxos().len(), chain.utxos().len());
+        assert!(
+            chain
+                .utxos()
+                .keys()
+                .all(|hash| loaded.utxos().contains_key(hash))
+        );
+
+        // a snapshot from before the last save is stale
+        let mut stale = vec![];
+        chain.save(&mut stale).unwrap();
+        let mut stale = crate::types::BlockChain::load(stale.as_slice()).unwrap();
+        assert!(matches!(
+            stale.import_utxo_snapshot(snapshot.as_slice()),
+            Err(crate::error::BtcError::SnapshotMismatch)
+        ));
+
+        std::fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn a_torn_tail_is_cut_off() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        let journal = temp_journal();
+
+        chain.enable_journal(journal.clone());
+        extend(&mut chain, &key, 2);
+        let good_len = std::fs::metadata(journal.path()).unwrap().len();
+
+        // a frame that promises more bytes than were written
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(journal.path())
+            .unwrap();
+        file.write_all(&1000u32.to_be_bytes()).unwrap();
+        file.write_all(&[0xa5; 10]).unwrap();
+        drop(file);
+
+        assert_eq!(journal.read().unwrap().len(), 2);
+        assert_eq!(std::fs::metadata(journal.path()).unwrap().len(), good_len);
+
+        // later appends follow the last good frame
+        extend(&mut chain, &key, 1);
+        let blocks = journal.read().unwrap();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(
+            blocks.last().unwrap().hash(),
+            chain.blocks().last().unwrap().hash()
+        );
+
+        std::fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn a_corrupt_frame_is_an_error_not_a_truncation() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        let journal = temp_journal();
+
+        chain.enable_journal(journal.clone());
+        extend(&mut chain, &key, 3);
+
+        // garble the body of the second frame, keeping its length
+        let mut bytes = std::fs::read(journal.path()).unwrap();
+        let first_len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+        bytes[4 + first_len + 4] = 0xff;
+        bytes[4 + first_len + 5] = 0xff;
+        std::fs::write(journal.path(), &bytes).unwrap();
+
+        let error = journal.read().unwrap_err();
+        assert_eq!(error.kind(), IoErrorKind::InvalidData);
+        assert_eq!(std::fs::read(journal.path()).unwrap(), bytes);
+
+        std::fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn a_missing_journal_is_empty() {
+        let key = PrivateKey::new_key();
+        let journal = temp_journal();
+
+        assert!(journal.read().unwrap().is_empty());
+        assert_eq!(
+            regtest_chain(&key).replay_journal(&journal).unwrap(),
+            (0, 0)
+        );
+        assert!(!journal.path().exists());
+
+        journal.clear().unwrap();
+        assert!(journal.read().unwrap().is_empty());
+        std::fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn a_block_that_cant_be_journaled_is_kept_and_reported() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        // a directory can't be appended to
+        chain.enable_journal(BlockJournal::new(std::env::temp_dir()));
+
+        let block = next_block(&chain, &key, vec![]);
+        assert!(matches!(
+            chain.add_block(block.clone()),
+            Err(BtcError::JournalFailed(_))
+        ));
+        assert_eq!(chain.blocks().last().unwrap().hash(), block.hash());
+
+        // journaling is off until the chain is saved whole
+        assert!(chain.journal().is_none());
+        extend(&mut chain, &key, 1);
+    }
+}
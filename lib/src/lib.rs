@@ -1,27 +1,521 @@
 pub mod crypto;
 pub mod error;
+pub mod journal;
 pub mod network;
+pub mod noise;
 pub mod sha256;
 pub mod types;
 pub mod util;
 
+#[cfg(test)]
+mod test_util;
+
+pub use u256::U256;
+
+use std::fmt;
+use std::str::FromStr;
+
+use ecdsa::VerifyingKey;
+use k256::Secp256k1;
 use serde::{Deserialize, Serialize};
-use uint::construct_uint;
 
-construct_uint! {
-    #[derive(Serialize, Deserialize)]
-    pub struct U256(4);
+#[allow(clippy::manual_div_ceil)]
+mod u256 {
+    use serde::{Deserialize, Serialize};
+    use uint::construct_uint;
+
+    construct_uint! {
+        #[derive(Serialize, Deserialize)]
+        pub struct U256(4);
+    }
+
+    // Bitcoin's "nBits" form: one byte of size followed by a three byte
+    // mantissa, so only the top 23 bits of a value survive. Targets are
+    // kept in full; this is only a way of writing them down
+    impl U256 {
+        pub fn to_compact(&self) -> u32 {
+            let mut size = self.bits().div_ceil(8) as u32;
+
+            let mut compact = if size <= 3 {
+                (self.low_u64() << (8 * (3 - size))) as u32
+            } else {
+                (*self >> (8 * (size - 3)) as usize).low_u32()
+            };
+
+            // the top mantissa bit is a sign bit, so keep it clear
+            if compact & 0x0080_0000 != 0 {
+                compact >>= 8;
+                size += 1;
+            }
+
+            compact | (size << 24)
+        }
+
+        // negative and overflowing encodings decode to zero, which no hash
+        // can meet
+        pub fn from_compact(bits: u32) -> Self {
+            let size = bits >> 24;
+            let mantissa = Self::from(bits & 0x007f_ffff);
+
+            if bits & 0x0080_0000 != 0 {
+                return Self::zero();
+            }
+
+            if size <= 3 {
+                return mantissa >> (8 * (3 - size)) as usize;
+            }
+
+            let shift = 8 * (size - 3) as usize;
+            if mantissa.bits() + shift > 256 {
+                return Self::zero();
+            }
+
+            mantissa << shift
+        }
+    }
 }
 
 pub const INITIAL_REWARD: u64 = 50;
 pub const HALVING_INTERVAL: u64 = 210;
 pub const IDEAL_BLOCK_TIME: u64 = 10;
+// a larger target is an easier block, so this is the easiest target allowed:
+// difficulty adjustment never moves the target above it
 pub const MIN_TARGET: U256 = U256([
     0xFFFF_FFFF_FFFF_FFFF,
     0xFFFF_FFFF_FFFF_FFFF,
     0xFFFF_FFFF_FFFF_FFFF,
     0x0000_FFFF_FFFF_FFFF,
 ]);
-pub const DIFICULTY_UPDATE_INTERVAL: u64 = 50;
+// 256 times easier than MIN_TARGET, so test chains mine quickly
+pub const TEST_MIN_TARGET: U256 = U256([
+    0xFFFF_FFFF_FFFF_FFFF,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0xFFFF_FFFF_FFFF_FFFF,
+    0x00FF_FFFF_FFFF_FFFF,
+]);
+// 2026-01-01T00:00:00Z, fixed so every node builds the same genesis block
+pub const GENESIS_TIMESTAMP: i64 = 1_767_225_600;
+// the default genesis coinbase recipient; its x coordinate is
+// sha256("rsbtc genesis"), so nobody holds the private key and the first
+// reward can never be spent
+pub const GENESIS_PUBKEY: [u8; 33] = [
+    0x02, 0x17, 0x1b, 0x95, 0xc8, 0xa7, 0xda, 0xf7, 0xa9, 0x44, 0x91, 0x7e, 0x61, 0xe1, 0xf1, 0x8e,
+    0x85, 0x57, 0x91, 0x1b, 0x8f, 0xa9, 0x4b, 0xa3, 0x9c, 0xee, 0xeb, 0x43, 0x78, 0xa4, 0x52, 0x4e,
+    0x64,
+];
+// bumped whenever saved chains stop meaning the same thing; 1 identifies
+// blocks by their header hash instead of the hash of the whole block
+pub const CHAIN_FORMAT_VERSION: u32 = 1;
+pub const DIFFICULTY_UPDATE_INTERVAL: u64 = 50;
+#[deprecated(note = "misspelled, use DIFFICULTY_UPDATE_INTERVAL")]
+pub const DIFICULTY_UPDATE_INTERVAL: u64 = DIFFICULTY_UPDATE_INTERVAL;
 pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
+pub const MAX_FUTURE_DRIFT: u64 = 120;
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// smallest value a transaction may pay into one output; the coinbase is
+// exempt
+pub const DUST_THRESHOLD: u64 = 1000;
+pub const MAX_MEMPOOL_SIZE: usize = 5000;
+// transactions whose inputs are not known yet are held this long, in case a
+// block arrives that creates them
+pub const MAX_ORPHAN_TRANSACTION_AGE: u64 = 300;
+pub const MAX_ORPHAN_TRANSACTIONS: usize = 100;
+// blocks kept off the active chain, for reorganizations and for blocks that
+// arrive before their parent; past this the oldest are dropped
+pub const MAX_SIDE_BLOCKS: usize = 500;
+// blocks a coinbase output must be buried under before it can be spent, so
+// a reorganization cannot take back coins that were already passed on
+pub const COINBASE_MATURITY: u64 = 100;
 pub const BLOCK_TRANSACTION_CAP: usize = 20;
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+pub const MAX_BLOCKS_PER_MESSAGE: usize = 500;
+// what the blocks in one Blocks message may add up to; the count alone
+// allows far more than MAX_MESSAGE_SIZE, and this leaves room for the
+// message around them
+pub const MAX_BLOCKS_MESSAGE_BYTES: usize = MAX_MESSAGE_SIZE - MAX_BLOCK_SIZE;
+pub const ADDRESS_VERSION: u8 = 0x00;
+pub const WIF_VERSION: u8 = 0x80;
+// bumped whenever what a signature commits to changes; 2 added the inputs
+pub const SIGHASH_VERSION: u8 = 2;
+pub const PROTOCOL_VERSION: u32 = 3;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+// first version that understands Inv and GetData
+pub const INV_PROTOCOL_VERSION: u32 = 2;
+// first version that answers Ping
+pub const PING_PROTOCOL_VERSION: u32 = 3;
+
+// the reward on the main network; chains with other parameters use
+// ChainParams::reward_at_height
+pub fn reward_at_height(height: u64) -> u64 {
+    ChainParams::default().reward_at_height(height)
+}
+
+// the consensus values a chain follows; they are saved with the chain, so a
+// fast local testnet only needs different values, not a rebuild
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ChainParams {
+    pub network: Network,
+    pub ideal_block_time: u64,
+    pub difficulty_update_interval: u64,
+    pub halving_interval: u64,
+    pub min_target: U256,
+    // in whole coins; chains saved before this was a parameter used the
+    // main network's reward
+    #[serde(default = "default_initial_reward")]
+    pub initial_reward: u64,
+    // who the genesis coinbase pays; chains saved before this was a
+    // parameter paid GENESIS_PUBKEY
+    #[serde(default = "default_genesis_key")]
+    pub genesis_key: VerifyingKey<Secp256k1>,
+}
+
+fn default_initial_reward() -> u64 {
+    INITIAL_REWARD
+}
+
+fn default_genesis_key() -> VerifyingKey<Secp256k1> {
+    VerifyingKey::from_sec1_bytes(&GENESIS_PUBKEY).expect("BUG: GENESIS_PUBKEY is a valid key")
+}
+
+impl ChainParams {
+    // the only block a chain with these parameters can start from
+    pub fn genesis_hash(&self) -> sha256::Hash {
+        types::Block::genesis(self).hash()
+    }
+
+    pub fn reward_at_height(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval;
+
+        // after 64 halvings every bit has been shifted out
+        u32::try_from(halvings)
+            .ok()
+            .zip(self.initial_reward.checked_mul(10u64.pow(8)))
+            .and_then(|(halvings, reward)| reward.checked_shr(halvings))
+            .unwrap_or(0)
+    }
+
+    // zero intervals would divide by zero, no hash meets a zero target and
+    // the reward is counted in satoshis, which must fit in a u64;
+    // the main network may be made harder but never easier, which keeps
+    // regtest-style targets off it
+    pub fn validate(&self) -> error::Result<()> {
+        if self.ideal_block_time == 0
+            || self.difficulty_update_interval == 0
+            || self.halving_interval == 0
+            || self.initial_reward.checked_mul(10u64.pow(8)).is_none()
+            || self.min_target.is_zero()
+            || (self.network == Network::Main && self.min_target > MIN_TARGET)
+        {
+            return Err(error::BtcError::InvalidChainParams);
+        }
+
+        Ok(())
+    }
+
+    // every hash meets the target and it never moves, so blocks are found on
+    // the first try
+    pub fn is_regtest(&self) -> bool {
+        self.network == Network::Regtest
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Network::Main.params()
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum Network {
+    #[default]
+    Main,
+    Test,
+    Regtest,
+}
+
+impl Network {
+    pub fn params(self) -> ChainParams {
+        let main = ChainParams {
+            network: Network::Main,
+            ideal_block_time: IDEAL_BLOCK_TIME,
+            difficulty_update_interval: DIFFICULTY_UPDATE_INTERVAL,
+            halving_interval: HALVING_INTERVAL,
+            min_target: MIN_TARGET,
+            initial_reward: INITIAL_REWARD,
+            genesis_key: default_genesis_key(),
+        };
+
+        match self {
+            Network::Main => main,
+            Network::Test => ChainParams {
+                network: Network::Test,
+                min_target: TEST_MIN_TARGET,
+                ..main
+            },
+            Network::Regtest => ChainParams {
+                network: Network::Regtest,
+                min_target: U256::MAX,
+                ..main
+            },
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Network::Main => "main",
+            Network::Test => "test",
+            Network::Regtest => "regtest",
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Network {
+    type Err = error::BtcError;
+
+    fn from_str(s: &str) -> error::Result<Self> {
+        match s {
+            "main" => Ok(Network::Main),
+            "test" => Ok(Network::Test),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(error::BtcError::UnknownNetwork(s.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::sha256::Hash;
+    use crate::test_util::*;
+    use crate::types::{BlockChain, BlockHeader, CompactBlockHeader};
+
+    #[test]
+    fn reward_halves_and_runs_out_without_panicking() {
+        let params = Network::Main.params();
+        let initial = INITIAL_REWARD * 100_000_000;
+
+        assert_eq!(params.reward_at_height(0), initial);
+        assert_eq!(params.reward_at_height(HALVING_INTERVAL), initial / 2);
+        assert_eq!(
+            params.reward_at_height(HALVING_INTERVAL * 10),
+            initial >> 10
+        );
+
+        for halvings in [63, 64, 65, 1000] {
+            assert_eq!(params.reward_at_height(HALVING_INTERVAL * halvings), 0);
+        }
+        assert_eq!(params.reward_at_height(u64::MAX), 0);
+        assert_eq!(reward_at_height(HALVING_INTERVAL * 64), 0);
+    }
+
+    #[test]
+    fn a_custom_schedule_sets_the_reward() {
+        let params = ChainParams {
+            initial_reward: 10,
+            halving_interval: 5,
+            ..Network::Regtest.params()
+        };
+        assert!(params.validate().is_ok());
+
+        assert_eq!(params.reward_at_height(0), 10 * 100_000_000);
+        assert_eq!(params.reward_at_height(4), 10 * 100_000_000);
+        assert_eq!(params.reward_at_height(5), 5 * 100_000_000);
+        assert_eq!(params.reward_at_height(10), 250_000_000);
+    }
+
+    #[test]
+    fn a_reward_too_large_for_satoshis_is_refused() {
+        // the largest whole-coin reward that still fits in a u64
+        let largest = u64::MAX / 100_000_000;
+        let params = ChainParams {
+            initial_reward: largest,
+            ..Network::Regtest.params()
+        };
+        assert!(params.validate().is_ok());
+
+        let params = ChainParams {
+            initial_reward: largest + 1,
+            ..params
+        };
+        assert!(matches!(
+            params.validate(),
+            Err(error::BtcError::InvalidChainParams)
+        ));
+        // and it pays nothing rather than overflowing if used anyway
+        assert_eq!(params.reward_at_height(0), 0);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn the_misspelled_interval_is_an_alias() {
+        assert_eq!(DIFFICULTY_UPDATE_INTERVAL, DIFICULTY_UPDATE_INTERVAL);
+    }
+
+    #[test]
+    fn compact_form_matches_bitcoin() {
+        let vectors = [
+            (0x1d00_ffff, U256::from(0xffff) << 208),
+            (0x0500_9234, U256::from(0x9234_0000u64)),
+            (0x0212_3400, U256::from(0x1234)),
+            (0x0112_0000, U256::from(0x12)),
+        ];
+
+        for (bits, value) in vectors {
+            assert_eq!(U256::from_compact(bits), value);
+            assert_eq!(value.to_compact(), bits);
+        }
+
+        // a set sign bit, or a value past 256 bits, decodes to zero
+        assert_eq!(U256::from_compact(0x0492_3456), U256::zero());
+        assert_eq!(U256::from_compact(0xff12_3456), U256::zero());
+    }
+
+    #[test]
+    fn compact_round_trip_rounds_down_and_then_holds() {
+        // MIN_TARGET scaled the way retargeting scales it, by a window that
+        // took some fraction of the ideal time
+        let adjusted = [(1, 4), (2, 3), (493, 500), (7, 11), (1, 1000)]
+            .map(|(num, den)| MIN_TARGET / U256::from(den) * U256::from(num));
+
+        let targets = [MIN_TARGET, TEST_MIN_TARGET, U256::one(), U256::MAX]
+            .into_iter()
+            .chain(adjusted);
+
+        for target in targets {
+            let rounded = U256::from_compact(target.to_compact());
+
+            assert!(rounded <= target);
+            // only the bits below a 16-bit mantissa can be lost
+            assert!(target - rounded <= target >> 15);
+            assert_eq!(U256::from_compact(rounded.to_compact()), rounded);
+
+            // the reconstructed value agrees with the full one on either
+            // side of itself, and is never easier
+            let as_hash = |value: U256| Hash::from_hex(&format!("{:064x}", value)).unwrap();
+            assert!(as_hash(rounded).matches_target(rounded));
+            assert!(as_hash(rounded).matches_target(target));
+            if rounded < U256::MAX {
+                let above = as_hash(rounded + 1);
+                assert!(!above.matches_target(rounded));
+                assert_eq!(above.matches_target(target), rounded < target);
+            }
+        }
+    }
+
+    #[test]
+    fn compact_headers_keep_their_hash_or_refuse() {
+        let header = |target| {
+            BlockHeader::new(
+                chrono::Utc::now(),
+                7,
+                Hash::zero(),
+                crate::util::MerkleRoot::calculate(&[]),
+                target,
+            )
+        };
+
+        let exact = header(U256::from(0xffff) << 208);
+        let compact = CompactBlockHeader::try_from(&exact).unwrap();
+        assert_eq!(compact.bits, 0x1d00_ffff);
+
+        let restored = BlockHeader::from(compact);
+        assert_eq!(restored.target, exact.target);
+        assert_eq!(restored.hash(), exact.hash());
+
+        // MIN_TARGET has no exact compact form
+        assert!(CompactBlockHeader::try_from(&header(MIN_TARGET)).is_err());
+    }
+
+    #[test]
+    fn blocks_past_the_last_halving_pay_nothing() {
+        let params = ChainParams {
+            halving_interval: 1,
+            ..Network::Regtest.params()
+        };
+        let key = PrivateKey::new_key();
+        let mut chain = BlockChain::from_genesis(params);
+        extend(&mut chain, &key, 70);
+
+        assert_eq!(chain.calculate_block_reward(), 0);
+
+        // the coinbase is checked against the same helper
+        let mut block = next_block(&chain, &key, vec![]);
+        block.transactions[0].outputs[0].value = 1;
+        block.header.merkle_root = crate::util::MerkleRoot::calculate(&block.transactions);
+        assert!(matches!(
+            chain.add_block(block),
+            Err(error::BtcError::CoinbaseMismatch)
+        ));
+
+        chain.add_block(next_block(&chain, &key, vec![])).unwrap();
+    }
+
+    #[test]
+    fn regtest_blocks_need_no_mining() {
+        let params = Network::Regtest.params();
+        let mut header = BlockHeader::new(
+            chrono::Utc::now(),
+            0,
+            Hash::zero(),
+            crate::util::MerkleRoot::calculate(&[]),
+            params.min_target,
+        );
+        assert!(header.mine(0));
+        assert_eq!(header.nonce, 0);
+    }
+
+    #[test]
+    fn main_never_takes_a_regtest_target() {
+        let main = ChainParams {
+            min_target: U256::MAX,
+            ..Network::Main.params()
+        };
+        assert!(matches!(
+            main.validate(),
+            Err(error::BtcError::InvalidChainParams)
+        ));
+
+        let key = PrivateKey::new_key();
+        let mut chain = BlockChain::from_genesis(Network::Main.params());
+        let genesis = chain.blocks().next().unwrap().clone();
+
+        let mut block = block_on(&chain, &genesis, 1, &key, vec![]);
+        block.header.target = U256::MAX;
+        assert!(matches!(
+            chain.add_block(block.clone()),
+            Err(error::BtcError::UnexpectedTarget)
+        ));
+
+        // nor as an orphan, whose required target isn't known yet
+        let mut orphan = block.clone();
+        orphan.header.prev_block_hash = block.hash();
+        assert!(matches!(
+            chain.add_block(orphan.clone()),
+            Err(error::BtcError::UnexpectedTarget)
+        ));
+
+        // nor in a batch of headers ahead of the bodies
+        let headers = [block.header, orphan.header];
+        assert!(matches!(
+            BlockHeader::verify_chain(chain.params(), genesis.hash(), &headers),
+            Err(error::BtcError::UnexpectedTarget)
+        ));
+
+        // where regtest takes the same headers
+        let mut regtest = BlockChain::from_genesis(Network::Regtest.params());
+        let genesis = regtest.blocks().next().unwrap().clone();
+        let block = block_on(&regtest, &genesis, 1, &key, vec![]);
+        let headers = [block.header.clone()];
+        BlockHeader::verify_chain(regtest.params(), genesis.hash(), &headers).unwrap();
+        regtest.add_block(block).unwrap();
+    }
+}
@@ -1,22 +1,53 @@
+use bytes::{Buf, BytesMut};
 use serde::{Deserialize, Serialize};
-use std::io::{Error as IoError, Read, Write};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{
+    U256,
     crypto::PublicKey,
-    types::{Block, Transaction, TransactionOutput},
+    sha256::Hash,
+    types::{Block, BlockHeader, Transaction, TransactionOutput},
+    util::MerkleRoot,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
+    Version {
+        protocol_version: u32,
+        user_agent: String,
+        best_height: u64,
+    },
+
     FetchUTXOs(PublicKey),
 
     UTXOs(Vec<(TransactionOutput, bool)>),
 
+    FetchUTXO(Hash),
+
+    UTXOStatus(Option<(TransactionOutput, bool)>),
+
     SubmitTransaction(Transaction),
 
+    SubmitTransactionResult {
+        accepted: bool,
+        reason: Option<String>,
+    },
+
+    // asks whether SubmitTransaction would be accepted, without submitting
+    TestTransaction(Transaction),
+
+    TestResult {
+        accepted: bool,
+        reason: Option<String>,
+    },
+
     NewTransaction(Transaction),
 
+    FetchMempool(Option<PublicKey>),
+
+    MempoolResponse(Vec<Transaction>),
+
     FetchTemplate(PublicKey),
 
     Template(Block),
@@ -35,9 +66,78 @@ pub enum Message {
 
     Difference(i32),
 
+    AskWork,
+
+    Work(U256),
+
+    ChainInfo,
+
+    ChainInfoResponse {
+        height: u64,
+        tip_hash: Hash,
+        target: U256,
+        difficulty: f64,
+        mempool_size: usize,
+        total_work: U256,
+    },
+
+    FetchMerkleProof {
+        block_height: usize,
+        tx_hash: Hash,
+    },
+
+    MerkleProof {
+        index: usize,
+        proof: Vec<Hash>,
+        root: MerkleRoot,
+    },
+
     FetchBlock(usize),
 
+    FetchBlockByHash(Hash),
+
+    FetchHeaders {
+        start_height: usize,
+        count: usize,
+    },
+
+    Headers(Vec<BlockHeader>),
+
+    FetchBlocks {
+        start: usize,
+        count: usize,
+    },
+
+    Blocks(Vec<Block>),
+
     NewBlock(Block),
+
+    // the body of the block at this height was pruned
+    BlockUnavailable(usize),
+
+    FetchTransaction(Hash),
+
+    TransactionLocation {
+        block_height: u64,
+        index: usize,
+        tx: Transaction,
+    },
+
+    TransactionNotFound(Hash),
+
+    // transaction hashes offered to a peer, which answers with the ones it
+    // wants the bodies of
+    Inv(Vec<Hash>),
+
+    GetData(Vec<Hash>),
+
+    // keepalive; the peer echoes the nonce back in a Pong
+    Ping(u64),
+
+    Pong(u64),
+
+    // tells a peer that the message it sent was not acceptable
+    Error(String),
 }
 
 impl Message {
@@ -60,14 +160,42 @@ impl Message {
     }
 
     pub fn recv(stream: &mut impl Read) -> Result<Self, ciborium::de::Error<IoError>> {
+        Self::recv_with_limit(stream, crate::MAX_MESSAGE_SIZE)
+    }
+
+    pub fn recv_with_limit(
+        stream: &mut impl Read,
+        max: usize,
+    ) -> Result<Self, ciborium::de::Error<IoError>> {
         let mut len_bytes = [0u8; 8];
         stream.read_exact(&mut len_bytes)?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
+        let len = Self::check_len(u64::from_be_bytes(len_bytes), max)?;
         let mut bytes = vec![0u8; len];
         stream.read_exact(&mut bytes)?;
         Self::decode(&bytes)
     }
 
+    // decodes one length-prefixed message from the front of `buf`, or returns
+    // None if the buffer does not hold a complete frame yet
+    pub fn try_decode(buf: &mut BytesMut) -> Result<Option<Self>, ciborium::de::Error<IoError>> {
+        if buf.len() < 8 {
+            return Ok(None);
+        }
+
+        let mut len_bytes = [0u8; 8];
+        len_bytes.copy_from_slice(&buf[..8]);
+        let len = Self::check_len(u64::from_be_bytes(len_bytes), crate::MAX_MESSAGE_SIZE)?;
+
+        if buf.len() < 8 + len {
+            buf.reserve(8 + len - buf.len());
+            return Ok(None);
+        }
+
+        buf.advance(8);
+        let data = buf.split_to(len);
+        Self::decode(&data).map(Some)
+    }
+
     pub async fn send_async(
         &self,
         stream: &mut (impl AsyncWrite + Unpin),
@@ -76,17 +204,164 @@ impl Message {
         let len = bytes.len() as u64;
         stream.write_all(&len.to_be_bytes()).await?;
         stream.write_all(&bytes).await?;
+        // encrypted streams may hold back the end of a frame until flushed
+        stream.flush().await?;
         Ok(())
     }
 
     pub async fn recv_async(
         stream: &mut (impl AsyncRead + Unpin),
+    ) -> Result<Self, ciborium::de::Error<IoError>> {
+        Self::recv_async_with_limit(stream, crate::MAX_MESSAGE_SIZE).await
+    }
+
+    pub async fn recv_async_with_limit(
+        stream: &mut (impl AsyncRead + Unpin),
+        max: usize,
     ) -> Result<Self, ciborium::de::Error<IoError>> {
         let mut len_bytes = [0u8; 8];
         stream.read_exact(&mut len_bytes).await?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
+        let len = Self::check_len(u64::from_be_bytes(len_bytes), max)?;
         let mut data = vec![0u8; len];
         stream.read_exact(&mut data).await?;
         Self::decode(&data)
     }
+
+    pub fn is_compatible_version(protocol_version: u32) -> bool {
+        protocol_version >= crate::MIN_PROTOCOL_VERSION
+    }
+
+    // sends our version and waits for the peer's, returning the negotiated
+    // protocol version
+    pub async fn handshake_async(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        user_agent: &str,
+        best_height: u64,
+    ) -> Result<u32, IoError> {
+        Self::version(user_agent, best_height)
+            .send_async(stream)
+            .await
+            .map_err(|e| IoError::other(e.to_string()))?;
+
+        let message = Self::recv_async(stream)
+            .await
+            .map_err(|e| IoError::other(e.to_string()))?;
+
+        Self::negotiate(message)
+    }
+
+    // the accepting side of handshake_async: the peer must open with its
+    // version before anything else
+    pub async fn accept_handshake_async(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        user_agent: &str,
+        best_height: u64,
+    ) -> Result<u32, IoError> {
+        let message = Self::recv_async(stream)
+            .await
+            .map_err(|e| IoError::other(e.to_string()))?;
+
+        let version = Self::negotiate(message)?;
+
+        Self::version(user_agent, best_height)
+            .send_async(stream)
+            .await
+            .map_err(|e| IoError::other(e.to_string()))?;
+
+        Ok(version)
+    }
+
+    fn version(user_agent: &str, best_height: u64) -> Self {
+        Self::Version {
+            protocol_version: crate::PROTOCOL_VERSION,
+            user_agent: user_agent.to_string(),
+            best_height,
+        }
+    }
+
+    fn negotiate(message: Self) -> Result<u32, IoError> {
+        match message {
+            Self::Version {
+                protocol_version, ..
+            } if Self::is_compatible_version(protocol_version) => {
+                Ok(protocol_version.min(crate::PROTOCOL_VERSION))
+            }
+
+            Self::Version {
+                protocol_version, ..
+            } => Err(IoError::new(
+                IoErrorKind::Unsupported,
+                format!("unsupported protocol version {}", protocol_version),
+            )),
+
+            _ => Err(IoError::new(
+                IoErrorKind::InvalidData,
+                "expected a version message",
+            )),
+        }
+    }
+
+    // reject oversized length prefixes before allocating the buffer
+    fn check_len(len: u64, max: usize) -> Result<usize, ciborium::de::Error<IoError>> {
+        match usize::try_from(len) {
+            Ok(len) if len <= max => Ok(len),
+            _ => Err(IoError::new(
+                IoErrorKind::InvalidData,
+                format!("message length {} exceeds limit of {} bytes", len, max),
+            )
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn a_frame_over_the_limit_is_refused_before_reading_it() {
+        let message = Message::Error("hello".to_string());
+        let mut frame = vec![];
+        message.send(&mut frame).unwrap();
+        let len = frame.len() - 8;
+
+        let error = Message::recv_with_limit(&mut Cursor::new(&frame), len - 1).unwrap_err();
+        assert!(error.to_string().contains("exceeds limit"));
+        assert!(Message::recv_with_limit(&mut Cursor::new(&frame), len).is_ok());
+
+        // only the prefix is there: a reader that allocated and read the
+        // body would fail with an unexpected eof, or on the allocation
+        let prefix = u64::MAX.to_be_bytes();
+        let error = Message::recv_with_limit(&mut Cursor::new(&prefix), 16).unwrap_err();
+        assert!(error.to_string().contains("exceeds limit"));
+    }
+
+    #[test]
+    fn a_message_is_decoded_only_once_all_of_it_has_arrived() {
+        let (first, second) = (Message::Ping(7), Message::Error("bye".to_string()));
+        let mut frames = vec![];
+        first.send(&mut frames).unwrap();
+        let first_len = frames.len();
+        second.send(&mut frames).unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut decoded = vec![];
+
+        for (fed, byte) in frames.iter().enumerate() {
+            buf.extend_from_slice(&[*byte]);
+
+            if let Some(message) = Message::try_decode(&mut buf).unwrap() {
+                decoded.push((fed + 1, message));
+            }
+        }
+
+        // each one exactly when its last byte came in, with nothing left over
+        assert_eq!(decoded.len(), 2);
+        assert!(matches!(decoded[0], (len, Message::Ping(7)) if len == first_len));
+        assert!(
+            matches!(&decoded[1], (len, Message::Error(e)) if *len == frames.len() && e == "bye")
+        );
+        assert!(buf.is_empty());
+    }
 }
@@ -0,0 +1,288 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use bytes::{Buf, BytesMut};
+use snow::{Builder, HandshakeState, TransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+// a plaintext connection opens with the big-endian length of its first
+// message, whose top byte is always zero, so a preamble starting with 0xff
+// cannot be mistaken for one
+pub const NOISE_PREAMBLE: &[u8; 6] = b"\xffnoise";
+const MAX_NOISE_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_PLAINTEXT: usize = MAX_NOISE_MESSAGE - TAG_LEN;
+
+// an encrypted stream over `inner`: every frame is a two byte length
+// followed by a Noise transport message. Both sides use throwaway static
+// keys that nobody checks, so the channel is unauthenticated: it only
+// protects against passive eavesdropping, and anyone who can intercept the
+// connection can run a handshake with each side and read or change
+// everything in between
+pub struct NoiseStream<S> {
+    inner: S,
+    transport: TransportState,
+    read_raw: BytesMut,
+    read_plain: BytesMut,
+    write_raw: BytesMut,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> NoiseStream<S> {
+    pub async fn connect(mut inner: S) -> IoResult<Self> {
+        inner.write_all(NOISE_PREAMBLE).await?;
+
+        let mut handshake = handshake_state(true)?;
+        write_handshake(&mut inner, &mut handshake).await?;
+        read_handshake(&mut inner, &mut handshake).await?;
+        write_handshake(&mut inner, &mut handshake).await?;
+
+        Self::new(inner, handshake)
+    }
+
+    // the accepting side; expects the preamble to still be unread
+    pub async fn accept(mut inner: S) -> IoResult<Self> {
+        let mut preamble = [0u8; NOISE_PREAMBLE.len()];
+        inner.read_exact(&mut preamble).await?;
+
+        if &preamble != NOISE_PREAMBLE {
+            return Err(IoError::new(
+                IoErrorKind::InvalidData,
+                "Missing Noise preamble",
+            ));
+        }
+
+        let mut handshake = handshake_state(false)?;
+        read_handshake(&mut inner, &mut handshake).await?;
+        write_handshake(&mut inner, &mut handshake).await?;
+        read_handshake(&mut inner, &mut handshake).await?;
+
+        Self::new(inner, handshake)
+    }
+
+    fn new(inner: S, handshake: HandshakeState) -> IoResult<Self> {
+        Ok(NoiseStream {
+            inner,
+            transport: handshake.into_transport_mode().map_err(noise_error)?,
+            read_raw: BytesMut::new(),
+            read_plain: BytesMut::new(),
+            write_raw: BytesMut::new(),
+        })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    // moves one complete frame from `read_raw` into `read_plain`
+    fn decrypt_frame(&mut self) -> IoResult<bool> {
+        if self.read_raw.len() < 2 {
+            return Ok(false);
+        }
+
+        let len = u16::from_be_bytes([self.read_raw[0], self.read_raw[1]]) as usize;
+        if self.read_raw.len() < 2 + len {
+            return Ok(false);
+        }
+
+        self.read_raw.advance(2);
+        let frame = self.read_raw.split_to(len);
+
+        let mut plain = vec![0u8; len];
+        let read = self
+            .transport
+            .read_message(&frame, &mut plain)
+            .map_err(noise_error)?;
+        self.read_plain.extend_from_slice(&plain[..read]);
+
+        Ok(true)
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        while !self.write_raw.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_raw))?;
+
+            if written == 0 {
+                return Poll::Ready(Err(IoErrorKind::WriteZero.into()));
+            }
+
+            self.write_raw.advance(written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.read_plain.is_empty() {
+                let len = this.read_plain.len().min(buf.remaining());
+                buf.put_slice(&this.read_plain.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.decrypt_frame()? {
+                continue;
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+
+            if chunk_buf.filled().is_empty() {
+                return if this.read_raw.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(IoErrorKind::UnexpectedEof.into()))
+                };
+            }
+
+            this.read_raw.extend_from_slice(chunk_buf.filled());
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_drain(cx))?;
+
+        let plain = &buf[..buf.len().min(MAX_PLAINTEXT)];
+        let mut frame = vec![0u8; plain.len() + TAG_LEN];
+        let len = this
+            .transport
+            .write_message(plain, &mut frame)
+            .map_err(noise_error)?;
+
+        this.write_raw
+            .extend_from_slice(&(len as u16).to_be_bytes());
+        this.write_raw.extend_from_slice(&frame[..len]);
+
+        // the frame is accepted either way; whatever the socket does not take
+        // now goes out on the next write or flush
+        let _ = this.poll_drain(cx)?;
+
+        Poll::Ready(Ok(plain.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn handshake_state(initiator: bool) -> IoResult<HandshakeState> {
+    let builder = Builder::new(NOISE_PARAMS.parse().map_err(noise_error)?);
+    let keypair = builder.generate_keypair().map_err(noise_error)?;
+    let builder = builder
+        .local_private_key(&keypair.private)
+        .map_err(noise_error)?;
+
+    if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    }
+    .map_err(noise_error)
+}
+
+async fn write_handshake(
+    stream: &mut (impl AsyncWrite + Unpin),
+    handshake: &mut HandshakeState,
+) -> IoResult<()> {
+    let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+    let len = handshake
+        .write_message(&[], &mut message)
+        .map_err(noise_error)?;
+
+    stream.write_all(&(len as u16).to_be_bytes()).await?;
+    stream.write_all(&message[..len]).await?;
+    stream.flush().await
+}
+
+async fn read_handshake(
+    stream: &mut (impl AsyncRead + Unpin),
+    handshake: &mut HandshakeState,
+) -> IoResult<()> {
+    let mut len_bytes = [0u8; 2];
+    stream.read_exact(&mut len_bytes).await?;
+
+    let mut message = vec![0u8; u16::from_be_bytes(len_bytes) as usize];
+    stream.read_exact(&mut message).await?;
+
+    let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+    handshake
+        .read_message(&message, &mut payload)
+        .map_err(noise_error)?;
+
+    Ok(())
+}
+
+fn noise_error(e: snow::Error) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Message;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn messages_cross_a_noise_stream_both_ways() {
+        let (client, server) = duplex(4096);
+        let accepted = tokio::spawn(NoiseStream::accept(server));
+        let mut client = NoiseStream::connect(client).await.unwrap();
+        let mut server = accepted.await.unwrap().unwrap();
+
+        // larger than one Noise message, so it spans several frames
+        let long = "x".repeat(3 * MAX_PLAINTEXT);
+        let sent = tokio::spawn(async move {
+            Message::Error(long).send_async(&mut client).await.unwrap();
+            client
+        });
+
+        let received = Message::recv_async(&mut server).await.unwrap();
+        let mut client = sent.await.unwrap();
+        assert!(matches!(received, Message::Error(text) if text.len() == 3 * MAX_PLAINTEXT));
+
+        Message::FetchMempool(None)
+            .send_async(&mut server)
+            .await
+            .unwrap();
+        assert!(matches!(
+            Message::recv_async(&mut client).await.unwrap(),
+            Message::FetchMempool(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_plaintext_peer_is_refused() {
+        let (mut client, server) = duplex(4096);
+        let accepted = tokio::spawn(NoiseStream::accept(server));
+
+        Message::FetchMempool(None)
+            .send_async(&mut client)
+            .await
+            .unwrap();
+
+        let error = accepted.await.unwrap().err().unwrap();
+        assert_eq!(error.kind(), IoErrorKind::InvalidData);
+    }
+}
@@ -1,9 +1,11 @@
-use std::fmt::Display;
+use std::fmt::{Display, LowerHex};
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 use sha256::digest;
 
 use crate::U256;
+use crate::error::{BtcError, Result};
 
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
 pub struct Hash(U256);
@@ -39,10 +41,66 @@ impl Hash {
     pub fn as_bytes(&self) -> [u8; 32] {
         self.0.to_little_endian()
     }
+
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        if hex.len() != 64 {
+            return Err(BtcError::InvalidHash);
+        }
+
+        U256::from_str_radix(hex, 16)
+            .map(Hash)
+            .map_err(|_| BtcError::InvalidHash)
+    }
+}
+
+impl LowerHex for Hash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:0>64}", format!("{:x}", self.0))
+    }
 }
 
 impl Display for Hash {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:x}", self.0)
+        write!(f, "{:x}", self)
+    }
+}
+
+impl FromStr for Hash {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_hex(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trips_at_full_width() {
+        let hash = Hash::hash(&"some data");
+        let hex = hash.to_string();
+        assert_eq!(hex.len(), 64);
+        assert_eq!(hex.parse::<Hash>().unwrap(), hash);
+
+        // leading zeroes are kept, so every hash prints the same width
+        assert_eq!(Hash::zero().to_string(), "0".repeat(64));
+        assert_eq!(format!("{:x}", Hash::zero()), "0".repeat(64));
+        assert_eq!(Hash::from_hex(&"0".repeat(64)).unwrap(), Hash::zero());
+    }
+
+    #[test]
+    fn hex_of_the_wrong_length_or_alphabet_is_rejected() {
+        let hex = Hash::hash(&"some data").to_string();
+
+        for bad in [
+            String::new(),
+            hex[1..].to_owned(),
+            format!("{hex}0"),
+            "g".repeat(64),
+        ] {
+            assert!(matches!(Hash::from_hex(&bad), Err(BtcError::InvalidHash)));
+        }
     }
 }
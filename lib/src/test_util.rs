@@ -0,0 +1,91 @@
+// builders shared by the unit tests; they work on regtest chains, where every
+// hash meets the target, so no block needs real mining
+use chrono::Duration;
+use uuid::Uuid;
+
+use crate::{
+    Network,
+    crypto::{PrivateKey, PublicKey, Signature},
+    types::{Block, BlockChain, BlockHeader, Transaction, TransactionInput, TransactionOutput},
+    util::MerkleRoot,
+};
+
+// the genesis reward can't be spent, so the first block pays `key` instead
+pub fn regtest_chain(key: &PrivateKey) -> BlockChain {
+    let mut chain = BlockChain::from_genesis(Network::Regtest.params());
+    extend(&mut chain, key, 1);
+    chain
+}
+
+// a block on `prev` at `height`, a second after it, whose coinbase pays the
+// reward plus whatever `transactions` leave over in the chain's UTXO set
+pub fn block_on(
+    chain: &BlockChain,
+    prev: &Block,
+    height: u64,
+    key: &PrivateKey,
+    transactions: Vec<Transaction>,
+) -> Block {
+    let reward = chain.params().reward_at_height(height) + chain.calculate_fees(&transactions);
+    let mut transactions = transactions;
+    transactions.insert(
+        0,
+        Transaction::new(vec![], vec![output(reward, &key.public_key())]),
+    );
+
+    let mut header = BlockHeader::new(
+        prev.header.timestamp + Duration::seconds(1),
+        0,
+        prev.hash(),
+        MerkleRoot::calculate(&transactions),
+        chain.target(),
+    );
+    assert!(header.mine(1_000_000));
+
+    Block::new(header, transactions)
+}
+
+pub fn next_block(chain: &BlockChain, key: &PrivateKey, transactions: Vec<Transaction>) -> Block {
+    let tip = chain.blocks().last().expect("chain has a genesis block");
+    block_on(chain, tip, chain.block_height(), key, transactions)
+}
+
+// adds `count` empty blocks to the tip
+pub fn extend(chain: &mut BlockChain, key: &PrivateKey, count: usize) {
+    for _ in 0..count {
+        let block = next_block(chain, key, vec![]);
+        chain.add_block(block).expect("empty block connects");
+    }
+}
+
+pub fn output(value: u64, pubkey: &PublicKey) -> TransactionOutput {
+    TransactionOutput {
+        value,
+        unique_id: Uuid::new_v4(),
+        pubkey: pubkey.clone(),
+    }
+}
+
+// spends `prev` with `key`, paying `value` back to the same key
+pub fn spend(prev: &TransactionOutput, key: &PrivateKey, value: u64) -> Transaction {
+    let mut tx = Transaction::new(
+        vec![TransactionInput {
+            prev_tx_output_hash: prev.hash(),
+            signature: Signature::sign_input(
+                &Transaction::new(vec![], vec![]),
+                0,
+                &prev.hash(),
+                key,
+            ),
+        }],
+        vec![output(value, &key.public_key())],
+    );
+    tx.inputs[0].signature = Signature::sign_input(&tx, 0, &prev.hash(), key);
+    tx
+}
+
+// the output of the first block's coinbase, spendable once the chain is
+// COINBASE_MATURITY blocks past it
+pub fn first_output(chain: &BlockChain) -> TransactionOutput {
+    chain.blocks().nth(1).unwrap().transactions[0].outputs[0].clone()
+}
@@ -2,6 +2,6 @@ mod block;
 mod blockchain;
 mod transaction;
 
-pub use block::{Block, BlockHeader};
+pub use block::{Block, BlockHeader, CompactBlockHeader};
 pub use blockchain::BlockChain;
-pub use transaction::{Transaction, TransactionInput, TransactionOutput};
+pub use transaction::{Transaction, TransactionInput, TransactionOutput, fee_rate};
@@ -1,12 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 
 use crate::{
-    U256,
+    ChainParams, U256,
+    crypto::{PublicKey, Signature},
     error::{BtcError, Result},
     sha256::Hash,
     types::{Transaction, TransactionOutput},
@@ -27,92 +31,170 @@ impl Block {
         }
     }
 
+    // the header commits to the transactions through the merkle root, so a
+    // header alone is enough to link and verify a chain
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        self.header.hash()
+    }
+
+    // nothing in it depends on when or where it is built, only on the
+    // chain parameters, so every node agrees on it without a download
+    pub fn genesis(params: &ChainParams) -> Self {
+        let coinbase = Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                value: params.reward_at_height(0),
+                unique_id: Uuid::nil(),
+                pubkey: PublicKey(params.genesis_key),
+            }],
+        );
+        let transactions = vec![coinbase];
+
+        let header = BlockHeader::new(
+            DateTime::from_timestamp(crate::GENESIS_TIMESTAMP, 0).expect("BUG: impossible"),
+            0,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            params.min_target,
+        );
+
+        Self::new(header, transactions)
     }
 
+    // `immature` holds the coinbase outputs that cannot be spent at
+    // `predicted_block_height` yet. A transaction may spend the outputs of
+    // one earlier in the same block, but not of one after it
     pub fn verify_transactions(
         &self,
+        params: &ChainParams,
         predicted_block_height: u64,
         utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        immature: &HashSet<Hash>,
     ) -> Result<()> {
-        let mut inputs = HashMap::new();
+        let mut inputs = HashSet::new();
+        let mut created = HashMap::new();
+        // every output spent in the block, wherever it was created
+        let mut spent = HashMap::new();
 
         if self.transactions.is_empty() {
-            return Err(BtcError::InvalidTransaction);
+            return Err(BtcError::EmptyBlock);
         }
 
-        self.verify_coinbase_transaction(predicted_block_height, utxos)?;
+        self.verify_coinbase_transaction(params, predicted_block_height, utxos)?;
 
         for tx in self.transactions.iter().skip(1) {
-            let mut input_value = 0;
-            let mut output_value = 0;
+            if !tx.is_final(predicted_block_height) {
+                return Err(BtcError::TransactionLocked);
+            }
 
-            for input in &tx.inputs {
-                let prev_output = utxos
-                    .get(&input.prev_tx_output_hash)
-                    .map(|(_, output)| output);
+            let view = input_view(tx, utxos, &created);
+            tx.validate_unsigned(&view, immature)?;
 
-                if prev_output.is_none() {
-                    return Err(BtcError::InvalidTransaction);
+            for input in &tx.inputs {
+                if !inputs.insert(input.prev_tx_output_hash) {
+                    return Err(BtcError::DoubleSpend);
                 }
+            }
 
-                let prev_output = prev_output.unwrap();
+            spent.extend(view);
+            add_outputs(&mut created, tx);
+        }
 
-                if inputs.contains_key(&input.prev_tx_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
-                }
+        let checks = self
+            .transactions
+            .iter()
+            .skip(1)
+            .flat_map(|tx| tx.signature_checks(&spent))
+            .collect::<Vec<_>>();
 
-                if !input
-                    .signature
-                    .verify(&input.prev_tx_output_hash, &prev_output.pubkey)
-                {
-                    return Err(BtcError::InvalidSignature);
-                }
+        let batch = checks
+            .iter()
+            .map(|(message, signature, public_key)| (message, *signature, *public_key))
+            .collect::<Vec<_>>();
 
-                input_value += prev_output.value;
-                inputs.insert(input.prev_tx_output_hash, prev_output.clone());
+        if !Signature::verify_batch(&batch) {
+            // find the transaction at fault one signature at a time
+            for tx in self.transactions.iter().skip(1) {
+                tx.validate(&spent, immature)?;
             }
 
-            for output in &tx.outputs {
-                output_value += output.value;
+            return Err(BtcError::InvalidSignature);
+        }
+
+        Ok(())
+    }
+
+    // for diagnosing a rejected block: reports every failure rather than the
+    // first, so it is slower than verify_transactions and not used for
+    // consensus
+    pub fn verify_transactions_verbose(
+        &self,
+        params: &ChainParams,
+        predicted_block_height: u64,
+        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        immature: &HashSet<Hash>,
+    ) -> Vec<BtcError> {
+        if self.transactions.is_empty() {
+            return vec![BtcError::EmptyBlock];
+        }
+
+        let mut errors = vec![];
+        let mut inputs = HashSet::new();
+        let mut created = HashMap::new();
+
+        if let Err(e) = self.verify_coinbase_transaction(params, predicted_block_height, utxos) {
+            errors.push(e);
+        }
+
+        for tx in self.transactions.iter().skip(1) {
+            if !tx.is_final(predicted_block_height) {
+                errors.push(BtcError::TransactionLocked);
             }
 
-            if input_value < output_value {
-                return Err(BtcError::InvalidTransaction);
+            errors.extend(tx.validation_errors(&input_view(tx, utxos, &created), immature));
+
+            // spends repeated within one transaction were reported above
+            let tx_inputs = tx
+                .inputs
+                .iter()
+                .map(|input| input.prev_tx_output_hash)
+                .collect::<HashSet<_>>();
+
+            for input in tx_inputs {
+                if !inputs.insert(input) {
+                    errors.push(BtcError::DoubleSpend);
+                }
             }
+
+            add_outputs(&mut created, tx);
         }
 
-        Ok(())
+        errors
     }
 
     pub fn verify_coinbase_transaction(
         &self,
+        params: &ChainParams,
         predicted_block_height: u64,
         utxos: &HashMap<Hash, (bool, TransactionOutput)>,
     ) -> Result<()> {
         let coinbase_transaction = &self.transactions[0];
 
-        if !coinbase_transaction.inputs.is_empty() {
-            return Err(BtcError::InvalidTransaction);
-        }
-
-        if coinbase_transaction.outputs.is_empty() {
-            return Err(BtcError::InvalidTransaction);
+        if !coinbase_transaction.inputs.is_empty() || coinbase_transaction.outputs.is_empty() {
+            return Err(BtcError::InvalidCoinbase);
         }
 
         let miner_fees = self.calculate_miner_fees(utxos)?;
-        let block_reward = crate::INITIAL_REWARD * 10u64.pow(8)
-            / 2u64.pow((predicted_block_height / crate::HALVING_INTERVAL) as u32);
+        let block_reward = params.reward_at_height(predicted_block_height);
 
-        let total_coinbase_outputs: u64 = coinbase_transaction
+        let total_coinbase_outputs = coinbase_transaction
             .outputs
             .iter()
-            .map(|output| output.value)
-            .sum();
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+            .ok_or(BtcError::ValueOverflow)?;
 
-        if total_coinbase_outputs != block_reward + miner_fees {
-            return Err(BtcError::InvalidTransaction);
+        if Some(total_coinbase_outputs) != block_reward.checked_add(miner_fees) {
+            return Err(BtcError::CoinbaseMismatch);
         }
         Ok(())
     }
@@ -126,18 +208,20 @@ impl Block {
 
         for transaction in self.transactions.iter().skip(1) {
             for input in &transaction.inputs {
-                let prev_output = utxos
-                    .get(&input.prev_tx_output_hash)
-                    .map(|(_, output)| output);
-
-                if prev_output.is_none() {
-                    return Err(BtcError::InvalidTransaction);
-                }
-
-                let prev_output = prev_output.unwrap();
+                // an output of an earlier transaction in the block counts on
+                // both sides, so it adds nothing to the fees
+                let prev_output = outputs.get(&input.prev_tx_output_hash).or_else(|| {
+                    utxos
+                        .get(&input.prev_tx_output_hash)
+                        .map(|(_, output)| output)
+                });
+
+                let Some(prev_output) = prev_output else {
+                    return Err(BtcError::UnknownInput);
+                };
 
                 if inputs.contains_key(&input.prev_tx_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
+                    return Err(BtcError::DoubleSpend);
                 }
 
                 inputs.insert(input.prev_tx_output_hash, prev_output.clone());
@@ -145,16 +229,48 @@ impl Block {
 
             for output in &transaction.outputs {
                 if outputs.contains_key(&output.hash()) {
-                    return Err(BtcError::InvalidTransaction);
+                    return Err(BtcError::DuplicateOutput);
                 }
                 outputs.insert(output.hash(), output.clone());
             }
         }
 
-        let inputs_value: u64 = inputs.values().map(|output| output.value).sum();
-        let outputs_value: u64 = outputs.values().map(|output| output.value).sum();
+        let inputs_value = inputs
+            .values()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+            .ok_or(BtcError::ValueOverflow)?;
+        let outputs_value = outputs
+            .values()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+            .ok_or(BtcError::ValueOverflow)?;
+
+        inputs_value
+            .checked_sub(outputs_value)
+            .ok_or(BtcError::InsufficientInputValue)
+    }
+}
 
-        Ok(inputs_value - outputs_value)
+// the entries `tx` spends, looked up among the chain's unspent outputs and
+// those created earlier in the block; anything else is left out, so
+// validation reports it as an unknown input
+fn input_view(
+    tx: &Transaction,
+    utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+    created: &HashMap<Hash, (bool, TransactionOutput)>,
+) -> HashMap<Hash, (bool, TransactionOutput)> {
+    tx.inputs
+        .iter()
+        .filter_map(|input| {
+            let hash = input.prev_tx_output_hash;
+            let entry = created.get(&hash).or_else(|| utxos.get(&hash))?;
+            Some((hash, entry.clone()))
+        })
+        .collect()
+}
+
+fn add_outputs(created: &mut HashMap<Hash, (bool, TransactionOutput)>, tx: &Transaction) {
+    for output in &tx.outputs {
+        created.insert(output.hash(), (false, output.clone()));
     }
 }
 
@@ -179,6 +295,52 @@ pub struct BlockHeader {
     pub target: U256,
 }
 
+// a header written with its target in compact form. The header hash covers
+// the full target, so only headers whose target compact form represents
+// exactly can be written this way
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompactBlockHeader {
+    pub timestamp: DateTime<Utc>,
+    pub nonce: u64,
+    pub prev_block_hash: Hash,
+    pub merkle_root: MerkleRoot,
+    pub bits: u32,
+}
+
+impl TryFrom<&BlockHeader> for CompactBlockHeader {
+    type Error = BtcError;
+
+    // fails if the target would not survive the round trip, since that would
+    // change the header's hash
+    fn try_from(header: &BlockHeader) -> Result<Self> {
+        let bits = header.target.to_compact();
+
+        if U256::from_compact(bits) != header.target {
+            return Err(BtcError::InvalidBlockHeader);
+        }
+
+        Ok(Self {
+            timestamp: header.timestamp,
+            nonce: header.nonce,
+            prev_block_hash: header.prev_block_hash,
+            merkle_root: header.merkle_root,
+            bits,
+        })
+    }
+}
+
+impl From<CompactBlockHeader> for BlockHeader {
+    fn from(header: CompactBlockHeader) -> Self {
+        Self {
+            timestamp: header.timestamp,
+            nonce: header.nonce,
+            prev_block_hash: header.prev_block_hash,
+            merkle_root: header.merkle_root,
+            target: U256::from_compact(header.bits),
+        }
+    }
+}
+
 impl BlockHeader {
     pub fn new(
         timestamp: DateTime<Utc>,
@@ -200,6 +362,60 @@ impl BlockHeader {
         Hash::hash(self)
     }
 
+    pub fn is_too_far_in_future(&self) -> bool {
+        self.timestamp > Utc::now() + Duration::seconds(crate::MAX_FUTURE_DRIFT as i64)
+    }
+
+    // checks that `headers` extend `prev_hash` and carry valid proof of work
+    // at a target no easier than the network allows; the exact target and
+    // the median time rule are left to connect_block, which sees the whole
+    // chain, so here the median only covers the headers within this batch
+    pub fn verify_chain(
+        params: &ChainParams,
+        mut prev_hash: Hash,
+        headers: &[BlockHeader],
+    ) -> Result<()> {
+        let mut timestamps = vec![];
+
+        for header in headers {
+            if header.prev_block_hash != prev_hash {
+                return Err(BtcError::BadPrevHash);
+            }
+
+            if prev_hash == Hash::zero() {
+                if header.hash() != params.genesis_hash() {
+                    return Err(BtcError::InvalidGenesis);
+                }
+            } else {
+                if header.target > params.min_target {
+                    return Err(BtcError::UnexpectedTarget);
+                }
+
+                if !header.hash().matches_target(header.target) {
+                    return Err(BtcError::TargetNotMet);
+                }
+            }
+
+            if header.is_too_far_in_future() {
+                return Err(BtcError::FutureTimestamp);
+            }
+
+            let recent = &timestamps[timestamps.len().saturating_sub(crate::MEDIAN_TIME_SPAN)..];
+            if median_timestamp(recent).is_some_and(|median| header.timestamp <= median) {
+                return Err(BtcError::StaleTimestamp);
+            }
+
+            prev_hash = header.hash();
+            timestamps.push(header.timestamp);
+        }
+
+        Ok(())
+    }
+
+    pub fn work(&self) -> U256 {
+        U256::MAX / self.target.max(U256::one())
+    }
+
     pub fn mine(&mut self, steps: usize) -> bool {
         if self.hash().matches_target(self.target) {
             return true;
@@ -219,4 +435,267 @@ impl BlockHeader {
         }
         false
     }
+
+    pub fn mine_parallel(&self, threads: usize) -> Option<BlockHeader> {
+        self.mine_parallel_until(threads, &AtomicBool::new(false))
+    }
+
+    // each worker searches its own slice of the nonce space; when a slice is
+    // exhausted the worker moves to a timestamp no other worker will use
+    pub fn mine_parallel_until(&self, threads: usize, stop: &AtomicBool) -> Option<BlockHeader> {
+        let threads = threads.max(1) as u64;
+        let chunk = u64::MAX / threads;
+        let found = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            let workers = (0..threads)
+                .map(|worker| {
+                    let found = &found;
+                    let mut header = self.clone();
+                    let start = worker * chunk;
+                    let end = if worker == threads - 1 {
+                        u64::MAX
+                    } else {
+                        start + chunk - 1
+                    };
+                    header.nonce = start;
+
+                    scope.spawn(move || {
+                        let mut rollovers = 0;
+
+                        loop {
+                            if found.load(Ordering::Relaxed) || stop.load(Ordering::Relaxed) {
+                                return None;
+                            }
+
+                            if header.hash().matches_target(header.target) {
+                                found.store(true, Ordering::Relaxed);
+                                return Some(header);
+                            }
+
+                            if header.nonce == end {
+                                header.nonce = start;
+                                header.timestamp = self.timestamp
+                                    + Duration::milliseconds(
+                                        (rollovers * threads + worker + 1) as i64,
+                                    );
+                                rollovers += 1;
+                            } else {
+                                header.nonce += 1;
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            workers
+                .into_iter()
+                .filter_map(|worker| worker.join().ok().flatten())
+                .next()
+        })
+    }
+}
+
+pub(crate) fn median_timestamp(timestamps: &[DateTime<Utc>]) -> Option<DateTime<Utc>> {
+    let mut timestamps = timestamps.to_vec();
+    timestamps.sort();
+    timestamps.get(timestamps.len() / 2).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::test_util::*;
+
+    #[test]
+    fn outputs_above_inputs_are_an_error_not_a_wrap() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, crate::COINBASE_MATURITY as usize - 1);
+
+        let prev = first_output(&chain);
+        let tx = spend(&prev, &key, prev.value + 1);
+        let block = next_block(&chain, &key, vec![tx]);
+
+        assert!(matches!(
+            block.calculate_miner_fees(chain.utxos()),
+            Err(BtcError::InsufficientInputValue)
+        ));
+        assert!(matches!(
+            chain.add_block(block),
+            Err(BtcError::InsufficientInputValue)
+        ));
+    }
+
+    #[test]
+    fn fees_are_inputs_minus_outputs() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, crate::COINBASE_MATURITY as usize - 1);
+
+        let prev = first_output(&chain);
+        let tx = spend(&prev, &key, prev.value - 5000);
+        let block = next_block(&chain, &key, vec![tx]);
+
+        assert_eq!(block.calculate_miner_fees(chain.utxos()).unwrap(), 5000);
+        chain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn a_transaction_may_spend_an_earlier_one_in_the_block() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, crate::COINBASE_MATURITY as usize - 1);
+
+        // the child pays no fee, so the coinbase is the same whichever
+        // order they come in
+        let prev = first_output(&chain);
+        let parent = spend(&prev, &key, prev.value - 5000);
+        let child = spend(&parent.outputs[0], &key, parent.outputs[0].value);
+
+        let reversed = next_block(&chain, &key, vec![child.clone(), parent.clone()]);
+        assert!(matches!(
+            chain.add_block(reversed),
+            Err(BtcError::UnknownInput)
+        ));
+
+        let block = next_block(&chain, &key, vec![parent, child.clone()]);
+        assert_eq!(block.calculate_miner_fees(chain.utxos()).unwrap(), 5000);
+        chain.add_block(block).unwrap();
+
+        assert!(chain.utxos().contains_key(&child.outputs[0].hash()));
+        assert!(!chain.utxos().contains_key(&prev.hash()));
+    }
+
+    #[test]
+    fn verbose_verification_reports_every_failing_transaction() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, crate::COINBASE_MATURITY as usize);
+
+        // signed by someone other than the owner
+        let prev = first_output(&chain);
+        let forged = spend(&prev, &PrivateKey::new_key(), prev.value - 5000);
+
+        // outputs whose sum does not fit in a u64
+        let prev = chain.blocks().nth(2).unwrap().transactions[0].outputs[0].clone();
+        let mut overflowing = spend(&prev, &key, u64::MAX);
+        overflowing
+            .outputs
+            .push(output(u64::MAX, &key.public_key()));
+        overflowing.inputs[0].signature =
+            Signature::sign_input(&overflowing, 0, &prev.hash(), &key);
+
+        let mut block = next_block(&chain, &key, vec![]);
+        block.transactions.extend([forged, overflowing]);
+
+        let height = chain.block_height();
+        let errors = block.verify_transactions_verbose(
+            chain.params(),
+            height,
+            chain.utxos(),
+            &chain.immature_coinbase_outputs(height),
+        );
+        assert!(
+            errors
+                .iter()
+                .any(|e| matches!(e, BtcError::InvalidSignature))
+        );
+        assert!(errors.iter().any(|e| matches!(e, BtcError::ValueOverflow)));
+
+        // the consensus path stops at the first
+        assert!(chain.add_block(block).is_err());
+    }
+
+    #[test]
+    fn one_forged_input_among_many_rejects_the_block() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, crate::COINBASE_MATURITY as usize + 4);
+
+        let mut transactions = (1..=5)
+            .map(|height| {
+                let prev = chain.blocks().nth(height).unwrap().transactions[0].outputs[0].clone();
+                spend(&prev, &key, prev.value - 1000)
+            })
+            .collect::<Vec<_>>();
+
+        // signed over the right message, but by the wrong key
+        let prev = chain.blocks().nth(3).unwrap().transactions[0].outputs[0].clone();
+        transactions[2] = spend(&prev, &PrivateKey::new_key(), prev.value - 1000);
+
+        let height = chain.block_height();
+        let block = next_block(&chain, &key, transactions);
+        assert!(matches!(
+            chain.add_block(block),
+            Err(BtcError::InvalidSignature)
+        ));
+        assert_eq!(chain.block_height(), height);
+    }
+
+    #[test]
+    fn only_the_coinbase_may_have_no_inputs() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+
+        // pays nothing, so the coinbase amount is still right
+        let second_coinbase = Transaction::new(vec![], vec![output(0, &key.public_key())]);
+        let block = next_block(&chain, &key, vec![second_coinbase]);
+
+        assert!(matches!(
+            chain.add_block(block),
+            Err(BtcError::MissingInputs)
+        ));
+        assert_eq!(chain.block_height(), 2);
+    }
+
+    #[test]
+    fn a_header_mined_in_parallel_is_accepted() {
+        let key = PrivateKey::new_key();
+        let mut chain = crate::types::BlockChain::from_genesis(crate::Network::Main.params());
+        let genesis = chain.blocks().next().unwrap().clone();
+
+        // start from a nonce that misses, so the workers have to search
+        let mut block = block_on(&chain, &genesis, 1, &key, vec![]);
+        while block.header.hash().matches_target(block.header.target) {
+            block.header.nonce += 1;
+        }
+
+        let mined = block.header.mine_parallel(4).unwrap();
+        assert!(mined.hash().matches_target(mined.target));
+        assert_eq!(mined.prev_block_hash, block.header.prev_block_hash);
+        assert_eq!(mined.merkle_root, block.header.merkle_root);
+
+        block.header = mined;
+        chain.add_block(block).unwrap();
+        assert_eq!(chain.block_height(), 2);
+    }
+
+    #[test]
+    fn a_block_saved_as_json_loads_back_the_same() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, crate::COINBASE_MATURITY as usize - 1);
+
+        let prev = first_output(&chain);
+        let block = next_block(&chain, &key, vec![spend(&prev, &key, prev.value - 5000)]);
+
+        let mut json = vec![];
+        block.save_json(&mut json).unwrap();
+        let loaded = Block::load_json(json.as_slice()).unwrap();
+
+        // the hash covers the header and the merkle root the transactions,
+        // signatures included
+        assert_eq!(loaded.hash(), block.hash());
+        assert_eq!(
+            MerkleRoot::calculate(&loaded.transactions),
+            block.header.merkle_root
+        );
+
+        let (mut original, mut reloaded) = (vec![], vec![]);
+        block.save(&mut original).unwrap();
+        loaded.save(&mut reloaded).unwrap();
+        assert_eq!(original, reloaded);
+    }
 }
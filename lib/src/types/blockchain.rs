@@ -1,330 +1,2639 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 
+use std::fs::File;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
 
 use crate::{
-    U256,
+    CHAIN_FORMAT_VERSION, ChainParams, Network, U256,
+    crypto::PublicKey,
     error::{BtcError, Result},
+    journal::BlockJournal,
     sha256::Hash,
     types::{
-        block::Block,
-        transaction::{Transaction, TransactionOutput},
+        block::{Block, BlockHeader, median_timestamp},
+        transaction::{self, Transaction, TransactionOutput},
     },
-    util::{MerkleRoot, Savable},
+    util::{MerkleRoot, Savable, write_atomically},
 };
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BlockChain {
+    // CHAIN_FORMAT_VERSION when saved; missing in files from before it
+    #[serde(default)]
+    format: u32,
     utxos: HashMap<Hash, (bool, TransactionOutput)>,
+    // the hashes in `utxos` by the key they pay to
+    #[serde(default, skip_serializing)]
+    owners: HashMap<PublicKey, HashSet<Hash>>,
     target: U256,
     blocks: Vec<Block>,
     #[serde(default, skip_serializing)]
     mempool: Vec<(DateTime<Utc>, Transaction)>,
+    #[serde(default, skip_serializing)]
+    orphans: Vec<(DateTime<Utc>, Transaction)>,
+    #[serde(skip)]
+    side_blocks: SideBlocks,
+    #[serde(default, skip_serializing)]
+    total_work: U256,
+    #[serde(default, skip_serializing)]
+    block_index: HashMap<Hash, usize>,
+    // transaction hash to block height and index, only kept when enabled
+    #[serde(default, skip_serializing)]
+    tx_index: Option<HashMap<Hash, (u64, usize)>>,
+    #[serde(default)]
+    params: ChainParams,
+    // blocks below this height only keep their header
+    #[serde(default)]
+    pruned_height: u64,
+    // where accepted blocks are appended, when enabled
+    #[serde(skip)]
+    journal: Option<BlockJournal>,
 }
 
 impl BlockChain {
-    pub fn new() -> Self {
+    pub fn new(params: ChainParams) -> Self {
         BlockChain {
+            format: CHAIN_FORMAT_VERSION,
             blocks: Vec::new(),
             utxos: HashMap::new(),
-            target: crate::MIN_TARGET,
+            owners: HashMap::new(),
+            target: params.min_target,
             mempool: vec![],
+            orphans: vec![],
+            side_blocks: SideBlocks::default(),
+            total_work: U256::zero(),
+            block_index: HashMap::new(),
+            tx_index: None,
+            params,
+            pruned_height: 0,
+            journal: None,
         }
     }
 
+    pub fn with_network(network: Network) -> Self {
+        Self::new(network.params())
+    }
+
+    // a main network chain whose genesis coinbase pays `miner_pubkey`
+    pub fn with_genesis(miner_pubkey: PublicKey) -> Self {
+        Self::from_genesis(ChainParams {
+            genesis_key: miner_pubkey.0,
+            ..Network::Main.params()
+        })
+    }
+
+    pub fn from_genesis(params: ChainParams) -> Self {
+        let mut blockchain = Self::new(params);
+        blockchain
+            .add_block(Block::genesis(&params))
+            .expect("BUG: genesis block must connect to an empty chain");
+        blockchain
+    }
+
+    pub fn network(&self) -> Network {
+        self.params.network
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    // full rescan of the chain, only needed after loading; connect_block and
+    // disconnect_tip keep the set up to date incrementally
     pub fn rebuild_utxos(&mut self) {
+        // pruned blocks cannot be replayed, so the stored set is kept and
+        // only the mempool marks are redone
+        if self.pruned_height > 0 {
+            for (marked, _) in self.utxos.values_mut() {
+                *marked = false;
+            }
+
+            self.mark_mempool_inputs();
+            return;
+        }
+
+        self.utxos.clear();
+        self.owners.clear();
+
         for block in &self.blocks {
-            for tx in &block.transactions {
-                for input in &tx.inputs {
-                    self.utxos.remove(&input.prev_tx_output_hash);
-                }
+            apply_utxos(&mut self.utxos, &mut self.owners, block);
+        }
 
-                for output in tx.outputs.iter() {
-                    self.utxos.insert(tx.hash(), (false, output.clone()));
-                }
+        self.mark_mempool_inputs();
+    }
+
+    fn mark_mempool_inputs(&mut self) {
+        for (_, tx) in &self.mempool {
+            for input in &tx.inputs {
+                self.utxos
+                    .entry(input.prev_tx_output_hash)
+                    .and_modify(|(marked, _)| {
+                        *marked = true;
+                    });
             }
         }
     }
 
+    // side blocks are journaled too, so replaying the journal through
+    // add_block arrives at the same chain, reorganizations included
     pub fn add_block(&mut self, block: Block) -> Result<()> {
+        let Some(journal) = self.journal.clone() else {
+            return self.accept_block(block);
+        };
+
+        self.accept_block(block.clone())?;
+
+        // the block is kept either way; with journaling off, whoever saves
+        // the chain has to write it out whole
+        if let Err(e) = journal.append(&block) {
+            self.journal = None;
+            return Err(BtcError::JournalFailed(e));
+        }
+
+        Ok(())
+    }
+
+    pub fn enable_journal(&mut self, journal: BlockJournal) {
+        self.journal = Some(journal);
+    }
+
+    pub fn journal(&self) -> Option<&BlockJournal> {
+        self.journal.as_ref()
+    }
+
+    // adds the journaled blocks on top of this chain, returning how many
+    // were added and how many it already had or rejected
+    pub fn replay_journal(&mut self, journal: &BlockJournal) -> IoResult<(usize, usize)> {
+        // replayed blocks must not be appended to the journal again
+        let enabled = self.journal.take();
+        let mut added = 0;
+        let mut skipped = 0;
+
+        for block in journal.read()? {
+            match self.add_block(block) {
+                Ok(()) => added += 1,
+                Err(_) => skipped += 1,
+            }
+        }
+
+        self.journal = enabled;
+        Ok((added, skipped))
+    }
+
+    fn accept_block(&mut self, block: Block) -> Result<()> {
+        if crate::util::cbor_size(&block) > crate::MAX_BLOCK_SIZE {
+            return Err(BtcError::BlockTooLarge);
+        }
+
+        if block.header.is_too_far_in_future() {
+            return Err(BtcError::FutureTimestamp);
+        }
+
+        if let Some(last_block) = self.blocks.last()
+            && block.header.prev_block_hash != last_block.hash()
+        {
+            return self.add_side_block(block);
+        }
+
+        self.connect_block(block)
+    }
+
+    fn connect_block(&mut self, block: Block) -> Result<()> {
         if self.blocks.is_empty() {
             if block.header.prev_block_hash != Hash::zero() {
-                println!("zero hash");
-                return Err(BtcError::InvalidBlock);
+                return Err(BtcError::BadPrevHash);
+            }
+
+            // the genesis is fixed by the parameters, so a peer can't hand
+            // over a chain rooted in a block of its own
+            if block.hash() != self.params.genesis_hash() {
+                return Err(BtcError::InvalidGenesis);
             }
         } else {
             let last_block = self.blocks.last().unwrap();
 
             if block.header.prev_block_hash != last_block.hash() {
-                println!("prev hash is wrong");
-                return Err(BtcError::InvalidBlock);
+                return Err(BtcError::BadPrevHash);
+            }
+
+            // the sender picks the target it mines against, so a header is
+            // only worth its proof of work if that is the one required here
+            if block.header.target != self.target {
+                return Err(BtcError::UnexpectedTarget);
             }
 
             if !block.header.hash().matches_target(block.header.target) {
-                println!("target does not match");
-                return Err(BtcError::InvalidBlock);
+                return Err(BtcError::TargetNotMet);
             }
 
-            let calculated_merkel_root = MerkleRoot::calculate(&block.transactions);
+            let calculated_merkle_root = MerkleRoot::calculate_strict(&block.transactions)?;
 
-            if calculated_merkel_root != block.header.merkle_root {
-                println!("merkel root does not match");
+            if calculated_merkle_root != block.header.merkle_root {
                 return Err(BtcError::InvalidMerkleRoot);
             }
 
-            if block.header.timestamp <= last_block.header.timestamp {
-                return Err(BtcError::InvalidBlock);
+            if self
+                .median_time_past()
+                .is_some_and(|median| block.header.timestamp <= median)
+            {
+                return Err(BtcError::StaleTimestamp);
             }
 
-            block.verify_transactions(self.block_height(), self.utxos())?;
+            let height = self.block_height();
+            block.verify_transactions(
+                &self.params,
+                height,
+                self.utxos(),
+                &self.immature_coinbase_outputs(height),
+            )?;
         }
 
+        apply_utxos(&mut self.utxos, &mut self.owners, &block);
+
         let block_transactions: HashSet<_> =
             block.transactions.iter().map(|tx| tx.hash()).collect();
 
-        self.mempool
+        // drop what the block confirmed, and anything that conflicted with it
+        let utxos = &self.utxos;
+        let (mempool, dropped): (Vec<_>, Vec<_>) = std::mem::take(&mut self.mempool)
+            .into_iter()
+            .partition(|(_, tx)| {
+                !block_transactions.contains(&tx.hash())
+                    && tx
+                        .inputs
+                        .iter()
+                        .all(|input| utxos.contains_key(&input.prev_tx_output_hash))
+            });
+
+        self.mempool = mempool;
+        self.orphans
             .retain(|(_, tx)| !block_transactions.contains(&tx.hash()));
 
+        for (_, tx) in dropped {
+            for input in &tx.inputs {
+                self.utxos
+                    .entry(input.prev_tx_output_hash)
+                    .and_modify(|(marked, _)| *marked = false);
+            }
+        }
+
+        self.total_work = self.total_work.saturating_add(block.header.work());
+        self.block_index.insert(block.hash(), self.blocks.len());
+
+        if let Some(tx_index) = &mut self.tx_index {
+            let height = self.blocks.len() as u64;
+            for (index, tx) in block.transactions.iter().enumerate() {
+                tx_index.insert(tx.hash(), (height, index));
+            }
+        }
+
         self.blocks.push(block);
         self.try_adjust_target();
+        self.process_orphans();
         Ok(())
     }
 
-    pub fn try_adjust_target(&mut self) {
-        if self.blocks.is_empty() {
-            return;
+    // replays every block from genesis under the rules add_block applies and
+    // reports the first one that fails; pruned blocks only have their header
+    // checked, and a pruned chain cannot have its transactions replayed
+    pub fn verify_chain(&self) -> Result<()> {
+        let failed = |height, reason| BtcError::VerificationFailed {
+            height,
+            reason: Box::new(reason),
+        };
+
+        let mut replay = BlockChain::new(self.params);
+        let mut prev_hash = Hash::zero();
+
+        for (height, block) in self.blocks_with_height() {
+            if block.header.prev_block_hash != prev_hash {
+                return Err(failed(height, BtcError::BadPrevHash));
+            }
+
+            // the genesis block is not mined, it has to be the fixed one
+            if height == 0 && block.hash() != self.params.genesis_hash() {
+                return Err(failed(height, BtcError::InvalidGenesis));
+            }
+
+            if height > 0 && !block.header.hash().matches_target(block.header.target) {
+                return Err(failed(height, BtcError::TargetNotMet));
+            }
+
+            prev_hash = block.hash();
+
+            if height < self.pruned_height {
+                continue;
+            }
+
+            if block.transactions.is_empty() {
+                return Err(failed(height, BtcError::EmptyBlock));
+            }
+
+            if !MerkleRoot::calculate_strict(&block.transactions)
+                .is_ok_and(|root| root == block.header.merkle_root)
+            {
+                return Err(failed(height, BtcError::InvalidMerkleRoot));
+            }
+
+            if self.pruned_height == 0 {
+                replay
+                    .add_block(block.clone())
+                    .map_err(|e| failed(height, e))?;
+            }
         }
 
-        if !self
+        Ok(())
+    }
+
+    // recomputes the state derived from `blocks` that is not serialized
+    fn reindex(&mut self) {
+        self.total_work = self.blocks.iter().fold(U256::zero(), |work, block| {
+            work.saturating_add(block.header.work())
+        });
+
+        self.block_index = self
             .blocks
-            .len()
-            .is_multiple_of(crate::DIFICULTY_UPDATE_INTERVAL as usize)
+            .iter()
+            .enumerate()
+            .map(|(height, block)| (block.hash(), height))
+            .collect();
+
+        self.index_owners();
+    }
+
+    fn add_side_block(&mut self, block: Block) -> Result<()> {
+        let hash = block.hash();
+
+        if self.side_blocks.contains(&hash) || self.block_index.contains_key(&hash) {
+            return Err(BtcError::DuplicateBlock);
+        }
+
+        // the only block without a parent is the genesis, which is already
+        // on the active chain
+        if block.header.prev_block_hash == Hash::zero() {
+            return Err(BtcError::InvalidGenesis);
+        }
+
+        // a block whose parent is unknown can't be held to its exact target
+        // yet, that is checked once its branch is connected; it still may
+        // not be easier than the network allows
+        if block.header.target > self.params.min_target {
+            return Err(BtcError::UnexpectedTarget);
+        }
+
+        if let Some(required) = self.required_target(&block.header.prev_block_hash)
+            && block.header.target != required
         {
-            return;
+            return Err(BtcError::UnexpectedTarget);
+        }
+
+        if !block.header.hash().matches_target(block.header.target) {
+            return Err(BtcError::TargetNotMet);
+        }
+
+        // a mutated copy would otherwise be stored under the genuine block's
+        // hash and get the real one turned away as a duplicate
+        if MerkleRoot::calculate_strict(&block.transactions)? != block.header.merkle_root {
+            return Err(BtcError::InvalidMerkleRoot);
         }
 
-        let start_time = self.blocks[self.blocks.len() - crate::DIFICULTY_UPDATE_INTERVAL as usize]
-            .header
-            .timestamp;
+        self.side_blocks.insert(block);
 
-        let end_time = self.blocks.last().unwrap().header.timestamp;
+        let Some((fork_height, branch)) = self.find_branch(hash) else {
+            debug!("stored orphan block {}", hash);
+            return Ok(());
+        };
+
+        let branch_work = branch.iter().fold(U256::zero(), |work, block| {
+            work.saturating_add(block.header.work())
+        });
+        let active_work = self.blocks[fork_height as usize..]
+            .iter()
+            .fold(U256::zero(), |work, block| {
+                work.saturating_add(block.header.work())
+            });
 
-        let time_diff = end_time - start_time;
+        if branch_work > active_work {
+            info!(
+                "side branch at height {} has more work, reorganizing",
+                fork_height
+            );
+            self.reorganize(fork_height, branch)?;
+        }
 
-        let time_diff_in_seconds = time_diff.num_seconds();
+        Ok(())
+    }
 
-        let target_seconds = crate::IDEAL_BLOCK_TIME * crate::DIFICULTY_UPDATE_INTERVAL;
+    // walks a side block back to the active chain and forward through any
+    // stored descendants, returning the fork height and the branch blocks
+    fn find_branch(&self, hash: Hash) -> Option<(u64, Vec<Block>)> {
+        let mut branch = vec![];
+        let mut current = hash;
 
-        let new_target = BigDecimal::parse_bytes(self.target.to_string().as_bytes(), 10)
-            .expect("BUG: impossible")
-            * (BigDecimal::from(time_diff_in_seconds) / BigDecimal::from(target_seconds));
+        let fork_height = loop {
+            let block = self.side_blocks.get(&current)?;
+            branch.push(block.clone());
+            let prev = block.header.prev_block_hash;
 
-        let new_target_str = new_target
-            .to_string()
-            .split('.')
-            .next()
-            .expect("BUG: expected decimal type")
-            .to_owned();
+            if prev == Hash::zero() {
+                break 0;
+            }
 
-        let new_target: U256 = U256::from_str_radix(&new_target_str, 10).expect("BUG: impossible");
+            if let Some(height) = self.height_of(&prev) {
+                break height + 1;
+            }
 
-        let new_target = if new_target < self.target / 4 {
-            self.target / 4
-        } else if new_target > self.target * 4 {
-            self.target * 4
-        } else {
-            new_target
+            current = prev;
         };
 
-        self.target = new_target.min(crate::MIN_TARGET);
+        branch.reverse();
+
+        let mut current = hash;
+        while let Some(child) = self.side_blocks.first_child(&current) {
+            branch.push(child.clone());
+            current = child.hash();
+        }
+
+        Some((fork_height, branch))
     }
 
-    pub fn add_to_mempool(&mut self, tx: Transaction) -> Result<()> {
-        let mut known_inputs = HashSet::new();
-        for input in &tx.inputs {
-            if !self.utxos.contains_key(&input.prev_tx_output_hash) {
-                return Err(BtcError::InvalidTransaction);
+    // the target a block built on `prev_hash` has to carry, following the
+    // adjustment rule along that block's own branch; None while the branch
+    // doesn't reach the active chain
+    fn required_target(&self, prev_hash: &Hash) -> Option<U256> {
+        let mut side = vec![];
+        let mut current = *prev_hash;
+
+        let fork_height = loop {
+            if current == Hash::zero() {
+                break 0;
             }
 
-            if known_inputs.contains(&input.prev_tx_output_hash) {
-                return Err(BtcError::InvalidTransaction);
+            if let Some(height) = self.height_of(&current) {
+                break height as usize + 1;
             }
 
-            known_inputs.insert(input.prev_tx_output_hash);
+            let block = self.side_blocks.get(&current)?;
+            side.push(&block.header);
+            current = block.header.prev_block_hash;
+        };
+
+        side.reverse();
+
+        let headers = self.blocks[..fork_height]
+            .iter()
+            .map(|block| &block.header)
+            .chain(side)
+            .collect::<Vec<_>>();
+
+        Some(required_target_after(&self.params, &headers))
+    }
+
+    fn reorganize(&mut self, fork_height: u64, branch: Vec<Block>) -> Result<()> {
+        let mut candidate = self.clone();
+
+        // the old mempool may build on blocks the branch also contains, so it
+        // is only revalidated once the branch is connected
+        let mempool = std::mem::take(&mut candidate.mempool);
+
+        while candidate.block_height() > fork_height {
+            let block = candidate.disconnect_tip()?;
+            candidate.side_blocks.insert(block);
         }
 
-        for input in &tx.inputs {
-            if let Some((true, _)) = self.utxos.get(&input.prev_tx_output_hash) {
-                let referencing_tx =
-                    self.mempool
-                        .iter()
-                        .enumerate()
-                        .find(|(_, (_, transaction))| {
-                            transaction
-                                .outputs
-                                .iter()
-                                .any(|output| output.hash() == input.prev_tx_output_hash)
-                        });
-
-                if let Some((idx, (_, referencing_tx))) = referencing_tx {
-                    for input in &referencing_tx.inputs {
-                        self.utxos
-                            .entry(input.prev_tx_output_hash)
-                            .and_modify(|(marked, _)| {
-                                *marked = false;
-                            });
-                    }
-
-                    self.mempool.remove(idx);
-                } else {
-                    self.utxos
-                        .entry(input.prev_tx_output_hash)
-                        .and_modify(|(marked, _)| {
-                            *marked = false;
-                        });
-                }
+        for block in branch {
+            let hash = block.hash();
+            candidate.side_blocks.remove(&hash);
+
+            if let Err(e) = candidate.connect_block(block) {
+                warn!("side branch block {} is invalid: {}", hash, e);
+                self.side_blocks.remove(&hash);
+                return Err(e);
             }
         }
 
-        let all_inputs = tx
-            .inputs
+        for (timestamp, tx) in mempool {
+            let _ = candidate.add_to_mempool_at(timestamp, tx);
+        }
+
+        *self = candidate;
+        Ok(())
+    }
+
+    // undoes connect_block for the last block, putting its transactions back
+    // into the mempool
+    pub fn disconnect_tip(&mut self) -> Result<Block> {
+        if self.block_height() <= self.pruned_height {
+            return Err(BtcError::BlockUnavailable);
+        }
+
+        let block = self.blocks.pop().ok_or(BtcError::EmptyChain)?;
+
+        let created = block
+            .transactions
             .iter()
-            .map(|input| {
-                self.utxos
-                    .get(&input.prev_tx_output_hash)
-                    .expect("BUG: impossible")
-                    .1
-                    .value
-            })
-            .sum::<u64>();
+            .flat_map(|tx| tx.outputs.iter().map(|output| output.hash()))
+            .collect::<HashSet<_>>();
+
+        let spent = block
+            .transactions
+            .iter()
+            .flat_map(|tx| tx.inputs.iter().map(|input| input.prev_tx_output_hash))
+            .collect::<HashSet<_>>();
 
-        let all_outputs = tx.outputs.iter().map(|output| output.value).sum::<u64>();
+        let restored = self
+            .blocks
+            .iter()
+            .flat_map(|block| block.transactions.iter())
+            .flat_map(|tx| tx.outputs.iter())
+            .filter(|output| spent.contains(&output.hash()))
+            .cloned()
+            .collect::<Vec<_>>();
 
-        if all_inputs < all_outputs {
-            print!("all inputs are less than all outputs");
-            return Err(BtcError::InvalidTransaction);
+        // the outputs this block spent may have been created in a pruned block
+        if self.pruned_height > 0 && restored.len() < spent.difference(&created).count() {
+            self.blocks.push(block);
+            return Err(BtcError::BlockUnavailable);
         }
 
-        for input in &tx.inputs {
-            self.utxos
-                .entry(input.prev_tx_output_hash)
-                .and_modify(|(marked, _)| {
-                    *marked = true;
-                });
+        for hash in &created {
+            remove_utxo(&mut self.utxos, &mut self.owners, hash);
         }
 
-        self.mempool.push((Utc::now(), tx));
+        for output in restored {
+            insert_utxo(&mut self.utxos, &mut self.owners, output);
+        }
 
-        self.mempool.sort_by_key(|(_, transaction)| {
-            let all_input: u64 = transaction
-                .inputs
-                .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.prev_tx_output_hash)
-                        .expect("BUG: impossible")
-                        .1
-                        .value
-                })
-                .sum();
+        // mempool transactions spending this block's outputs lost their inputs
+        let (mempool, dropped): (Vec<_>, Vec<_>) = std::mem::take(&mut self.mempool)
+            .into_iter()
+            .partition(|(_, tx)| {
+                tx.inputs
+                    .iter()
+                    .all(|input| !created.contains(&input.prev_tx_output_hash))
+            });
 
-            let all_output: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+        self.mempool = mempool;
 
-            all_input - all_output // sort by miner fees
-        });
-        Ok(())
-    }
+        for (_, tx) in dropped {
+            for input in &tx.inputs {
+                self.utxos
+                    .entry(input.prev_tx_output_hash)
+                    .and_modify(|(marked, _)| *marked = false);
+            }
+        }
 
-    pub fn cleanup_mempool(&mut self) {
-        let now = Utc::now();
-        let mut utxo_hashes_to_unmark = vec![];
+        self.total_work = self.total_work.saturating_sub(block.header.work());
+        self.block_index.remove(&block.hash());
 
-        self.mempool.retain(|(timestamp, tx)| {
-            if now - *timestamp
-                > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64)
-            {
-                utxo_hashes_to_unmark
-                    .extend(tx.inputs.iter().map(|input| input.prev_tx_output_hash));
-                false
-            } else {
-                true
+        if let Some(tx_index) = &mut self.tx_index {
+            for tx in &block.transactions {
+                tx_index.remove(&tx.hash());
             }
-        });
+        }
 
-        for hash in utxo_hashes_to_unmark {
-            self.utxos.entry(hash).and_modify(|(marked, _)| {
-                *marked = false;
-            });
+        // recomputed by the adjustment rule rather than taken from the
+        // popped header; connected headers were checked to carry exactly
+        // the target required of them, so this is the target from before
+        let tip = self
+            .blocks
+            .last()
+            .map(|block| block.hash())
+            .unwrap_or(Hash::zero());
+        self.target = self
+            .required_target(&tip)
+            .expect("BUG: the tip is on the active chain");
+
+        // in block order, so a parent is back before its child; a child
+        // spending an output of this block waits as an orphan, since only
+        // confirmed outputs can be spent, until its parent is mined again
+        let now = Utc::now();
+        for tx in block.transactions.iter().skip(1) {
+            if let Err(BtcError::UnknownInput) = self.add_to_mempool_at(now, tx.clone()) {
+                self.add_orphan(tx.clone());
+            }
         }
-    }
 
-    pub fn calculate_block_reward(&self) -> u64 {
-        let block_height = self.block_height();
-        let halvings = block_height / crate::HALVING_INTERVAL;
-        (crate::INITIAL_REWARD * 10u64.pow(8)) >> halvings
+        Ok(block)
     }
 
-    pub fn calculate_fees(&self, transactions: &[Transaction]) -> u64 {
-        transactions
-            .iter()
-            .map(|tx| {
-                let all_inputs: u64 = tx
-                    .inputs
-                    .iter()
-                    .map(|input| {
-                        self.utxos
-                            .get(&input.prev_tx_output_hash)
-                            .map(|(_, output)| output.value)
-                            .unwrap_or(0)
-                    })
-                    .sum();
+    pub fn try_adjust_target(&mut self) {
+        if self.blocks.is_empty() || self.params.is_regtest() {
+            return;
+        }
 
-                let all_outputs: u64 = tx.outputs.iter().map(|output| output.value).sum();
+        let interval = self.params.difficulty_update_interval as usize;
 
-                all_inputs - all_outputs
-            })
-            .sum()
-    }
+        if !self.blocks.len().is_multiple_of(interval) {
+            return;
+        }
 
-    pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
-        &self.utxos
-    }
+        let start_time = self.blocks[self.blocks.len() - interval].header.timestamp;
 
-    pub fn target(&self) -> U256 {
-        self.target
-    }
+        let end_time = self.blocks.last().unwrap().header.timestamp;
 
-    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
-        self.blocks.iter()
+        self.target = retarget(&self.params, self.target, start_time, end_time);
     }
 
-    pub fn block_height(&self) -> u64 {
-        self.blocks.len() as u64
-    }
+    // `height` is that of the block the transaction would go into
+    pub fn validate_transaction(&self, tx: &Transaction, height: u64) -> Result<u64> {
+        if !tx.is_final(height) {
+            return Err(BtcError::TransactionLocked);
+        }
 
-    pub fn mempool(&self) -> &[(DateTime<Utc>, Transaction)] {
-        &self.mempool
+        tx.validate(&self.utxos, &self.immature_coinbase_outputs(height))
     }
-}
 
-impl Default for BlockChain {
-    fn default() -> Self {
-        Self::new()
+    // the coinbase outputs of the last COINBASE_MATURITY blocks before
+    // `height`; pruning keeps at least that many bodies around
+    pub fn immature_coinbase_outputs(&self, height: u64) -> HashSet<Hash> {
+        let first = (height + 1).saturating_sub(crate::COINBASE_MATURITY) as usize;
+
+        self.blocks
+            .iter()
+            .skip(first)
+            .filter_map(|block| block.transactions.first())
+            .flat_map(|coinbase| coinbase.outputs.iter().map(|output| output.hash()))
+            .collect()
     }
-}
 
-impl Savable for BlockChain {
-    fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader)
-            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize BlockChain"))
+    pub fn add_to_mempool(&mut self, tx: Transaction) -> Result<()> {
+        match self.add_to_mempool_at(Utc::now(), tx.clone()) {
+            // an output that was confirmed and is gone has been spent, and
+            // no parent can bring it back
+            Err(BtcError::UnknownInput) if self.spends_confirmed_output(&tx) => {
+                Err(BtcError::SpentInput)
+            }
+            Err(BtcError::UnknownInput) => {
+                self.add_orphan(tx);
+                Err(BtcError::OrphanTransaction)
+            }
+            result => result,
+        }
     }
 
-    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
-        ciborium::ser::into_writer(self, writer)
+    // whether one of `tx`'s inputs was created on the active chain and is no
+    // longer unspent; outputs of pruned blocks can't be told apart from ones
+    // never seen
+    fn spends_confirmed_output(&self, tx: &Transaction) -> bool {
+        let missing = tx
+            .inputs
+            .iter()
+            .map(|input| input.prev_tx_output_hash)
+            .filter(|hash| !self.utxos.contains_key(hash))
+            .collect::<HashSet<_>>();
+
+        !missing.is_empty()
+            && self
+                .blocks
+                .iter()
+                .flat_map(|block| block.transactions.iter())
+                .flat_map(|tx| tx.outputs.iter())
+                .any(|output| missing.contains(&output.hash()))
+    }
+
+    fn add_orphan(&mut self, tx: Transaction) {
+        let hash = tx.hash();
+        if self.orphans.iter().any(|(_, orphan)| orphan.hash() == hash) {
+            return;
+        }
+
+        // oldest first, so the front is what gets evicted
+        if self.orphans.len() >= crate::MAX_ORPHAN_TRANSACTIONS {
+            self.orphans.remove(0);
+        }
+
+        self.orphans.push((Utc::now(), tx));
+    }
+
+    // only confirmed outputs can be spent, so it takes a new block for an
+    // orphan's inputs to show up
+    fn process_orphans(&mut self) {
+        self.expire_orphans();
+
+        for (timestamp, tx) in std::mem::take(&mut self.orphans) {
+            // anything other than still missing inputs resolves the orphan,
+            // whether it was accepted or turned out to be invalid
+            if let Err(BtcError::UnknownInput) = self.add_to_mempool_at(Utc::now(), tx.clone()) {
+                self.orphans.push((timestamp, tx));
+            }
+        }
+    }
+
+    fn expire_orphans(&mut self) {
+        let now = Utc::now();
+        self.orphans.retain(|(timestamp, _)| {
+            now - *timestamp <= chrono::Duration::seconds(crate::MAX_ORPHAN_TRANSACTION_AGE as i64)
+        });
+    }
+
+    // what add_to_mempool would say about `tx`, except that missing inputs
+    // are reported as such rather than the transaction being held as an
+    // orphan; nothing is changed either way
+    pub fn test_mempool_accept(&self, tx: &Transaction) -> Result<()> {
+        self.check_mempool_accept(tx).map(|_| ())
+    }
+
+    // returns the positions of the mempool transactions `tx` would replace
+    fn check_mempool_accept(&self, tx: &Transaction) -> Result<Vec<usize>> {
+        let fee = self.validate_transaction(tx, self.block_height())?;
+
+        if tx.has_dust_output(&self.utxos) {
+            return Err(BtcError::DustOutput);
+        }
+
+        // it would otherwise conflict with itself and never pay more
+        if self.in_mempool(&tx.hash()) {
+            return Ok(vec![]);
+        }
+
+        let conflicts = self
+            .mempool
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, transaction))| {
+                transaction.inputs.iter().any(|existing| {
+                    tx.inputs
+                        .iter()
+                        .any(|input| input.prev_tx_output_hash == existing.prev_tx_output_hash)
+                })
+            })
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+
+        if !conflicts.is_empty() {
+            let conflicting_fees: u64 = conflicts
+                .iter()
+                .map(|&idx| self.calculate_fees(std::slice::from_ref(&self.mempool[idx].1)))
+                .sum();
+
+            if fee <= conflicting_fees {
+                return Err(BtcError::ReplacementFeeTooLow);
+            }
+        }
+
+        if self.mempool.len() - conflicts.len() >= crate::MAX_MEMPOOL_SIZE {
+            let lowest_fee_rate = self
+                .mempool
+                .last()
+                .map(|(_, lowest)| self.fee_rate(lowest))
+                .unwrap_or(0.0);
+
+            if self.fee_rate(tx) <= lowest_fee_rate {
+                return Err(BtcError::MempoolFull);
+            }
+        }
+
+        Ok(conflicts)
+    }
+
+    fn in_mempool(&self, hash: &Hash) -> bool {
+        self.mempool.iter().any(|(_, tx)| tx.hash() == *hash)
+    }
+
+    // a transaction that is already in the mempool is left where it is, so a
+    // repeated relay is not an error
+    fn add_to_mempool_at(&mut self, timestamp: DateTime<Utc>, tx: Transaction) -> Result<()> {
+        if self.in_mempool(&tx.hash()) {
+            return Ok(());
+        }
+
+        let conflicts = self.check_mempool_accept(&tx)?;
+
+        for idx in conflicts.into_iter().rev() {
+            self.remove_from_mempool(idx);
+        }
+
+        for input in &tx.inputs {
+            self.utxos
+                .entry(input.prev_tx_output_hash)
+                .and_modify(|(marked, _)| {
+                    *marked = true;
+                });
+        }
+
+        while self.mempool.len() >= crate::MAX_MEMPOOL_SIZE {
+            self.remove_from_mempool(self.mempool.len() - 1);
+        }
+
+        self.mempool.push((timestamp, tx));
+        self.sort_mempool();
+        Ok(())
+    }
+
+    fn remove_from_mempool(&mut self, idx: usize) -> Transaction {
+        let (_, tx) = self.mempool.remove(idx);
+
+        for input in &tx.inputs {
+            self.utxos
+                .entry(input.prev_tx_output_hash)
+                .and_modify(|(marked, _)| {
+                    *marked = false;
+                });
+        }
+
+        tx
+    }
+
+    // highest fee rate first, so templates take the best-paying transactions
+    fn sort_mempool(&mut self) {
+        let mut mempool = std::mem::take(&mut self.mempool)
+            .into_iter()
+            .map(|entry| (self.fee_rate(&entry.1), entry))
+            .collect::<Vec<_>>();
+
+        mempool.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+
+        self.mempool = mempool.into_iter().map(|(_, entry)| entry).collect();
+    }
+
+    pub fn fee_rate(&self, tx: &Transaction) -> f64 {
+        let fee = self.calculate_fees(std::slice::from_ref(tx));
+        transaction::fee_rate(fee, tx.serialized_size())
+    }
+
+    pub fn cleanup_mempool(&mut self) {
+        let now = Utc::now();
+        let mut utxo_hashes_to_unmark = vec![];
+
+        self.mempool.retain(|(timestamp, tx)| {
+            if now - *timestamp
+                > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64)
+            {
+                utxo_hashes_to_unmark
+                    .extend(tx.inputs.iter().map(|input| input.prev_tx_output_hash));
+                false
+            } else {
+                true
+            }
+        });
+
+        for hash in utxo_hashes_to_unmark {
+            self.utxos.entry(hash).and_modify(|(marked, _)| {
+                *marked = false;
+            });
+        }
+
+        self.expire_orphans();
+    }
+
+    pub fn calculate_block_reward(&self) -> u64 {
+        self.params.reward_at_height(self.block_height())
+    }
+
+    pub fn calculate_fees(&self, transactions: &[Transaction]) -> u64 {
+        transactions
+            .iter()
+            .map(|tx| {
+                let all_inputs: u64 = tx
+                    .inputs
+                    .iter()
+                    .map(|input| {
+                        self.utxos
+                            .get(&input.prev_tx_output_hash)
+                            .map(|(_, output)| output.value)
+                            .unwrap_or(0)
+                    })
+                    .sum();
+
+                let all_outputs: u64 = tx.outputs.iter().map(|output| output.value).sum();
+
+                all_inputs.saturating_sub(all_outputs)
+            })
+            .sum()
+    }
+
+    pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
+        &self.utxos
+    }
+
+    // the unspent outputs paying to `pubkey` and whether each is marked,
+    // without scanning the whole set
+    pub fn utxos_of<'a>(
+        &'a self,
+        pubkey: &PublicKey,
+    ) -> impl Iterator<Item = &'a (bool, TransactionOutput)> + 'a {
+        self.owners
+            .get(pubkey)
+            .into_iter()
+            .flatten()
+            .filter_map(|hash| self.utxos.get(hash))
+    }
+
+    fn index_owners(&mut self) {
+        self.owners.clear();
+
+        for (hash, (_, output)) in &self.utxos {
+            self.owners
+                .entry(output.pubkey.clone())
+                .or_default()
+                .insert(*hash);
+        }
+    }
+
+    pub fn utxo_status(&self, hash: &Hash) -> Option<(bool, &TransactionOutput)> {
+        self.utxos
+            .get(hash)
+            .map(|(marked, output)| (*marked, output))
+    }
+
+    pub fn target(&self) -> U256 {
+        self.target
+    }
+
+    pub fn median_time_past(&self) -> Option<DateTime<Utc>> {
+        let start = self.blocks.len().saturating_sub(crate::MEDIAN_TIME_SPAN);
+        let timestamps = self.blocks[start..]
+            .iter()
+            .map(|block| block.header.timestamp)
+            .collect::<Vec<_>>();
+
+        median_timestamp(&timestamps)
+    }
+
+    // how many times harder than the network's easiest target the current
+    // target is
+    pub fn difficulty(&self) -> f64 {
+        u256_to_f64(self.params.min_target) / u256_to_f64(self.target.max(U256::one()))
+    }
+
+    pub fn total_work(&self) -> U256 {
+        self.total_work
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+        self.blocks.iter()
+    }
+
+    pub fn blocks_with_height(&self) -> impl Iterator<Item = (u64, &Block)> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .map(|(height, block)| (height as u64, block))
+    }
+
+    // every confirmed transaction with the height of its block and its index
+    // within that block
+    pub fn transactions(&self) -> impl Iterator<Item = (u64, usize, &Transaction)> {
+        self.blocks_with_height().flat_map(|(height, block)| {
+            block
+                .transactions
+                .iter()
+                .enumerate()
+                .map(move |(index, tx)| (height, index, tx))
+        })
+    }
+
+    pub fn block_height(&self) -> u64 {
+        self.blocks.len() as u64
+    }
+
+    pub fn block_by_hash(&self, hash: &Hash) -> Option<&Block> {
+        self.block_index
+            .get(hash)
+            .map(|&height| &self.blocks[height])
+    }
+
+    // builds the transaction index from the current chain; from then on it is
+    // kept up to date as blocks are connected and disconnected
+    pub fn enable_tx_index(&mut self) {
+        let tx_index = self
+            .transactions()
+            .map(|(height, index, tx)| (tx.hash(), (height, index)))
+            .collect();
+
+        self.tx_index = Some(tx_index);
+    }
+
+    // falls back to scanning the chain without the index; transactions in
+    // pruned blocks are not found either way
+    pub fn find_transaction(&self, hash: &Hash) -> Option<(u64, usize, &Transaction)> {
+        match &self.tx_index {
+            Some(tx_index) => {
+                let &(height, index) = tx_index.get(hash)?;
+                let tx = self.blocks.get(height as usize)?.transactions.get(index)?;
+                Some((height, index, tx))
+            }
+            None => self.transactions().find(|(_, _, tx)| tx.hash() == *hash),
+        }
+    }
+
+    pub fn height_of(&self, hash: &Hash) -> Option<u64> {
+        self.block_index.get(hash).map(|&height| height as u64)
+    }
+
+    pub fn mempool(&self) -> &[(DateTime<Utc>, Transaction)] {
+        &self.mempool
+    }
+
+    pub fn orphans(&self) -> &[(DateTime<Utc>, Transaction)] {
+        &self.orphans
+    }
+
+    pub fn pruned_height(&self) -> u64 {
+        self.pruned_height
+    }
+
+    // drops the transactions of every block below `height`; headers stay so
+    // the chain can still be followed and its work counted
+    pub fn prune_below(&mut self, height: u64) {
+        let height = height.min(self.block_height());
+
+        for block in self
+            .blocks
+            .iter_mut()
+            .take(height as usize)
+            .skip(self.pruned_height as usize)
+        {
+            block.transactions = vec![];
+        }
+
+        self.pruned_height = self.pruned_height.max(height);
+    }
+
+    pub fn save_mempool<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(&self.mempool, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize mempool"))
+    }
+
+    pub fn save_mempool_to_file<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
+        write_atomically(path, |file| self.save_mempool(file))
+    }
+
+    pub fn load_mempool<I: Read>(&mut self, reader: I) -> IoResult<usize> {
+        let mempool: Vec<(DateTime<Utc>, Transaction)> = ciborium::de::from_reader(reader)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to deserialize mempool"))?;
+
+        let mut dropped = 0;
+        for (timestamp, tx) in mempool {
+            if self.add_to_mempool_at(timestamp, tx).is_err() {
+                dropped += 1;
+            }
+        }
+
+        Ok(dropped)
+    }
+
+    pub fn load_mempool_from_file<P: AsRef<Path>>(&mut self, path: P) -> IoResult<usize> {
+        let file = File::open(&path)?;
+        self.load_mempool(file)
+    }
+
+    pub fn export_utxo_snapshot<W: Write>(&self, writer: W) -> IoResult<()> {
+        let snapshot = UtxoSnapshot {
+            height: self.block_height(),
+            tip: self
+                .blocks
+                .last()
+                .map(|block| block.hash())
+                .unwrap_or(Hash::zero()),
+            utxos: self
+                .utxos
+                .iter()
+                .map(|(hash, (_, output))| (*hash, output.clone()))
+                .collect(),
+        };
+
+        ciborium::ser::into_writer(&snapshot, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize snapshot"))
+    }
+
+    pub fn export_utxo_snapshot_to_file<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
+        write_atomically(path, |file| self.export_utxo_snapshot(file))
+    }
+
+    // replaces the UTXO set instead of replaying every block; the snapshot is
+    // trusted, so all that is checked is that it was taken at this chain's tip
+    pub fn import_utxo_snapshot<R: Read>(&mut self, reader: R) -> Result<()> {
+        let snapshot: UtxoSnapshot =
+            ciborium::de::from_reader(reader).map_err(|_| BtcError::InvalidSnapshot)?;
+
+        let tip = self
+            .blocks
+            .last()
+            .map(|block| block.hash())
+            .unwrap_or(Hash::zero());
+
+        if snapshot.height != self.block_height() || snapshot.tip != tip {
+            return Err(BtcError::SnapshotMismatch);
+        }
+
+        self.utxos = snapshot
+            .utxos
+            .into_iter()
+            .map(|(hash, output)| (hash, (false, output)))
+            .collect();
+        self.index_owners();
+        self.mark_mempool_inputs();
+        Ok(())
+    }
+
+    pub fn import_utxo_snapshot_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let file = File::open(&path).map_err(|_| BtcError::InvalidSnapshot)?;
+        self.import_utxo_snapshot(file)
+    }
+}
+
+fn apply_utxos(
+    utxos: &mut HashMap<Hash, (bool, TransactionOutput)>,
+    owners: &mut HashMap<PublicKey, HashSet<Hash>>,
+    block: &Block,
+) {
+    for tx in &block.transactions {
+        for input in &tx.inputs {
+            remove_utxo(utxos, owners, &input.prev_tx_output_hash);
+        }
+
+        for output in &tx.outputs {
+            insert_utxo(utxos, owners, output.clone());
+        }
+    }
+}
+
+fn insert_utxo(
+    utxos: &mut HashMap<Hash, (bool, TransactionOutput)>,
+    owners: &mut HashMap<PublicKey, HashSet<Hash>>,
+    output: TransactionOutput,
+) {
+    let hash = output.hash();
+    owners
+        .entry(output.pubkey.clone())
+        .or_default()
+        .insert(hash);
+    utxos.insert(hash, (false, output));
+}
+
+// a key left without outputs is dropped, so spent keys don't pile up
+fn remove_utxo(
+    utxos: &mut HashMap<Hash, (bool, TransactionOutput)>,
+    owners: &mut HashMap<PublicKey, HashSet<Hash>>,
+    hash: &Hash,
+) {
+    let Some((_, output)) = utxos.remove(hash) else {
+        return;
+    };
+
+    if let Some(hashes) = owners.get_mut(&output.pubkey) {
+        hashes.remove(hash);
+
+        if hashes.is_empty() {
+            owners.remove(&output.pubkey);
+        }
+    }
+}
+
+// the target a block has to carry when `chain`, every header from genesis up
+// to its parent, comes before it; the same rule try_adjust_target follows
+fn required_target_after(params: &ChainParams, chain: &[&BlockHeader]) -> U256 {
+    let Some(parent) = chain.last() else {
+        return params.min_target;
+    };
+
+    let interval = params.difficulty_update_interval as usize;
+
+    if params.is_regtest() || !chain.len().is_multiple_of(interval) {
+        return parent.target;
+    }
+
+    retarget(
+        params,
+        parent.target,
+        chain[chain.len() - interval].timestamp,
+        parent.timestamp,
+    )
+}
+
+// scales `target` by how long the last adjustment window took against how
+// long it should have taken
+fn retarget(
+    params: &ChainParams,
+    target: U256,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> U256 {
+    let time_diff = end_time - start_time;
+
+    // timestamps only have to beat the median time past, so the window can
+    // in principle end before it starts
+    let time_diff_in_seconds = time_diff.num_seconds().max(1);
+
+    let target_seconds = params.ideal_block_time * params.difficulty_update_interval;
+
+    let new_target = BigDecimal::parse_bytes(target.to_string().as_bytes(), 10)
+        .expect("BUG: impossible")
+        * (BigDecimal::from(time_diff_in_seconds) / BigDecimal::from(target_seconds));
+
+    let new_target_str = new_target
+        .to_string()
+        .split('.')
+        .next()
+        .expect("BUG: expected decimal type")
+        .to_owned();
+
+    // a slow window on a target near the maximum can scale past what a
+    // U256 holds; the clamp below brings that back down anyway
+    let new_target = U256::from_str_radix(&new_target_str, 10).unwrap_or(U256::MAX);
+
+    // blocks that came too fast give a smaller (harder) target, slow ones
+    // a larger (easier) one, by at most a factor of four either way
+    let new_target = if new_target < target / 4 {
+        target / 4
+    } else if new_target > target.saturating_mul(U256::from(4)) {
+        target.saturating_mul(U256::from(4))
+    } else {
+        new_target
+    };
+
+    // no hash meets a zero target, which would stall the chain for good
+    new_target.clamp(U256::one(), params.min_target)
+}
+
+// blocks off the active chain, indexed by parent so a branch can be followed
+// forward without a scan; past MAX_SIDE_BLOCKS the oldest are dropped
+#[derive(Clone, Debug, Default)]
+struct SideBlocks {
+    blocks: HashMap<Hash, Block>,
+    children: HashMap<Hash, Vec<Hash>>,
+    order: VecDeque<Hash>,
+}
+
+impl SideBlocks {
+    fn contains(&self, hash: &Hash) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    fn get(&self, hash: &Hash) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    fn first_child(&self, hash: &Hash) -> Option<&Block> {
+        self.children
+            .get(hash)
+            .and_then(|children| children.first())
+            .and_then(|child| self.blocks.get(child))
+    }
+
+    fn insert(&mut self, block: Block) {
+        let hash = block.hash();
+        if self.blocks.contains_key(&hash) {
+            return;
+        }
+
+        while self.blocks.len() >= crate::MAX_SIDE_BLOCKS {
+            let Some(oldest) = self.order.front().copied() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+
+        self.children
+            .entry(block.header.prev_block_hash)
+            .or_default()
+            .push(hash);
+        self.order.push_back(hash);
+        self.blocks.insert(hash, block);
+    }
+
+    fn remove(&mut self, hash: &Hash) -> Option<Block> {
+        let block = self.blocks.remove(hash)?;
+        let prev_hash = block.header.prev_block_hash;
+
+        if let Some(children) = self.children.get_mut(&prev_hash) {
+            children.retain(|child| child != hash);
+
+            if children.is_empty() {
+                self.children.remove(&prev_hash);
+            }
+        }
+
+        self.order.retain(|entry| entry != hash);
+        Some(block)
+    }
+}
+
+// U256 has no float conversion, so fold its words from the most significant
+fn u256_to_f64(value: U256) -> f64 {
+    value.0.iter().rev().fold(0.0, |acc, word| {
+        acc * 18_446_744_073_709_551_616.0 + *word as f64
+    })
+}
+
+impl Default for BlockChain {
+    fn default() -> Self {
+        Self::new(ChainParams::default())
+    }
+}
+
+impl Savable for BlockChain {
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        // the format is checked on its own first, since an older chain may
+        // still deserialize but would no longer link up
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let format: ChainFormat = ciborium::de::from_reader(bytes.as_slice()).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to deserialize BlockChain")
+        })?;
+        check_format(format.format)?;
+
+        let mut blockchain: BlockChain =
+            ciborium::de::from_reader(bytes.as_slice()).map_err(|_| {
+                IoError::new(IoErrorKind::InvalidData, "Failed to deserialize BlockChain")
+            })?;
+        blockchain.reindex();
+        Ok(blockchain)
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer)
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize BlockChain"))
     }
+
+    // JSON object keys must be strings, so the hash-keyed UTXO map is left
+    // out and rebuilt from the blocks on load
+    fn save_json<O: Write>(&self, writer: O) -> IoResult<()> {
+        if self.pruned_height > 0 {
+            return Err(IoError::new(
+                IoErrorKind::Unsupported,
+                "A pruned BlockChain cannot be saved as JSON",
+            ));
+        }
+
+        let json = JsonBlockChain {
+            format: self.format,
+            target: self.target,
+            blocks: self.blocks.clone(),
+            params: self.params,
+        };
+        serde_json::to_writer_pretty(writer, &json).map_err(IoError::from)
+    }
+
+    fn load_json<I: Read>(reader: I) -> IoResult<Self> {
+        let json: JsonBlockChain = serde_json::from_reader(reader).map_err(IoError::from)?;
+        check_format(json.format)?;
+
+        let mut blockchain = BlockChain {
+            target: json.target,
+            blocks: json.blocks,
+            ..BlockChain::new(json.params)
+        };
+        blockchain.reindex();
+        blockchain.rebuild_utxos();
+        Ok(blockchain)
+    }
+}
+
+// refuses chains saved in any other format, which can't be converted since
+// every block hash they refer to has changed
+fn check_format(format: u32) -> IoResult<()> {
+    if format == CHAIN_FORMAT_VERSION {
+        return Ok(());
+    }
+
+    Err(IoError::new(
+        IoErrorKind::InvalidData,
+        format!(
+            "BlockChain was saved in format {} but this version only reads format {}, \
+             which identifies blocks by their header hash; remove the chain files \
+             and resync from peers",
+            format, CHAIN_FORMAT_VERSION
+        ),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ChainFormat {
+    #[serde(default)]
+    format: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UtxoSnapshot {
+    height: u64,
+    tip: Hash,
+    utxos: HashMap<Hash, TransactionOutput>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonBlockChain {
+    #[serde(default)]
+    format: u32,
+    target: U256,
+    blocks: Vec<Block>,
+    #[serde(default)]
+    params: ChainParams,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::test_util::*;
+
+    fn tip(chain: &BlockChain) -> Hash {
+        chain.blocks().last().unwrap().hash()
+    }
+
+    #[test]
+    fn blocks_are_found_by_hash_only_on_the_active_chain() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, 2);
+
+        let old_tip = chain.blocks().last().unwrap().clone();
+        assert_eq!(chain.height_of(&old_tip.hash()), Some(3));
+        assert_eq!(
+            chain.block_by_hash(&old_tip.hash()).map(Block::hash),
+            Some(old_tip.hash())
+        );
+        assert!(chain.block_by_hash(&Hash::zero()).is_none());
+        assert!(chain.height_of(&Hash::zero()).is_none());
+
+        // a side block is stored but not on the chain, until its branch wins
+        let fork = chain.blocks().nth(2).unwrap().clone();
+        let side_1 = block_on(&chain, &fork, 3, &key, vec![]);
+        chain.add_block(side_1.clone()).unwrap();
+        assert!(chain.height_of(&side_1.hash()).is_none());
+
+        let side_2 = block_on(&chain, &side_1, 4, &key, vec![]);
+        chain.add_block(side_2.clone()).unwrap();
+        assert_eq!(chain.height_of(&side_1.hash()), Some(3));
+        assert_eq!(chain.height_of(&side_2.hash()), Some(4));
+        assert!(chain.block_by_hash(&old_tip.hash()).is_none());
+        assert!(chain.height_of(&old_tip.hash()).is_none());
+
+        chain.disconnect_tip().unwrap();
+        assert!(chain.block_by_hash(&side_2.hash()).is_none());
+        assert_eq!(chain.height_of(&side_1.hash()), Some(3));
+    }
+
+    #[test]
+    fn only_the_fixed_genesis_starts_a_chain() {
+        let key = PrivateKey::new_key();
+        let params = Network::Regtest.params();
+        assert_eq!(Block::genesis(&params).hash(), params.genesis_hash());
+        assert_ne!(params.genesis_hash(), Network::Main.params().genesis_hash());
+
+        // the same block paying someone else's key
+        let mut foreign = Block::genesis(&params);
+        foreign.transactions[0].outputs[0].pubkey = key.public_key();
+        foreign.header.merkle_root = MerkleRoot::calculate(&foreign.transactions);
+
+        let mut chain = BlockChain::new(params);
+        assert!(matches!(
+            chain.add_block(foreign.clone()),
+            Err(BtcError::InvalidGenesis)
+        ));
+
+        chain.add_block(Block::genesis(&params)).unwrap();
+        assert_eq!(tip(&chain), params.genesis_hash());
+
+        // nor can it compete with the real one as a side block
+        assert!(matches!(
+            chain.add_block(foreign),
+            Err(BtcError::InvalidGenesis)
+        ));
+        assert_eq!(chain.block_height(), 1);
+    }
+
+    #[test]
+    fn the_genesis_pays_the_configured_key() {
+        let miner = PrivateKey::new_key().public_key();
+        let chain = BlockChain::with_genesis(miner.clone());
+        let genesis = chain.blocks().next().unwrap();
+
+        // the same key always gives the same root, and it is the one the
+        // chain's parameters expect
+        assert_eq!(genesis.transactions[0].outputs[0].pubkey, miner);
+        assert_eq!(
+            genesis.hash(),
+            BlockChain::with_genesis(miner.clone())
+                .blocks()
+                .next()
+                .unwrap()
+                .hash()
+        );
+        assert_eq!(genesis.hash(), chain.params().genesis_hash());
+        assert_eq!(chain.block_height(), 1);
+        assert!(chain.verify_chain().is_ok());
+
+        let other = BlockChain::with_genesis(PrivateKey::new_key().public_key());
+        assert_ne!(other.params().genesis_hash(), genesis.hash());
+        assert_ne!(Network::Main.params().genesis_hash(), genesis.hash());
+
+        // a chain expecting another key refuses it
+        let mut chain = BlockChain::new(*other.params());
+        assert!(matches!(
+            chain.add_block(genesis.clone()),
+            Err(BtcError::InvalidGenesis)
+        ));
+    }
+
+    #[test]
+    fn chains_saved_in_an_older_format_are_refused() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+
+        let mut saved = Vec::new();
+        chain.save(&mut saved).unwrap();
+        assert!(BlockChain::load(saved.as_slice()).is_ok());
+
+        // a file from before the format field loads as format 0
+        chain.format = 0;
+        let mut saved = Vec::new();
+        chain.save(&mut saved).unwrap();
+        let err = BlockChain::load(saved.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::InvalidData);
+        assert!(err.to_string().contains("format 0"), "{}", err);
+
+        let mut json = Vec::new();
+        chain.save_json(&mut json).unwrap();
+        let err = BlockChain::load_json(json.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("format 0"), "{}", err);
+    }
+
+    #[test]
+    fn longer_side_branch_takes_over_and_returns_transactions() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+
+        let fork = chain.blocks().last().unwrap().clone();
+        let fork_height = chain.block_height();
+
+        let tx = spend(&first_output(&chain), &key, 40 * 100_000_000);
+        chain.add_to_mempool(tx.clone()).unwrap();
+        let active = next_block(&chain, &key, vec![tx.clone()]);
+        chain.add_block(active.clone()).unwrap();
+        assert!(chain.mempool().is_empty());
+
+        let side_1 = block_on(&chain, &fork, fork_height, &key, vec![]);
+        let side_2 = block_on(&chain, &side_1, fork_height + 1, &key, vec![]);
+
+        // as much work as the active branch is not enough
+        chain.add_block(side_1.clone()).unwrap();
+        assert_eq!(tip(&chain), active.hash());
+
+        chain.add_block(side_2.clone()).unwrap();
+        assert_eq!(tip(&chain), side_2.hash());
+        assert_eq!(chain.block_height(), fork_height + 2);
+        assert_eq!(chain.height_of(&side_1.hash()), Some(fork_height));
+        assert_eq!(chain.height_of(&active.hash()), None);
+
+        let mempool = chain
+            .mempool()
+            .iter()
+            .map(|(_, tx)| tx.hash())
+            .collect::<Vec<_>>();
+        assert_eq!(mempool, vec![tx.hash()]);
+        assert!(chain.utxos().contains_key(&first_output(&chain).hash()));
+    }
+
+    #[test]
+    fn equal_length_branches_are_chosen_by_work() {
+        // every hash meets MAX, so even the retargeted blocks mine at once
+        let params = ChainParams {
+            network: Network::Test,
+            min_target: U256::MAX,
+            difficulty_update_interval: 3,
+            ..Network::Test.params()
+        };
+        let key = PrivateKey::new_key();
+        let mut chain = BlockChain::from_genesis(params);
+        let genesis = chain.blocks().next().unwrap().clone();
+
+        // a block on `prev` `secs` after it, against the target its branch
+        // requires
+        let spaced = |chain: &BlockChain, prev: &Block, height: u64, secs: i64| {
+            let mut block = block_on(chain, prev, height, &key, vec![]);
+            block.header.timestamp = prev.header.timestamp + chrono::Duration::seconds(secs);
+            block.header.target = chain.required_target(&prev.hash()).unwrap();
+            assert!(block.header.mine(1_000_000));
+            block
+        };
+
+        // blocks on time keep the target at its easiest
+        let mut prev = genesis.clone();
+        for height in 1..=3 {
+            let block = spaced(&chain, &prev, height, 15);
+            chain.add_block(block.clone()).unwrap();
+            prev = block;
+        }
+        let active = tip(&chain);
+        let active_work = chain.total_work();
+        assert_eq!(chain.target(), U256::MAX);
+
+        // fast ones make the third block of the side branch four times
+        // harder, which is only worth anything at the same length
+        let mut prev = genesis;
+        let mut side = vec![];
+        for height in 1..=3 {
+            let block = spaced(&chain, &prev, height, 1);
+            chain.add_block(block.clone()).unwrap();
+            side.push(block.clone());
+            prev = block;
+
+            if height < 3 {
+                assert_eq!(tip(&chain), active);
+            }
+        }
+
+        assert_eq!(chain.block_height(), 4);
+        assert_eq!(tip(&chain), side[2].hash());
+        assert!(side[2].header.target < side[1].header.target);
+        assert!(chain.total_work() > active_work);
+    }
+
+    #[test]
+    fn branch_arriving_child_first_is_followed_forward() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, 3);
+
+        let fork = chain.blocks().nth(2).unwrap().clone();
+        let side_1 = block_on(&chain, &fork, 3, &key, vec![]);
+        let side_2 = block_on(&chain, &side_1, 4, &key, vec![]);
+        let side_3 = block_on(&chain, &side_2, 5, &key, vec![]);
+
+        chain.add_block(side_3.clone()).unwrap();
+        chain.add_block(side_2).unwrap();
+        assert_eq!(chain.block_height(), 5);
+
+        // the missing link pulls in both stored descendants
+        chain.add_block(side_1).unwrap();
+        assert_eq!(tip(&chain), side_3.hash());
+        assert_eq!(chain.block_height(), 6);
+    }
+
+    #[test]
+    fn blocks_with_the_wrong_target_are_rejected() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, 2);
+
+        let mut block = next_block(&chain, &key, vec![]);
+        block.header.target = chain.target() / 2;
+        assert!(block.header.mine(1_000_000));
+        assert!(matches!(
+            chain.add_block(block),
+            Err(BtcError::UnexpectedTarget)
+        ));
+
+        let fork = chain.blocks().nth(1).unwrap().clone();
+        let mut side = block_on(&chain, &fork, 2, &key, vec![]);
+        side.header.target = chain.target() / 2;
+        assert!(side.header.mine(1_000_000));
+
+        // rejected before it is stored, so a second try is not a duplicate
+        for _ in 0..2 {
+            assert!(matches!(
+                chain.add_block(side.clone()),
+                Err(BtcError::UnexpectedTarget)
+            ));
+        }
+        assert!(!chain.side_blocks.contains(&side.hash()));
+    }
+
+    #[test]
+    fn a_block_that_does_not_link_is_a_bad_prev_hash() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, 3);
+
+        let genesis = chain.blocks[0].hash();
+        let headers = chain.blocks[1..]
+            .iter()
+            .map(|block| block.header.clone())
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            BlockHeader::verify_chain(
+                chain.params(),
+                genesis,
+                &[headers[0].clone(), headers[2].clone()]
+            ),
+            Err(BtcError::BadPrevHash)
+        ));
+
+        chain.blocks[2].header.prev_block_hash = Hash::zero();
+        assert!(matches!(
+            chain.verify_chain(),
+            Err(BtcError::VerificationFailed { height: 2, reason })
+                if matches!(*reason, BtcError::BadPrevHash)
+        ));
+    }
+
+    #[test]
+    fn a_tampered_merkle_root_fails_verification_at_its_height() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, 4);
+        chain.verify_chain().unwrap();
+
+        // re-mined, so only the merkle root is wrong at that height; the
+        // next block no longer links, but verification stops before it
+        let other_root = MerkleRoot::calculate(&chain.blocks[2].transactions);
+        let block = &mut chain.blocks[3];
+        block.header.merkle_root = other_root;
+        assert!(block.header.mine(1_000_000));
+
+        assert!(matches!(
+            chain.verify_chain(),
+            Err(BtcError::VerificationFailed { height: 3, reason })
+                if matches!(*reason, BtcError::InvalidMerkleRoot)
+        ));
+    }
+
+    #[test]
+    fn a_hash_above_the_target_is_target_not_met() {
+        let key = PrivateKey::new_key();
+        let mut chain = BlockChain::from_genesis(Network::Main.params());
+        let genesis = chain.blocks().next().unwrap().clone();
+
+        let mut block = block_on(&chain, &genesis, 1, &key, vec![]);
+        while block.header.hash().matches_target(block.header.target) {
+            block.header.nonce += 1;
+        }
+
+        assert!(matches!(
+            chain.add_block(block),
+            Err(BtcError::TargetNotMet)
+        ));
+        assert_eq!(chain.block_height(), 1);
+    }
+
+    #[test]
+    fn transactions_the_header_does_not_commit_to_are_a_bad_merkle_root() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+
+        let mut block = next_block(&chain, &key, vec![]);
+        block.transactions[0].outputs[0].value -= 1;
+
+        assert!(matches!(
+            chain.add_block(block),
+            Err(BtcError::InvalidMerkleRoot)
+        ));
+    }
+
+    #[test]
+    fn a_header_no_later_than_the_median_is_a_stale_timestamp() {
+        let key = PrivateKey::new_key();
+        let chain = regtest_chain(&key);
+        let genesis = chain.blocks[0].hash();
+
+        let first = chain.blocks[1].header.clone();
+        let mut second = next_block(&chain, &key, vec![]).header;
+        second.timestamp = first.timestamp;
+        assert!(second.mine(1_000_000));
+
+        assert!(matches!(
+            BlockHeader::verify_chain(chain.params(), genesis, &[first, second]),
+            Err(BtcError::StaleTimestamp)
+        ));
+    }
+
+    #[test]
+    fn a_coinbase_paying_too_much_is_a_coinbase_mismatch() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+
+        let mut block = next_block(&chain, &key, vec![]);
+        block.transactions[0].outputs[0].value += 1;
+        block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+        assert!(block.header.mine(1_000_000));
+
+        assert!(matches!(
+            chain.add_block(block),
+            Err(BtcError::CoinbaseMismatch)
+        ));
+    }
+
+    #[test]
+    fn side_blocks_are_capped_oldest_first() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        let genesis = chain.blocks().next().unwrap().clone();
+
+        // orphans: each claims a parent nobody has
+        let orphans = (0..=crate::MAX_SIDE_BLOCKS)
+            .map(|_| {
+                let mut parent = genesis.clone();
+                parent.header.nonce = rand_nonce();
+                block_on(&chain, &parent, 1, &key, vec![])
+            })
+            .collect::<Vec<_>>();
+
+        for orphan in &orphans {
+            chain.add_block(orphan.clone()).unwrap();
+        }
+
+        assert_eq!(chain.side_blocks.blocks.len(), crate::MAX_SIDE_BLOCKS);
+        assert!(!chain.side_blocks.contains(&orphans[0].hash()));
+        assert!(chain.side_blocks.contains(&orphans[1].hash()));
+        assert_eq!(chain.side_blocks.order.len(), crate::MAX_SIDE_BLOCKS);
+        assert_eq!(chain.side_blocks.children.len(), crate::MAX_SIDE_BLOCKS);
+    }
+
+    // a chain whose genesis coinbase has matured, so it can be spent
+    fn mature_chain(key: &PrivateKey) -> BlockChain {
+        let mut chain = regtest_chain(key);
+        extend(&mut chain, key, crate::COINBASE_MATURITY as usize - 1);
+        chain
+    }
+
+    fn mempool_hashes(chain: &BlockChain) -> Vec<Hash> {
+        chain.mempool().iter().map(|(_, tx)| tx.hash()).collect()
+    }
+
+    #[test]
+    fn blocks_and_transactions_are_numbered_in_order() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        extend(&mut chain, &key, 1);
+
+        // one block carrying two spends after its coinbase
+        let spends = (1..=2)
+            .map(|height| {
+                let prev = chain.blocks[height].transactions[0].outputs[0].clone();
+                spend(&prev, &key, prev.value - 1000)
+            })
+            .collect::<Vec<_>>();
+        let block = next_block(&chain, &key, spends);
+        chain.add_block(block).unwrap();
+        extend(&mut chain, &key, 1);
+
+        let heights = chain
+            .blocks_with_height()
+            .map(|(height, block)| (height, block.hash()))
+            .collect::<Vec<_>>();
+        let expected = chain
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(height, block)| (height as u64, block.hash()))
+            .collect::<Vec<_>>();
+        assert_eq!(heights, expected);
+
+        let numbered = chain
+            .transactions()
+            .map(|(height, index, tx)| (height, index, tx.hash()))
+            .collect::<Vec<_>>();
+        let expected = chain
+            .blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(height, block)| {
+                block
+                    .transactions
+                    .iter()
+                    .enumerate()
+                    .map(move |(index, tx)| (height as u64, index, tx.hash()))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(numbered, expected);
+
+        // a coinbase at index zero of every block, and the spends after it
+        let coinbases = numbered.iter().filter(|(_, index, _)| *index == 0).count();
+        assert_eq!(coinbases, chain.blocks.len());
+        let tip = chain.block_height() - 2;
+        let in_block = numbered
+            .iter()
+            .filter(|(height, _, _)| *height == tip)
+            .map(|(_, index, _)| *index)
+            .collect::<Vec<_>>();
+        assert_eq!(in_block, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn a_saved_mempool_keeps_what_is_still_valid_on_reload() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        extend(&mut chain, &key, 1);
+
+        let first = first_output(&chain);
+        let second = chain.blocks().nth(2).unwrap().transactions[0].outputs[0].clone();
+        let kept = spend(&first, &key, 40 * 100_000_000);
+        let conflicting = spend(&second, &key, 40 * 100_000_000);
+        chain.add_to_mempool(kept.clone()).unwrap();
+        chain.add_to_mempool(conflicting).unwrap();
+
+        let dir = std::env::temp_dir();
+        let id = uuid::Uuid::new_v4();
+        let chain_file = dir.join(format!("rsbtc-chain-{}.cbor", id));
+        let mempool_file = dir.join(format!("rsbtc-mempool-{}.cbor", id));
+        chain.save_mempool_to_file(&mempool_file).unwrap();
+
+        // a block spending the second output arrives before the restart
+        let block = next_block(&chain, &key, vec![spend(&second, &key, 30 * 100_000_000)]);
+        chain.add_block(block).unwrap();
+        chain.save_to_file(&chain_file).unwrap();
+
+        let mut reloaded = BlockChain::load_from_file(&chain_file).unwrap();
+        assert_eq!(reloaded.load_mempool_from_file(&mempool_file).unwrap(), 1);
+        assert_eq!(mempool_hashes(&reloaded), vec![kept.hash()]);
+    }
+
+    #[test]
+    fn a_block_with_its_last_transaction_repeated_is_refused() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        extend(&mut chain, &key, 1);
+
+        // an odd count, so the last transaction is the one padded
+        let prev = first_output(&chain);
+        let first = spend(&prev, &key, prev.value - 1000);
+        let prev = chain.blocks().nth(2).unwrap().transactions[0].outputs[0].clone();
+        let second = spend(&prev, &key, prev.value - 1000);
+        let block = next_block(&chain, &key, vec![first, second.clone()]);
+
+        // same header, so same hash, with one more transaction
+        let mut mutated = block.clone();
+        mutated.transactions.push(second);
+        assert_eq!(mutated.hash(), block.hash());
+
+        let height = chain.block_height();
+        assert!(matches!(
+            chain.add_block(mutated),
+            Err(BtcError::InvalidMerkleRoot)
+        ));
+        assert_eq!(chain.block_height(), height);
+
+        // and the genuine block is not turned away as already seen
+        chain.add_block(block.clone()).unwrap();
+        assert_eq!(tip(&chain), block.hash());
+    }
+
+    #[test]
+    fn replacement_paying_more_evicts_the_original() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+
+        let original = spend(&prev, &key, prev.value - 1000);
+        let replacement = spend(&prev, &key, prev.value - 2000);
+
+        chain.add_to_mempool(original).unwrap();
+        chain.add_to_mempool(replacement.clone()).unwrap();
+
+        assert_eq!(mempool_hashes(&chain), vec![replacement.hash()]);
+        assert!(chain.utxos()[&prev.hash()].0);
+    }
+
+    #[test]
+    fn a_full_mempool_makes_room_only_for_better_payers() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+
+        // filled directly, over made-up outputs and with one signature
+        // copied around, since validating this many transactions is slow;
+        // the cheapest comes last
+        let signed = spend(&output(100_000, &key.public_key()), &key, 0);
+        let fillers = (0..crate::MAX_MEMPOOL_SIZE as u64)
+            .map(|i| {
+                let prev = output(100_000, &key.public_key());
+                chain.utxos.insert(prev.hash(), (true, prev.clone()));
+
+                let mut tx = signed.clone();
+                tx.inputs[0].prev_tx_output_hash = prev.hash();
+                tx.outputs[0] = output(100_000 - 7000 + i, &key.public_key());
+                tx
+            })
+            .collect::<Vec<_>>();
+        let cheapest = fillers.last().unwrap().clone();
+        chain.mempool = fillers.into_iter().map(|tx| (Utc::now(), tx)).collect();
+        chain.sort_mempool();
+
+        let first = first_output(&chain);
+        let cheap = spend(&first, &key, first.value - 1000);
+        assert!(matches!(
+            chain.add_to_mempool(cheap),
+            Err(BtcError::MempoolFull)
+        ));
+        assert!(!chain.utxos()[&first.hash()].0);
+
+        let generous = spend(&first, &key, first.value - 1_000_000);
+        chain.add_to_mempool(generous.clone()).unwrap();
+
+        let hashes = mempool_hashes(&chain);
+        assert_eq!(hashes.len(), crate::MAX_MEMPOOL_SIZE);
+        assert_eq!(hashes[0], generous.hash());
+        assert!(!hashes.contains(&cheapest.hash()));
+        assert!(!chain.utxos()[&cheapest.inputs[0].prev_tx_output_hash].0);
+    }
+
+    #[test]
+    fn replacement_paying_the_same_or_less_is_rejected() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+
+        let original = spend(&prev, &key, prev.value - 2000);
+        chain.add_to_mempool(original.clone()).unwrap();
+
+        for fee in [2000, 1000] {
+            let replacement = spend(&prev, &key, prev.value - fee);
+            assert!(matches!(
+                chain.add_to_mempool(replacement),
+                Err(BtcError::ReplacementFeeTooLow)
+            ));
+        }
+
+        assert_eq!(mempool_hashes(&chain), vec![original.hash()]);
+    }
+
+    #[test]
+    fn resubmitting_a_mempool_transaction_is_a_no_op() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+
+        let tx = spend(&prev, &key, prev.value - 1000);
+        chain.add_to_mempool(tx.clone()).unwrap();
+
+        chain.test_mempool_accept(&tx).unwrap();
+        chain.add_to_mempool(tx.clone()).unwrap();
+        assert_eq!(mempool_hashes(&chain), vec![tx.hash()]);
+    }
+
+    fn utxo_state(chain: &BlockChain) -> Vec<(Hash, bool, u64)> {
+        let mut state = chain
+            .utxos()
+            .iter()
+            .map(|(hash, (marked, output))| (*hash, *marked, output.value))
+            .collect::<Vec<_>>();
+        state.sort_by_key(|(hash, _, _)| hash.to_string());
+        state
+    }
+
+    #[test]
+    fn an_exported_snapshot_imports_the_same_utxos() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+        chain
+            .add_to_mempool(spend(&prev, &key, prev.value - 1000))
+            .unwrap();
+
+        let mut snapshot = vec![];
+        chain.export_utxo_snapshot(&mut snapshot).unwrap();
+
+        let mut restored = chain.clone();
+        restored.utxos.clear();
+        restored.owners.clear();
+        restored.import_utxo_snapshot(snapshot.as_slice()).unwrap();
+
+        // every output comes back unchanged, the mempool's input still marked
+        let entries = |chain: &BlockChain| {
+            let mut entries = chain
+                .utxos()
+                .iter()
+                .map(|(hash, (marked, output))| (*hash, *marked, output.hash()))
+                .collect::<Vec<_>>();
+            entries.sort_by_key(|(hash, _, _)| hash.to_string());
+            entries
+        };
+        assert_eq!(entries(&restored), entries(&chain));
+        assert!(restored.utxos()[&prev.hash()].0);
+        assert_eq!(
+            restored.utxos_of(&key.public_key()).count(),
+            chain.utxos_of(&key.public_key()).count()
+        );
+
+        // a chain of the same height with another tip refuses it
+        let mut other = mature_chain(&PrivateKey::new_key());
+        assert_eq!(other.block_height(), chain.block_height());
+        assert!(matches!(
+            other.import_utxo_snapshot(snapshot.as_slice()),
+            Err(BtcError::SnapshotMismatch)
+        ));
+    }
+
+    #[test]
+    fn incremental_utxos_match_a_full_rebuild() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+
+        // each block spends the coinbase that just matured, splitting it in
+        // two, and one output of the block before it
+        let mut change = None;
+        for height in 1..=100 {
+            let coinbase = chain.blocks().nth(height).unwrap().transactions[0].outputs[0].clone();
+            let mut split = spend(&coinbase, &key, coinbase.value / 2);
+            split
+                .outputs
+                .push(output(coinbase.value / 2 - 1000, &key.public_key()));
+            split.inputs[0].signature = Signature::sign_input(&split, 0, &coinbase.hash(), &key);
+
+            let mut transactions = vec![split.clone()];
+            if let Some(prev) = change.take() {
+                transactions.push(spend(&prev, &key, 5000));
+            }
+            change = Some(split.outputs[1].clone());
+
+            let block = next_block(&chain, &key, transactions);
+            chain.add_block(block).unwrap();
+        }
+
+        let incremental = utxo_state(&chain);
+        let mut rebuilt = chain.clone();
+        rebuilt.rebuild_utxos();
+
+        assert_eq!(incremental, utxo_state(&rebuilt));
+        assert_eq!(chain.block_height(), 201);
+    }
+
+    fn with_timestamp(mut block: Block, timestamp: DateTime<Utc>) -> Block {
+        block.header.timestamp = timestamp;
+        assert!(block.header.mine(1_000_000));
+        block
+    }
+
+    #[test]
+    fn a_block_past_the_size_limit_is_rejected() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+
+        // the coinbase split into `extra` more outputs worth nothing, so the
+        // block is valid apart from its size
+        let padded = |chain: &BlockChain, extra: usize| {
+            let mut block = next_block(chain, &key, vec![]);
+            let coinbase = &mut block.transactions[0];
+            coinbase
+                .outputs
+                .extend((0..extra).map(|_| output(0, &key.public_key())));
+            block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+            assert!(block.header.mine(1_000_000));
+            block
+        };
+
+        let per_output = crate::util::cbor_size(&output(0, &key.public_key()));
+        let oversized = padded(&chain, crate::MAX_BLOCK_SIZE / per_output + 1);
+        assert!(crate::util::cbor_size(&oversized) > crate::MAX_BLOCK_SIZE);
+        assert!(matches!(
+            chain.add_block(oversized),
+            Err(BtcError::BlockTooLarge)
+        ));
+        assert_eq!(chain.block_height(), 2);
+
+        chain.add_block(padded(&chain, 100)).unwrap();
+        assert_eq!(chain.block_height(), 3);
+    }
+
+    #[test]
+    fn far_future_blocks_are_rejected() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, 2);
+
+        let drift = chrono::Duration::seconds(crate::MAX_FUTURE_DRIFT as i64);
+        let block = next_block(&chain, &key, vec![]);
+
+        let future = with_timestamp(block.clone(), Utc::now() + drift * 2);
+        assert!(matches!(
+            chain.add_block(future),
+            Err(BtcError::FutureTimestamp)
+        ));
+
+        let near = with_timestamp(block, Utc::now() + drift / 2);
+        chain.add_block(near).unwrap();
+    }
+
+    #[test]
+    fn blocks_at_or_below_the_median_time_past_are_rejected() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, crate::MEDIAN_TIME_SPAN * 2);
+
+        let median = chain.median_time_past().unwrap();
+        let tip_time = chain.blocks().last().unwrap().header.timestamp;
+        assert!(median < tip_time);
+
+        let block = next_block(&chain, &key, vec![]);
+
+        for timestamp in [median - chrono::Duration::seconds(1), median] {
+            assert!(matches!(
+                chain.add_block(with_timestamp(block.clone(), timestamp)),
+                Err(BtcError::StaleTimestamp)
+            ));
+        }
+
+        // earlier than the tip is fine, as long as it beats the median
+        let after_median = median + chrono::Duration::seconds(1);
+        assert!(after_median < tip_time);
+        chain
+            .add_block(with_timestamp(block, after_median))
+            .unwrap();
+    }
+
+    #[test]
+    fn disconnecting_the_tip_returns_its_transactions() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+
+        let tx = spend(&prev, &key, prev.value - 1000);
+        chain.add_to_mempool(tx.clone()).unwrap();
+        let before = utxo_state(&chain);
+        let height = chain.block_height();
+
+        let block = next_block(&chain, &key, vec![tx.clone()]);
+        chain.add_block(block.clone()).unwrap();
+        assert!(chain.mempool().is_empty());
+
+        let disconnected = chain.disconnect_tip().unwrap();
+        assert_eq!(disconnected.hash(), block.hash());
+        assert_eq!(chain.block_height(), height);
+        assert_eq!(mempool_hashes(&chain), vec![tx.hash()]);
+        assert_eq!(utxo_state(&chain), before);
+        assert_eq!(chain.height_of(&block.hash()), None);
+
+        // and the block can be connected again
+        chain.add_block(block).unwrap();
+    }
+
+    // the owners index answers exactly what a scan of the whole set would,
+    // and holds nothing the set has lost
+    fn assert_owners_indexed(chain: &BlockChain, keys: &[&PrivateKey]) {
+        for key in keys {
+            let pubkey = key.public_key();
+            let mut indexed = chain
+                .utxos_of(&pubkey)
+                .map(|(marked, output)| (output.hash(), *marked))
+                .collect::<Vec<_>>();
+            let mut scanned = chain
+                .utxos()
+                .values()
+                .filter(|(_, output)| output.pubkey == pubkey)
+                .map(|(marked, output)| (output.hash(), *marked))
+                .collect::<Vec<_>>();
+            indexed.sort_by_key(|(hash, _)| hash.to_string());
+            scanned.sort_by_key(|(hash, _)| hash.to_string());
+            assert_eq!(indexed, scanned);
+        }
+
+        for (pubkey, hashes) in &chain.owners {
+            assert!(!hashes.is_empty());
+            for hash in hashes {
+                assert_eq!(&chain.utxos()[hash].1.pubkey, pubkey);
+            }
+        }
+    }
+
+    #[test]
+    fn the_owners_index_follows_blocks_reorgs_and_disconnects() {
+        let key = PrivateKey::new_key();
+        let other = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+        assert_owners_indexed(&chain, &[&key, &other]);
+
+        let mut tx = spend(&prev, &key, prev.value - 2000);
+        tx.outputs.push(output(1000, &other.public_key()));
+        tx.inputs[0].signature = Signature::sign_input(&tx, 0, &prev.hash(), &key);
+        chain.add_to_mempool(tx.clone()).unwrap();
+        assert_owners_indexed(&chain, &[&key, &other]);
+
+        let fork = chain.blocks().last().unwrap().clone();
+        let height = chain.block_height();
+        chain.add_block(next_block(&chain, &key, vec![tx])).unwrap();
+        assert_eq!(chain.utxos_of(&other.public_key()).count(), 1);
+        assert_owners_indexed(&chain, &[&key, &other]);
+
+        // a longer branch without the transaction swaps its output for the
+        // branch's two coinbases, both paying the other key
+        let side_1 = block_on(&chain, &fork, height, &other, vec![]);
+        chain.add_block(side_1.clone()).unwrap();
+        let side_2 = block_on(&chain, &side_1, height + 1, &other, vec![]);
+        chain.add_block(side_2).unwrap();
+        assert_eq!(chain.height_of(&side_1.hash()), Some(height));
+        assert_eq!(chain.utxos_of(&other.public_key()).count(), 2);
+        assert_owners_indexed(&chain, &[&key, &other]);
+
+        chain.disconnect_tip().unwrap();
+        chain.disconnect_tip().unwrap();
+        assert_eq!(chain.utxos_of(&other.public_key()).count(), 0);
+        assert_owners_indexed(&chain, &[&key, &other]);
+    }
+
+    #[test]
+    fn disconnecting_keeps_a_child_of_a_transaction_in_the_same_block() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+
+        let parent = spend(&prev, &key, prev.value - 1000);
+        let child = spend(&parent.outputs[0], &key, prev.value - 2000);
+        chain.add_to_mempool(parent.clone()).unwrap();
+        let before = utxo_state(&chain);
+
+        // the child's input is unknown to calculate_fees, so its fee is
+        // added to the coinbase by hand
+        let mut block = next_block(&chain, &key, vec![parent.clone()]);
+        block.transactions[0].outputs[0].value += 1000;
+        block.transactions.push(child.clone());
+        block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+        assert!(block.header.mine(1_000_000));
+        chain.add_block(block.clone()).unwrap();
+
+        chain.disconnect_tip().unwrap();
+        assert_eq!(mempool_hashes(&chain), vec![parent.hash()]);
+        assert_eq!(utxo_state(&chain), before);
+        let orphans = chain
+            .orphans()
+            .iter()
+            .map(|(_, tx)| tx.hash())
+            .collect::<Vec<_>>();
+        assert_eq!(orphans, vec![child.hash()]);
+
+        // once the parent is mined again the child returns to the mempool
+        let parent_block = next_block(&chain, &key, vec![parent.clone()]);
+        chain.add_block(parent_block).unwrap();
+        assert_eq!(mempool_hashes(&chain), vec![child.hash()]);
+        assert!(chain.orphans().is_empty());
+
+        // and the original block confirms both outright
+        chain.disconnect_tip().unwrap();
+        chain.add_block(block).unwrap();
+        assert!(chain.mempool().is_empty());
+        assert!(chain.orphans().is_empty());
+    }
+
+    #[test]
+    fn a_child_sent_before_its_parent_waits_for_it() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+
+        let parent = spend(&prev, &key, prev.value - 1000);
+        let child = spend(&parent.outputs[0], &key, prev.value - 2000);
+        assert!(matches!(
+            chain.add_to_mempool(child.clone()),
+            Err(BtcError::OrphanTransaction)
+        ));
+        assert!(chain.mempool().is_empty());
+        assert_eq!(chain.orphans().len(), 1);
+
+        // the parent lands in a block and the child is taken in
+        chain
+            .add_block(next_block(&chain, &key, vec![parent]))
+            .unwrap();
+        assert_eq!(mempool_hashes(&chain), vec![child.hash()]);
+        assert!(chain.orphans().is_empty());
+    }
+
+    #[test]
+    fn spending_an_output_already_spent_on_chain_is_not_an_orphan() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+
+        let spent = spend(&prev, &key, prev.value - 1000);
+        chain
+            .add_block(next_block(&chain, &key, vec![spent]))
+            .unwrap();
+
+        // no parent can bring the output back, so it is refused outright
+        let again = spend(&prev, &key, prev.value - 2000);
+        assert!(matches!(
+            chain.add_to_mempool(again),
+            Err(BtcError::SpentInput)
+        ));
+        assert!(chain.mempool().is_empty());
+        assert!(chain.orphans().is_empty());
+    }
+
+    #[test]
+    fn fast_windows_make_the_target_harder_and_slow_ones_easier() {
+        // retargets every third block, from the easiest target there is
+        let params = ChainParams {
+            network: Network::Test,
+            min_target: U256::MAX,
+            difficulty_update_interval: 3,
+            ..Network::Test.params()
+        };
+        let key = PrivateKey::new_key();
+        let mut chain = BlockChain::from_genesis(params);
+
+        // blocks `secs` apart until the next retarget, then the new target
+        let mut window = |secs: i64| {
+            loop {
+                let tip = chain.blocks().last().unwrap().clone();
+                let mut block = block_on(&chain, &tip, chain.block_height(), &key, vec![]);
+                block.header.timestamp = tip.header.timestamp + chrono::Duration::seconds(secs);
+                assert!(block.header.mine(1_000_000));
+                chain.add_block(block).unwrap();
+
+                if chain.blocks.len().is_multiple_of(3) {
+                    return chain.target();
+                }
+            }
+        };
+
+        // a thirtieth of the ideal time, but never more than four times
+        // harder per window
+        let harder = window(1);
+        assert_eq!(harder, U256::MAX / 4);
+        let hardest = window(1);
+        assert_eq!(hardest, harder / 4);
+
+        // slow windows ease it again, never past the easiest target
+        assert_eq!(window(100), hardest * 4);
+        assert_eq!(window(100), hardest * 16);
+        assert_eq!(window(100), U256::MAX);
+
+        // on time leaves it where it is
+        assert_eq!(window(15), U256::MAX);
+    }
+
+    #[test]
+    fn difficulty_is_one_at_the_easiest_target_and_grows_as_it_shrinks() {
+        let mut chain = BlockChain::from_genesis(Network::Main.params());
+        assert_eq!(chain.target, crate::MIN_TARGET);
+        assert_eq!(chain.difficulty(), 1.0);
+
+        let mut last = chain.difficulty();
+        for divisor in [2u64, 1 << 20, 1 << 40] {
+            chain.target = crate::MIN_TARGET / U256::from(divisor);
+            let difficulty = chain.difficulty();
+            assert!(difficulty > last);
+            assert!((difficulty / divisor as f64 - 1.0).abs() < 1e-9);
+            last = difficulty;
+        }
+
+        // the hardest possible target still gives a finite number
+        chain.target = U256::one();
+        assert!(chain.difficulty().is_finite());
+        assert!(chain.difficulty() > last);
+    }
+
+    #[test]
+    fn disconnecting_restores_the_target_by_rule() {
+        // every hash meets MAX, so retargets stay cheap to mine against
+        let params = ChainParams {
+            network: Network::Test,
+            min_target: U256::MAX,
+            difficulty_update_interval: 3,
+            ..Network::Test.params()
+        };
+        let key = PrivateKey::new_key();
+        let mut chain = BlockChain::from_genesis(params);
+
+        // blocks a second apart are ten times too fast, so each window
+        // makes the target four times harder
+        let mut targets = vec![chain.target()];
+        for _ in 0..7 {
+            extend(&mut chain, &key, 1);
+            targets.push(chain.target());
+        }
+        assert!(targets[7] < targets[2]);
+
+        while chain.block_height() > 1 {
+            chain.disconnect_tip().unwrap();
+            assert_eq!(chain.target(), targets[chain.block_height() as usize - 1]);
+        }
+    }
+
+    #[test]
+    fn dust_is_kept_out_of_the_mempool_but_not_out_of_blocks() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+        let other = PrivateKey::new_key().public_key();
+
+        let pay = |value| {
+            let mut tx = spend(&prev, &key, prev.value - value - 1000);
+            tx.outputs.push(output(value, &other));
+            tx.inputs[0].signature = Signature::sign_input(&tx, 0, &prev.hash(), &key);
+            tx
+        };
+
+        let dust = pay(crate::DUST_THRESHOLD - 1);
+        assert!(matches!(
+            chain.add_to_mempool(dust.clone()),
+            Err(BtcError::DustOutput)
+        ));
+        chain
+            .test_mempool_accept(&pay(crate::DUST_THRESHOLD))
+            .unwrap();
+
+        let block = next_block(&chain, &key, vec![dust]);
+        chain.add_block(block).unwrap();
+    }
+
+    fn rand_nonce() -> u64 {
+        uuid::Uuid::new_v4().as_u64_pair().0
+    }
+
+    // a block on the tip holding `tx` after a coinbase that pays only the
+    // reward, built without asking the chain what `tx` is worth
+    fn block_with(chain: &BlockChain, key: &PrivateKey, tx: Transaction) -> Block {
+        let mut block = next_block(chain, key, vec![]);
+        block.transactions.push(tx);
+        block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+        assert!(block.header.mine(1_000_000));
+        block
+    }
+
+    fn assert_same_error(chain: &mut BlockChain, key: &PrivateKey, tx: Transaction) -> BtcError {
+        let mempool_error = chain.test_mempool_accept(&tx).unwrap_err();
+        let block_error = chain.add_block(block_with(chain, key, tx)).unwrap_err();
+        assert_eq!(mempool_error.to_string(), block_error.to_string());
+        block_error
+    }
+
+    #[test]
+    fn mempool_and_blocks_reject_bad_transactions_alike() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+
+        let missing = spend(&output(prev.value, &key.public_key()), &key, prev.value);
+        assert!(matches!(
+            assert_same_error(&mut chain, &key, missing),
+            BtcError::UnknownInput
+        ));
+
+        let mut forged = spend(&prev, &key, prev.value);
+        forged.inputs[0].signature =
+            Signature::sign_input(&forged, 0, &prev.hash(), &PrivateKey::new_key());
+        assert!(matches!(
+            assert_same_error(&mut chain, &key, forged),
+            BtcError::InvalidSignature
+        ));
+
+        let mut overflowing = spend(&prev, &key, prev.value);
+        overflowing.outputs = vec![
+            output(u64::MAX / 2 + 1, &key.public_key()),
+            output(u64::MAX / 2 + 1, &key.public_key()),
+        ];
+        overflowing.inputs[0].signature =
+            Signature::sign_input(&overflowing, 0, &prev.hash(), &key);
+        assert!(matches!(
+            assert_same_error(&mut chain, &key, overflowing),
+            BtcError::ValueOverflow
+        ));
+    }
+
+    #[test]
+    fn a_coinbase_is_spendable_only_once_it_matures() {
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key);
+        extend(&mut chain, &key, crate::COINBASE_MATURITY as usize - 2);
+
+        // a block short, in the mempool and in a block alike; no fee, so
+        // the coinbase of the block is right either way
+        let prev = first_output(&chain);
+        assert!(matches!(
+            assert_same_error(&mut chain, &key, spend(&prev, &key, prev.value)),
+            BtcError::ImmatureCoinbase
+        ));
+
+        extend(&mut chain, &key, 1);
+        let tx = spend(&prev, &key, prev.value - 1000);
+        chain.test_mempool_accept(&tx).unwrap();
+        let block = next_block(&chain, &key, vec![tx]);
+        chain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn a_locked_transaction_is_accepted_only_from_its_lock_height() {
+        let key = PrivateKey::new_key();
+        let mut chain = mature_chain(&key);
+        let prev = first_output(&chain);
+
+        // two blocks past the next one; the lock is signed over, so it is set
+        // before signing
+        let lock_time = chain.block_height() as u32 + 2;
+        let locked = |value| {
+            let mut tx = spend(&prev, &key, value);
+            tx.lock_time = lock_time;
+            tx.inputs[0].signature = Signature::sign_input(&tx, 0, &prev.hash(), &key);
+            tx
+        };
+        let tx = locked(prev.value - 1000);
+
+        assert!(matches!(
+            assert_same_error(&mut chain, &key, locked(prev.value)),
+            BtcError::TransactionLocked
+        ));
+        assert!(matches!(
+            chain.add_to_mempool(tx.clone()),
+            Err(BtcError::TransactionLocked)
+        ));
+
+        extend(&mut chain, &key, 1);
+        assert!(matches!(
+            chain.test_mempool_accept(&tx),
+            Err(BtcError::TransactionLocked)
+        ));
+
+        extend(&mut chain, &key, 1);
+        chain.add_to_mempool(tx.clone()).unwrap();
+        let block = next_block(&chain, &key, vec![tx]);
+        chain.add_block(block).unwrap();
+    }
 }
@@ -3,26 +3,258 @@ use uuid::Uuid;
 
 use crate::{
     crypto::{PublicKey, Signature},
+    error::{BtcError, Result},
     sha256::Hash,
     util::Savable,
 };
 
+use std::collections::{HashMap, HashSet};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 
+// sats per serialized byte
+pub fn fee_rate(fee: u64, size: usize) -> f64 {
+    fee as f64 / size.max(1) as f64
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    // the lowest block height this transaction may be included at; zero is
+    // left out of the encoding, so transactions from before it existed keep
+    // their hashes and signatures
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub lock_time: u32,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
 }
 
 impl Transaction {
     pub fn new(inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Self {
-        Self { inputs, outputs }
+        Self {
+            inputs,
+            outputs,
+            lock_time: 0,
+        }
+    }
+
+    pub fn is_final(&self, height: u64) -> bool {
+        height >= self.lock_time as u64
     }
 
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
+
+    pub fn serialized_size(&self) -> usize {
+        crate::util::cbor_size(self)
+    }
+
+    // the message signed by each input: it commits to the spent output, to
+    // every other input and to every output of this transaction, so a
+    // signature can't be reused in a different spend of the same coin, nor
+    // moved next to other inputs. Every input has to be in place before any
+    // of them is signed
+    pub fn sighash(&self, input_index: usize, prev_hash: &Hash) -> Hash {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|input| input.prev_tx_output_hash)
+            .collect::<Vec<_>>();
+
+        if self.lock_time == 0 {
+            return Hash::hash(&(
+                crate::SIGHASH_VERSION,
+                input_index as u64,
+                prev_hash,
+                &inputs,
+                &self.outputs,
+            ));
+        }
+
+        // signed too, or anyone relaying the transaction could move it
+        Hash::hash(&(
+            crate::SIGHASH_VERSION,
+            input_index as u64,
+            prev_hash,
+            &inputs,
+            &self.outputs,
+            self.lock_time,
+        ))
+    }
+
+    // `immature` holds the coinbase outputs that cannot be spent yet
+    pub(crate) fn validate(
+        &self,
+        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        immature: &HashSet<Hash>,
+    ) -> Result<u64> {
+        self.validate_inner(utxos, immature, true)
+    }
+
+    // everything but the signatures, which the caller checks in bulk
+    pub(crate) fn validate_unsigned(
+        &self,
+        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        immature: &HashSet<Hash>,
+    ) -> Result<u64> {
+        self.validate_inner(utxos, immature, false)
+    }
+
+    // the (sighash, signature, key) of every input whose output is known
+    pub(crate) fn signature_checks<'a>(
+        &'a self,
+        utxos: &'a HashMap<Hash, (bool, TransactionOutput)>,
+    ) -> impl Iterator<Item = (Hash, &'a Signature, &'a PublicKey)> {
+        self.inputs
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, input)| {
+                let (_, prev_output) = utxos.get(&input.prev_tx_output_hash)?;
+                Some((
+                    self.sighash(index, &input.prev_tx_output_hash),
+                    &input.signature,
+                    &prev_output.pubkey,
+                ))
+            })
+    }
+
+    fn validate_inner(
+        &self,
+        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        immature: &HashSet<Hash>,
+        check_signatures: bool,
+    ) -> Result<u64> {
+        // only the coinbase may create value out of nothing
+        if self.inputs.is_empty() {
+            return Err(BtcError::MissingInputs);
+        }
+
+        let mut known_inputs = HashSet::new();
+        let mut input_value: u64 = 0;
+
+        for (index, input) in self.inputs.iter().enumerate() {
+            let Some((_, prev_output)) = utxos.get(&input.prev_tx_output_hash) else {
+                return Err(BtcError::UnknownInput);
+            };
+
+            if !known_inputs.insert(input.prev_tx_output_hash) {
+                return Err(BtcError::DoubleSpend);
+            }
+
+            if immature.contains(&input.prev_tx_output_hash) {
+                return Err(BtcError::ImmatureCoinbase);
+            }
+
+            if check_signatures
+                && !input.signature.verify_input(
+                    self,
+                    index,
+                    &input.prev_tx_output_hash,
+                    &prev_output.pubkey,
+                )
+            {
+                return Err(BtcError::InvalidSignature);
+            }
+
+            input_value = input_value
+                .checked_add(prev_output.value)
+                .ok_or(BtcError::ValueOverflow)?;
+        }
+
+        let output_value = self
+            .outputs
+            .iter()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+            .ok_or(BtcError::ValueOverflow)?;
+
+        input_value
+            .checked_sub(output_value)
+            .ok_or(BtcError::InsufficientInputValue)
+    }
+
+    // the same checks as validate, but collecting every failure instead of
+    // stopping at the first
+    pub(crate) fn validation_errors(
+        &self,
+        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        immature: &HashSet<Hash>,
+    ) -> Vec<BtcError> {
+        let mut errors = vec![];
+
+        if self.inputs.is_empty() {
+            errors.push(BtcError::MissingInputs);
+        }
+
+        let mut known_inputs = HashSet::new();
+        let mut all_inputs_known = true;
+        let mut input_value = Some(0u64);
+
+        for (index, input) in self.inputs.iter().enumerate() {
+            if !known_inputs.insert(input.prev_tx_output_hash) {
+                errors.push(BtcError::DoubleSpend);
+                continue;
+            }
+
+            let Some((_, prev_output)) = utxos.get(&input.prev_tx_output_hash) else {
+                errors.push(BtcError::UnknownInput);
+                all_inputs_known = false;
+                continue;
+            };
+
+            if immature.contains(&input.prev_tx_output_hash) {
+                errors.push(BtcError::ImmatureCoinbase);
+            }
+
+            if !input.signature.verify_input(
+                self,
+                index,
+                &input.prev_tx_output_hash,
+                &prev_output.pubkey,
+            ) {
+                errors.push(BtcError::InvalidSignature);
+            }
+
+            input_value = input_value.and_then(|sum| sum.checked_add(prev_output.value));
+        }
+
+        let output_value = self
+            .outputs
+            .iter()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.value));
+
+        if input_value.is_none() || output_value.is_none() {
+            errors.push(BtcError::ValueOverflow);
+        }
+
+        if let (true, Some(input_value), Some(output_value)) =
+            (all_inputs_known, input_value, output_value)
+            && input_value < output_value
+        {
+            errors.push(BtcError::InsufficientInputValue);
+        }
+
+        errors
+    }
+
+    // tiny outputs cost more to spend than they are worth, so they would sit
+    // in the UTXO set forever. Change paid back to the key of one of the
+    // inputs is let through, since its owner is the one making it. This is
+    // relay policy: blocks may still carry dust
+    pub(crate) fn has_dust_output(&self, utxos: &HashMap<Hash, (bool, TransactionOutput)>) -> bool {
+        let spenders = self
+            .inputs
+            .iter()
+            .filter_map(|input| utxos.get(&input.prev_tx_output_hash))
+            .map(|(_, output)| &output.pubkey)
+            .collect::<HashSet<_>>();
+
+        self.outputs.iter().any(|output| {
+            output.value < crate::DUST_THRESHOLD && !spenders.contains(&output.pubkey)
+        })
+    }
 }
 
 impl Savable for Transaction {
@@ -59,3 +291,158 @@ impl TransactionOutput {
         Hash::hash(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::test_util::*;
+
+    fn pay(
+        prev: &TransactionOutput,
+        key: &PrivateKey,
+        outputs: Vec<TransactionOutput>,
+    ) -> Transaction {
+        let mut tx = spend(prev, key, 0);
+        tx.outputs = outputs;
+        tx.inputs[0].signature = Signature::sign_input(&tx, 0, &prev.hash(), key);
+        tx
+    }
+
+    #[test]
+    fn dust_is_judged_by_value_and_recipient() {
+        let key = PrivateKey::new_key();
+        let other = PrivateKey::new_key().public_key();
+        let prev = output(100_000, &key.public_key());
+        let utxos = HashMap::from([(prev.hash(), (false, prev.clone()))]);
+        let threshold = crate::DUST_THRESHOLD;
+
+        let below = pay(&prev, &key, vec![output(threshold - 1, &other)]);
+        assert!(below.has_dust_output(&utxos));
+
+        let exact = pay(&prev, &key, vec![output(threshold, &other)]);
+        assert!(!exact.has_dust_output(&utxos));
+
+        // dust change back to the spender is the spender's own business
+        let change = pay(
+            &prev,
+            &key,
+            vec![output(50_000, &other), output(1, &key.public_key())],
+        );
+        assert!(!change.has_dust_output(&utxos));
+
+        // validation itself no longer cares, so blocks may carry dust
+        assert!(below.validate(&utxos, &HashSet::new()).is_ok());
+    }
+
+    #[test]
+    fn signature_does_not_carry_over_to_another_spend() {
+        let key = PrivateKey::new_key();
+        let prev = output(10_000, &key.public_key());
+        let utxos = HashMap::from([(prev.hash(), (false, prev.clone()))]);
+
+        let original = spend(&prev, &key, 9000);
+        assert_eq!(original.validate(&utxos, &HashSet::new()).unwrap(), 1000);
+
+        // same coin, same signature, different recipient
+        let mut replayed = original.clone();
+        replayed.outputs = vec![output(9000, &PrivateKey::new_key().public_key())];
+
+        assert!(!replayed.inputs[0].signature.verify_input(
+            &replayed,
+            0,
+            &prev.hash(),
+            &key.public_key()
+        ));
+        assert!(matches!(
+            replayed.validate(&utxos, &HashSet::new()),
+            Err(BtcError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn signature_does_not_carry_over_to_other_inputs() {
+        let key = PrivateKey::new_key();
+        let coins = [10_000, 20_000, 30_000].map(|value| output(value, &key.public_key()));
+        let utxos = coins
+            .iter()
+            .map(|coin| (coin.hash(), (false, coin.clone())))
+            .collect::<HashMap<_, _>>();
+
+        let mut original = spend(&coins[0], &key, 25_000);
+        original
+            .inputs
+            .push(spend(&coins[1], &key, 0).inputs.remove(0));
+        for (index, coin) in coins[..2].iter().enumerate() {
+            original.inputs[index].signature =
+                Signature::sign_input(&original, index, &coin.hash(), &key);
+        }
+        assert_eq!(original.validate(&utxos, &HashSet::new()).unwrap(), 5000);
+
+        // the first input's signature next to a different sibling, with the
+        // sibling properly signed and the same outputs
+        let mut swapped = original.clone();
+        swapped.inputs[1].prev_tx_output_hash = coins[2].hash();
+        swapped.inputs[1].signature = Signature::sign_input(&swapped, 1, &coins[2].hash(), &key);
+        assert!(!swapped.inputs[0].signature.verify_input(
+            &swapped,
+            0,
+            &coins[0].hash(),
+            &key.public_key()
+        ));
+        assert!(matches!(
+            swapped.validate(&utxos, &HashSet::new()),
+            Err(BtcError::InvalidSignature)
+        ));
+
+        // or with the sibling dropped
+        let mut alone = original.clone();
+        alone.inputs.truncate(1);
+        assert!(!alone.inputs[0].signature.verify_input(
+            &alone,
+            0,
+            &coins[0].hash(),
+            &key.public_key()
+        ));
+    }
+
+    #[test]
+    fn signature_is_bound_to_its_input_index_and_lock_time() {
+        let key = PrivateKey::new_key();
+        let prev = output(10_000, &key.public_key());
+        let tx = spend(&prev, &key, 9000);
+        let signature = &tx.inputs[0].signature;
+
+        assert!(signature.verify_input(&tx, 0, &prev.hash(), &key.public_key()));
+        assert!(!signature.verify_input(&tx, 1, &prev.hash(), &key.public_key()));
+
+        let mut locked = tx.clone();
+        locked.lock_time = 5;
+        assert!(!signature.verify_input(&locked, 0, &prev.hash(), &key.public_key()));
+    }
+
+    #[test]
+    fn more_inputs_make_a_larger_transaction_and_a_lower_fee_rate() {
+        let key = PrivateKey::new_key();
+        let coins = [10_000, 20_000].map(|value| output(value, &key.public_key()));
+
+        let single = spend(&coins[0], &key, 9000);
+        let mut double = spend(&coins[0], &key, 29_000);
+        double
+            .inputs
+            .push(spend(&coins[1], &key, 0).inputs.remove(0));
+        for (index, coin) in coins.iter().enumerate() {
+            double.inputs[index].signature =
+                Signature::sign_input(&double, index, &coin.hash(), &key);
+        }
+
+        let (small, large) = (single.serialized_size(), double.serialized_size());
+        assert!(large > small);
+
+        // the same fee spread over more bytes
+        assert_eq!(fee_rate(1000, small), 1000.0 / small as f64);
+        assert!(fee_rate(1000, large) < fee_rate(1000, small));
+        assert_eq!(fee_rate(1000, 250), 4.0);
+        assert_eq!(fee_rate(1000, 0), 1000.0);
+    }
+}
@@ -1,11 +1,12 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
+use crate::error::{BtcError, Result};
 use crate::sha256::Hash;
 use crate::types::Transaction;
 
 use std::{
-    fs::File,
-    io::{Read, Result as IoResult, Write},
+    fs::{self, File},
+    io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write},
     path::Path,
 };
 
@@ -17,37 +18,291 @@ where
     fn save<O: Write>(&self, writer: O) -> IoResult<()>;
 
     fn save_to_file<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
-        let file = File::create(&path)?;
-        self.save(file)
+        write_atomically(path, |file| self.save(file))
     }
 
     fn load_from_file<P: AsRef<Path>>(path: P) -> IoResult<Self> {
         let file = File::open(&path)?;
         Self::load(file)
     }
+
+    fn save_json<O: Write>(&self, writer: O) -> IoResult<()>
+    where
+        Self: Serialize,
+    {
+        serde_json::to_writer_pretty(writer, self).map_err(IoError::from)
+    }
+
+    fn load_json<I: Read>(reader: I) -> IoResult<Self>
+    where
+        Self: DeserializeOwned,
+    {
+        serde_json::from_reader(reader).map_err(IoError::from)
+    }
+}
+
+// writes to a temporary file beside `path` and only renames it over `path`
+// once it is complete and synced, so a crash or a failed write leaves the
+// previous file as it was
+pub fn write_atomically<P: AsRef<Path>>(
+    path: P,
+    write: impl FnOnce(&mut File) -> IoResult<()>,
+) -> IoResult<()> {
+    let path = path.as_ref();
+
+    let mut temp_name = path
+        .file_name()
+        .ok_or_else(|| IoError::new(IoErrorKind::InvalidInput, "Path has no file name"))?
+        .to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let written = File::create(&temp_path).and_then(|mut file| {
+        write(&mut file)?;
+        file.sync_all()
+    });
+
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    fs::rename(&temp_path, path)?;
+
+    // the rename is only durable once the directory is; not every platform
+    // can open a directory for that, so this is best effort
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct MerkleRoot(Hash);
 
 impl MerkleRoot {
+    // a block always has its coinbase, so there is no real empty tree; it is
+    // given the zero hash rather than a panic
     pub fn calculate(transactions: &[Transaction]) -> Self {
-        let mut layer = vec![];
+        Self::calculate_with_mutation(transactions).0
+    }
 
-        for transaction in transactions {
-            layer.push(Hash::hash(transaction));
+    // the padding rule means [a, b, c] and [a, b, c, c] share a root
+    // (CVE-2012-2459), so a block can be made invalid by repeating its tail
+    // without changing its header. Any pair of equal hashes that was not
+    // padding marks such a list, and it is refused
+    pub fn calculate_strict(transactions: &[Transaction]) -> Result<Self> {
+        match Self::calculate_with_mutation(transactions) {
+            (_, true) => Err(BtcError::InvalidMerkleRoot),
+            (root, false) => Ok(root),
         }
+    }
+
+    fn calculate_with_mutation(transactions: &[Transaction]) -> (Self, bool) {
+        if transactions.is_empty() {
+            return (Self(Hash::zero()), false);
+        }
+
+        let mut layer = transactions.iter().map(Hash::hash).collect::<Vec<_>>();
+        let mut mutated = false;
 
         while layer.len() > 1 {
-            let mut new_layer = vec![];
-            for pair in layer.chunks(2) {
-                let left = pair[0];
-                let right = pair.get(1).unwrap_or(&pair[0]);
-                new_layer.push(Hash::hash(&[left, *right]));
+            mutated |= layer.chunks_exact(2).any(|pair| pair[0] == pair[1]);
+            layer = next_layer(layer);
+        }
+
+        (Self(layer[0]), mutated)
+    }
+
+    // sibling hashes from the leaf at `index` up to the root
+    pub fn proof(transactions: &[Transaction], index: usize) -> Option<Vec<Hash>> {
+        if index >= transactions.len() {
+            return None;
+        }
+
+        let mut layer = transactions.iter().map(Hash::hash).collect::<Vec<_>>();
+        let mut index = index;
+        let mut proof = vec![];
+
+        while layer.len() > 1 {
+            let sibling = layer.get(index ^ 1).unwrap_or(&layer[index]);
+            proof.push(*sibling);
+
+            layer = next_layer(layer);
+            index /= 2;
+        }
+
+        Some(proof)
+    }
+
+    pub fn verify_proof(&self, tx_hash: Hash, index: usize, proof: &[Hash]) -> bool {
+        let mut hash = tx_hash;
+        let mut index = index;
+
+        for sibling in proof {
+            hash = if index.is_multiple_of(2) {
+                Hash::hash(&[hash, *sibling])
+            } else {
+                Hash::hash(&[*sibling, hash])
+            };
+            index /= 2;
+        }
+
+        index == 0 && hash == self.0
+    }
+}
+
+// as in Bitcoin, a layer with an odd number of hashes has its last hash
+// paired with itself: [a, b, c] hashes to [H(a|b), H(c|c)]
+fn next_layer(mut layer: Vec<Hash>) -> Vec<Hash> {
+    if !layer.len().is_multiple_of(2) {
+        layer.push(*layer.last().expect("BUG: odd layer is not empty"));
+    }
+
+    layer
+        .chunks_exact(2)
+        .map(|pair| Hash::hash(&[pair[0], pair[1]]))
+        .collect()
+}
+
+// a writer that only counts, so sizes don't need a buffer of the encoding
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+pub fn cbor_size<T: Serialize>(value: &T) -> usize {
+    let mut counter = ByteCounter(0);
+    match ciborium::into_writer(value, &mut counter) {
+        Ok(()) => counter.0,
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::test_util::output;
+
+    fn transactions(count: usize) -> Vec<Transaction> {
+        let key = PrivateKey::new_key().public_key();
+        (0..count)
+            .map(|_| Transaction::new(vec![], vec![output(1000, &key)]))
+            .collect()
+    }
+
+    #[test]
+    fn roots_match_the_tree_worked_by_hand() {
+        let pair = |left: Hash, right: Hash| Hash::hash(&[left, right]);
+        let txs = transactions(4);
+        let [a, b, c, d] = [0, 1, 2, 3].map(|i| Hash::hash(&txs[i]));
+
+        assert_eq!(MerkleRoot::calculate(&[]).0, Hash::zero());
+        assert_eq!(MerkleRoot::calculate(&txs[..1]).0, a);
+        assert_eq!(MerkleRoot::calculate(&txs[..2]).0, pair(a, b));
+        // the odd one out is paired with itself
+        assert_eq!(
+            MerkleRoot::calculate(&txs[..3]).0,
+            pair(pair(a, b), pair(c, c))
+        );
+        assert_eq!(MerkleRoot::calculate(&txs).0, pair(pair(a, b), pair(c, d)));
+    }
+
+    #[test]
+    fn every_transaction_has_a_valid_proof() {
+        for count in 1..=9 {
+            let transactions = transactions(count);
+            let root = MerkleRoot::calculate(&transactions);
+
+            for (index, tx) in transactions.iter().enumerate() {
+                let proof = MerkleRoot::proof(&transactions, index).unwrap();
+                assert!(
+                    root.verify_proof(tx.hash(), index, &proof),
+                    "index {} of {}",
+                    index,
+                    count
+                );
             }
-            layer = new_layer;
+
+            assert!(MerkleRoot::proof(&transactions, count).is_none());
+        }
+    }
+
+    #[test]
+    fn tampered_proofs_are_rejected() {
+        let transactions = transactions(7);
+        let root = MerkleRoot::calculate(&transactions);
+        let tx_hash = transactions[2].hash();
+        let proof = MerkleRoot::proof(&transactions, 2).unwrap();
+
+        for level in 0..proof.len() {
+            let mut tampered = proof.clone();
+            tampered[level] = transactions[6].hash();
+            assert!(!root.verify_proof(tx_hash, 2, &tampered));
+        }
+
+        assert!(!root.verify_proof(transactions[3].hash(), 2, &proof));
+        assert!(!root.verify_proof(tx_hash, 3, &proof));
+        assert!(!root.verify_proof(tx_hash, 2, &proof[..proof.len() - 1]));
+    }
+
+    #[test]
+    fn repeated_tails_share_the_root_but_are_refused() {
+        for (count, repeat) in [(3, 1), (5, 1), (6, 2)] {
+            let genuine = transactions(count);
+            let mut mutated = genuine.clone();
+            mutated.extend_from_slice(&genuine[count - repeat..]);
+
+            let root = MerkleRoot::calculate_strict(&genuine).unwrap();
+            assert_eq!(MerkleRoot::calculate(&mutated), root);
+            assert!(matches!(
+                MerkleRoot::calculate_strict(&mutated),
+                Err(BtcError::InvalidMerkleRoot)
+            ));
         }
 
-        Self(layer[0])
+        // equal transactions anywhere in the list are caught, not just at
+        // the tail
+        let mut pair = transactions(4);
+        pair[1] = pair[0].clone();
+        assert!(MerkleRoot::calculate_strict(&pair).is_err());
+    }
+
+    #[test]
+    fn a_failed_write_leaves_the_previous_file_intact() {
+        let dir = std::env::temp_dir().join(format!("rsbtc-atomic-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("blockchain.cbor");
+
+        write_atomically(&path, |file| file.write_all(b"good")).unwrap();
+
+        // half written when serializing fails
+        let failed = write_atomically(&path, |file| {
+            file.write_all(b"partial")?;
+            Err(IoError::other("serialize failed"))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"good");
+        // and the temporary file is gone with it
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        write_atomically(&path, |file| file.write_all(b"better")).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"better");
+
+        let _ = fs::remove_dir_all(&dir);
     }
 }
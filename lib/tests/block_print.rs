@@ -0,0 +1,76 @@
+use btclib::Network;
+use btclib::crypto::PrivateKey;
+use btclib::types::{Block, BlockChain, Transaction};
+use btclib::util::Savable;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn block_print(args: &[&str], path: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_block_print"))
+        .args(args)
+        .arg(path)
+        .output()
+        .unwrap()
+}
+
+fn printed(args: &[&str], path: &Path) -> String {
+    let output = block_print(args, path);
+    assert!(output.status.success(), "{:?} failed on {:?}", args, path);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn temp_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rsbtc-print-{}-{}", uuid::Uuid::new_v4(), name))
+}
+
+#[test]
+fn each_type_is_loaded_and_printed_through_its_flag() {
+    let chain = BlockChain::from_genesis(Network::Regtest.params());
+    let block = chain.blocks().next().unwrap().clone();
+    let tx = block.transactions[0].clone();
+    let pubkey = PrivateKey::new_key().public_key();
+
+    let block_file = temp_file("block.cbor");
+    let tx_file = temp_file("tx.cbor");
+    let chain_file = temp_file("chain.cbor");
+    let pubkey_file = temp_file("key.pub.pem");
+    block.save_to_file(&block_file).unwrap();
+    tx.save_to_file(&tx_file).unwrap();
+    chain.save_to_file(&chain_file).unwrap();
+    pubkey.save_to_file(&pubkey_file).unwrap();
+
+    // a block by default, as before --type existed
+    assert!(printed(&[], &block_file).starts_with("Block {"));
+    assert!(printed(&["--type", "block"], &block_file).starts_with("Block {"));
+    assert!(printed(&["--type", "tx"], &tx_file).starts_with("Transaction {"));
+    assert!(printed(&["--type", "chain"], &chain_file).starts_with("BlockChain {"));
+    assert!(printed(&["--type", "pubkey"], &pubkey_file).starts_with("PublicKey("));
+
+    // and as JSON that reads back as the same value
+    let json = printed(&["--json", "--type", "block"], &block_file);
+    assert_eq!(
+        Block::load_json(json.as_bytes()).unwrap().hash(),
+        block.hash()
+    );
+    let json = printed(&["--json", "--type", "tx"], &tx_file);
+    assert_eq!(
+        Transaction::load_json(json.as_bytes()).unwrap().hash(),
+        tx.hash()
+    );
+
+    // the wrong type or an unknown one fails instead of printing garbage
+    assert!(
+        !block_print(&["--type", "tx"], &pubkey_file)
+            .status
+            .success()
+    );
+    assert!(
+        !block_print(&["--type", "utxo"], &block_file)
+            .status
+            .success()
+    );
+
+    for file in [&block_file, &tx_file, &chain_file, &pubkey_file] {
+        let _ = std::fs::remove_file(file);
+    }
+}
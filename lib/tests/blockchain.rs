@@ -0,0 +1,69 @@
+use btclib::Network;
+use btclib::crypto::{PrivateKey, Signature};
+use btclib::types::{
+    Block, BlockChain, BlockHeader, Transaction, TransactionInput, TransactionOutput,
+};
+use btclib::util::MerkleRoot;
+use chrono::Duration;
+
+fn output(value: u64, key: &PrivateKey) -> TransactionOutput {
+    TransactionOutput {
+        value,
+        unique_id: uuid::Uuid::new_v4(),
+        pubkey: key.public_key(),
+    }
+}
+
+// an empty block paying the reward to `key`, a second after the tip
+fn add_block(chain: &mut BlockChain, key: &PrivateKey) {
+    let tip = chain.blocks().last().unwrap().clone();
+    let coinbase = Transaction::new(vec![], vec![output(chain.calculate_block_reward(), key)]);
+
+    let mut header = BlockHeader::new(
+        tip.header.timestamp + Duration::seconds(1),
+        0,
+        tip.hash(),
+        MerkleRoot::calculate(std::slice::from_ref(&coinbase)),
+        chain.target(),
+    );
+    assert!(header.mine(1_000_000));
+    chain.add_block(Block::new(header, vec![coinbase])).unwrap();
+}
+
+#[test]
+fn the_chain_api_is_reachable_through_the_types_module() {
+    let key = PrivateKey::new_key();
+    let mut chain = BlockChain::from_genesis(Network::Regtest.params());
+    for _ in 0..btclib::COINBASE_MATURITY {
+        add_block(&mut chain, &key);
+    }
+
+    assert_eq!(
+        chain.calculate_block_reward(),
+        chain.params().reward_at_height(chain.block_height())
+    );
+
+    // the first mined coinbase is mature now; spending it leaves 1000 over
+    let prev = chain.blocks().nth(1).unwrap().transactions[0].outputs[0].clone();
+    let mut tx = Transaction::new(
+        vec![TransactionInput {
+            prev_tx_output_hash: prev.hash(),
+            signature: Signature::sign_input(
+                &Transaction::new(vec![], vec![]),
+                0,
+                &prev.hash(),
+                &key,
+            ),
+        }],
+        vec![output(prev.value - 1000, &key)],
+    );
+    tx.inputs[0].signature = Signature::sign_input(&tx, 0, &prev.hash(), &key);
+    assert_eq!(chain.calculate_fees(std::slice::from_ref(&tx)), 1000);
+
+    // a fresh transaction outlives a cleanup, and its input stays reserved
+    chain.add_to_mempool(tx.clone()).unwrap();
+    chain.cleanup_mempool();
+    assert_eq!(chain.mempool().len(), 1);
+    assert_eq!(chain.mempool()[0].1.hash(), tx.hash());
+    assert!(chain.utxos()[&prev.hash()].0);
+}
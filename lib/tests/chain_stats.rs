@@ -0,0 +1,64 @@
+use btclib::Network;
+use btclib::crypto::PrivateKey;
+use btclib::types::{Block, BlockChain, BlockHeader, Transaction, TransactionOutput};
+use btclib::util::{MerkleRoot, Savable};
+use chrono::Duration;
+use std::process::Command;
+
+// an empty block paying the reward to `key`, `spacing` after the tip
+fn add_block(chain: &mut BlockChain, key: &PrivateKey, spacing: Duration) {
+    let tip = chain.blocks().last().unwrap().clone();
+    let coinbase = Transaction::new(
+        vec![],
+        vec![TransactionOutput {
+            value: chain.params().reward_at_height(chain.block_height()),
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: key.public_key(),
+        }],
+    );
+
+    let mut header = BlockHeader::new(
+        tip.header.timestamp + spacing,
+        0,
+        tip.hash(),
+        MerkleRoot::calculate(std::slice::from_ref(&coinbase)),
+        chain.target(),
+    );
+    assert!(header.mine(1_000_000));
+    chain.add_block(Block::new(header, vec![coinbase])).unwrap();
+}
+
+#[test]
+fn totals_over_a_small_chain_are_printed() {
+    let key = PrivateKey::new_key();
+    let mut chain = BlockChain::from_genesis(Network::Regtest.params());
+    for _ in 0..3 {
+        add_block(&mut chain, &key, Duration::seconds(10));
+    }
+
+    let path = std::env::temp_dir().join(format!("rsbtc-stats-{}.cbor", uuid::Uuid::new_v4()));
+    chain.save_to_file(&path).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_chain_stats"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert!(output.status.success());
+    let stats = String::from_utf8(output.stdout).unwrap();
+
+    // the genesis and three more, each with only its coinbase
+    let issued = (0..4)
+        .map(|height| chain.params().reward_at_height(height))
+        .sum::<u64>();
+    assert!(stats.contains("network: regtest"), "{}", stats);
+    assert!(stats.contains("blocks: 4\n"), "{}", stats);
+    assert!(stats.contains("transactions: 4\n"), "{}", stats);
+    assert!(stats.contains(&format!(
+        "coins issued: {}.{:08}\n",
+        issued / 100_000_000,
+        issued % 100_000_000
+    )));
+    assert!(stats.contains("average block interval: 10.0s"), "{}", stats);
+    assert!(stats.contains("difficulty: 1.0000"), "{}", stats);
+}
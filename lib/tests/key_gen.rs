@@ -0,0 +1,35 @@
+use btclib::crypto::{PrivateKey, PublicKey};
+use btclib::util::Savable;
+use std::process::Command;
+
+fn key_gen(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_key_gen"))
+        .args(args)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn a_generated_keypair_reloads_as_a_matching_pair() {
+    let prefix = std::env::temp_dir().join(format!("rsbtc-key-{}", uuid::Uuid::new_v4()));
+    let prefix = prefix.to_str().unwrap();
+    key_gen(&["--out-prefix", prefix]);
+
+    let private_file = format!("{}.priv.cbor", prefix);
+    let public_file = format!("{}.pub.pem", prefix);
+    let private = PrivateKey::load_from_file(&private_file).unwrap();
+    let public = PublicKey::load_from_file(&public_file).unwrap();
+    assert_eq!(private.public_key(), public);
+
+    // either half shows the same address
+    let address = format!("address: {}", public.to_address());
+    for file in [&private_file, &public_file] {
+        assert!(key_gen(&["--show", file]).contains(&address));
+    }
+
+    let _ = std::fs::remove_file(&private_file);
+    let _ = std::fs::remove_file(&public_file);
+}
@@ -1,9 +1,9 @@
 use anyhow::{Result, anyhow};
+use argh::FromArgs;
 use btclib::crypto::PublicKey;
 use btclib::network::Message;
 use btclib::types::Block;
 use btclib::util::Savable;
-use clap::Parser;
 use std::sync::{
     Arc,
     atomic::{AtomicBool, Ordering},
@@ -13,14 +13,26 @@ use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 use tokio::time::{Duration, interval};
 
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-struct Cli {
-    #[arg(short, long)]
+const USER_AGENT: &str = concat!("rsbtc-miner/", env!("CARGO_PKG_VERSION"));
+
+#[derive(FromArgs)]
+/// A toy blockchain miner
+struct Args {
+    #[argh(option)]
+    /// address of the node to mine against
     address: String,
 
-    #[arg(short, long)]
-    public_key_file: String,
+    #[argh(option)]
+    /// path to the public key that receives the block reward
+    pubkey_file: String,
+
+    #[argh(option, default = "default_threads()")]
+    /// number of mining threads
+    threads: usize,
+}
+
+fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
 }
 
 struct Miner {
@@ -28,13 +40,16 @@ struct Miner {
     stream: Mutex<TcpStream>,
     current_template: Arc<std::sync::Mutex<Option<Block>>>,
     mining: Arc<AtomicBool>,
+    stale: Arc<AtomicBool>,
+    threads: usize,
     mined_block_sender: flume::Sender<Block>,
     mined_block_receiver: flume::Receiver<Block>,
 }
 
 impl Miner {
-    async fn new(address: String, public_key: PublicKey) -> Result<Self> {
-        let stream = TcpStream::connect(&address).await?;
+    async fn new(address: String, public_key: PublicKey, threads: usize) -> Result<Self> {
+        let mut stream = TcpStream::connect(&address).await?;
+        Message::handshake_async(&mut stream, USER_AGENT, 0).await?;
         let (mined_block_sender, mined_block_receiver) = flume::unbounded();
 
         Ok(Self {
@@ -42,6 +57,8 @@ impl Miner {
             stream: Mutex::new(stream),
             current_template: Arc::new(std::sync::Mutex::new(None)),
             mining: Arc::new(AtomicBool::new(false)),
+            stale: Arc::new(AtomicBool::new(false)),
+            threads,
             mined_block_sender,
             mined_block_receiver,
         })
@@ -68,6 +85,8 @@ impl Miner {
     fn spawn_mining_thread(&self) -> thread::JoinHandle<()> {
         let template = self.current_template.clone();
         let mining = self.mining.clone();
+        let stale = self.stale.clone();
+        let threads = self.threads;
         let sender = self.mined_block_sender.clone();
 
         thread::spawn(move || {
@@ -75,9 +94,13 @@ impl Miner {
                 if mining.load(Ordering::Relaxed)
                     && let Some(mut block) = template.lock().unwrap().clone()
                 {
-                    println!("Mining block with target: {}", block.header.target);
+                    println!(
+                        "Mining block with target: {} on {} threads",
+                        block.header.target, threads
+                    );
 
-                    if block.header.mine(2_000_000) {
+                    if let Some(header) = block.header.mine_parallel_until(threads, &stale) {
+                        block.header = header;
                         println!("Block mined: {}", block.hash());
                         sender.send(block).expect("Failed to send mined block");
                         mining.store(false, Ordering::Relaxed);
@@ -113,6 +136,7 @@ impl Miner {
                     template.header.target
                 );
                 *self.current_template.lock().unwrap() = Some(template);
+                self.stale.store(false, Ordering::Relaxed);
                 self.mining.store(true, Ordering::Relaxed);
                 Ok(())
             }
@@ -140,6 +164,7 @@ impl Miner {
                     if !valid {
                         println!("Current template is no longer valid");
                         self.mining.store(false, Ordering::Relaxed);
+                        self.stale.store(true, Ordering::Relaxed);
                     } else {
                         println!("Current template is still valid");
                     }
@@ -167,11 +192,11 @@ impl Miner {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let args: Args = argh::from_env();
 
-    let public_key = PublicKey::load_from_file(&cli.public_key_file)
+    let public_key = PublicKey::load_from_file(&args.pubkey_file)
         .map_err(|e| anyhow!("Error reading public key: {}", e))?;
 
-    let miner = Miner::new(cli.address, public_key).await?;
+    let miner = Miner::new(args.address, public_key, args.threads).await?;
     miner.run().await
 }
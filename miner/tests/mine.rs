@@ -0,0 +1,110 @@
+use btclib::Network;
+use btclib::crypto::PrivateKey;
+use btclib::network::Message;
+use btclib::types::{Block, BlockChain, BlockHeader, Transaction, TransactionOutput};
+use btclib::util::{MerkleRoot, Savable};
+use chrono::Utc;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::time::{self, Duration};
+
+// kills the miner even when an assertion fails
+struct Miner(Child);
+
+impl Drop for Miner {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn template(chain: &BlockChain, pubkey: btclib::crypto::PublicKey) -> Block {
+    let coinbase = Transaction::new(
+        vec![],
+        vec![TransactionOutput {
+            value: chain.calculate_block_reward(),
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey,
+        }],
+    );
+    let transactions = vec![coinbase];
+    let tip = chain.blocks().last().unwrap().hash();
+
+    let header = BlockHeader::new(
+        Utc::now(),
+        0,
+        tip,
+        MerkleRoot::calculate(&transactions),
+        chain.target(),
+    );
+    Block::new(header, transactions)
+}
+
+// the part of a node a miner talks to, over a regtest chain
+async fn serve(listener: TcpListener, chain: Arc<Mutex<BlockChain>>) {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    Message::accept_handshake_async(&mut stream, "test-node", 0)
+        .await
+        .unwrap();
+
+    while let Ok(message) = Message::recv_async(&mut stream).await {
+        let reply = match message {
+            Message::FetchTemplate(pubkey) => {
+                Message::Template(template(&chain.lock().unwrap(), pubkey))
+            }
+            Message::ValidateTemplate(block) => {
+                let chain = chain.lock().unwrap();
+                let tip = chain.blocks().last().unwrap().hash();
+                Message::TemplateValidity(block.header.prev_block_hash == tip)
+            }
+            Message::SubmitTemplate(block) => {
+                chain.lock().unwrap().add_block(block).unwrap();
+                continue;
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+        reply.send_async(&mut stream).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn the_miner_extends_the_chain_it_is_given() {
+    let chain = Arc::new(Mutex::new(BlockChain::from_genesis(
+        Network::Regtest.params(),
+    )));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, chain.clone()));
+
+    let key_file =
+        std::env::temp_dir().join(format!("rsbtc-miner-{}.pub.pem", uuid::Uuid::new_v4()));
+    let key = PrivateKey::new_key().public_key();
+    key.save_to_file(&key_file).unwrap();
+
+    let _miner = Miner(
+        Command::new(env!("CARGO_BIN_EXE_miner"))
+            .arg("--address")
+            .arg(address.to_string())
+            .arg("--pubkey-file")
+            .arg(&key_file)
+            .args(["--threads", "2"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    let mined = time::timeout(Duration::from_secs(30), async {
+        while chain.lock().unwrap().block_height() < 2 {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    });
+    mined.await.expect("no block was mined");
+
+    let chain = chain.lock().unwrap();
+    let block = chain.blocks().last().unwrap();
+    assert_eq!(block.transactions[0].outputs[0].pubkey, key);
+
+    let _ = std::fs::remove_file(&key_file);
+}
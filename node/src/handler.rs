@@ -1,37 +1,301 @@
+use btclib::error::BtcError;
 use btclib::network::Message;
 use btclib::sha256::Hash;
-use btclib::types::{Block, BlockHeader, Transaction, TransactionOutput};
 use btclib::util::MerkleRoot;
-use chrono::Utc;
+use bytes::BytesMut;
+use std::collections::HashSet;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use tokio::io::AsyncReadExt;
 use tokio::net::TcpStream;
-use uuid::Uuid;
 
-pub async fn handle_connection(mut socket: TcpStream) {
+use crate::util::PeerStream;
+use tokio::time::{self, Duration};
+use tracing::{debug, info, warn};
+
+use crate::metrics::{self, BLOCKS_ACCEPTED, BLOCKS_REJECTED};
+use crate::metrics::{TRANSACTIONS_ACCEPTED, TRANSACTIONS_REJECTED};
+
+// how long a peer may take to finish a message once it has started one
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+// connections are limited, so one that never says hello must not hold a slot
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// a peer held back this many messages in a row is flooding, not bursting
+const MAX_THROTTLED_MESSAGES: u32 = 100;
+// a stray reply is likely a bug on the other side; a steady stream of them is not
+const MAX_PROTOCOL_VIOLATIONS: u32 = 10;
+
+async fn read_message(socket: &mut PeerStream, buf: &mut BytesMut) -> anyhow::Result<Message> {
+    loop {
+        if let Some(message) = Message::try_decode(buf)? {
+            return Ok(message);
+        }
+
+        let read = if buf.is_empty() {
+            socket.read_buf(buf).await?
+        } else {
+            time::timeout(READ_TIMEOUT, socket.read_buf(buf)).await??
+        };
+
+        if read == 0 {
+            return Err(IoError::from(IoErrorKind::UnexpectedEof).into());
+        }
+    }
+}
+
+// a peer that can't be written to is gone, so callers just return
+async fn reply(socket: &mut PeerStream, message: &Message) -> anyhow::Result<()> {
+    if let Err(e) = message.send_async(socket).await {
+        warn!("failed to reply to peer: {}, closing the connection", e);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+pub async fn handle_connection(socket: TcpStream, message_rate: u32) {
+    let best_height = crate::BLOCKCHAIN.read().await.block_height();
+
+    let handshake = async {
+        let mut socket = PeerStream::accept(socket).await?;
+        let version =
+            Message::accept_handshake_async(&mut socket, crate::util::USER_AGENT, best_height)
+                .await?;
+        anyhow::Ok((socket, version))
+    };
+
+    let mut socket = match time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok((socket, version))) => {
+            info!(
+                "peer connected with protocol version {}{}",
+                version,
+                if socket.is_encrypted() {
+                    ", encrypted"
+                } else {
+                    ""
+                }
+            );
+            socket
+        }
+        Ok(Err(e)) => {
+            warn!("handshake failed: {}, closing the connection", e);
+            return;
+        }
+        Err(_) => {
+            warn!("handshake timed out, closing the connection");
+            return;
+        }
+    };
+
+    let mut buf = BytesMut::new();
+    let mut limiter = crate::util::RateLimiter::new(message_rate);
+    let mut throttled = 0;
+    let mut violations = 0;
+
     loop {
-        let message = match Message::recv_async(&mut socket).await {
+        let message = match read_message(&mut socket, &mut buf).await {
             Ok(message) => message,
             Err(e) => {
-                println!("invalid message from peer: {}, closing the connection", e);
+                warn!("invalid message from peer: {}, closing the connection", e);
                 return;
             }
         };
 
+        // while we wait the peer's further messages queue up in the socket,
+        // which slows it down without anything else being locked
+        let wait = limiter.take();
+        if wait.is_zero() {
+            throttled = 0;
+        } else {
+            throttled += 1;
+
+            if throttled > MAX_THROTTLED_MESSAGES {
+                warn!(
+                    "peer keeps exceeding {} messages per second, closing the connection",
+                    message_rate
+                );
+                return;
+            }
+
+            debug!("peer is over its message rate, waiting {:?}", wait);
+            time::sleep(wait).await;
+        }
+
         use btclib::network::Message::*;
 
         match message {
-            UTXOs(_) | Template(_) | Difference(_) | TemplateValidity(_) | NodeList(_) => {
-                println!("These are for miners and wallets");
+            Version { .. } => {
+                warn!("version already negotiated, closing the connection");
                 return;
             }
 
+            UTXOs(_)
+            | UTXOStatus(_)
+            | Template(_)
+            | Difference(_)
+            | Work(_)
+            | TemplateValidity(_)
+            | NodeList(_)
+            | Headers(_)
+            | Blocks(_)
+            | MempoolResponse(_)
+            | SubmitTransactionResult { .. }
+            | TestResult { .. }
+            | MerkleProof { .. }
+            | BlockUnavailable(_)
+            | TransactionLocation { .. }
+            | TransactionNotFound(_)
+            | Pong(_)
+            | GetData(_)
+            | ChainInfoResponse { .. } => {
+                violations += 1;
+
+                if violations > MAX_PROTOCOL_VIOLATIONS {
+                    warn!(
+                        "peer sent {} unexpected messages, closing the connection",
+                        violations
+                    );
+                    return;
+                }
+
+                warn!("peer sent a reply without a request");
+                let message = Error("unexpected message: only nodes send this reply".to_string());
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
+            }
+
+            // answering it would only start an exchange of errors
+            Error(e) => warn!("peer reported an error: {}", e),
+
             FetchBlock(height) => {
-                let blockchain = crate::BLOCKCHAIN.read().await;
-                let Some(block) = blockchain.blocks().nth(height).cloned() else {
+                // the reply is built under the lock and sent after it is
+                // released, so a client slow to read can't hold up the node
+                let message = {
+                    let blockchain = crate::BLOCKCHAIN.read().await;
+
+                    // a miss is an answer like any other, not a reason to hang up
+                    match blockchain.blocks().nth(height) {
+                        None => Error(format!("no block at height {}", height)),
+                        Some(_) if (height as u64) < blockchain.pruned_height() => {
+                            BlockUnavailable(height)
+                        }
+                        Some(block) => NewBlock(block.clone()),
+                    }
+                };
+                if reply(&mut socket, &message).await.is_err() {
                     return;
+                }
+            }
+
+            FetchMerkleProof {
+                block_height,
+                tx_hash,
+            } => {
+                let message = {
+                    let blockchain = crate::BLOCKCHAIN.read().await;
+
+                    // no such block, or the transaction is not in it
+                    match blockchain.blocks().nth(block_height) {
+                        Some(_) if (block_height as u64) < blockchain.pruned_height() => {
+                            BlockUnavailable(block_height)
+                        }
+                        Some(block) => block
+                            .transactions
+                            .iter()
+                            .position(|tx| tx.hash() == tx_hash)
+                            .map_or(TransactionNotFound(tx_hash), |index| MerkleProof {
+                                index,
+                                proof: MerkleRoot::proof(&block.transactions, index).unwrap(),
+                                root: block.header.merkle_root,
+                            }),
+                        None => TransactionNotFound(tx_hash),
+                    }
                 };
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
+            }
 
-                let message = NewBlock(block);
-                message.send_async(&mut socket).await.unwrap();
+            FetchTransaction(hash) => {
+                let message = match crate::BLOCKCHAIN.read().await.find_transaction(&hash) {
+                    Some((block_height, index, tx)) => TransactionLocation {
+                        block_height,
+                        index,
+                        tx: tx.clone(),
+                    },
+                    None => TransactionNotFound(hash),
+                };
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
+            }
+
+            FetchBlockByHash(hash) => {
+                let message = {
+                    let blockchain = crate::BLOCKCHAIN.read().await;
+
+                    match (blockchain.block_by_hash(&hash), blockchain.height_of(&hash)) {
+                        (Some(_), Some(height)) if height < blockchain.pruned_height() => {
+                            BlockUnavailable(height as usize)
+                        }
+                        (Some(block), Some(_)) => NewBlock(block.clone()),
+                        _ => Error(format!("unknown block {}", hash)),
+                    }
+                };
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
+            }
+
+            FetchHeaders {
+                start_height,
+                count,
+            } => {
+                let headers = crate::BLOCKCHAIN
+                    .read()
+                    .await
+                    .blocks()
+                    .skip(start_height)
+                    .take(count.min(btclib::MAX_HEADERS_PER_MESSAGE))
+                    .map(|block| block.header.clone())
+                    .collect::<Vec<_>>();
+
+                let message = Headers(headers);
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
+            }
+
+            FetchBlocks { start, count } => {
+                // up to a whole message of blocks is copied out, so the chain
+                // is free again before a slow peer starts reading them
+                let message = {
+                    let blockchain = crate::BLOCKCHAIN.read().await;
+
+                    if (start as u64) < blockchain.pruned_height() {
+                        BlockUnavailable(start)
+                    } else {
+                        // a block is never larger than the byte budget, so at
+                        // least one is sent whenever there is one
+                        let mut size = 0;
+                        let blocks = blockchain
+                            .blocks()
+                            .skip(start)
+                            .take(count.min(btclib::MAX_BLOCKS_PER_MESSAGE))
+                            .take_while(|block| {
+                                size += btclib::util::cbor_size(block);
+                                size <= btclib::MAX_BLOCKS_MESSAGE_BYTES
+                            })
+                            .cloned()
+                            .collect::<Vec<_>>();
+
+                        Blocks(blocks)
+                    }
+                };
+
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
             }
 
             DiscoverNodes => {
@@ -41,173 +305,788 @@ pub async fn handle_connection(mut socket: TcpStream) {
                     .collect::<Vec<_>>();
 
                 let message = NodeList(nodes);
-                message.send_async(&mut socket).await.unwrap();
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
             }
 
             AskDifference(height) => {
-                let blockchain = crate::BLOCKCHAIN.read().await;
-                let count = blockchain.block_height() as i32 - height as i32;
+                let count = crate::BLOCKCHAIN.read().await.block_height() as i32 - height as i32;
                 let message = Difference(count);
-                message.send_async(&mut socket).await.unwrap();
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
+            }
+
+            AskWork => {
+                let message = Work(crate::BLOCKCHAIN.read().await.total_work());
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
+            }
+
+            ChainInfo => {
+                let message = {
+                    let blockchain = crate::BLOCKCHAIN.read().await;
+
+                    ChainInfoResponse {
+                        height: blockchain.block_height(),
+                        tip_hash: blockchain
+                            .blocks()
+                            .last()
+                            .map(|last_block| last_block.hash())
+                            .unwrap_or(Hash::zero()),
+                        target: blockchain.target(),
+                        difficulty: blockchain.difficulty(),
+                        mempool_size: blockchain.mempool().len(),
+                        total_work: blockchain.total_work(),
+                    }
+                };
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
             }
 
             FetchUTXOs(key) => {
-                println!("received request to fetch UTXOs");
+                debug!("received request to fetch UTXOs");
 
-                let blockchain = crate::BLOCKCHAIN.read().await;
-                let utxos = blockchain
-                    .utxos()
-                    .iter()
-                    .filter(|(_, (_, txout))| txout.pubkey == key)
-                    .map(|(_, (marked, txout))| (txout.clone(), *marked))
+                let utxos = crate::BLOCKCHAIN
+                    .read()
+                    .await
+                    .utxos_of(&key)
+                    .map(|(marked, txout)| (txout.clone(), *marked))
                     .collect::<Vec<_>>();
 
                 let message = UTXOs(utxos);
-                message.send_async(&mut socket).await.unwrap();
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
+            }
+
+            FetchMempool(key) => {
+                let transactions = {
+                    let blockchain = crate::BLOCKCHAIN.read().await;
+
+                    // a transaction concerns a key if it pays to it or spends from it
+                    blockchain
+                        .mempool()
+                        .iter()
+                        .map(|(_, tx)| tx)
+                        .filter(|tx| {
+                            let Some(key) = &key else {
+                                return true;
+                            };
+
+                            tx.outputs.iter().any(|output| output.pubkey == *key)
+                                || tx.inputs.iter().any(|input| {
+                                    blockchain
+                                        .utxos()
+                                        .get(&input.prev_tx_output_hash)
+                                        .is_some_and(|(_, output)| output.pubkey == *key)
+                                })
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>()
+                };
+
+                let message = MempoolResponse(transactions);
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
+            }
+
+            FetchUTXO(hash) => {
+                let status = crate::BLOCKCHAIN
+                    .read()
+                    .await
+                    .utxo_status(&hash)
+                    .map(|(marked, output)| (output.clone(), marked));
+
+                let message = UTXOStatus(status);
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
             }
 
             NewBlock(block) => {
                 let mut blockchain = crate::BLOCKCHAIN.write().await;
-                println!("received new block");
+                info!("received new block");
 
-                if blockchain.add_block(block).is_err() {
-                    println!("block rejected");
+                if let Err(e) = crate::util::block_added(blockchain.add_block(block)) {
+                    warn!("block rejected: {}", e);
+                    metrics::inc(&BLOCKS_REJECTED);
+                } else {
+                    metrics::inc(&BLOCKS_ACCEPTED);
                 }
             }
 
             NewTransaction(tx) => {
                 let mut blockchain = crate::BLOCKCHAIN.write().await;
-                println!("received new transaction");
+                debug!("received new transaction");
 
-                if blockchain.add_to_mempool(tx).is_err() {
-                    println!("transaction rejected, closing connection");
+                match blockchain.add_to_mempool(tx.clone()) {
+                    Ok(()) => {}
+                    // a parent we have not seen yet is no reason to drop the peer
+                    Err(BtcError::OrphanTransaction) => {
+                        debug!("transaction spends unknown outputs, holding it as an orphan");
+                        continue;
+                    }
+                    // the peer may simply be a block ahead of or behind us, or
+                    // keep a mempool with other limits; none of that makes it
+                    // misbehave
+                    Err(
+                        e @ (BtcError::ImmatureCoinbase
+                        | BtcError::SpentInput
+                        | BtcError::TransactionLocked
+                        | BtcError::ReplacementFeeTooLow
+                        | BtcError::MempoolFull
+                        | BtcError::DustOutput),
+                    ) => {
+                        debug!("transaction rejected: {}, ignoring it", e);
+                        metrics::inc(&TRANSACTIONS_REJECTED);
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("transaction rejected: {}, closing connection", e);
+                        metrics::inc(&TRANSACTIONS_REJECTED);
+                        return;
+                    }
+                }
+
+                metrics::inc(&TRANSACTIONS_ACCEPTED);
+
+                // peers wait on our replies while relaying, so the chain is
+                // not held across the network round trips
+                drop(blockchain);
+                crate::util::relay_transactions(&[tx]).await;
+            }
+
+            Inv(hashes) => {
+                let mut known = {
+                    let blockchain = crate::BLOCKCHAIN.read().await;
+                    blockchain
+                        .mempool()
+                        .iter()
+                        .chain(blockchain.orphans())
+                        .map(|(_, tx)| tx.hash())
+                        .collect::<HashSet<_>>()
+                };
+
+                // insert doubles as dropping repeats within the announcement
+                let wanted = hashes
+                    .into_iter()
+                    .filter(|hash| known.insert(*hash))
+                    .collect::<Vec<_>>();
+
+                debug!("requesting {} announced transactions", wanted.len());
+                let message = GetData(wanted);
+                if reply(&mut socket, &message).await.is_err() {
                     return;
                 }
             }
 
-            ValidateTemplate(block_template) => {
-                let blockchain = crate::BLOCKCHAIN.write().await;
+            Ping(nonce) => {
+                let message = Pong(nonce);
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
+            }
 
+            ValidateTemplate(block_template) => {
                 let status = block_template.header.prev_block_hash
-                    == blockchain
+                    == crate::BLOCKCHAIN
+                        .read()
+                        .await
                         .blocks()
                         .last()
                         .map(|last_block| last_block.hash())
                         .unwrap_or(Hash::zero());
 
                 let message = TemplateValidity(status);
-                message.send_async(&mut socket).await.unwrap();
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
             }
 
             SubmitTemplate(block) => {
-                println!("Received mined template");
+                info!("Received mined template");
 
                 let mut blockchain = crate::BLOCKCHAIN.write().await;
 
-                if let Err(e) = blockchain.add_block(block.clone()) {
-                    println!("block rejected: {}, closing connection", e);
+                if let Err(e) = crate::util::block_added(blockchain.add_block(block.clone())) {
+                    warn!("block rejected: {}, closing connection", e);
+                    metrics::inc(&BLOCKS_REJECTED);
                     return;
                 }
 
-                blockchain.rebuild_utxos();
-
-                println!("Good block, broadcasting to peers");
-                let nodes = crate::NODES
-                    .iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
+                metrics::inc(&BLOCKS_ACCEPTED);
 
-                for node in nodes {
-                    if let Some(mut stream) = crate::NODES.get_mut(&node) {
-                        let message = NewBlock(block.clone());
-                        if message.send_async(&mut *stream).await.is_err() {
-                            println!("failed to broadcast to {}", node);
-                        }
-                    }
-                }
+                // broadcasting waits on each peer in turn
+                drop(blockchain);
+                info!("Good block, broadcasting to peers");
+                crate::util::broadcast(&NewBlock(block)).await;
             }
 
             SubmitTransaction(tx) => {
-                println!("submit tx");
+                debug!("submit tx");
 
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
+                // released before replying, so a client slow to read can't
+                // hold up the rest of the node
+                let result = crate::BLOCKCHAIN.write().await.add_to_mempool(tx.clone());
 
-                if let Err(e) = blockchain.add_to_mempool(tx.clone()) {
-                    println!("transaction rejected: {}, closing connection", e);
+                let message = SubmitTransactionResult {
+                    accepted: result.is_ok(),
+                    reason: result.as_ref().err().map(|e| e.to_string()),
+                };
+                if reply(&mut socket, &message).await.is_err() {
                     return;
                 }
 
-                println!("added transaction to mempool");
+                if let Err(e) = result {
+                    warn!("transaction rejected: {}", e);
+                    metrics::inc(&TRANSACTIONS_REJECTED);
+                    continue;
+                }
 
-                let nodes = crate::NODES
-                    .iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
+                info!("added transaction to mempool");
+                metrics::inc(&TRANSACTIONS_ACCEPTED);
 
-                for node in nodes {
-                    println!("broadcasting to {}", node);
+                crate::util::relay_transactions(&[tx]).await;
+            }
 
-                    if let Some(mut stream) = crate::NODES.get_mut(&node) {
-                        let message = NewTransaction(tx.clone());
-                        if message.send_async(&mut *stream).await.is_err() {
-                            println!("failed to broadcast to {}", node);
-                        }
-                    }
+            TestTransaction(tx) => {
+                let result = crate::BLOCKCHAIN.read().await.test_mempool_accept(&tx);
 
-                    println!("broadcasted to {}", node);
+                let message = TestResult {
+                    accepted: result.is_ok(),
+                    reason: result.err().map(|e| e.to_string()),
+                };
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
                 }
             }
 
             FetchTemplate(pubkey) => {
-                let blockchain = crate::BLOCKCHAIN.read().await;
+                let block = {
+                    let blockchain = crate::BLOCKCHAIN.read().await;
+                    crate::util::block_template(&blockchain, pubkey)
+                };
 
-                let mut transactions = vec![];
+                let message = Template(block);
+                if reply(&mut socket, &message).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
 
-                transactions.extend(
-                    blockchain
-                        .mempool()
-                        .iter()
-                        .take(btclib::BLOCK_TRANSACTION_CAP)
-                        .map(|(_, tx)| tx)
-                        .cloned()
-                        .collect::<Vec<_>>(),
-                );
+#[cfg(test)]
+mod tests {
+    use crate::test_util::*;
+    use crate::util::PeerStream;
+    use btclib::crypto::PrivateKey;
+    use btclib::network::Message;
+    use btclib::network::Message::*;
+    use btclib::sha256::Hash;
+    use btclib::types::BlockHeader;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::{self, Duration};
 
-                let miner_fees = blockchain.calculate_fees(&transactions);
-                let reward = blockchain.calculate_block_reward();
-
-                transactions.insert(
-                    0,
-                    Transaction {
-                        inputs: vec![],
-                        outputs: vec![TransactionOutput {
-                            pubkey,
-                            unique_id: Uuid::new_v4(),
-                            value: reward + miner_fees,
-                        }],
-                    },
-                );
+    #[tokio::test]
+    async fn a_block_range_past_the_tip_returns_what_there_is() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let chain = regtest_chain(&key, 3);
+        let hashes = chain.blocks().map(|block| block.hash()).collect::<Vec<_>>();
+        install_chain(chain).await;
 
-                let merkle_root = MerkleRoot::calculate(&transactions);
+        let mut stream = connect_handler(1000).await;
 
-                let block = Block::new(
-                    BlockHeader {
-                        timestamp: Utc::now(),
-                        nonce: 0,
-                        prev_block_hash: blockchain
-                            .blocks()
-                            .last()
-                            .map(|last_block| last_block.hash())
-                            .unwrap_or(Hash::zero()),
-                        merkle_root,
-                        target: blockchain.target(),
-                    },
-                    transactions,
-                );
+        let message = FetchBlocks {
+            start: 2,
+            count: 10,
+        };
+        let Blocks(blocks) = request(&mut stream, message).await else {
+            panic!("expected blocks");
+        };
+        let received = blocks.iter().map(|block| block.hash()).collect::<Vec<_>>();
+        assert_eq!(received, hashes[2..]);
 
-                let message = Template(block);
-                message.send_async(&mut socket).await.unwrap();
+        // wholly past the tip is an empty batch, not an error
+        let message = FetchBlocks { start: 4, count: 2 };
+        let Blocks(blocks) = request(&mut stream, message).await else {
+            panic!("expected blocks");
+        };
+        assert!(blocks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn chain_info_reports_blocks_it_was_sent() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key, 1);
+        install_chain(chain.clone()).await;
+
+        let mut stream = connect_handler(1000).await;
+
+        for _ in 0..3 {
+            let block = next_block(&chain, &key);
+            chain.add_block(block.clone()).unwrap();
+            NewBlock(block).send_async(&mut stream).await.unwrap();
+        }
+
+        let ChainInfoResponse {
+            height,
+            tip_hash,
+            target,
+            mempool_size,
+            total_work,
+            ..
+        } = request(&mut stream, ChainInfo).await
+        else {
+            panic!("expected chain info");
+        };
+        assert_eq!(height, 5);
+        assert_eq!(tip_hash, chain.blocks().last().unwrap().hash());
+        assert_eq!(target, chain.target());
+        assert_eq!(mempool_size, 0);
+        assert_eq!(total_work, chain.total_work());
+    }
+
+    #[tokio::test]
+    async fn headers_are_capped_and_link_up() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let chain = regtest_chain(&key, btclib::MAX_HEADERS_PER_MESSAGE + 10);
+        let params = *chain.params();
+        let hashes = chain.blocks().map(|block| block.hash()).collect::<Vec<_>>();
+        install_chain(chain).await;
+
+        let mut stream = connect_handler(1000).await;
+
+        // more than one message can carry
+        let message = FetchHeaders {
+            start_height: 0,
+            count: btclib::MAX_HEADERS_PER_MESSAGE * 2,
+        };
+        let Headers(headers) = request(&mut stream, message).await else {
+            panic!("expected headers");
+        };
+        assert_eq!(headers.len(), btclib::MAX_HEADERS_PER_MESSAGE);
+        BlockHeader::verify_chain(&params, Hash::zero(), &headers).unwrap();
+
+        // the next batch picks up where that one ended
+        let message = FetchHeaders {
+            start_height: headers.len(),
+            count: btclib::MAX_HEADERS_PER_MESSAGE,
+        };
+        let Headers(rest) = request(&mut stream, message).await else {
+            panic!("expected headers");
+        };
+        assert_eq!(rest.len(), hashes.len() - headers.len());
+        let last = headers.last().unwrap().hash();
+        BlockHeader::verify_chain(&params, last, &rest).unwrap();
+        assert_eq!(rest.last().unwrap().hash(), *hashes.last().unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_peer_with_an_unsupported_version_is_dropped_before_its_blocks() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let chain = regtest_chain(&key, 1);
+        let block = next_block(&chain, &key);
+        install_chain(chain).await;
+
+        let mut stream = spawn_handler(1000).await;
+
+        // sent back to back, so the block is already waiting when the
+        // version is turned down
+        let version = Version {
+            protocol_version: btclib::MIN_PROTOCOL_VERSION - 1,
+            user_agent: "old".to_string(),
+            best_height: 0,
+        };
+        version.send_async(&mut stream).await.unwrap();
+        NewBlock(block).send_async(&mut stream).await.unwrap();
+
+        // no version comes back, just the end of the connection
+        let closed = time::timeout(Duration::from_secs(10), Message::recv_async(&mut stream));
+        assert!(closed.await.unwrap().is_err());
+        assert_eq!(crate::BLOCKCHAIN.read().await.block_height(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_submitted_transaction_shows_up_in_the_mempool() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let chain = regtest_chain(&key, btclib::COINBASE_MATURITY as usize);
+        let prev = coinbase_output(&chain, 1);
+        install_chain(chain).await;
+
+        let mut stream = connect_handler(1000).await;
+
+        let tx = spend(&prev, &key, prev.value - 1000);
+        let SubmitTransactionResult { accepted, reason } =
+            request(&mut stream, SubmitTransaction(tx.clone())).await
+        else {
+            panic!("expected a submit result");
+        };
+        assert!(accepted, "rejected: {:?}", reason);
+
+        // everything, then only what concerns a key
+        let stranger = PrivateKey::new_key().public_key();
+        for (filter, expected) in [
+            (None, vec![tx.hash()]),
+            (Some(key.public_key()), vec![tx.hash()]),
+            (Some(stranger), vec![]),
+        ] {
+            let MempoolResponse(transactions) = request(&mut stream, FetchMempool(filter)).await
+            else {
+                panic!("expected the mempool");
+            };
+            let hashes = transactions.iter().map(|tx| tx.hash()).collect::<Vec<_>>();
+            assert_eq!(hashes, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_tested_transaction_is_judged_without_entering_the_mempool() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let chain = regtest_chain(&key, btclib::COINBASE_MATURITY as usize);
+        let prev = coinbase_output(&chain, 1);
+        install_chain(chain).await;
+
+        let mut stream = connect_handler(1000).await;
+
+        let tx = spend(&prev, &key, prev.value - 1000);
+        let TestResult { accepted, reason } =
+            request(&mut stream, TestTransaction(tx.clone())).await
+        else {
+            panic!("expected a test result");
+        };
+        assert!(accepted, "rejected: {:?}", reason);
+        assert!(crate::BLOCKCHAIN.read().await.mempool().is_empty());
+
+        // once it is in, spending the same output again is refused with why
+        let SubmitTransactionResult { accepted, .. } =
+            request(&mut stream, SubmitTransaction(tx.clone())).await
+        else {
+            panic!("expected a submit result");
+        };
+        assert!(accepted);
+
+        let double_spend = spend(&prev, &key, prev.value - 500);
+        let TestResult { accepted, reason } =
+            request(&mut stream, TestTransaction(double_spend)).await
+        else {
+            panic!("expected a test result");
+        };
+        // paying less than the one it would replace, it can't displace it
+        assert!(!accepted);
+        assert!(reason.unwrap().contains("higher fee"));
+        assert_eq!(crate::BLOCKCHAIN.read().await.mempool().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_utxo_is_reported_unspent_reserved_or_absent() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let chain = regtest_chain(&key, btclib::COINBASE_MATURITY as usize);
+        let reserved = coinbase_output(&chain, 1);
+        let unspent = coinbase_output(&chain, 2);
+        install_chain(chain).await;
+
+        let mut stream = connect_handler(1000).await;
+
+        // a mempool transaction marks the output it spends
+        let tx = spend(&reserved, &key, reserved.value - 1000);
+        let SubmitTransactionResult { accepted, .. } =
+            request(&mut stream, SubmitTransaction(tx)).await
+        else {
+            panic!("expected a submit result");
+        };
+        assert!(accepted);
+
+        for (output, marked) in [(&unspent, false), (&reserved, true)] {
+            let UTXOStatus(Some((found, found_marked))) =
+                request(&mut stream, FetchUTXO(output.hash())).await
+            else {
+                panic!("expected the output");
+            };
+            assert_eq!(found.hash(), output.hash());
+            assert_eq!(found_marked, marked);
+        }
+
+        let UTXOStatus(status) = request(&mut stream, FetchUTXO(Hash::zero())).await else {
+            panic!("expected a utxo status");
+        };
+        assert!(status.is_none());
+    }
+
+    #[tokio::test]
+    async fn pruned_blocks_are_unavailable_and_recent_ones_still_served() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key, 5);
+        let hashes = chain.blocks().map(|block| block.hash()).collect::<Vec<_>>();
+
+        let utxos = |chain: &btclib::types::BlockChain| {
+            let mut utxos = chain.utxos().keys().copied().collect::<Vec<_>>();
+            utxos.sort_by_key(|hash| hash.to_string());
+            utxos
+        };
+        let before = utxos(&chain);
+        chain.prune_below(3);
+        assert_eq!(utxos(&chain), before);
+        install_chain(chain).await;
+
+        let mut stream = connect_handler(1000).await;
+
+        for height in 0..3 {
+            let BlockUnavailable(unavailable) = request(&mut stream, FetchBlock(height)).await
+            else {
+                panic!("expected block {} to be unavailable", height);
+            };
+            assert_eq!(unavailable, height);
+        }
+
+        // the connection is still good for the blocks that were kept
+        for (height, hash) in hashes.iter().enumerate().skip(3) {
+            let NewBlock(block) = request(&mut stream, FetchBlock(height)).await else {
+                panic!("expected block {}", height);
+            };
+            assert_eq!(block.hash(), *hash);
+            assert!(!block.transactions.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn a_transaction_is_found_by_hash_with_its_location() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key, btclib::COINBASE_MATURITY as usize);
+        let prev = coinbase_output(&chain, 1);
+        let tx = spend(&prev, &key, prev.value - 1000);
+        chain.add_to_mempool(tx.clone()).unwrap();
+        let block = next_block(&chain, &key);
+        let height = chain.block_height();
+        // indexed as the block comes in, not by a rescan
+        chain.enable_tx_index();
+        chain.add_block(block).unwrap();
+        install_chain(chain).await;
+
+        let mut stream = connect_handler(1000).await;
+
+        let TransactionLocation {
+            block_height,
+            index,
+            tx: found,
+        } = request(&mut stream, FetchTransaction(tx.hash())).await
+        else {
+            panic!("expected the transaction");
+        };
+        assert_eq!((block_height, index), (height, 1));
+        assert_eq!(found.hash(), tx.hash());
+
+        let missing = Hash::hash(&"no such transaction");
+        let TransactionNotFound(hash) = request(&mut stream, FetchTransaction(missing)).await
+        else {
+            panic!("expected a miss");
+        };
+        assert_eq!(hash, missing);
+    }
+
+    #[tokio::test]
+    async fn only_announced_transactions_not_yet_known_are_requested() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key, btclib::COINBASE_MATURITY as usize + 1);
+        let known = spend(&coinbase_output(&chain, 1), &key, 1000);
+        chain.add_to_mempool(known.clone()).unwrap();
+        install_chain(chain).await;
+
+        let mut stream = connect_handler(1000).await;
+
+        // announced twice in one go, but wanted once
+        let new = Hash::hash(&"a transaction the node hasn't seen");
+        let announcement = Inv(vec![known.hash(), new, new]);
+        let GetData(wanted) = request(&mut stream, announcement).await else {
+            panic!("expected a request for bodies");
+        };
+        assert_eq!(wanted, vec![new]);
+
+        // nothing new, nothing asked for
+        let GetData(wanted) = request(&mut stream, Inv(vec![known.hash()])).await else {
+            panic!("expected a request for bodies");
+        };
+        assert!(wanted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_peer_flooding_past_its_rate_is_slowed_then_dropped() {
+        let _serial = serial().await;
+        install_chain(regtest_chain(&PrivateKey::new_key(), 1)).await;
+
+        // a burst of twice the rate goes straight through; the rate is low
+        // enough that a wait overslept on a busy machine still leaves the
+        // peer short of a token, which would count as it having slowed down
+        let rate = 25;
+        let sent = 400;
+        let (mut reader, mut writer) = tokio::io::split(connect_handler(rate).await);
+        let started = time::Instant::now();
+        tokio::spawn(async move {
+            for nonce in 0..sent {
+                // the handler hangs up part way through
+                if Ping(nonce).send_async(&mut writer).await.is_err() {
+                    break;
+                }
             }
+        });
+
+        // read as they come, so none are lost in the reset at the end
+        let mut answered = 0;
+        while let Ok(Ok(message)) =
+            time::timeout(Duration::from_secs(10), Message::recv_async(&mut reader)).await
+        {
+            assert!(matches!(message, Pong(_)));
+            answered += 1;
         }
+
+        // each one past the burst waited its turn, until there had been
+        // too many in a row
+        assert!(answered > 2 * rate as u64);
+        assert!(answered < sent);
+        let throttled = Duration::from_secs(super::MAX_THROTTLED_MESSAGES as u64) / rate;
+        assert!(started.elapsed() >= throttled * 9 / 10);
+    }
+
+    #[tokio::test]
+    async fn a_stray_reply_gets_an_error_and_the_connection_stays_open() {
+        let _serial = serial().await;
+        install_chain(regtest_chain(&PrivateKey::new_key(), 1)).await;
+
+        let mut stream = connect_handler(1000).await;
+
+        let Error(reason) = request(&mut stream, Pong(7)).await else {
+            panic!("expected an error");
+        };
+        assert!(reason.contains("unexpected message"));
+
+        let ChainInfoResponse { height, .. } = request(&mut stream, ChainInfo).await else {
+            panic!("expected chain info");
+        };
+        assert_eq!(height, 2);
+
+        // every one is answered up to the limit, and then the peer is dropped
+        for _ in 1..super::MAX_PROTOCOL_VIOLATIONS {
+            assert!(matches!(request(&mut stream, Pong(7)).await, Error(_)));
+        }
+        Pong(7).send_async(&mut stream).await.unwrap();
+        let closed = time::timeout(Duration::from_secs(10), Message::recv_async(&mut stream));
+        assert!(closed.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn writing_to_a_closed_peer_ends_the_handler_without_a_panic() {
+        let _serial = serial().await;
+        install_chain(regtest_chain(&PrivateKey::new_key(), 3)).await;
+
+        // a reply to a peer that has hung up fails once its end resets
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut server = PeerStream::Plain(listener.accept().await.unwrap().0);
+        drop(client);
+        let mut failed = false;
+        for _ in 0..50 {
+            if super::reply(&mut server, &Ping(1)).await.is_err() {
+                failed = true;
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(failed);
+
+        // and the handler asked for blocks by such a peer just returns
+        let address = listener.local_addr().unwrap();
+        let handler = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            super::handle_connection(socket, 1000).await;
+        });
+        let mut stream = PeerStream::Plain(TcpStream::connect(address).await.unwrap());
+        Message::handshake_async(&mut stream, "test", 0)
+            .await
+            .unwrap();
+        for height in 0..20 {
+            FetchBlock(height % 3)
+                .send_async(&mut stream)
+                .await
+                .unwrap();
+        }
+        drop(stream);
+
+        time::timeout(Duration::from_secs(10), handler)
+            .await
+            .expect("the handler returned")
+            .expect("the handler did not panic");
+    }
+
+    // everything logged, as the fmt subscriber would print it
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn a_rejected_block_is_logged_as_a_warning() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let chain = regtest_chain(&key, 1);
+        let mut block = next_block(&chain, &key);
+        install_chain(chain).await;
+
+        // the header no longer commits to the transactions
+        block.transactions[0].outputs[0].value += 1;
+
+        // the handler runs on this thread, so a thread-local subscriber
+        // sees what it logs
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let mut stream = connect_handler(1000).await;
+        NewBlock(block).send_async(&mut stream).await.unwrap();
+
+        // replies come in order, so this one means the block was handled
+        let ChainInfoResponse { height, .. } = request(&mut stream, ChainInfo).await else {
+            panic!("expected chain info");
+        };
+        assert_eq!(height, 2);
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            logs.lines()
+                .any(|line| line.contains("WARN") && line.contains("block rejected")),
+            "{}",
+            logs
+        );
     }
 }
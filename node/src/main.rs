@@ -1,15 +1,36 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use argh::FromArgs;
+use btclib::Network;
+use btclib::crypto::PublicKey;
+use btclib::sha256::Hash;
 use btclib::types::BlockChain;
+use btclib::util::Savable;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
 use static_init::dynamic;
-use std::path::Path;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::net::TcpListener;
+use tokio::sync::{RwLock, Semaphore};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
 
 mod handler;
+mod metrics;
+mod mining;
+mod rpc;
+#[cfg(test)]
+mod test_util;
 mod util;
 
+const MAX_INBOUND_CONNECTIONS: usize = 128;
+const MAX_CONNECTIONS_PER_IP: usize = 8;
+// reorganizations can only unwind blocks whose bodies are still around
+const MIN_PRUNE_DEPTH: u64 = 100;
+
 #[derive(FromArgs)]
 /// A toy blockchain node
 struct Args {
@@ -17,9 +38,66 @@ struct Args {
     /// port number
     port: u16,
 
-    #[argh(option, default = "String::from(\"./blockchain.cbor\")")]
-    /// path to the blockchain
-    blockchain_file: String,
+    #[argh(option, default = "PathBuf::from(\"./data\")")]
+    /// directory holding the chain state, one subfolder per network
+    datadir: PathBuf,
+
+    #[argh(option, default = "Network::Main")]
+    /// network to join: main, test or regtest
+    network: Network,
+
+    #[argh(option)]
+    /// JSON file with the chain parameters for a new chain, instead of the
+    /// network defaults
+    chain_params: Option<PathBuf>,
+
+    #[argh(option)]
+    /// public key file that a new chain pays its genesis block to, instead
+    /// of the unspendable default; every node on it must use the same key
+    genesis_key: Option<PathBuf>,
+
+    #[argh(option)]
+    /// take the utxo set from this snapshot instead of replaying the chain
+    load_snapshot: Option<PathBuf>,
+
+    #[argh(option)]
+    /// only keep the bodies of this many recent blocks
+    prune: Option<u64>,
+
+    #[argh(switch)]
+    /// re-validate the whole chain on load and refuse to start if it fails
+    verify: bool,
+
+    #[argh(switch)]
+    /// index confirmed transactions by hash for FetchTransaction lookups
+    txindex: bool,
+
+    #[argh(option)]
+    /// mine blocks locally, paying the rewards to this public key file
+    mine: Option<PathBuf>,
+
+    #[argh(option, default = "50")]
+    /// messages per second a connection may send on average before it is
+    /// throttled
+    peer_message_rate: u32,
+
+    #[argh(switch)]
+    /// encrypt connections this node opens to other peers; incoming
+    /// connections are accepted either way
+    encrypt: bool,
+
+    #[argh(option)]
+    /// serve read-only JSON queries over HTTP on 127.0.0.1 at this port
+    rpc_port: Option<u16>,
+
+    #[argh(option, default = "10")]
+    /// seconds to wait for a peer to accept a connection and finish the
+    /// handshake
+    connect_timeout: u64,
+
+    #[argh(option, default = "3")]
+    /// times to retry each initial node that can't be reached
+    connect_retries: u32,
 
     #[argh(positional)]
     /// address of initial nodes
@@ -31,27 +109,97 @@ async fn main() -> Result<()> {
     let args: Args = argh::from_env();
 
     let port = args.port;
-    let blockchain_file = args.blockchain_file;
+    let message_rate = args.peer_message_rate;
+
+    if message_rate == 0 {
+        anyhow::bail!("--peer-message-rate must be at least 1");
+    }
+    let network = args.network;
     let nodes = args.nodes;
+    ENCRYPT_PEERS.store(args.encrypt, Ordering::Relaxed);
 
-    if Path::new(&blockchain_file).exists() {
-        util::load_blockchain(&blockchain_file).await?;
+    if args.connect_timeout == 0 {
+        anyhow::bail!("--connect-timeout must be at least 1");
+    }
+    CONNECT_TIMEOUT_SECS.store(args.connect_timeout, Ordering::Relaxed);
+
+    if args.prune.is_some_and(|depth| depth < MIN_PRUNE_DEPTH) {
+        anyhow::bail!("--prune must keep at least {} blocks", MIN_PRUNE_DEPTH);
+    }
+
+    let data_dir = util::data_dir(&args.datadir, network)?;
+    util::setup_tracing(&data_dir)?;
+    let blockchain_file = data_dir.join("blockchain.cbor");
+    info!("using data directory {}", data_dir.display());
+
+    let mut params = match &args.chain_params {
+        Some(path) => util::load_chain_params(path, network)?,
+        None => network.params(),
+    };
+    if let Some(path) = &args.genesis_key {
+        let miner_pubkey = PublicKey::load_from_file(path)
+            .with_context(|| format!("failed to load genesis key {}", path.display()))?;
+        params.genesis_key = miner_pubkey.0;
+    }
+
+    *BLOCKCHAIN.write().await = BlockChain::from_genesis(params);
+
+    match util::load_peers(&util::peers_file(&blockchain_file)) {
+        Ok(count) => info!("loaded {} saved peers", count),
+        Err(e) => warn!("failed to load saved peers: {:#}", e),
+    }
+
+    if blockchain_file.exists() {
+        util::load_blockchain(&blockchain_file, network, args.load_snapshot.as_deref()).await?;
+
+        if args.verify {
+            info!("verifying the chain");
+            BLOCKCHAIN
+                .read()
+                .await
+                .verify_chain()
+                .context("chain verification failed")?;
+            info!("chain verified");
+        }
+
+        // a chain keeps the parameters it was started with
+        if (args.chain_params.is_some() || args.genesis_key.is_some())
+            && *BLOCKCHAIN.read().await.params() != params
+        {
+            anyhow::bail!(
+                "{} was created with other chain parameters",
+                blockchain_file.display()
+            );
+        }
     } else {
-        println!("Blockchain file does not exist");
-        util::populate_connections(&nodes).await?;
-        println!("total amount of known nodes: {}", NODES.len());
+        info!("Blockchain file does not exist");
+        util::populate_connections(&nodes, args.connect_retries).await;
         if nodes.is_empty() {
-            println!("No initial nodes provided, starting a seed node");
+            util::connect_known_peers().await;
+        }
+        info!("total amount of known nodes: {}", NODES.len());
+        if NODES.is_empty() {
+            info!(
+                "No reachable nodes, starting a seed node from genesis block {}",
+                params.genesis_hash()
+            );
         } else {
-            let (longest_name, longest_count) = util::find_longest_chain_node().await?;
+            // a peer that fails is dropped, so each pass picks another one
+            loop {
+                let (best_name, best_count) = util::find_best_chain_node().await?;
 
-            util::download_blockchain(&longest_name, longest_count).await?;
+                if best_name.is_empty() {
+                    warn!("no peer answered, starting from the blocks we have");
+                    break;
+                }
 
-            println!("Downloaded blockchain from {}", longest_name);
-
-            {
-                let mut blockchain = BLOCKCHAIN.write().await;
-                blockchain.rebuild_utxos();
+                match util::download_blockchain(&best_name, best_count).await {
+                    Ok(()) => {
+                        info!("Downloaded blockchain from {}", best_name);
+                        break;
+                    }
+                    Err(e) => warn!("failed to download from {}: {:#}", best_name, e),
+                }
             }
 
             {
@@ -61,22 +209,116 @@ async fn main() -> Result<()> {
         }
     }
 
+    // journaling starts from a full save, which also takes in any blocks
+    // replayed from the journal
+    util::compact(&blockchain_file).await?;
+
+    if args.txindex {
+        let mut blockchain = BLOCKCHAIN.write().await;
+        blockchain.enable_tx_index();
+        info!(
+            "indexed transactions in {} blocks",
+            blockchain.block_height()
+        );
+    }
+
     let addr = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(&addr).await?;
 
-    println!("Listening on {}", addr);
+    info!("Listening on {}", addr);
 
     tokio::spawn(util::cleanup());
     tokio::spawn(util::save(blockchain_file.clone()));
+    tokio::spawn(util::reconnect());
+    tokio::spawn(util::keepalive());
+
+    if let Some(depth) = args.prune {
+        tokio::spawn(util::prune(depth));
+    }
+
+    if let Some(mine) = &args.mine {
+        let pubkey = PublicKey::load_from_file(mine)?;
+        let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+        tokio::spawn(mining::mine(pubkey, threads));
+    }
+
+    if let Some(rpc_port) = args.rpc_port {
+        tokio::spawn(async move {
+            if let Err(e) = rpc::serve(rpc_port).await {
+                error!("RPC server stopped: {}", e);
+            }
+        });
+    }
+
+    let inbound = Arc::new(Semaphore::new(MAX_INBOUND_CONNECTIONS));
 
     loop {
-        let (socket, _) = listener.accept().await?;
-        tokio::spawn(handler::handle_connection(socket));
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, peer) = accepted?;
+
+                // dropping the socket closes it straight away
+                let Ok(permit) = inbound.clone().try_acquire_owned() else {
+                    warn!("inbound connection limit reached, rejecting {}", peer);
+                    continue;
+                };
+
+                let Some(ip_slot) = util::IpSlot::acquire(peer.ip(), MAX_CONNECTIONS_PER_IP) else {
+                    warn!("too many connections from {}, rejecting", peer.ip());
+                    continue;
+                };
+
+                tokio::spawn(async move {
+                    // run apart so that a bug hit by one peer ends only its
+                    // own connection, and is logged against it
+                    let handler = tokio::spawn(handler::handle_connection(socket, message_rate));
+
+                    if let Err(e) = handler.await
+                        && e.is_panic()
+                    {
+                        let panic = e.into_panic();
+                        let reason = panic
+                            .downcast_ref::<&str>()
+                            .copied()
+                            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                            .unwrap_or("unknown cause");
+                        error!("handler for {} panicked: {}, connection dropped", peer, reason);
+                    }
+
+                    drop((permit, ip_slot));
+                });
+            }
+
+            _ = tokio::signal::ctrl_c() => {
+                return util::shutdown(&blockchain_file).await;
+            }
+        }
     }
 }
 
 #[dynamic]
-pub static BLOCKCHAIN: RwLock<BlockChain> = RwLock::new(BlockChain::new());
+pub static BLOCKCHAIN: RwLock<BlockChain> = RwLock::new(BlockChain::default());
+
+#[dynamic]
+pub static NODES: DashMap<String, util::Peer> = DashMap::new();
+
+pub static ENCRYPT_PEERS: AtomicBool = AtomicBool::new(false);
+
+pub static CONNECT_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(10);
+
+#[dynamic]
+pub static KNOWN_NODES: DashMap<String, DateTime<Utc>> = DashMap::new();
+
+#[dynamic]
+pub static PEER_VERSIONS: DashMap<String, u32> = DashMap::new();
+
+// transactions this node forwarded, and when
+#[dynamic]
+pub static RECENTLY_RELAYED: DashMap<Hash, Instant> = DashMap::new();
+
+// transaction hashes each peer is known to have, and when that was learned
+#[dynamic]
+pub static PEER_INVENTORY: DashMap<String, HashMap<Hash, Instant>> = DashMap::new();
 
 #[dynamic]
-pub static NODES: DashMap<String, TcpStream> = DashMap::new();
+pub static INBOUND_PER_IP: DashMap<IpAddr, usize> = DashMap::new();
@@ -0,0 +1,92 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub static BLOCKS_ACCEPTED: AtomicU64 = AtomicU64::new(0);
+pub static BLOCKS_REJECTED: AtomicU64 = AtomicU64::new(0);
+pub static TRANSACTIONS_ACCEPTED: AtomicU64 = AtomicU64::new(0);
+pub static TRANSACTIONS_REJECTED: AtomicU64 = AtomicU64::new(0);
+pub static TRANSACTIONS_RELAYED: AtomicU64 = AtomicU64::new(0);
+
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+// Prometheus text exposition format; the gauges are read from the live
+// structures rather than tracked separately
+pub async fn render() -> String {
+    let counters = [
+        (
+            "blocks_accepted_total",
+            "Blocks added to the chain",
+            &BLOCKS_ACCEPTED,
+        ),
+        (
+            "blocks_rejected_total",
+            "Blocks that failed validation",
+            &BLOCKS_REJECTED,
+        ),
+        (
+            "transactions_accepted_total",
+            "Transactions added to the mempool",
+            &TRANSACTIONS_ACCEPTED,
+        ),
+        (
+            "transactions_rejected_total",
+            "Transactions refused by the mempool",
+            &TRANSACTIONS_REJECTED,
+        ),
+        (
+            "transactions_relayed_total",
+            "Transactions broadcast to peers",
+            &TRANSACTIONS_RELAYED,
+        ),
+    ];
+
+    let (height, mempool_size, orphans) = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        (
+            blockchain.block_height(),
+            blockchain.mempool().len() as u64,
+            blockchain.orphans().len() as u64,
+        )
+    };
+
+    let gauges = [
+        ("peers", "Connected peers", crate::NODES.len() as u64),
+        (
+            "mempool_transactions",
+            "Transactions in the mempool",
+            mempool_size,
+        ),
+        (
+            "orphan_transactions",
+            "Transactions waiting for unknown inputs",
+            orphans,
+        ),
+        ("block_height", "Blocks in the main chain", height),
+    ];
+
+    let mut out = String::new();
+
+    for (name, help, counter) in counters {
+        write_metric(
+            &mut out,
+            name,
+            help,
+            "counter",
+            counter.load(Ordering::Relaxed),
+        );
+    }
+
+    for (name, help, value) in gauges {
+        write_metric(&mut out, name, help, "gauge", value);
+    }
+
+    out
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+    let _ = writeln!(out, "# HELP rsbtc_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE rsbtc_{} {}", name, kind);
+    let _ = writeln!(out, "rsbtc_{} {}", name, value);
+}
@@ -0,0 +1,83 @@
+use btclib::crypto::PublicKey;
+use btclib::network::Message;
+use btclib::sha256::Hash;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::metrics::{self, BLOCKS_ACCEPTED};
+
+// templates are rebuilt this often so new mempool transactions get mined
+const TEMPLATE_REFRESH: Duration = Duration::from_secs(10);
+const TIP_POLL_INTERVAL: Duration = Duration::from_millis(250);
+// at an easy target, regtest's in particular, a block is found at once;
+// without a pause the loop would hog the chain lock and the runtime, and
+// outrun the median-time-past rule
+const MIN_BLOCK_INTERVAL: Duration = Duration::from_secs(1);
+
+// an embedded miner: mines on top of the local tip and applies what it finds
+// through add_block, like any block submitted by a miner
+pub async fn mine(pubkey: PublicKey, threads: usize) {
+    info!("mining to {} on {} threads", pubkey.to_address(), threads);
+
+    loop {
+        let template = {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            crate::util::block_template(&blockchain, pubkey.clone())
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let watcher = tokio::spawn(stop_when_stale(
+            template.header.prev_block_hash,
+            stop.clone(),
+        ));
+
+        debug!("mining block with target {}", template.header.target);
+        let header = template.header.clone();
+        let mined =
+            tokio::task::spawn_blocking(move || header.mine_parallel_until(threads, &stop)).await;
+        watcher.abort();
+
+        let header = match mined {
+            Ok(Some(header)) => header,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("mining task failed: {}", e);
+                continue;
+            }
+        };
+
+        let mut block = template;
+        block.header = header;
+
+        let result =
+            crate::util::block_added(crate::BLOCKCHAIN.write().await.add_block(block.clone()));
+
+        match result {
+            Ok(()) => {
+                info!("mined block {}", block.hash());
+                metrics::inc(&BLOCKS_ACCEPTED);
+                crate::util::broadcast(&Message::NewBlock(block)).await;
+            }
+            Err(e) => warn!("mined block rejected: {}", e),
+        }
+
+        time::sleep(MIN_BLOCK_INTERVAL).await;
+    }
+}
+
+async fn stop_when_stale(tip: Hash, stop: Arc<AtomicBool>) {
+    let deadline = Instant::now() + TEMPLATE_REFRESH;
+    let mut interval = time::interval(TIP_POLL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let current_tip = crate::util::tip_hash(&*crate::BLOCKCHAIN.read().await);
+        if current_tip != tip || Instant::now() >= deadline {
+            stop.store(true, Ordering::Relaxed);
+            return;
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use axum::Router;
+use axum::extract::Path;
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use btclib::sha256::Hash;
+use btclib::util::Savable;
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tracing::info;
+
+// read-only: every handler only ever takes the BLOCKCHAIN read lock
+pub async fn serve(port: u16) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/height", get(height))
+        .route("/block/{height}", get(block))
+        .route("/tx/{hash}", get(transaction))
+        .route("/metrics", get(metrics));
+
+    let addr = format!("127.0.0.1:{}", port);
+    let listener = TcpListener::bind(&addr).await?;
+    info!("RPC listening on http://{}", addr);
+
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+fn json<T: Savable + Serialize>(value: &T) -> Response {
+    let mut body = vec![];
+
+    match value.save_json(&mut body) {
+        Ok(()) => ([(header::CONTENT_TYPE, "application/json")], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn metrics() -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        crate::metrics::render().await,
+    )
+        .into_response()
+}
+
+async fn height() -> Response {
+    let height = crate::BLOCKCHAIN.read().await.block_height();
+    axum::Json(serde_json::json!({ "height": height })).into_response()
+}
+
+async fn block(Path(height): Path<usize>) -> Response {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    match blockchain.blocks().nth(height) {
+        Some(_) if (height as u64) < blockchain.pruned_height() => {
+            (StatusCode::GONE, "block body has been pruned").into_response()
+        }
+        Some(block) => json(block),
+        None => (StatusCode::NOT_FOUND, "no block at that height").into_response(),
+    }
+}
+
+// confirmed transactions first, then the mempool
+async fn transaction(Path(hash): Path<String>) -> Response {
+    let Ok(hash) = hash.parse::<Hash>() else {
+        return (StatusCode::BAD_REQUEST, "invalid transaction hash").into_response();
+    };
+
+    let blockchain = crate::BLOCKCHAIN.read().await;
+
+    let tx = blockchain
+        .find_transaction(&hash)
+        .map(|(_, _, tx)| tx)
+        .or_else(|| {
+            blockchain
+                .mempool()
+                .iter()
+                .map(|(_, tx)| tx)
+                .find(|tx| tx.hash() == hash)
+        });
+
+    match tx {
+        Some(tx) => json(tx),
+        None => (StatusCode::NOT_FOUND, "unknown transaction").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use btclib::crypto::PrivateKey;
+    use btclib::types::Block;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::time::{self, Duration};
+
+    // serves on a free port and returns it once it accepts connections
+    async fn start() -> u16 {
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        tokio::spawn(serve(port));
+
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+                return port;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the RPC server never started listening");
+    }
+
+    // the status code and body of a plain GET
+    async fn get(port: u16, path: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    #[tokio::test]
+    async fn height_and_blocks_are_served_as_json() {
+        let _serial = serial().await;
+        let chain = regtest_chain(&PrivateKey::new_key(), 2);
+        let genesis = chain.blocks().next().unwrap().hash();
+        install_chain(chain).await;
+        let port = start().await;
+
+        let (status, body) = get(port, "/height").await;
+        assert_eq!(status, 200);
+        let height: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(height["height"], 3);
+
+        let (status, body) = get(port, "/block/0").await;
+        assert_eq!(status, 200);
+        let block = Block::load_json(body.as_bytes()).unwrap();
+        assert_eq!(block.hash(), genesis);
+
+        let (status, _) = get(port, "/block/3").await;
+        assert_eq!(status, 404);
+    }
+
+    // the value of an unlabelled sample in a metrics scrape
+    fn sample(scrape: &str, name: &str) -> u64 {
+        scrape
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn an_accepted_block_shows_up_in_the_metrics() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let chain = regtest_chain(&key, 1);
+        let block = next_block(&chain, &key);
+        install_chain(chain).await;
+        let port = start().await;
+
+        let (status, scrape) = get(port, "/metrics").await;
+        assert_eq!(status, 200);
+        let accepted = sample(&scrape, "rsbtc_blocks_accepted_total");
+        assert_eq!(sample(&scrape, "rsbtc_block_height"), 2);
+
+        let mut stream = connect_handler(1000).await;
+        btclib::network::Message::NewBlock(block)
+            .send_async(&mut stream)
+            .await
+            .unwrap();
+
+        // nothing comes back for a block, so wait for the chain to grow
+        for _ in 0..100 {
+            if crate::BLOCKCHAIN.read().await.block_height() == 3 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (_, scrape) = get(port, "/metrics").await;
+        assert_eq!(sample(&scrape, "rsbtc_blocks_accepted_total"), accepted + 1);
+        assert_eq!(sample(&scrape, "rsbtc_block_height"), 3);
+    }
+}
@@ -0,0 +1,197 @@
+// helpers shared by the unit tests. The chain and the peer tables are
+// process-wide, so a test that touches them holds `serial` throughout
+use btclib::Network;
+use btclib::crypto::{PrivateKey, Signature};
+use btclib::network::Message;
+use btclib::types::{Block, BlockChain, Transaction, TransactionInput, TransactionOutput};
+use chrono::Duration as ChronoDuration;
+use std::sync::atomic::Ordering;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::{self, Duration};
+
+use crate::util::PeerStream;
+
+static SERIAL: Mutex<()> = Mutex::const_new(());
+
+// a reply that takes longer than this means the handler is stuck
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn serial() -> MutexGuard<'static, ()> {
+    let guard = SERIAL.lock().await;
+    crate::NODES.clear();
+    crate::PEER_VERSIONS.clear();
+    crate::RECENTLY_RELAYED.clear();
+    crate::PEER_INVENTORY.clear();
+    crate::KNOWN_NODES.clear();
+    crate::CONNECT_TIMEOUT_SECS.store(10, Ordering::Relaxed);
+    guard
+}
+
+// the template a miner would get, a second after the tip so the
+// median-time-past rule holds however fast blocks are made
+pub fn next_block(chain: &BlockChain, key: &PrivateKey) -> Block {
+    let mut block = crate::util::block_template(chain, key.public_key());
+    let tip = chain.blocks().last().expect("chain has a genesis block");
+    block.header.timestamp = tip.header.timestamp + ChronoDuration::seconds(1);
+    assert!(block.header.mine(1_000_000));
+    block
+}
+
+// a regtest chain of `count` blocks past the genesis, all paying `key`
+pub fn regtest_chain(key: &PrivateKey, count: usize) -> BlockChain {
+    let mut chain = BlockChain::from_genesis(Network::Regtest.params());
+    for _ in 0..count {
+        let block = next_block(&chain, key);
+        chain.add_block(block).expect("template connects");
+    }
+    chain
+}
+
+// the coinbase output of the block at `height`, spendable once the chain is
+// COINBASE_MATURITY blocks past it
+pub fn coinbase_output(chain: &BlockChain, height: usize) -> TransactionOutput {
+    chain.blocks().nth(height).unwrap().transactions[0].outputs[0].clone()
+}
+
+// spends `prev` with `key`, paying `value` back to the same key
+pub fn spend(prev: &TransactionOutput, key: &PrivateKey, value: u64) -> Transaction {
+    let placeholder =
+        Signature::sign_input(&Transaction::new(vec![], vec![]), 0, &prev.hash(), key);
+    let mut tx = Transaction::new(
+        vec![TransactionInput {
+            prev_tx_output_hash: prev.hash(),
+            signature: placeholder,
+        }],
+        vec![TransactionOutput {
+            value,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: key.public_key(),
+        }],
+    );
+    tx.inputs[0].signature = Signature::sign_input(&tx, 0, &prev.hash(), key);
+    tx
+}
+
+pub async fn install_chain(chain: BlockChain) {
+    *crate::BLOCKCHAIN.write().await = chain;
+}
+
+// a handler serving one connection, and the client end of it, past the
+// handshake
+pub async fn connect_handler(message_rate: u32) -> PeerStream {
+    let mut stream = PeerStream::Plain(spawn_handler(message_rate).await);
+    Message::handshake_async(&mut stream, "test", 0)
+        .await
+        .unwrap();
+    stream
+}
+
+// a handler serving one connection, and the client end of it before any
+// handshake
+pub async fn spawn_handler(message_rate: u32) -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        crate::handler::handle_connection(socket, message_rate).await;
+    });
+
+    TcpStream::connect(address).await.unwrap()
+}
+
+pub async fn receive(stream: &mut PeerStream) -> Message {
+    time::timeout(REPLY_TIMEOUT, Message::recv_async(stream))
+        .await
+        .expect("the handler replied in time")
+        .expect("the reply decodes")
+}
+
+pub async fn request(stream: &mut PeerStream, message: Message) -> Message {
+    message.send_async(stream).await.unwrap();
+    receive(stream).await
+}
+
+// completes the handshake and then never says anything
+pub async fn silent_peer() -> PeerStream {
+    mock_peer(true).await
+}
+
+// completes the handshake and then hangs up
+pub async fn vanishing_peer() -> PeerStream {
+    mock_peer(false).await
+}
+
+async fn mock_peer(stay: bool) -> PeerStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut stream = PeerStream::Plain(socket);
+        Message::accept_handshake_async(&mut stream, "mock", 0)
+            .await
+            .unwrap();
+        if stay {
+            std::future::pending::<()>().await;
+        }
+        drop(stream);
+    });
+
+    let mut stream = PeerStream::Plain(TcpStream::connect(address).await.unwrap());
+    Message::handshake_async(&mut stream, "test", 0)
+        .await
+        .unwrap();
+    stream
+}
+
+// completes the handshake and then answers header and block requests from
+// `chain`, capped as a node would cap them
+pub async fn serving_peer(chain: BlockChain) -> PeerStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut stream = PeerStream::Plain(socket);
+        Message::accept_handshake_async(&mut stream, "mock", chain.block_height())
+            .await
+            .unwrap();
+
+        while let Ok(message) = Message::recv_async(&mut stream).await {
+            let reply = match message {
+                Message::FetchHeaders {
+                    start_height,
+                    count,
+                } => Message::Headers(
+                    chain
+                        .blocks()
+                        .skip(start_height)
+                        .take(count.min(btclib::MAX_HEADERS_PER_MESSAGE))
+                        .map(|block| block.header.clone())
+                        .collect(),
+                ),
+                Message::FetchBlocks { start, count } => Message::Blocks(
+                    chain
+                        .blocks()
+                        .skip(start)
+                        .take(count.min(btclib::MAX_BLOCKS_PER_MESSAGE))
+                        .cloned()
+                        .collect(),
+                ),
+                _ => break,
+            };
+
+            if reply.send_async(&mut stream).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut stream = PeerStream::Plain(TcpStream::connect(address).await.unwrap());
+    Message::handshake_async(&mut stream, "test", 0)
+        .await
+        .unwrap();
+    stream
+}
@@ -1,135 +1,880 @@
 use anyhow::{Context, Result};
+use btclib::crypto::PublicKey;
+use btclib::error::BtcError;
+use btclib::journal::BlockJournal;
 use btclib::network::Message;
-use btclib::types::BlockChain;
-use btclib::util::Savable;
+use btclib::noise::NoiseStream;
+use btclib::sha256::Hash;
+use btclib::types::{Block, BlockChain, BlockHeader, Transaction, TransactionOutput};
+use btclib::util::{MerkleRoot, Savable, write_atomically};
+use btclib::{ChainParams, Network};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Error as IoError, Result as IoResult};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::{Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
-use tokio::time;
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info, warn};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{EnvFilter, fmt, prelude::*};
+use uuid::Uuid;
 
-pub async fn load_blockchain(blockchain_file: &str) -> Result<()> {
-    println!("Blockchain file exists!");
+pub const USER_AGENT: &str = concat!("rsbtc-node/", env!("CARGO_PKG_VERSION"));
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(300);
+const MAX_SAVED_PEERS: usize = 256;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+const INV_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const PEER_INVENTORY_EXPIRY: Duration = Duration::from_secs(600);
+const RELAY_WINDOW: Duration = Duration::from_secs(300);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+const SAVE_INTERVAL: Duration = Duration::from_secs(15);
+// blocks are journaled as they come, so the whole chain is only rewritten
+// this often
+const COMPACT_INTERVAL: Duration = Duration::from_secs(600);
+// the first pause between attempts at an initial node; it doubles each time
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// a peer connection, encrypted or not; either way it reads and writes
+// the same framed messages
+pub enum PeerStream {
+    Plain(TcpStream),
+    Noise(Box<NoiseStream<TcpStream>>),
+}
+
+impl PeerStream {
+    // tells the two kinds apart by the first byte the peer sends
+    pub async fn accept(socket: TcpStream) -> IoResult<Self> {
+        let mut first = [0u8; 1];
+        socket.peek(&mut first).await?;
+
+        if first[0] == btclib::noise::NOISE_PREAMBLE[0] {
+            Ok(PeerStream::Noise(Box::new(
+                NoiseStream::accept(socket).await?,
+            )))
+        } else {
+            Ok(PeerStream::Plain(socket))
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, PeerStream::Noise(_))
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Noise(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<IoResult<usize>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::Noise(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<IoResult<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Noise(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<IoResult<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Noise(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedPeer {
+    address: String,
+    last_seen: DateTime<Utc>,
+}
+
+// logs go to the console and to a daily file under the data directory;
+// RUST_LOG picks the verbosity, info by default
+pub fn setup_tracing(data_dir: &Path) -> Result<()> {
+    let file_appender =
+        RollingFileAppender::new(Rotation::DAILY, data_dir.join("logs"), "node.log");
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(fmt::layer().with_ansi(false).with_writer(file_appender))
+        .with(filter)
+        .init();
+
+    Ok(())
+}
+
+pub fn data_dir(datadir: &Path, network: Network) -> Result<PathBuf> {
+    let dir = datadir.join(network.name());
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("failed to create data directory {}", dir.display()))?;
+    Ok(dir)
+}
+
+pub fn mempool_file(blockchain_file: &Path) -> PathBuf {
+    blockchain_file.with_file_name("mempool.cbor")
+}
+
+// one inbound connection counted against its source address, released
+// when dropped
+pub struct IpSlot(IpAddr);
+
+impl IpSlot {
+    pub fn acquire(ip: IpAddr, limit: usize) -> Option<Self> {
+        let mut count = crate::INBOUND_PER_IP.entry(ip).or_insert(0);
+
+        if *count >= limit {
+            return None;
+        }
+
+        *count += 1;
+        Some(IpSlot(ip))
+    }
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        crate::INBOUND_PER_IP.remove_if_mut(&self.0, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+// a token bucket allowing `rate` messages a second on average and bursts of
+// twice that
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> Self {
+        let rate = f64::from(rate.max(1));
+
+        RateLimiter {
+            rate,
+            burst: rate * 2.0,
+            tokens: rate * 2.0,
+            last: Instant::now(),
+        }
+    }
+
+    // takes a token for one message and returns how long to wait before
+    // handling it
+    pub fn take(&mut self) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+
+        self.tokens = (self.tokens + refill).min(self.burst) - 1.0;
+        self.last = now;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+pub fn peers_file(blockchain_file: &Path) -> PathBuf {
+    blockchain_file.with_file_name("peers.json")
+}
+
+pub fn journal_file(blockchain_file: &Path) -> PathBuf {
+    blockchain_file.with_file_name("blocks.log")
+}
+
+pub fn snapshot_file(blockchain_file: &Path) -> PathBuf {
+    blockchain_file.with_file_name("utxos.cbor")
+}
+
+// keeps only the most recently seen peers so the file cannot grow without
+// bound
+pub fn save_peers(path: &Path) -> Result<()> {
+    let mut peers = crate::KNOWN_NODES
+        .iter()
+        .map(|x| SavedPeer {
+            address: x.key().clone(),
+            last_seen: *x.value(),
+        })
+        .collect::<Vec<_>>();
+
+    peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+    peers.truncate(MAX_SAVED_PEERS);
+
+    write_atomically(path, |file| {
+        serde_json::to_writer_pretty(file, &peers).map_err(IoError::from)
+    })?;
+    Ok(())
+}
+
+pub fn load_peers(path: &Path) -> Result<usize> {
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let file = std::fs::File::open(path)?;
+    let peers: Vec<SavedPeer> = serde_json::from_reader(file)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+
+    let count = peers.len().min(MAX_SAVED_PEERS);
+    for peer in peers.into_iter().take(MAX_SAVED_PEERS) {
+        crate::KNOWN_NODES
+            .entry(peer.address)
+            .and_modify(|last_seen| *last_seen = (*last_seen).max(peer.last_seen))
+            .or_insert(peer.last_seen);
+    }
+
+    Ok(count)
+}
+
+pub fn tip_hash(blockchain: &BlockChain) -> Hash {
+    blockchain
+        .blocks()
+        .last()
+        .map(|last_block| last_block.hash())
+        .unwrap_or(Hash::zero())
+}
+
+// the best-paying mempool transactions behind a coinbase paying `pubkey`
+pub fn block_template(blockchain: &BlockChain, pubkey: PublicKey) -> Block {
+    let mut transactions = blockchain
+        .mempool()
+        .iter()
+        .take(btclib::BLOCK_TRANSACTION_CAP)
+        .map(|(_, tx)| tx)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let miner_fees = blockchain.calculate_fees(&transactions);
+    let reward = blockchain.calculate_block_reward();
+
+    transactions.insert(
+        0,
+        Transaction {
+            inputs: vec![],
+            outputs: vec![TransactionOutput {
+                pubkey,
+                unique_id: Uuid::new_v4(),
+                value: reward + miner_fees,
+            }],
+            lock_time: 0,
+        },
+    );
+
+    let merkle_root = MerkleRoot::calculate(&transactions);
+
+    Block::new(
+        BlockHeader {
+            timestamp: Utc::now(),
+            nonce: 0,
+            prev_block_hash: tip_hash(blockchain),
+            merkle_root,
+            target: blockchain.target(),
+        },
+        transactions,
+    )
+}
+
+// add_block keeps a block it could not journal; the save task notices the
+// journal is off and writes the chain out whole
+pub fn block_added(result: Result<(), BtcError>) -> Result<(), BtcError> {
+    match result {
+        Err(BtcError::JournalFailed(e)) => {
+            error!(
+                "failed to journal block: {}, saving the whole chain instead",
+                e
+            );
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+pub fn load_chain_params(path: &Path, network: Network) -> Result<ChainParams> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("failed to open chain parameters {}", path.display()))?;
+    let params: ChainParams = serde_json::from_reader(file)
+        .with_context(|| format!("failed to parse chain parameters {}", path.display()))?;
+
+    params.validate()?;
+
+    if params.network != network {
+        return Err(BtcError::NetworkMismatch.into());
+    }
+
+    Ok(params)
+}
+
+pub async fn load_blockchain(
+    blockchain_file: &Path,
+    network: Network,
+    snapshot: Option<&Path>,
+) -> Result<()> {
+    info!("Blockchain file exists!");
 
     let new_blockchain = BlockChain::load_from_file(blockchain_file)?;
-    println!("BlockChain loaded");
+    info!("BlockChain loaded");
+
+    if new_blockchain.network() != network {
+        return Err(BtcError::NetworkMismatch.into());
+    }
+
+    // a chain saved before the genesis was fixed starts somewhere else
+    if new_blockchain
+        .blocks()
+        .next()
+        .is_some_and(|genesis| genesis.hash() != new_blockchain.params().genesis_hash())
+    {
+        return Err(BtcError::InvalidGenesis.into());
+    }
 
     let mut blockchain = crate::BLOCKCHAIN.write().await;
     *blockchain = new_blockchain;
 
-    println!("Rebuilding utxos");
-    blockchain.rebuild_utxos();
+    // the snapshot written with the last compaction matches the chain file
+    // unless the node stopped between writing the two; only then, or
+    // without one, is every block replayed
+    if let Some(snapshot) = snapshot {
+        info!("Loading utxos from {}", snapshot.display());
+        blockchain
+            .import_utxo_snapshot_from_file(snapshot)
+            .with_context(|| format!("failed to load snapshot {}", snapshot.display()))?;
+
+        info!("utxos loaded");
+    } else {
+        let snapshot = snapshot_file(blockchain_file);
+
+        match blockchain.import_utxo_snapshot_from_file(&snapshot) {
+            Ok(()) => info!("utxos loaded from {}", snapshot.display()),
+            Err(e) => {
+                if snapshot.exists() {
+                    warn!("ignoring utxo snapshot {}: {}", snapshot.display(), e);
+                }
 
-    println!("utxos rebuilt");
-    println!("checking if target needs to be adjusted");
-    println!("current target: {}", blockchain.target());
+                info!("Rebuilding utxos");
+                blockchain.rebuild_utxos();
+
+                info!("utxos rebuilt");
+            }
+        }
+    }
+
+    let (replayed, skipped) = blockchain
+        .replay_journal(&BlockJournal::new(journal_file(blockchain_file)))
+        .context("failed to read the block journal")?;
+    if replayed + skipped > 0 {
+        info!(
+            "replayed {} journaled blocks, skipped {} already known or invalid",
+            replayed, skipped
+        );
+    }
+    info!("checking if target needs to be adjusted");
+    info!("current target: {}", blockchain.target());
     blockchain.try_adjust_target();
 
-    println!("new target: {}", blockchain.target());
-    println!("Initialization complete");
+    info!("new target: {}", blockchain.target());
+
+    let mempool_file = mempool_file(blockchain_file);
+    if mempool_file.exists() {
+        let dropped = blockchain.load_mempool_from_file(&mempool_file)?;
+        info!(
+            "mempool loaded: {} transactions, {} no longer valid",
+            blockchain.mempool().len(),
+            dropped
+        );
+    }
+
+    info!("Initialization complete");
 
     Ok(())
 }
 
-pub async fn populate_connections(nodes: &[String]) -> Result<()> {
-    println!("trying to connect to other nodes");
+// bounded by --connect-timeout, so a peer that never answers can't hold up
+// whoever is dialling it
+pub async fn connect(address: &str) -> Result<PeerStream> {
+    let timeout = Duration::from_secs(crate::CONNECT_TIMEOUT_SECS.load(Ordering::Relaxed));
+
+    time::timeout(timeout, dial(address))
+        .await
+        .with_context(|| format!("connecting to {} timed out after {:?}", address, timeout))?
+}
+
+async fn dial(address: &str) -> Result<PeerStream> {
+    let socket = TcpStream::connect(address).await?;
+
+    let mut stream = if crate::ENCRYPT_PEERS.load(Ordering::Relaxed) {
+        let stream = NoiseStream::connect(socket)
+            .await
+            .with_context(|| format!("encrypted handshake with {} failed", address))?;
+        PeerStream::Noise(Box::new(stream))
+    } else {
+        PeerStream::Plain(socket)
+    };
+
+    let best_height = crate::BLOCKCHAIN.read().await.block_height();
+
+    let version = Message::handshake_async(&mut stream, USER_AGENT, best_height)
+        .await
+        .with_context(|| format!("handshake with {} failed", address))?;
+
+    info!("{} speaks protocol version {}", address, version);
+    crate::PEER_VERSIONS.insert(address.to_string(), version);
+
+    Ok(stream)
+}
+
+// each connection sits behind its own lock, so a task waiting on one peer
+// never holds a shard of NODES, and with it other peers, across an await
+pub type Peer = Arc<Mutex<PeerStream>>;
+
+pub fn add_peer(address: String, stream: PeerStream) {
+    crate::KNOWN_NODES.insert(address.clone(), Utc::now());
+    crate::NODES.insert(address, Arc::new(Mutex::new(stream)));
+}
+
+// the map's guard is dropped before this returns
+fn peer(node: &str) -> Option<Peer> {
+    crate::NODES.get(node).map(|peer| peer.clone())
+}
+
+// unless the address was reconnected meanwhile
+fn drop_peer(node: &str, peer: &Peer) {
+    crate::NODES.remove_if(node, |_, current| Arc::ptr_eq(current, peer));
+}
+
+// best effort: saved peers may well have gone away since they were written
+pub async fn connect_known_peers() {
+    let mut peers = crate::KNOWN_NODES
+        .iter()
+        .map(|x| (x.key().clone(), *x.value()))
+        .collect::<Vec<_>>();
+    peers.sort_by_key(|(_, last_seen)| std::cmp::Reverse(*last_seen));
+
+    for (peer, _) in peers {
+        info!("connecting to saved peer {}", peer);
+
+        match connect(&peer).await {
+            Ok(stream) => add_peer(peer, stream),
+            Err(e) => warn!("failed to connect to {}: {}", peer, e),
+        }
+    }
+}
+
+pub async fn broadcast(message: &Message) {
+    let nodes = crate::NODES
+        .iter()
+        .map(|x| x.key().clone())
+        .collect::<Vec<_>>();
 
     for node in nodes {
-        println!("connecting to {}", node);
+        debug!("broadcasting to {}", node);
+
+        let Some(peer) = peer(&node) else {
+            continue;
+        };
 
-        let mut stream = TcpStream::connect(&node).await?;
-        let message = Message::DiscoverNodes;
-        message.send_async(&mut stream).await?;
-        println!("sent DiscoverNodes to {}", node);
+        let failed = message.send_async(&mut *peer.lock().await).await.is_err();
 
-        let message = Message::recv_async(&mut stream).await?;
+        if failed {
+            warn!("failed to broadcast to {}, dropping peer", node);
+            drop_peer(&node, &peer);
+        }
+    }
+}
 
-        match message {
-            Message::NodeList(child_nodes) => {
-                println!("received NodeList from {}", node);
+// announces transactions by hash and only sends the bodies a peer asks for;
+// peers from before inventory messages are sent the full transactions
+pub async fn relay_transactions(transactions: &[Transaction]) {
+    let now = Instant::now();
+
+    // in a loop of peers the same transaction comes back around, so each is
+    // forwarded once per window no matter how often it arrives
+    let transactions = transactions
+        .iter()
+        .filter(|tx| {
+            let mut fresh = false;
+            crate::RECENTLY_RELAYED
+                .entry(tx.hash())
+                .and_modify(|relayed| {
+                    if now.duration_since(*relayed) >= RELAY_WINDOW {
+                        *relayed = now;
+                        fresh = true;
+                    }
+                })
+                .or_insert_with(|| {
+                    fresh = true;
+                    now
+                });
+            fresh
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if transactions.is_empty() {
+        debug!("transactions were relayed recently, not forwarding them again");
+        return;
+    }
+
+    for _ in &transactions {
+        crate::metrics::inc(&crate::metrics::TRANSACTIONS_RELAYED);
+    }
+
+    let nodes = crate::NODES
+        .iter()
+        .map(|x| x.key().clone())
+        .collect::<Vec<_>>();
+
+    for node in nodes {
+        let Some(peer) = peer(&node) else {
+            continue;
+        };
+
+        let result = relay_to(&node, &mut *peer.lock().await, &transactions).await;
+
+        if let Err(e) = result {
+            warn!("failed to relay to {}: {:#}, dropping peer", node, e);
+            drop_peer(&node, &peer);
+        }
+    }
+}
+
+async fn relay_to(node: &str, stream: &mut PeerStream, transactions: &[Transaction]) -> Result<()> {
+    let now = Instant::now();
+
+    let fresh = {
+        let mut known = crate::PEER_INVENTORY.entry(node.to_string()).or_default();
+        known.retain(|_, seen| now.duration_since(*seen) < PEER_INVENTORY_EXPIRY);
+
+        transactions
+            .iter()
+            .filter(|tx| !known.contains_key(&tx.hash()))
+            .collect::<Vec<_>>()
+    };
+
+    if fresh.is_empty() {
+        return Ok(());
+    }
+
+    let version = crate::PEER_VERSIONS
+        .get(node)
+        .map(|version| *version)
+        .unwrap_or(btclib::MIN_PROTOCOL_VERSION);
+
+    let requested = if version < btclib::INV_PROTOCOL_VERSION {
+        fresh.iter().map(|tx| tx.hash()).collect::<Vec<_>>()
+    } else {
+        let message = Message::Inv(fresh.iter().map(|tx| tx.hash()).collect());
+        message.send_async(&mut *stream).await?;
+
+        match time::timeout(INV_REPLY_TIMEOUT, Message::recv_async(&mut *stream)).await?? {
+            Message::GetData(hashes) => hashes,
+            e => anyhow::bail!("expected GetData, got {:?}", e),
+        }
+    };
+
+    for tx in &fresh {
+        if requested.contains(&tx.hash()) {
+            Message::NewTransaction((*tx).clone())
+                .send_async(&mut *stream)
+                .await?;
+        }
+    }
+
+    debug!(
+        "relayed {} of {} transactions to {}",
+        requested.len(),
+        fresh.len(),
+        node
+    );
+
+    // whatever the peer did not ask for, it already had
+    let mut known = crate::PEER_INVENTORY.entry(node.to_string()).or_default();
+    for tx in fresh {
+        known.insert(tx.hash(), now);
+    }
+
+    Ok(())
+}
+
+// nodes that can't be reached are skipped, so startup goes on with whichever
+// of them answered
+pub async fn populate_connections(nodes: &[String], retries: u32) {
+    info!("trying to connect to other nodes");
+
+    for node in nodes {
+        let Some(mut stream) = connect_with_retries(node, retries).await else {
+            continue;
+        };
+
+        match discover_nodes(&mut stream).await {
+            Ok(child_nodes) => {
+                debug!("received NodeList from {}", node);
 
                 for child_node in child_nodes {
-                    println!("adding node {}", child_node);
+                    info!("adding node {}", child_node);
 
-                    let new_stream = TcpStream::connect(&child_node).await?;
-                    crate::NODES.insert(child_node, new_stream);
+                    match connect(&child_node).await {
+                        Ok(new_stream) => add_peer(child_node, new_stream),
+                        Err(e) => warn!("failed to connect to {}: {:#}", child_node, e),
+                    }
                 }
             }
 
-            _ => {
-                println!("received unexpected message from {}", node);
-            }
+            Err(e) => warn!("failed to discover nodes through {}: {:#}", node, e),
         }
 
-        crate::NODES.insert(node.clone(), stream);
+        add_peer(node.clone(), stream);
     }
+}
 
-    Ok(())
+async fn connect_with_retries(address: &str, retries: u32) -> Option<PeerStream> {
+    let mut delay = CONNECT_RETRY_DELAY;
+
+    for attempt in 0..=retries {
+        info!("connecting to {}", address);
+
+        match connect(address).await {
+            Ok(stream) => return Some(stream),
+            Err(e) if attempt < retries => {
+                warn!(
+                    "failed to connect to {}: {:#}, retrying in {:?}",
+                    address, e, delay
+                );
+                time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => warn!(
+                "giving up on {} after {} attempts: {:#}",
+                address,
+                attempt + 1,
+                e
+            ),
+        }
+    }
+
+    None
 }
 
-pub async fn find_longest_chain_node() -> Result<(String, u32)> {
-    println!("finding nodes with longest chain");
+async fn discover_nodes(stream: &mut PeerStream) -> Result<Vec<String>> {
+    let timeout = Duration::from_secs(crate::CONNECT_TIMEOUT_SECS.load(Ordering::Relaxed));
+
+    let message = time::timeout(timeout, async {
+        Message::DiscoverNodes.send_async(&mut *stream).await?;
+        anyhow::Ok(Message::recv_async(&mut *stream).await?)
+    })
+    .await
+    .context("no reply in time")??;
 
-    let mut longest_name = String::new();
-    let mut longest_count = 0;
+    match message {
+        Message::NodeList(nodes) => Ok(nodes),
+        _ => Err(anyhow::anyhow!("unexpected reply")),
+    }
+}
+
+// peers that don't answer in time are dropped, so they are not asked again
+pub async fn find_best_chain_node() -> Result<(String, u32)> {
+    info!("finding node with the most chain work");
 
     let all_nodes = crate::NODES
         .iter()
         .map(|x| x.key().clone())
         .collect::<Vec<_>>();
 
+    let mut candidates = vec![];
+
     for node in all_nodes {
-        println!("asking {} for blockchain length", node);
+        debug!("asking {} for chain work", node);
 
-        let mut stream = crate::NODES.get_mut(&node).context("no node")?;
+        match ask(&node, Message::AskWork).await {
+            Ok(Message::Work(work)) => {
+                debug!("received {} work from {}", work, node);
+                candidates.push((node, work));
+            }
+            Ok(e) => warn!("received unexpected message from {}: {:?}", node, e),
+            Err(e) => warn!("{:#}, dropping peer", e),
+        }
+    }
 
-        let message = Message::AskDifference(0);
-        message.send_async(&mut *stream).await.unwrap();
-        println!("sent AskDifference to {}", node);
+    candidates.sort_by_key(|(_, work)| std::cmp::Reverse(*work));
 
-        let message = Message::recv_async(&mut *stream).await?;
+    for (node, work) in candidates {
+        match ask(&node, Message::AskDifference(0)).await {
+            Ok(Message::Difference(count)) => {
+                info!("best blockchain: {} work from {}", work, node);
+                return Ok((node, count.max(0) as u32));
+            }
+            Ok(e) => warn!("received unexpected message from {}: {:?}", node, e),
+            Err(e) => warn!("{:#}, dropping peer", e),
+        }
+    }
 
-        match message {
-            Message::Difference(count) => {
-                println!("received Difference from {}", node);
+    Ok((String::new(), 0))
+}
 
-                if count > longest_count {
-                    println!(
-                        "new longest blockchain: \
-                        {} blocks from {node}",
-                        count
-                    );
+// one request and its reply; a peer that fails either is dropped
+async fn ask(node: &str, message: Message) -> Result<Message> {
+    let peer = peer(node).context("no node")?;
+    let result = async {
+        let mut stream = peer.lock().await;
+        message.send_async(&mut *stream).await?;
+        receive(node, &mut stream).await
+    }
+    .await;
 
-                    longest_name = node;
-                    longest_count = count;
-                }
-            }
+    if result.is_err() {
+        drop_peer(node, &peer);
+    }
+
+    result
+}
+
+// bounded by --connect-timeout like dialling, so a peer that goes quiet
+// can't stall a sync
+async fn receive(node: &str, stream: &mut PeerStream) -> Result<Message> {
+    let timeout = Duration::from_secs(crate::CONNECT_TIMEOUT_SECS.load(Ordering::Relaxed));
+
+    let message = time::timeout(timeout, Message::recv_async(stream))
+        .await
+        .with_context(|| format!("{} did not answer within {:?}", node, timeout))??;
+
+    Ok(message)
+}
 
+// the peer is dropped if anything goes wrong, so the caller can move on to
+// another one
+pub async fn download_blockchain(node: &str, count: u32) -> Result<()> {
+    let peer = peer(node).context("no node")?;
+    let result = download_from(node, &peer, count).await;
+
+    if result.is_err() {
+        drop_peer(node, &peer);
+    }
+
+    result
+}
+
+async fn download_from(node: &str, peer: &Peer, count: u32) -> Result<()> {
+    if count == 0 {
+        return Ok(());
+    }
+
+    let (start_height, mut prev_hash, params) = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        let tip_hash = blockchain
+            .blocks()
+            .last()
+            .map(|last_block| last_block.hash())
+            .unwrap_or(Hash::zero());
+
+        (
+            blockchain.block_height() as usize,
+            tip_hash,
+            *blockchain.params(),
+        )
+    };
+
+    let mut headers = vec![];
+    let mut stream = peer.lock().await;
+
+    while start_height + headers.len() < count as usize {
+        let message = Message::FetchHeaders {
+            start_height: start_height + headers.len(),
+            count: count as usize - start_height - headers.len(),
+        };
+        message.send_async(&mut *stream).await?;
+
+        let batch = match receive(node, &mut stream).await? {
+            Message::Headers(batch) => batch,
             e => {
-                println!("received unexpected message from {}: {:?}", node, e);
+                warn!("received unexpected message from {}: {:?}", node, e);
+                break;
             }
+        };
+
+        if batch.is_empty() {
+            break;
         }
+
+        BlockHeader::verify_chain(&params, prev_hash, &batch)?;
+        prev_hash = batch.last().unwrap().hash();
+        headers.extend(batch);
     }
 
-    Ok((longest_name, longest_count as u32))
-}
+    info!("verified {} headers from {}", headers.len(), node);
 
-pub async fn download_blockchain(node: &str, count: u32) -> Result<()> {
-    let mut stream = crate::NODES.get_mut(node).unwrap();
+    let mut downloaded = 0;
 
-    for i in 0..count as usize {
-        let message = Message::FetchBlock(i);
+    while downloaded < headers.len() {
+        let message = Message::FetchBlocks {
+            start: start_height + downloaded,
+            count: headers.len() - downloaded,
+        };
         message.send_async(&mut *stream).await?;
 
-        let message = Message::recv_async(&mut *stream).await?;
-
-        match message {
-            Message::NewBlock(block) => {
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-                blockchain.add_block(block)?;
+        let blocks = match receive(node, &mut stream).await? {
+            Message::Blocks(blocks) => blocks,
+            Message::BlockUnavailable(height) => {
+                anyhow::bail!("{} has pruned the block at height {}", node, height)
             }
+            e => anyhow::bail!("received unexpected message from {}: {:?}", node, e),
+        };
+
+        if blocks.is_empty() {
+            anyhow::bail!("{} stopped sending blocks at {}", node, downloaded);
+        }
 
-            _ => {
-                println!("received unexpected message from {}", node);
+        // nothing that holds the chain waits on this peer meanwhile
+        drop(stream);
+        let mut blockchain = crate::BLOCKCHAIN.write().await;
+
+        for block in blocks {
+            if headers
+                .get(downloaded)
+                .is_none_or(|header| header.hash() != block.hash())
+            {
+                anyhow::bail!("{} sent a block that does not match its header", node);
             }
+
+            block_added(blockchain.add_block(block))?;
+            downloaded += 1;
         }
+
+        drop(blockchain);
+        info!("downloaded {}/{} blocks", downloaded, headers.len());
+        stream = peer.lock().await;
     }
 
     Ok(())
@@ -140,19 +885,526 @@ pub async fn cleanup() {
 
     loop {
         interval.tick().await;
-        println!("cleaning the mempool from old transactions");
+        debug!("cleaning the mempool from old transactions");
         let mut blockchain = crate::BLOCKCHAIN.write().await;
         blockchain.cleanup_mempool();
+
+        let now = Instant::now();
+        crate::RECENTLY_RELAYED.retain(|_, relayed| now.duration_since(*relayed) < RELAY_WINDOW);
+    }
+}
+
+// peers that died quietly would otherwise only be noticed by the next
+// broadcast that fails to reach them
+pub async fn keepalive() {
+    let mut interval = time::interval(KEEPALIVE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let nodes = crate::NODES
+            .iter()
+            .map(|x| x.key().clone())
+            .collect::<Vec<_>>();
+
+        for node in nodes {
+            let version = crate::PEER_VERSIONS
+                .get(&node)
+                .map(|version| *version)
+                .unwrap_or(btclib::MIN_PROTOCOL_VERSION);
+
+            // older peers would take a Ping as a protocol violation
+            if version < btclib::PING_PROTOCOL_VERSION {
+                continue;
+            }
+
+            let Some(peer) = peer(&node) else {
+                continue;
+            };
+
+            // a dead peer can take the whole timeout to give up on, but only
+            // its own lock is held meanwhile
+            let result = ping(&mut *peer.lock().await).await;
+
+            if let Err(e) = result {
+                warn!("{} failed keepalive: {:#}, dropping peer", node, e);
+                drop_peer(&node, &peer);
+            }
+        }
+    }
+}
+
+async fn ping(stream: &mut PeerStream) -> Result<()> {
+    let nonce = Uuid::new_v4().as_u64_pair().0;
+    Message::Ping(nonce).send_async(&mut *stream).await?;
+
+    match time::timeout(PONG_TIMEOUT, Message::recv_async(&mut *stream))
+        .await
+        .context("no pong in time")??
+    {
+        Message::Pong(echoed) if echoed == nonce => Ok(()),
+        e => anyhow::bail!("expected Pong({}), got {:?}", nonce, e),
+    }
+}
+
+// keeps the bodies of the last `depth` blocks
+pub async fn prune(depth: u64) {
+    let mut interval = time::interval(PRUNE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let mut blockchain = crate::BLOCKCHAIN.write().await;
+        let height = blockchain.block_height().saturating_sub(depth);
+
+        if height > blockchain.pruned_height() {
+            blockchain.prune_below(height);
+            info!("pruned block bodies below height {}", height);
+        }
     }
 }
 
-pub async fn save(name: String) {
-    let mut interval = time::interval(time::Duration::from_secs(15));
+// blocks are in the journal already, so this saves everything else
+pub async fn flush(name: &Path) -> Result<()> {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    blockchain.save_mempool_to_file(mempool_file(name))?;
+    save_peers(&peers_file(name))?;
+    Ok(())
+}
+
+// writes the whole chain and a snapshot of its utxos, and starts the
+// journal over. Holding the chain for writing means no block can arrive
+// in between and be lost
+pub async fn compact(name: &Path) -> Result<()> {
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
+    blockchain.save_to_file(name)?;
+    blockchain.export_utxo_snapshot_to_file(snapshot_file(name))?;
+
+    let journal = BlockJournal::new(journal_file(name));
+    journal.clear()?;
+    blockchain.enable_journal(journal);
+    Ok(())
+}
+
+pub async fn save(name: PathBuf) {
+    let mut interval = time::interval(SAVE_INTERVAL);
+    let mut last_compaction = Instant::now();
 
     loop {
         interval.tick().await;
-        println!("saving blockchain");
+
+        // add_block turns the journal off if appending to it fails
+        let journaling = crate::BLOCKCHAIN.read().await.journal().is_some();
+
+        if !journaling || last_compaction.elapsed() >= COMPACT_INTERVAL {
+            debug!("saving blockchain");
+
+            // the files on disk are still the last good save, so try again later
+            match compact(&name).await {
+                Ok(()) => last_compaction = Instant::now(),
+                Err(e) => error!("failed to save blockchain: {:#}", e),
+            }
+        }
+
+        if let Err(e) = flush(&name).await {
+            error!("failed to save node state: {:#}", e);
+        }
+    }
+}
+
+pub async fn shutdown(name: &Path) -> Result<()> {
+    info!("shutting down, saving blockchain");
+    compact(name).await?;
+    flush(name).await?;
+
+    info!("closing {} peer connections", crate::NODES.len());
+    crate::NODES.clear();
+
+    Ok(())
+}
+
+pub async fn reconnect() {
+    let mut interval = time::interval(RECONNECT_INTERVAL);
+    let mut backoff: HashMap<String, (Duration, Instant)> = HashMap::new();
+
+    loop {
+        interval.tick().await;
+
+        let disconnected = crate::KNOWN_NODES
+            .iter()
+            .map(|x| x.key().clone())
+            .filter(|node| !crate::NODES.contains_key(node))
+            .collect::<Vec<_>>();
+
+        backoff.retain(|node, _| disconnected.contains(node));
+
+        for node in disconnected {
+            if let Some((_, retry_at)) = backoff.get(&node)
+                && Instant::now() < *retry_at
+            {
+                continue;
+            }
+
+            info!("reconnecting to {}", node);
+
+            match connect(&node).await {
+                Ok(stream) => {
+                    info!("reconnected to {}", node);
+                    backoff.remove(&node);
+                    add_peer(node, stream);
+                }
+
+                Err(e) => {
+                    let delay = backoff
+                        .get(&node)
+                        .map(|(delay, _)| (*delay * 2).min(MAX_RECONNECT_BACKOFF))
+                        .unwrap_or(RECONNECT_INTERVAL);
+
+                    warn!(
+                        "failed to reconnect to {}: {}, retrying in {:?}",
+                        node, e, delay
+                    );
+                    backoff.insert(node, (delay, Instant::now() + delay));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use btclib::crypto::PrivateKey;
+    use dashmap::DashMap;
+
+    #[tokio::test]
+    async fn a_silent_peer_is_skipped_while_finding_the_best_chain() {
+        let _serial = serial().await;
+        install_chain(regtest_chain(&PrivateKey::new_key(), 2)).await;
+        crate::CONNECT_TIMEOUT_SECS.store(1, Ordering::Relaxed);
+
+        add_peer("silent".to_string(), silent_peer().await);
+        add_peer("live".to_string(), connect_handler(1000).await);
+
+        let started = Instant::now();
+        let (best, count) = find_best_chain_node().await.unwrap();
+
+        assert_eq!((best.as_str(), count), ("live", 3));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!crate::NODES.contains_key("silent"));
+
+        // and downloading from it gives up instead of waiting forever
+        add_peer("silent".to_string(), silent_peer().await);
+        assert!(download_blockchain("silent", 5).await.is_err());
+        assert!(!crate::NODES.contains_key("silent"));
+    }
+
+    #[tokio::test]
+    async fn a_chain_is_synced_headers_first_from_a_peer() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        // more blocks than one message carries
+        let chain = regtest_chain(&key, btclib::MAX_BLOCKS_PER_MESSAGE + 10);
+        let hashes = chain.blocks().map(|block| block.hash()).collect::<Vec<_>>();
+
+        // a new node has the genesis and nothing else
+        install_chain(regtest_chain(&key, 0)).await;
+        add_peer("seed".to_string(), serving_peer(chain).await);
+
+        download_blockchain("seed", hashes.len() as u32)
+            .await
+            .unwrap();
+
+        // each block connected onto the header hash it was verified by
+        let synced = crate::BLOCKCHAIN.read().await;
+        let synced_hashes = synced
+            .blocks()
+            .map(|block| block.hash())
+            .collect::<Vec<_>>();
+        assert_eq!(synced_hashes, hashes);
+        for (parent, block) in synced.blocks().zip(synced.blocks().skip(1)) {
+            assert_eq!(block.header.prev_block_hash, parent.header.hash());
+        }
+    }
+
+    #[test]
+    fn the_template_packs_the_best_paying_transactions() {
+        let key = PrivateKey::new_key();
+        let count = btclib::BLOCK_TRANSACTION_CAP + 1;
+        let mut chain = regtest_chain(&key, count + btclib::COINBASE_MATURITY as usize);
+        let reward = chain.calculate_block_reward();
+
+        // cheapest first, so taking them as they came would pick the wrong ones
+        for i in 0..count {
+            let fee = (i as u64 + 1) * 1000;
+            let tx = spend(&coinbase_output(&chain, i + 1), &key, reward - fee);
+            chain.add_to_mempool(tx).unwrap();
+        }
+
+        let template = block_template(&chain, key.public_key());
+        let fees = template.transactions[1..]
+            .iter()
+            .map(|tx| chain.calculate_fees(std::slice::from_ref(tx)))
+            .collect::<Vec<_>>();
+
+        // the cheapest one is left for a later block
+        let expected = (2..=count as u64)
+            .rev()
+            .map(|i| i * 1000)
+            .collect::<Vec<_>>();
+        assert_eq!(fees, expected);
+    }
+
+    #[tokio::test]
+    async fn a_peer_that_hangs_up_is_dropped_by_the_next_broadcasts() {
+        let _serial = serial().await;
+        install_chain(regtest_chain(&PrivateKey::new_key(), 1)).await;
+
+        add_peer("gone".to_string(), vanishing_peer().await);
+        add_peer("live".to_string(), connect_handler(1000).await);
+
+        // the first write after the hang-up may still be buffered; the
+        // reset it provokes fails the ones after it
+        for nonce in 0..10 {
+            if !crate::NODES.contains_key("gone") {
+                break;
+            }
+            broadcast(&Message::Ping(nonce)).await;
+            time::sleep(Duration::from_millis(50)).await;
+        }
+
+        assert!(!crate::NODES.contains_key("gone"));
+        assert!(crate::NODES.contains_key("live"));
+        // still remembered, for the reconnection task to dial again
+        assert!(crate::KNOWN_NODES.contains_key("gone"));
+    }
+
+    #[tokio::test]
+    async fn shutting_down_leaves_the_chain_on_disk_as_it_was_in_memory() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let mut chain = regtest_chain(&key, btclib::COINBASE_MATURITY as usize);
+        let prev = coinbase_output(&chain, 1);
+        let tx = spend(&prev, &key, prev.value - 1000);
+        chain.add_to_mempool(tx.clone()).unwrap();
+        let (height, tip) = (chain.block_height(), tip_hash(&chain));
+        install_chain(chain).await;
+        add_peer("peer".to_string(), connect_handler(1000).await);
+
+        let dir = std::env::temp_dir().join(format!("rsbtc-node-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let blockchain_file = dir.join("blockchain.cbor");
+        shutdown(&blockchain_file).await.unwrap();
+
+        let mut saved = BlockChain::load_from_file(&blockchain_file).unwrap();
+        assert_eq!((saved.block_height(), tip_hash(&saved)), (height, tip));
+        let mut utxos = saved.utxos().keys().collect::<Vec<_>>();
         let blockchain = crate::BLOCKCHAIN.read().await;
-        blockchain.save_to_file(name.clone()).unwrap();
+        let mut expected = blockchain.utxos().keys().collect::<Vec<_>>();
+        utxos.sort_by_key(|hash| hash.to_string());
+        expected.sort_by_key(|hash| hash.to_string());
+        assert_eq!(utxos, expected);
+
+        assert_eq!(
+            saved
+                .load_mempool_from_file(mempool_file(&blockchain_file))
+                .unwrap(),
+            0
+        );
+        let mempool = saved
+            .mempool()
+            .iter()
+            .map(|(_, tx)| tx.hash())
+            .collect::<Vec<_>>();
+        assert_eq!(mempool, vec![tx.hash()]);
+
+        assert!(crate::NODES.is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn nodes_with_separate_datadirs_keep_separate_chains() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let root = std::env::temp_dir().join(format!("rsbtc-node-{}", Uuid::new_v4()));
+        let first = data_dir(&root.join("first"), Network::Regtest).unwrap();
+        let second = data_dir(&root.join("second"), Network::Regtest).unwrap();
+
+        // each node saves a chain of its own length
+        let mut tips = vec![];
+        for (dir, count) in [(&first, 3), (&second, 1)] {
+            let chain = regtest_chain(&key, count);
+            tips.push((chain.block_height(), tip_hash(&chain)));
+            install_chain(chain).await;
+            shutdown(&dir.join("blockchain.cbor")).await.unwrap();
+        }
+
+        // and gets that one back, whichever saved last
+        for (dir, expected) in [(&first, tips[0]), (&second, tips[1])] {
+            load_blockchain(&dir.join("blockchain.cbor"), Network::Regtest, None)
+                .await
+                .unwrap();
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            assert_eq!((blockchain.block_height(), tip_hash(&blockchain)), expected);
+        }
+
+        // another network under the same datadir gets a folder of its own
+        let test = data_dir(&root.join("first"), Network::Test).unwrap();
+        assert_ne!(test, first);
+        assert!(!test.join("blockchain.cbor").exists());
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn saved_peers_load_back_most_recent_first_up_to_the_cap() {
+        let _serial = serial().await;
+        let dir = std::env::temp_dir().join(format!("rsbtc-node-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = peers_file(&dir.join("blockchain.cbor"));
+
+        // one more than is kept, each seen a minute after the last
+        let start = Utc::now();
+        for i in 0..=MAX_SAVED_PEERS {
+            let last_seen = start + chrono::Duration::minutes(i as i64);
+            crate::KNOWN_NODES.insert(format!("127.0.0.1:{}", 10000 + i), last_seen);
+        }
+        let expected = crate::KNOWN_NODES
+            .iter()
+            .filter(|entry| entry.key() != "127.0.0.1:10000")
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect::<HashMap<_, _>>();
+
+        save_peers(&path).unwrap();
+        crate::KNOWN_NODES.clear();
+        assert_eq!(load_peers(&path).unwrap(), MAX_SAVED_PEERS);
+
+        // the oldest is the one left out
+        let loaded = crate::KNOWN_NODES
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(loaded, expected);
+
+        // loading over a newer sighting keeps the newer one
+        let newest = start + chrono::Duration::days(1);
+        crate::KNOWN_NODES.insert("127.0.0.1:10001".to_string(), newest);
+        load_peers(&path).unwrap();
+        assert_eq!(*crate::KNOWN_NODES.get("127.0.0.1:10001").unwrap(), newest);
+
+        // and a node that never saved starts with none
+        assert_eq!(load_peers(&dir.join("missing.json")).unwrap(), 0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // a peer that asks for every body it is announced, counting the
+    // announcements of each hash
+    async fn counting_peer() -> (PeerStream, Arc<DashMap<Hash, usize>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let announced = Arc::new(DashMap::new());
+        let counts = announced.clone();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = PeerStream::Plain(socket);
+            Message::accept_handshake_async(&mut stream, "mock", 0)
+                .await
+                .unwrap();
+
+            while let Ok(message) = Message::recv_async(&mut stream).await {
+                if let Message::Inv(hashes) = message {
+                    for hash in &hashes {
+                        *counts.entry(*hash).or_insert(0) += 1;
+                    }
+                    Message::GetData(hashes)
+                        .send_async(&mut stream)
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        let mut stream = PeerStream::Plain(TcpStream::connect(address).await.unwrap());
+        Message::handshake_async(&mut stream, "test", 0)
+            .await
+            .unwrap();
+        (stream, announced)
+    }
+
+    #[tokio::test]
+    async fn a_transaction_coming_back_around_a_loop_is_relayed_once() {
+        let _serial = serial().await;
+        let key = PrivateKey::new_key();
+        let chain = regtest_chain(&key, btclib::COINBASE_MATURITY as usize);
+        let prev = coinbase_output(&chain, 1);
+        install_chain(chain).await;
+
+        let mut peers = vec![];
+        for name in ["b", "c"] {
+            let (stream, announced) = counting_peer().await;
+            add_peer(name.to_string(), stream);
+            crate::PEER_VERSIONS.insert(name.to_string(), btclib::PROTOCOL_VERSION);
+            peers.push(announced);
+        }
+
+        // a wallet submits it, then b and c each pass it back to us as a
+        // loop of peers would
+        let tx = spend(&prev, &key, prev.value - 1000);
+        let mut wallet = connect_handler(1000).await;
+        let Message::SubmitTransactionResult { accepted, .. } =
+            request(&mut wallet, Message::SubmitTransaction(tx.clone())).await
+        else {
+            panic!("expected a submit result");
+        };
+        assert!(accepted);
+
+        // forgetting what each peer was sent leaves only the node's own
+        // record of what it relayed
+        crate::PEER_INVENTORY.clear();
+
+        for _ in 0..2 {
+            let mut peer = connect_handler(1000).await;
+            Message::NewTransaction(tx.clone())
+                .send_async(&mut peer)
+                .await
+                .unwrap();
+            // handled in order, so the transaction was dealt with first
+            request(&mut peer, Message::ChainInfo).await;
+        }
+        relay_transactions(std::slice::from_ref(&tx)).await;
+        time::sleep(Duration::from_millis(100)).await;
+
+        for announced in peers {
+            assert_eq!(announced.get(&tx.hash()).map(|count| *count), Some(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_peer_that_never_pongs_is_evicted() {
+        let _serial = serial().await;
+        install_chain(regtest_chain(&PrivateKey::new_key(), 1)).await;
+
+        for (name, stream) in [
+            ("silent", silent_peer().await),
+            ("live", connect_handler(1000).await),
+        ] {
+            add_peer(name.to_string(), stream);
+            crate::PEER_VERSIONS.insert(name.to_string(), btclib::PROTOCOL_VERSION);
+        }
+
+        // the first round starts straight away
+        let started = Instant::now();
+        let keepalive = tokio::spawn(keepalive());
+        while crate::NODES.contains_key("silent") {
+            assert!(started.elapsed() < PONG_TIMEOUT * 2, "never evicted");
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        keepalive.abort();
+
+        assert!(started.elapsed() >= PONG_TIMEOUT);
+        assert!(crate::NODES.contains_key("live"));
     }
 }
@@ -0,0 +1,17 @@
+use std::process::{Command, Stdio};
+
+#[test]
+fn a_shallow_prune_depth_is_refused_before_anything_is_written() {
+    let dir = std::env::temp_dir().join(format!("rsbtc-node-{}", uuid::Uuid::new_v4()));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_node"))
+        .args(["--network", "regtest", "--prune", "10", "--datadir"])
+        .arg(&dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+
+    assert!(!status.success());
+    assert!(!dir.exists());
+}
@@ -0,0 +1,92 @@
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::time::{self, Duration};
+
+// MAX_CONNECTIONS_PER_IP in main.rs
+const PER_IP: usize = 8;
+
+// kills the node even when an assertion fails
+struct Node(Child);
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+async fn connect_from(ip: [u8; 4], address: SocketAddr) -> std::io::Result<TcpStream> {
+    let socket = TcpSocket::new_v4()?;
+    socket.bind(SocketAddr::from((ip, 0)))?;
+    socket.connect(address).await
+}
+
+// whether the node hangs up within `wait`, rather than waiting for a
+// handshake
+async fn closed_within(stream: &mut TcpStream, wait: Duration) -> bool {
+    let mut buf = [0; 1];
+    matches!(
+        time::timeout(wait, stream.read(&mut buf)).await,
+        Ok(Ok(0) | Err(_))
+    )
+}
+
+#[tokio::test]
+async fn connections_past_the_per_ip_cap_are_closed() {
+    let dir = std::env::temp_dir().join(format!("rsbtc-node-{}", uuid::Uuid::new_v4()));
+    let port = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+
+    let node = Node(
+        Command::new(env!("CARGO_BIN_EXE_node"))
+            .args([
+                "--network",
+                "regtest",
+                "--port",
+                &port.to_string(),
+                "--datadir",
+            ])
+            .arg(&dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+
+    // wait for it to listen, from an address the test does not count on
+    let mut probe = None;
+    for _ in 0..100 {
+        if let Ok(stream) = connect_from([127, 0, 0, 2], address).await {
+            probe = Some(stream);
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(probe.is_some(), "the node never started listening");
+
+    let mut allowed = vec![];
+    for _ in 0..PER_IP {
+        allowed.push(connect_from([127, 0, 0, 1], address).await.unwrap());
+    }
+
+    // accepted by the kernel, then dropped by the node straight away
+    let mut excess = connect_from([127, 0, 0, 1], address).await.unwrap();
+    assert!(closed_within(&mut excess, Duration::from_secs(2)).await);
+
+    // the ones within the cap are still waiting for their handshakes
+    for stream in &mut allowed {
+        assert!(!closed_within(stream, Duration::from_millis(100)).await);
+    }
+
+    // and another address has a cap of its own
+    let mut other = connect_from([127, 0, 0, 3], address).await.unwrap();
+    assert!(!closed_within(&mut other, Duration::from_millis(100)).await);
+
+    drop(node);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,92 @@
+use btclib::crypto::PrivateKey;
+use btclib::util::Savable;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// kills the node even when an assertion fails
+struct Node(Child);
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn height(rpc_port: u16) -> Option<u64> {
+    let mut stream = TcpStream::connect(("127.0.0.1", rpc_port)).ok()?;
+    write!(
+        stream,
+        "GET /height HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n"
+    )
+    .ok()?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    let (_, body) = response.split_once("\r\n\r\n")?;
+    let body: serde_json::Value = serde_json::from_str(body).ok()?;
+    body["height"].as_u64()
+}
+
+fn start_miner(dir: &Path, rpc_port: u16) -> Node {
+    let key = dir.join("miner.pub.pem");
+    PrivateKey::new_key()
+        .public_key()
+        .save_to_file(&key)
+        .unwrap();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_node"))
+        .args(["--network", "regtest", "--port"])
+        .arg(free_port().to_string())
+        .arg("--rpc-port")
+        .arg(rpc_port.to_string())
+        .arg("--datadir")
+        .arg(dir)
+        .arg("--mine")
+        .arg(&key)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    Node(child)
+}
+
+#[test]
+fn a_mining_node_grows_its_own_chain() {
+    let dir = std::env::temp_dir().join(format!("rsbtc-node-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&dir).unwrap();
+
+    let rpc_port = free_port();
+    let _node = start_miner(&dir, rpc_port);
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let mut first = None;
+
+    // a couple of blocks past whatever the node started with
+    loop {
+        if let Some(height) = height(rpc_port) {
+            let start = *first.get_or_insert(height);
+            if height >= start + 2 {
+                break;
+            }
+        }
+
+        assert!(Instant::now() < deadline, "the chain did not grow");
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -1,17 +1,25 @@
 use anyhow::Result;
-use btclib::crypto::{PrivateKey, PublicKey};
+use btclib::DUST_THRESHOLD;
+use btclib::crypto::{PrivateKey, PublicKey, Signature};
 use btclib::network::Message;
-use btclib::types::{Transaction, TransactionOutput};
+use btclib::types::{Block, Transaction, TransactionInput, TransactionOutput, fee_rate};
 use btclib::util::Savable;
 use crossbeam_skiplist::SkipMap;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
+use tokio::time::{self, Duration, Instant};
 use tracing::*;
 
+use crate::history::History;
+
+const USER_AGENT: &str = concat!("rsbtc-wallet/", env!("CARGO_PKG_VERSION"));
+const NODE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Key {
     pub public: PathBuf,
@@ -27,7 +35,12 @@ struct LoadedKey {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Recipient {
     pub name: String,
+    // outputs pay to the whole public key, which an address only hashes,
+    // so a contact always needs its key file
     pub key: PathBuf,
+    // checked against the key when it is loaded, to catch the wrong file
+    #[serde(default, alias = "address", skip_serializing_if = "Option::is_none")]
+    pub expected_address: Option<String>,
 }
 
 #[derive(Clone)]
@@ -39,6 +52,17 @@ pub struct LoadedRecipient {
 impl Recipient {
     pub fn load(&self) -> Result<LoadedRecipient> {
         let key = PublicKey::load_from_file(&self.key)?;
+
+        if let Some(address) = &self.expected_address
+            && !key.matches_address(address)
+        {
+            return Err(anyhow::anyhow!(
+                "Address {} does not match key for {}",
+                address,
+                self.name
+            ));
+        }
+
         Ok(LoadedRecipient {
             name: self.name.clone(),
             key,
@@ -50,6 +74,7 @@ impl Recipient {
 pub enum FeeType {
     Fixed,
     Percent,
+    FixedPerByte,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -62,53 +87,286 @@ pub struct FeeConfig {
 pub struct Config {
     pub my_keys: Vec<Key>,
     pub contacts: Vec<Recipient>,
-    pub default_node: String,
+    // tried in order until one answers; older configs name a single
+    // `default_node`, which is read as a list of one
+    #[serde(alias = "default_node", deserialize_with = "one_or_more")]
+    pub nodes: Vec<String>,
     pub fee_config: FeeConfig,
+    #[serde(default = "default_refresh_interval")]
+    pub refresh_interval_secs: u64,
+    #[serde(default = "default_max_rebroadcasts")]
+    pub max_rebroadcasts: u32,
+    #[serde(default)]
+    pub log: LogConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Daily,
+    Hourly,
+    Never,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub rotation: LogRotation,
+    pub dir: PathBuf,
+    // the oldest files beyond this are deleted on rotation; 0 keeps them all
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::Info,
+            rotation: LogRotation::Daily,
+            dir: PathBuf::from("logs"),
+            max_files: 7,
+        }
+    }
+}
+
+impl LogConfig {
+    // logging starts before the rest of the config is loaded and checked, so
+    // this reads only the [log] table and falls back to the defaults if the
+    // file is missing or broken; Core::load reports the actual problem
+    pub fn load(config_path: &PathBuf) -> Self {
+        #[derive(Deserialize)]
+        struct LogSection {
+            #[serde(default)]
+            log: LogConfig,
+        }
+
+        fs::read_to_string(config_path)
+            .ok()
+            .and_then(|config| toml::from_str::<LogSection>(&config).ok())
+            .map(|section| section.log)
+            .unwrap_or_default()
+    }
+}
+
+fn default_refresh_interval() -> u64 {
+    20
+}
+
+fn default_max_rebroadcasts() -> u32 {
+    5
+}
+
+struct SentTransaction {
+    transaction: Transaction,
+    sent_at: Instant,
+    rebroadcasts: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SentState {
+    // waiting in the node's mempool
+    Pending,
+    // neither in the mempool nor in a block: its inputs are still unspent
+    Dropped,
+    // its inputs are spent, by this transaction or by a conflicting one
+    Settled,
+}
+
+pub fn sent_state(in_mempool: bool, any_input_unspent: bool) -> SentState {
+    match (in_mempool, any_input_unspent) {
+        (true, _) => SentState::Pending,
+        (false, true) => SentState::Dropped,
+        (false, false) => SentState::Settled,
+    }
+}
+
+fn one_or_more<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore {
+        One(String),
+        More(Vec<String>),
+    }
+
+    Ok(match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(node) => vec![node],
+        OneOrMore::More(nodes) => nodes,
+    })
+}
+
+async fn connect_to(node: &str) -> Result<TcpStream> {
+    time::timeout(NODE_TIMEOUT, async {
+        let mut stream = TcpStream::connect(node).await?;
+        Message::handshake_async(&mut stream, USER_AGENT, 0).await?;
+        Ok(stream)
+    })
+    .await?
+}
+
+pub async fn connect_first(nodes: &[String]) -> Result<(String, TcpStream)> {
+    first_reachable(nodes, |node| async move { connect_to(&node).await }).await
+}
+
+// the first node in the list that `connect` succeeds on; only an error if
+// none of them do
+async fn first_reachable<T, F, Fut>(nodes: &[String], mut connect: F) -> Result<(String, T)>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut errors = vec![];
+
+    for node in nodes {
+        match connect(node.clone()).await {
+            Ok(stream) => return Ok((node.clone(), stream)),
+            Err(e) => {
+                warn!("Node {} is unreachable: {}", node, e);
+                errors.push(format!("{}: {}", node, e));
+            }
+        }
+    }
+
+    Err(anyhow::anyhow!(
+        "No node is reachable ({})",
+        errors.join("; ")
+    ))
+}
+
+async fn exchange(stream: &mut TcpStream, message: &Message) -> Result<Message> {
+    time::timeout(NODE_TIMEOUT, async {
+        message.send_async(&mut *stream).await?;
+        Ok(Message::recv_async(&mut *stream).await?)
+    })
+    .await?
 }
 
 #[derive(Clone)]
 struct UtxoStore {
-    my_keys: Vec<LoadedKey>,
+    my_keys: Arc<RwLock<Vec<LoadedKey>>>,
     utxos: Arc<SkipMap<PublicKey, Vec<(bool, TransactionOutput)>>>,
+    pending: Arc<RwLock<Vec<Transaction>>>,
 }
 
 impl UtxoStore {
     fn new() -> Self {
         Self {
-            my_keys: Vec::new(),
+            my_keys: Arc::new(RwLock::new(Vec::new())),
             utxos: Arc::new(SkipMap::new()),
+            pending: Arc::new(RwLock::new(Vec::new())),
         }
     }
 
-    fn add_key(&mut self, key: LoadedKey) {
-        self.my_keys.push(key);
+    fn add_key(&self, key: LoadedKey) {
+        self.my_keys.write().unwrap().push(key);
+    }
+
+    fn public_keys(&self) -> Vec<PublicKey> {
+        self.my_keys
+            .read()
+            .unwrap()
+            .iter()
+            .map(|key| key.public.clone())
+            .collect()
+    }
+}
+
+// what a send would spend and pay; building one sends nothing
+pub struct TransactionPlan {
+    // our outputs that it spends
+    pub inputs: Vec<TransactionOutput>,
+    // zero when there was none, or it was dust and went to the fee
+    pub change: u64,
+    pub fee: u64,
+    // signed and ready to send, with the payments and change as its outputs
+    pub transaction: Transaction,
+}
+
+pub struct PreparedTransaction {
+    pub plan: TransactionPlan,
+    // recipient names, in output order
+    pub payments: Vec<(String, u64)>,
+}
+
+impl PreparedTransaction {
+    pub fn total(&self) -> u64 {
+        self.payments.iter().map(|(_, amount)| amount).sum()
     }
 }
 
 pub struct Core {
     pub config: Config,
+    config_path: PathBuf,
+    // moved out of `config` so the address book can change at runtime
+    contacts: RwLock<Vec<Recipient>>,
+    pub history: History,
     utxos: UtxoStore,
+    sent: RwLock<Vec<SentTransaction>>,
     pub tx_sender: kanal::Sender<Transaction>,
-    pub stream: Mutex<TcpStream>,
+    // the node in use and the connection to it
+    connection: Mutex<Option<(String, TcpStream)>>,
 }
 
 impl Core {
-    fn new(config: Config, utxos: UtxoStore, steam: TcpStream) -> Self {
+    fn new(
+        mut config: Config,
+        config_path: PathBuf,
+        history: History,
+        utxos: UtxoStore,
+        connection: Option<(String, TcpStream)>,
+    ) -> Self {
         let (tx_sender, _) = kanal::bounded(10);
+        let contacts = std::mem::take(&mut config.contacts);
 
         Self {
             config,
+            config_path,
+            contacts: RwLock::new(contacts),
+            history,
             utxos,
+            sent: RwLock::new(vec![]),
             tx_sender,
-            stream: Mutex::new(steam),
+            connection: Mutex::new(connection),
         }
     }
 
-    pub async fn load(config_path: PathBuf) -> Result<Self> {
+    pub async fn load(config_path: PathBuf, node: Option<String>) -> Result<Self> {
         info!("Loading config from config: {:?}", config_path);
-        let config: Config = toml::from_str(&fs::read_to_string(&config_path)?)?;
-        let mut utxos = UtxoStore::new();
-        let stream = TcpStream::connect(&config.default_node).await?;
+        let mut config: Config = toml::from_str(&fs::read_to_string(&config_path)?)?;
+
+        if let Some(node) = node {
+            info!("Trying node {:?} first", node);
+            config.nodes.retain(|known| *known != node);
+            config.nodes.insert(0, node);
+        }
+
+        let utxos = UtxoStore::new();
+        let connection = connect_first(&config.nodes).await?;
+        info!("Connected to node {}", connection.0);
 
         for key in &config.my_keys {
             let public = PublicKey::load_from_file(&key.public)?;
@@ -117,23 +375,52 @@ impl Core {
             utxos.add_key(LoadedKey { public, private });
         }
 
-        Ok(Self::new(config, utxos, stream))
+        let history = History::load(config_path.with_file_name("history.json"))?;
+
+        Ok(Self::new(
+            config,
+            config_path,
+            history,
+            utxos,
+            Some(connection),
+        ))
+    }
+
+    // a failed exchange drops the connection and is retried once on the
+    // first node that answers
+    async fn request(&self, message: Message) -> Result<Message> {
+        let mut connection = self.connection.lock().await;
+
+        if let Some((node, stream)) = connection.as_mut() {
+            match exchange(stream, &message).await {
+                Ok(response) => return Ok(response),
+                Err(e) => warn!("Request to node {} failed: {}, failing over", node, e),
+            }
+        }
+
+        *connection = None;
+
+        let (node, mut stream) = connect_first(&self.config.nodes).await?;
+        info!("Connected to node {}", node);
+
+        let response = exchange(&mut stream, &message).await?;
+        *connection = Some((node, stream));
+        Ok(response)
     }
 
     pub async fn fetch_utxos(&self) -> Result<()> {
-        debug!("Fetching UTXOs from node: {}", self.config.default_node);
+        debug!("Fetching UTXOs");
 
-        for key in &self.utxos.my_keys {
-            let message = Message::FetchUTXOs(key.public.clone());
-            message.send_async(&mut *self.stream.lock().await).await?;
+        for public in self.utxos.public_keys() {
+            let message = Message::FetchUTXOs(public.clone());
 
-            if let Message::UTXOs(utxos) =
-                Message::recv_async(&mut *self.stream.lock().await).await?
-            {
-                debug!("Received {} UTXOs for key: {:?}", utxos.len(), key.public);
+            if let Message::UTXOs(utxos) = self.request(message).await? {
+                debug!("Received {} UTXOs for key: {:?}", utxos.len(), public);
+                self.history
+                    .record_received(utxos.iter().map(|(output, _)| output));
 
                 self.utxos.utxos.insert(
-                    key.public.clone(),
+                    public,
                     utxos
                         .into_iter()
                         .map(|(output, marked)| (marked, output))
@@ -148,36 +435,314 @@ impl Core {
         Ok(())
     }
 
+    pub async fn fetch_mempool(&self) -> Result<()> {
+        debug!("Fetching mempool");
+
+        let mut pending: Vec<Transaction> = vec![];
+
+        for public in self.utxos.public_keys() {
+            let message = Message::FetchMempool(Some(public));
+
+            match self.request(message).await? {
+                Message::MempoolResponse(transactions) => {
+                    for transaction in transactions {
+                        if !pending.iter().any(|tx| tx.hash() == transaction.hash()) {
+                            pending.push(transaction);
+                        }
+                    }
+                }
+
+                _ => {
+                    error!("Unexpected response from node");
+                    return Err(anyhow::anyhow!("Unexpected response from node"));
+                }
+            }
+        }
+
+        debug!("{} pending transactions", pending.len());
+        *self.utxos.pending.write().unwrap() = pending;
+        Ok(())
+    }
+
     pub async fn send_transaction(&self, transaction: Transaction) -> Result<()> {
-        debug!("Sending transaction to node: {}", self.config.default_node);
+        debug!("Sending transaction to node");
 
         let message = Message::SubmitTransaction(transaction);
-        message.send_async(&mut *self.stream.lock().await).await?;
 
-        info!("Transaction sent successfully");
-        Ok(())
+        match self.request(message).await? {
+            Message::SubmitTransactionResult { accepted: true, .. } => {
+                info!("Transaction accepted by node");
+                Ok(())
+            }
+
+            Message::SubmitTransactionResult { reason, .. } => {
+                let reason = reason.unwrap_or_else(|| "no reason given".to_string());
+                warn!("Transaction rejected by node: {}", reason);
+                Err(anyhow::anyhow!("Transaction rejected: {}", reason))
+            }
+
+            _ => {
+                error!("Unexpected response from node");
+                Err(anyhow::anyhow!("Unexpected response from node"))
+            }
+        }
     }
 
-    pub fn send_transaction_async(&self, recipient: &str, amount: u64) -> Result<()> {
-        info!("Preparing to send {} satoshis to {}", amount, recipient);
+    // each payment is a contact name or address and an amount; they all go
+    // into one transaction
+    // builds and signs, but sends nothing, so the result can be shown to
+    // the user first
+    pub fn prepare_transaction(&self, payments: &[(String, u64)]) -> Result<PreparedTransaction> {
+        if payments.is_empty() {
+            return Err(anyhow::anyhow!("No recipients given"));
+        }
+
+        let mut outputs = vec![];
+        let mut named_payments = vec![];
+
+        for (recipient, amount) in payments {
+            info!("Preparing to send {} satoshis to {}", amount, recipient);
+
+            let recipient = self.find_recipient(recipient)?;
+            debug!("Loaded key for recipient {}", recipient.name);
+
+            outputs.push((recipient.key, *amount));
+            named_payments.push((recipient.name, *amount));
+        }
+
+        Ok(PreparedTransaction {
+            plan: self.build_transaction(&outputs)?,
+            payments: named_payments,
+        })
+    }
 
-        let recipient_key = self
-            .config
-            .contacts
+    pub fn send_transaction_async(&self, prepared: PreparedTransaction) -> Result<()> {
+        let names = prepared
+            .payments
             .iter()
-            .find(|r| r.name == recipient)
-            .ok_or_else(|| anyhow::anyhow!("Recipient not found"))?
-            .load()?
-            .key;
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
 
-        let transaction = self.create_transaction(&recipient_key, amount)?;
+        self.history.record_sent(
+            &prepared.plan.transaction,
+            &names.join(", "),
+            prepared.total(),
+        );
         debug!("Sending transaction asynchronously");
 
-        self.tx_sender.send(transaction)?;
+        self.sent.write().unwrap().push(SentTransaction {
+            transaction: prepared.plan.transaction.clone(),
+            sent_at: Instant::now(),
+            rebroadcasts: 0,
+        });
+        self.tx_sender.send(prepared.plan.transaction)?;
+
+        Ok(())
+    }
+
+    // resubmits sent transactions that fell out of the node's mempool
+    // without being confirmed, up to max_rebroadcasts times each
+    pub async fn rebroadcast(&self) -> Result<()> {
+        if self.sent.read().unwrap().is_empty() {
+            return Ok(());
+        }
+
+        let mempool = match self.request(Message::FetchMempool(None)).await? {
+            Message::MempoolResponse(transactions) => transactions
+                .iter()
+                .map(|tx| tx.hash())
+                .collect::<HashSet<_>>(),
+            _ => return Err(anyhow::anyhow!("Unexpected response from node")),
+        };
+
+        // the first submission may still be queued
+        let grace = Duration::from_secs(self.config.refresh_interval_secs.max(1));
+        let sent = std::mem::take(&mut *self.sent.write().unwrap());
+        let mut keep = vec![];
+
+        for mut entry in sent {
+            let hash = entry.transaction.hash();
+
+            if entry.sent_at.elapsed() < grace {
+                keep.push(entry);
+                continue;
+            }
+
+            let any_input_unspent = match self.any_input_unspent(&entry.transaction).await {
+                Ok(any_input_unspent) => any_input_unspent,
+                Err(e) => {
+                    warn!("Could not check transaction {}: {}", hash, e);
+                    keep.push(entry);
+                    continue;
+                }
+            };
+
+            match sent_state(mempool.contains(&hash), any_input_unspent) {
+                SentState::Pending => keep.push(entry),
+
+                SentState::Settled => info!("Transaction {} settled", hash),
+
+                SentState::Dropped if entry.rebroadcasts >= self.config.max_rebroadcasts => {
+                    warn!(
+                        "Giving up on transaction {} after {} rebroadcasts",
+                        hash, entry.rebroadcasts
+                    );
+                }
+
+                SentState::Dropped => {
+                    info!("Rebroadcasting transaction {}", hash);
+                    entry.rebroadcasts += 1;
+
+                    if let Err(e) = self.send_transaction(entry.transaction.clone()).await {
+                        warn!("Rebroadcast of {} failed: {}", hash, e);
+                    }
+
+                    keep.push(entry);
+                }
+            }
+        }
+
+        self.sent.write().unwrap().extend(keep);
+        Ok(())
+    }
+
+    // None if the node would accept the transaction, otherwise its reason
+    pub async fn test_transaction(&self, transaction: &Transaction) -> Result<Option<String>> {
+        let message = Message::TestTransaction(transaction.clone());
+
+        match self.request(message).await? {
+            Message::TestResult { accepted: true, .. } => Ok(None),
+            Message::TestResult { reason, .. } => Ok(Some(
+                reason.unwrap_or_else(|| "no reason given".to_string()),
+            )),
+            _ => Err(anyhow::anyhow!("Unexpected response from node")),
+        }
+    }
+
+    pub async fn fetch_chain_height(&self) -> Result<u64> {
+        match self.request(Message::ChainInfo).await? {
+            Message::ChainInfoResponse { height, .. } => Ok(height),
+            _ => Err(anyhow::anyhow!("Unexpected response from node")),
+        }
+    }
+
+    // None when the node has pruned the block's transactions
+    pub async fn fetch_block(&self, height: u64) -> Result<Option<Block>> {
+        match self.request(Message::FetchBlock(height as usize)).await? {
+            Message::NewBlock(block) => Ok(Some(block)),
+            Message::BlockUnavailable(_) => Ok(None),
+            _ => Err(anyhow::anyhow!("Unexpected response from node")),
+        }
+    }
+
+    async fn any_input_unspent(&self, transaction: &Transaction) -> Result<bool> {
+        for input in &transaction.inputs {
+            let message = Message::FetchUTXO(input.prev_tx_output_hash);
+
+            match self.request(message).await? {
+                Message::UTXOStatus(Some(_)) => return Ok(true),
+                Message::UTXOStatus(None) => (),
+                _ => return Err(anyhow::anyhow!("Unexpected response from node")),
+            }
+        }
+
+        Ok(false)
+    }
+
+    pub fn import_key(&self, wif: &str) -> Result<PublicKey> {
+        let private = PrivateKey::from_wif(wif)?;
+        let public = private.public_key();
+
+        if self.utxos.public_keys().contains(&public) {
+            return Err(anyhow::anyhow!(
+                "Key {} is already loaded",
+                public.to_address()
+            ));
+        }
+
+        info!("Imported key {}", public.to_address());
+        self.utxos.add_key(LoadedKey {
+            public: public.clone(),
+            private,
+        });
+
+        Ok(public)
+    }
+
+    // change goes to the first key, so that is the one to hand out
+    pub fn receive_address(&self) -> Option<String> {
+        self.utxos
+            .public_keys()
+            .first()
+            .map(|public| public.to_address())
+    }
+
+    pub fn contacts(&self) -> Vec<Recipient> {
+        self.contacts.read().unwrap().clone()
+    }
+
+    // the key has to load, and match the address when one is given, before
+    // the contact is saved
+    pub fn add_contact(&self, contact: Recipient) -> Result<()> {
+        if contact.name.trim().is_empty() {
+            return Err(anyhow::anyhow!("Contact name is empty"));
+        }
+
+        if let Some(address) = &contact.expected_address {
+            PublicKey::from_address(address)?;
+        }
+
+        contact.load()?;
+
+        let mut contacts = self.contacts.write().unwrap();
 
+        if contacts.iter().any(|c| c.name == contact.name) {
+            return Err(anyhow::anyhow!("Contact {} already exists", contact.name));
+        }
+
+        info!("Adding contact {}", contact.name);
+        contacts.push(contact);
+        self.save_contacts(&contacts)
+    }
+
+    pub fn remove_contact(&self, name: &str) -> Result<()> {
+        let mut contacts = self.contacts.write().unwrap();
+
+        let Some(index) = contacts.iter().position(|c| c.name == name) else {
+            return Err(anyhow::anyhow!("No contact named {}", name));
+        };
+
+        info!("Removing contact {}", name);
+        contacts.remove(index);
+        self.save_contacts(&contacts)
+    }
+
+    // rewrites the file as it is on disk with only the contacts replaced, so
+    // overrides such as --node are not persisted
+    fn save_contacts(&self, contacts: &[Recipient]) -> Result<()> {
+        let mut config: Config = toml::from_str(&fs::read_to_string(&self.config_path)?)?;
+        config.contacts = contacts.to_vec();
+        fs::write(&self.config_path, toml::to_string_pretty(&config)?)?;
         Ok(())
     }
 
+    fn find_recipient(&self, recipient: &str) -> Result<LoadedRecipient> {
+        let contacts = self.contacts.read().unwrap();
+
+        if let Some(contact) = contacts.iter().find(|r| r.name == recipient) {
+            return contact.load();
+        }
+
+        let key_hash = PublicKey::from_address(recipient)
+            .map_err(|_| anyhow::anyhow!("Recipient not found"))?;
+
+        contacts
+            .iter()
+            .filter_map(|contact| contact.load().ok())
+            .find(|contact| contact.key.address_hash() == key_hash)
+            .ok_or_else(|| anyhow::anyhow!("No contact with address {}", recipient))
+    }
+
     pub fn get_balance(&self) -> u64 {
         self.utxos
             .utxos
@@ -186,73 +751,864 @@ impl Core {
             .sum()
     }
 
-    pub fn create_transaction(&self, recipient: &PublicKey, amount: u64) -> Result<Transaction> {
-        let fee = self.calculate_fee(amount);
-        let total_amount = amount + fee;
-        let mut inputs = Vec::new();
-        let mut input_sum = 0;
+    // leaves out outputs a mempool transaction already spends
+    pub fn spendable_balance(&self) -> u64 {
+        self.utxos
+            .utxos
+            .iter()
+            .map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .filter(|(marked, _)| !marked)
+                    .map(|(_, utxo)| utxo.value)
+                    .sum::<u64>()
+            })
+            .sum()
+    }
 
-        for entry in self.utxos.utxos.iter() {
-            let pubkey = entry.key();
-            let utxos = entry.value();
+    // net effect of unconfirmed transactions: what they pay to our keys minus
+    // the outputs of ours they spend
+    pub fn get_pending_balance(&self) -> i64 {
+        let my_keys = self.utxos.public_keys();
+        let pending = self.utxos.pending.read().unwrap();
 
-            for (marked, utxo) in utxos.iter() {
-                if *marked {
-                    continue;
-                }
+        let incoming = pending
+            .iter()
+            .flat_map(|tx| tx.outputs.iter())
+            .filter(|output| my_keys.contains(&output.pubkey))
+            .map(|output| output.value as i64)
+            .sum::<i64>();
 
-                if input_sum >= total_amount {
-                    break;
+        let outgoing = pending
+            .iter()
+            .flat_map(|tx| tx.inputs.iter())
+            .filter_map(|input| {
+                self.utxos.utxos.iter().find_map(|entry| {
+                    entry
+                        .value()
+                        .iter()
+                        .find(|(_, utxo)| utxo.hash() == input.prev_tx_output_hash)
+                        .map(|(_, utxo)| utxo.value as i64)
+                })
+            })
+            .sum::<i64>();
+
+        incoming - outgoing
+    }
+
+    pub fn build_transaction(&self, payments: &[(PublicKey, u64)]) -> Result<TransactionPlan> {
+        let amount = payments
+            .iter()
+            .try_fold(0u64, |sum, (_, amount)| sum.checked_add(*amount))
+            .ok_or_else(|| anyhow::anyhow!("Total amount overflows"))?;
+
+        let available = self
+            .utxos
+            .utxos
+            .iter()
+            .flat_map(|entry| {
+                entry
+                    .value()
+                    .iter()
+                    .filter(|(marked, _)| !marked)
+                    .map(|(_, utxo)| utxo.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        // a per-byte fee depends on how many inputs get selected, so keep
+        // reselecting until the fee covers the signed transaction's size
+        let mut size = 0;
+
+        for _ in 0..MAX_FEE_ROUNDS {
+            let fee = self.calculate_fee(amount, size);
+
+            let Some((selected, change)) = select_coins(&available, amount, fee) else {
+                return Err(InsufficientFunds {
+                    needed: amount.saturating_add(fee),
+                    available: available.iter().map(|utxo| utxo.value).sum(),
                 }
+                .into());
+            };
 
-                inputs.push(btclib::types::TransactionInput {
-                    prev_tx_output_hash: utxo.hash(),
-                    signature: btclib::crypto::Signature::sign_output(
-                        &utxo.hash(),
-                        &self
-                            .utxos
-                            .my_keys
-                            .iter()
-                            .find(|k| k.public == *pubkey)
-                            .unwrap()
-                            .private,
-                    ),
-                });
+            // change below the dust threshold is added to the fee
+            let paid = selected.iter().map(|utxo| utxo.value).sum::<u64>() - amount - change;
 
-                input_sum += utxo.value;
-            }
+            let transaction = self.sign_transaction(payments, selected.clone(), change);
+            let new_size = transaction.serialized_size();
+
+            if self.calculate_fee(amount, new_size) <= fee {
+                debug!(
+                    "Built transaction of {} bytes paying {} sats ({:.2} sats/byte)",
+                    new_size,
+                    paid,
+                    fee_rate(paid, new_size)
+                );
 
-            if input_sum >= total_amount {
-                break;
+                return Ok(TransactionPlan {
+                    inputs: selected,
+                    change,
+                    fee: paid,
+                    transaction,
+                });
             }
-        }
 
-        if input_sum < total_amount {
-            return Err(anyhow::anyhow!("Insufficient funds"));
+            size = new_size;
         }
 
-        let mut outputs = vec![TransactionOutput {
-            value: amount,
-            unique_id: uuid::Uuid::new_v4(),
-            pubkey: recipient.clone(),
-        }];
+        Err(anyhow::anyhow!("Could not settle on a transaction fee"))
+    }
+
+    fn sign_transaction(
+        &self,
+        payments: &[(PublicKey, u64)],
+        selected: Vec<TransactionOutput>,
+        change: u64,
+    ) -> Transaction {
+        let mut outputs = payments
+            .iter()
+            .map(|(recipient, amount)| TransactionOutput {
+                value: *amount,
+                unique_id: uuid::Uuid::new_v4(),
+                pubkey: recipient.clone(),
+            })
+            .collect::<Vec<_>>();
 
-        if input_sum > total_amount {
+        if change > 0 {
             outputs.push(TransactionOutput {
-                value: input_sum - total_amount,
+                value: change,
                 unique_id: uuid::Uuid::new_v4(),
-                pubkey: self.utxos.my_keys[0].public.clone(),
+                pubkey: self.utxos.my_keys.read().unwrap()[0].public.clone(),
+            });
+        }
+
+        let mut transaction = Transaction::new(vec![], outputs);
+        let my_keys = self.utxos.my_keys.read().unwrap();
+        let private_keys = selected
+            .iter()
+            .map(|utxo| {
+                &my_keys
+                    .iter()
+                    .find(|k| k.public == utxo.pubkey)
+                    .unwrap()
+                    .private
+            })
+            .collect::<Vec<_>>();
+
+        // each signature commits to every input, so the ones made while
+        // adding them are only placeholders until all are in
+        for (utxo, private_key) in selected.iter().zip(&private_keys) {
+            let prev_hash = utxo.hash();
+            let signature = Signature::sign_input(&transaction, 0, &prev_hash, private_key);
+
+            transaction.inputs.push(TransactionInput {
+                prev_tx_output_hash: prev_hash,
+                signature,
             });
         }
 
-        Ok(Transaction::new(inputs, outputs))
+        for (index, private_key) in private_keys.into_iter().enumerate() {
+            let prev_hash = transaction.inputs[index].prev_tx_output_hash;
+            transaction.inputs[index].signature =
+                Signature::sign_input(&transaction, index, &prev_hash, private_key);
+        }
+
+        transaction
     }
 
-    fn calculate_fee(&self, amount: u64) -> u64 {
+    fn calculate_fee(&self, amount: u64, size: usize) -> u64 {
         match self.config.fee_config.fee_type {
             FeeType::Fixed => self.config.fee_config.value as u64,
 
             FeeType::Percent => (amount as f64 * self.config.fee_config.value / 100.0) as u64,
+
+            FeeType::FixedPerByte => (size as f64 * self.config.fee_config.value).ceil() as u64,
+        }
+    }
+}
+
+const MAX_SELECTION_TRIES: usize = 100_000;
+const MAX_FEE_ROUNDS: usize = 5;
+
+#[derive(Debug)]
+pub struct InsufficientFunds {
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl std::fmt::Display for InsufficientFunds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Insufficient funds: need {} sats but only {} sats are spendable",
+            self.needed, self.available
+        )
+    }
+}
+
+impl std::error::Error for InsufficientFunds {}
+
+// Picks inputs covering `target + fee` and returns them with the change.
+// A branch-and-bound search first looks for a set that needs no change
+// output; otherwise coins are taken largest first. Change below the dust
+// threshold is left to the miner instead of creating a dust output.
+pub fn select_coins(
+    utxos: &[TransactionOutput],
+    target: u64,
+    fee: u64,
+) -> Option<(Vec<TransactionOutput>, u64)> {
+    let needed = target.checked_add(fee)?;
+
+    let mut sorted = utxos.to_vec();
+    sorted.sort_by_key(|utxo| std::cmp::Reverse(utxo.value));
+
+    if let Some(indices) = branch_and_bound(&sorted, needed) {
+        let selected = indices.into_iter().map(|i| sorted[i].clone()).collect();
+        return Some((selected, 0));
+    }
+
+    let mut selected = vec![];
+    let mut sum: u64 = 0;
+
+    for utxo in sorted {
+        if sum >= needed {
+            break;
         }
+
+        sum = sum.saturating_add(utxo.value);
+        selected.push(utxo);
+    }
+
+    if sum < needed {
+        return None;
+    }
+
+    let change = sum - needed;
+    Some((selected, if change < DUST_THRESHOLD { 0 } else { change }))
+}
+
+fn branch_and_bound(sorted: &[TransactionOutput], needed: u64) -> Option<Vec<usize>> {
+    let mut remaining = vec![0u64; sorted.len() + 1];
+    for i in (0..sorted.len()).rev() {
+        remaining[i] = remaining[i + 1].saturating_add(sorted[i].value);
+    }
+
+    let mut search = BranchAndBound {
+        sorted,
+        remaining,
+        needed,
+        upper: needed.saturating_add(DUST_THRESHOLD),
+        tries: 0,
+        selection: vec![],
+    };
+
+    search.search(0, 0).then_some(search.selection)
+}
+
+struct BranchAndBound<'a> {
+    sorted: &'a [TransactionOutput],
+    remaining: Vec<u64>,
+    needed: u64,
+    upper: u64,
+    tries: usize,
+    selection: Vec<usize>,
+}
+
+impl BranchAndBound<'_> {
+    fn search(&mut self, index: usize, sum: u64) -> bool {
+        self.tries += 1;
+
+        if sum >= self.needed {
+            return sum < self.upper;
+        }
+
+        if index == self.sorted.len()
+            || self.tries > MAX_SELECTION_TRIES
+            || sum.saturating_add(self.remaining[index]) < self.needed
+        {
+            return false;
+        }
+
+        self.selection.push(index);
+        if self.search(index + 1, sum.saturating_add(self.sorted[index].value)) {
+            return true;
+        }
+        self.selection.pop();
+
+        self.search(index + 1, sum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn nodes(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rsbtc-wallet-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn coin(value: u64, key: &PublicKey) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: key.clone(),
+        }
+    }
+
+    // a wallet with one key that owns outputs of the given values, marked
+    // ones being spent by a mempool transaction; it has no node to talk to
+    fn wallet(fee_config: FeeConfig, coins: &[(bool, u64)]) -> Core {
+        let dir = temp_dir();
+        let config = Config {
+            my_keys: vec![],
+            contacts: vec![],
+            nodes: vec![],
+            fee_config,
+            refresh_interval_secs: 20,
+            max_rebroadcasts: 2,
+            log: LogConfig::default(),
+        };
+        let config_path = dir.join("wallet_config.toml");
+        fs::write(&config_path, toml::to_string_pretty(&config).unwrap()).unwrap();
+
+        let private = PrivateKey::new_key();
+        let public = private.public_key();
+        let utxos = UtxoStore::new();
+        utxos.utxos.insert(
+            public.clone(),
+            coins
+                .iter()
+                .map(|&(marked, value)| (marked, coin(value, &public)))
+                .collect(),
+        );
+        utxos.add_key(LoadedKey { public, private });
+
+        let history = History::load(dir.join("history.json")).unwrap();
+        Core::new(config, config_path, history, utxos, None)
+    }
+
+    fn fixed(fee: u64) -> FeeConfig {
+        FeeConfig {
+            fee_type: FeeType::Fixed,
+            value: fee as f64,
+        }
+    }
+
+    fn my_key(core: &Core) -> PublicKey {
+        core.utxos.public_keys()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn the_first_node_that_answers_is_used() {
+        let mut tried = vec![];
+        let (node, answer) = first_reachable(&nodes(&["down", "up", "later"]), |node| {
+            tried.push(node.clone());
+            async move {
+                match node.as_str() {
+                    "down" => Err(anyhow::anyhow!("connection refused")),
+                    _ => Ok(node.len()),
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!((node.as_str(), answer), ("up", 2));
+        assert_eq!(tried, nodes(&["down", "up"]));
+    }
+
+    #[tokio::test]
+    async fn all_nodes_down_is_an_error_naming_each() {
+        let error = first_reachable(&nodes(&["a", "b"]), |node| async move {
+            Err::<(), _>(anyhow::anyhow!("{} refused", node))
+        })
+        .await
+        .unwrap_err()
+        .to_string();
+
+        assert!(error.contains("a: a refused") && error.contains("b: b refused"));
+    }
+
+    #[test]
+    fn a_single_default_node_still_loads() {
+        let config = |nodes: &str| {
+            toml::from_str::<Config>(&format!(
+                "{}\nmy_keys = []\ncontacts = []\n\n[fee_config]\nfee_type = \"Fixed\"\nvalue = 1.0\n",
+                nodes
+            ))
+            .unwrap()
+            .nodes
+        };
+
+        assert_eq!(
+            config("default_node = \"127.0.0.1:9000\""),
+            nodes(&["127.0.0.1:9000"])
+        );
+        assert_eq!(config("nodes = [\"a:1\", \"b:2\"]"), nodes(&["a:1", "b:2"]));
+    }
+
+    #[test]
+    fn an_exact_selection_needs_no_change() {
+        let key = PrivateKey::new_key().public_key();
+        let coins = [coin(7000, &key), coin(5000, &key)];
+
+        let (selected, change) = select_coins(&coins, 4000, 1000).unwrap();
+        assert_eq!(change, 0);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].value, 5000);
+
+        let core = wallet(fixed(1000), &[(false, 20_000), (false, 6000)]);
+        let recipient = PrivateKey::new_key().public_key();
+        let plan = core
+            .build_transaction(&[(recipient.clone(), 5000)])
+            .unwrap();
+
+        assert_eq!(plan.change, 0);
+        assert_eq!(plan.fee, 1000);
+        assert_eq!(plan.inputs.len(), 1);
+        assert_eq!(plan.transaction.outputs.len(), 1);
+        assert_eq!(plan.transaction.outputs[0].pubkey, recipient);
+        assert_eq!(plan.transaction.outputs[0].value, 5000);
+
+        // paying two recipients exactly gives two outputs and still no change
+        let core = wallet(fixed(1000), &[(false, 20_000), (false, 9000)]);
+        let (first, second) = (
+            PrivateKey::new_key().public_key(),
+            PrivateKey::new_key().public_key(),
+        );
+        let plan = core
+            .build_transaction(&[(first.clone(), 5000), (second.clone(), 3000)])
+            .unwrap();
+
+        assert_eq!(plan.change, 0);
+        assert_eq!(plan.fee, 1000);
+        assert_eq!(plan.inputs.len(), 1);
+        let outputs = plan
+            .transaction
+            .outputs
+            .iter()
+            .map(|output| (output.pubkey.clone(), output.value))
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, vec![(first, 5000), (second, 3000)]);
+    }
+
+    #[test]
+    fn change_below_the_dust_threshold_goes_to_the_fee() {
+        let key = PrivateKey::new_key().public_key();
+        let dust = DUST_THRESHOLD - 1;
+
+        // largest first leaves dust over, and none of it comes back
+        let coins = [coin(4000, &key), coin(3000 + dust, &key)];
+        let (selected, change) = select_coins(&coins, 5000, 2000).unwrap();
+        assert_eq!(change, 0);
+        assert_eq!(selected.iter().map(|c| c.value).sum::<u64>(), 7000 + dust);
+
+        let core = wallet(fixed(1000), &[(false, 6000 + dust)]);
+        let plan = core
+            .build_transaction(&[(PrivateKey::new_key().public_key(), 5000)])
+            .unwrap();
+        assert_eq!(plan.change, 0);
+        assert_eq!(plan.fee, 1000 + dust);
+        assert_eq!(plan.transaction.outputs.len(), 1);
+
+        // at the threshold it is worth an output of its own
+        let core = wallet(fixed(1000), &[(false, 6000 + DUST_THRESHOLD)]);
+        let plan = core
+            .build_transaction(&[(PrivateKey::new_key().public_key(), 5000)])
+            .unwrap();
+        assert_eq!(plan.change, DUST_THRESHOLD);
+        assert_eq!(plan.fee, 1000);
+        assert_eq!(plan.transaction.outputs.len(), 2);
+        assert_eq!(plan.transaction.outputs[1].pubkey, my_key(&core));
+    }
+
+    #[test]
+    fn a_contact_is_only_checked_against_its_expected_address() {
+        let core = wallet(fixed(1000), &[]);
+        let dir = temp_dir();
+        let key = PrivateKey::new_key().public_key();
+        let key_file = dir.join("bob.pub.pem");
+        key.save_to_file(&key_file).unwrap();
+
+        let contact = |name: &str, expected_address: Option<String>| Recipient {
+            name: name.to_string(),
+            key: key_file.clone(),
+            expected_address,
+        };
+
+        let other = PrivateKey::new_key().public_key().to_address();
+        assert!(core.add_contact(contact("eve", Some(other))).is_err());
+
+        core.add_contact(contact("bob", Some(key.to_address())))
+            .unwrap();
+        assert_eq!(core.find_recipient("bob").unwrap().key, key);
+        assert_eq!(core.find_recipient(&key.to_address()).unwrap().key, key);
+
+        // configs written before the rename still load
+        let legacy: Recipient = toml::from_str(&format!(
+            "name = \"bob\"\nkey = \"bob.pub.pem\"\naddress = \"{}\"\n",
+            key.to_address()
+        ))
+        .unwrap();
+        assert_eq!(legacy.expected_address, Some(key.to_address()));
+    }
+
+    #[test]
+    fn added_contacts_are_saved_to_the_config_and_reload() {
+        let core = wallet(fixed(1000), &[]);
+        let dir = temp_dir();
+        let key = PrivateKey::new_key().public_key();
+        let key_file = dir.join("carol.pub.pem");
+        key.save_to_file(&key_file).unwrap();
+
+        let carol = Recipient {
+            name: "carol".to_string(),
+            key: key_file.clone(),
+            expected_address: Some(key.to_address()),
+        };
+        core.add_contact(carol.clone()).unwrap();
+        assert!(core.add_contact(carol).is_err());
+
+        // a key file that isn't there is refused before anything is written
+        let missing = Recipient {
+            name: "dave".to_string(),
+            key: dir.join("missing.pem"),
+            expected_address: None,
+        };
+        assert!(core.add_contact(missing).is_err());
+
+        let saved = || {
+            let config: Config =
+                toml::from_str(&fs::read_to_string(&core.config_path).unwrap()).unwrap();
+            config
+                .contacts
+                .iter()
+                .map(|contact| (contact.name.clone(), contact.load().unwrap().key))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(saved(), vec![("carol".to_string(), key.clone())]);
+
+        // the rest of the config is written back as it was
+        let config: Config =
+            toml::from_str(&fs::read_to_string(&core.config_path).unwrap()).unwrap();
+        assert_eq!(
+            config.refresh_interval_secs,
+            core.config.refresh_interval_secs
+        );
+
+        core.remove_contact("carol").unwrap();
+        assert!(saved().is_empty());
+        assert!(core.contacts().is_empty());
+        assert!(core.remove_contact("carol").is_err());
+    }
+
+    #[test]
+    fn insufficient_funds_names_what_was_needed_and_what_is_spendable() {
+        // the marked output is already spent by a mempool transaction
+        let core = wallet(fixed(1000), &[(false, 3000), (false, 2000), (true, 50_000)]);
+        let error = core
+            .build_transaction(&[(PrivateKey::new_key().public_key(), 6000)])
+            .err()
+            .unwrap();
+
+        let InsufficientFunds { needed, available } =
+            error.downcast_ref::<InsufficientFunds>().unwrap();
+        assert_eq!((*needed, *available), (7000, 5000));
+    }
+
+    #[test]
+    fn a_sent_transaction_is_judged_by_the_mempool_then_its_inputs() {
+        let table = [
+            (true, true, SentState::Pending),
+            (true, false, SentState::Pending),
+            (false, true, SentState::Dropped),
+            (false, false, SentState::Settled),
+        ];
+
+        for (in_mempool, any_input_unspent, state) in table {
+            assert_eq!(sent_state(in_mempool, any_input_unspent), state);
+        }
+    }
+
+    // a node that answers every request with `answer`, in use by `core`
+    async fn mock_node<F>(core: &Core, mut answer: F)
+    where
+        F: FnMut(Message) -> Message + Send + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            Message::accept_handshake_async(&mut stream, "mock", 0)
+                .await
+                .unwrap();
+
+            while let Ok(message) = Message::recv_async(&mut stream).await {
+                answer(message).send_async(&mut stream).await.unwrap();
+            }
+        });
+
+        let stream = connect_to(&address).await.unwrap();
+        *core.connection.lock().await = Some((address, stream));
+    }
+
+    // a node whose mempool is always empty and whose outputs are all
+    // unspent, so everything sent to it looks dropped; returns how many
+    // transactions were submitted
+    async fn forgetful_node(core: &Core) -> Arc<AtomicUsize> {
+        let submitted = Arc::new(AtomicUsize::new(0));
+        let counter = submitted.clone();
+
+        mock_node(core, move |message| match message {
+            Message::FetchMempool(_) => Message::MempoolResponse(vec![]),
+            Message::FetchUTXO(_) => {
+                let key = PrivateKey::new_key().public_key();
+                Message::UTXOStatus(Some((coin(1, &key), false)))
+            }
+            Message::SubmitTransaction(_) => {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Message::SubmitTransactionResult {
+                    accepted: true,
+                    reason: None,
+                }
+            }
+            other => panic!("unexpected {:?}", other),
+        })
+        .await;
+
+        submitted
+    }
+
+    #[tokio::test]
+    async fn a_dropped_transaction_is_rebroadcast_until_the_cap() {
+        let core = wallet(fixed(1000), &[(false, 10_000)]);
+        let submitted = forgetful_node(&core).await;
+        let plan = core
+            .build_transaction(&[(PrivateKey::new_key().public_key(), 5000)])
+            .unwrap();
+
+        // sent long enough ago that the first submission is not still queued
+        core.sent.write().unwrap().push(SentTransaction {
+            transaction: plan.transaction,
+            sent_at: Instant::now() - Duration::from_secs(60),
+            rebroadcasts: 0,
+        });
+
+        // max_rebroadcasts is 2: two resubmissions, then it is given up on
+        for expected in [1, 2, 2] {
+            core.rebroadcast().await.unwrap();
+            assert_eq!(submitted.load(Ordering::SeqCst), expected);
+        }
+        assert!(core.sent.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn outputs_already_being_spent_are_not_spendable() {
+        let core = wallet(fixed(1000), &[(false, 3000), (true, 5000), (false, 2000)]);
+
+        assert_eq!(core.get_balance(), 10_000);
+        assert_eq!(core.spendable_balance(), 5000);
+    }
+
+    #[test]
+    fn the_pending_balance_nets_incoming_against_what_we_spend() {
+        let core = wallet(fixed(1000), &[(true, 5000), (false, 2000)]);
+        let me = my_key(&core);
+        let spent = core.utxos.utxos.get(&me).unwrap().value()[0].1.clone();
+
+        // we spend our 5000 and get 1500 back as change, and someone else
+        // sends us 700
+        // only which output it spends matters here, not the signature
+        let signature = Signature::sign_input(
+            &Transaction::new(vec![], vec![]),
+            0,
+            &spent.hash(),
+            &PrivateKey::new_key(),
+        );
+        let outgoing = Transaction::new(
+            vec![TransactionInput {
+                prev_tx_output_hash: spent.hash(),
+                signature,
+            }],
+            vec![
+                coin(2500, &PrivateKey::new_key().public_key()),
+                coin(1500, &me),
+            ],
+        );
+        let incoming = Transaction::new(vec![], vec![coin(700, &me)]);
+        *core.utxos.pending.write().unwrap() = vec![outgoing, incoming];
+
+        assert_eq!(core.get_pending_balance(), 1500 + 700 - 5000);
+    }
+
+    #[test]
+    fn several_recipients_are_paid_in_one_transaction() {
+        let core = wallet(fixed(1000), &[(false, 20_000)]);
+        let dir = temp_dir();
+
+        let mut keys = vec![];
+        for name in ["alice", "bob"] {
+            let key = PrivateKey::new_key().public_key();
+            let key_file = dir.join(format!("{}.pub.pem", name));
+            key.save_to_file(&key_file).unwrap();
+            core.add_contact(Recipient {
+                name: name.to_string(),
+                key: key_file,
+                expected_address: None,
+            })
+            .unwrap();
+            keys.push(key);
+        }
+
+        // one by name, one by address
+        let prepared = core
+            .prepare_transaction(&[("alice".to_string(), 5000), (keys[1].to_address(), 3000)])
+            .unwrap();
+
+        assert_eq!(prepared.total(), 8000);
+        assert_eq!(
+            prepared.payments,
+            vec![("alice".to_string(), 5000), ("bob".to_string(), 3000)]
+        );
+
+        let outputs = prepared
+            .plan
+            .transaction
+            .outputs
+            .iter()
+            .map(|output| (output.pubkey.clone(), output.value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outputs,
+            vec![
+                (keys[0].clone(), 5000),
+                (keys[1].clone(), 3000),
+                (my_key(&core), 20_000 - 8000 - 1000),
+            ]
+        );
+
+        let unknown =
+            core.prepare_transaction(&[("alice".to_string(), 5000), ("mallory".to_string(), 1000)]);
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn each_fee_type_is_paid_out_of_the_inputs() {
+        let recipient = PrivateKey::new_key().public_key();
+        let totals = |fee_config: FeeConfig| {
+            let core = wallet(fee_config, &[(false, 100_000)]);
+            let plan = core
+                .build_transaction(&[(recipient.clone(), 40_000)])
+                .unwrap();
+            let inputs = plan.inputs.iter().map(|utxo| utxo.value).sum::<u64>();
+            let outputs = plan
+                .transaction
+                .outputs
+                .iter()
+                .map(|output| output.value)
+                .sum::<u64>();
+
+            assert_eq!(inputs, outputs + plan.fee);
+            (plan.fee, plan.change, plan.transaction.serialized_size())
+        };
+
+        let (fee, change, _) = totals(fixed(1500));
+        assert_eq!((fee, change), (1500, 58_500));
+
+        let percent = FeeConfig {
+            fee_type: FeeType::Percent,
+            value: 2.5,
+        };
+        assert_eq!(totals(percent).0, 1000);
+
+        let per_byte = FeeConfig {
+            fee_type: FeeType::FixedPerByte,
+            value: 3.0,
+        };
+        // enough for the signed size; a resigned transaction can come out a
+        // few bytes shorter than the one the fee was settled on
+        let (fee, change, size) = totals(per_byte);
+        assert!(fee >= 3 * size as u64 && fee <= 3 * (size as u64 + 16));
+        assert_eq!(change, 100_000 - 40_000 - fee);
+
+        // a fee the balance can't cover is refused, not taken from the payment
+        let core = wallet(fixed(1500), &[(false, 41_000)]);
+        let error = core
+            .build_transaction(&[(recipient.clone(), 40_000)])
+            .err()
+            .unwrap();
+        assert!(error.downcast_ref::<InsufficientFunds>().is_some());
+    }
+
+    #[test]
+    fn the_plan_reports_fee_and_change_for_each_balance() {
+        let recipient = PrivateKey::new_key().public_key();
+
+        // (coins held, inputs used, change, fee) when paying 5000 with a
+        // fixed fee of 1000
+        let cases: [(&[u64], usize, u64, u64); 5] = [
+            (&[10_000], 1, 4000, 1000),
+            (&[4000, 3000], 2, 1000, 1000),
+            (&[2000, 2000, 2000, 2000], 3, 0, 1000),
+            (&[100_000, 6000], 1, 0, 1000),
+            (&[6500], 1, 0, 1500),
+        ];
+
+        for (coins, inputs, change, fee) in cases {
+            let held = coins
+                .iter()
+                .map(|&value| (false, value))
+                .collect::<Vec<_>>();
+            let core = wallet(fixed(1000), &held);
+            let plan = core
+                .build_transaction(&[(recipient.clone(), 5000)])
+                .unwrap();
+
+            assert_eq!(
+                (plan.inputs.len(), plan.change, plan.fee),
+                (inputs, change, fee),
+                "holding {:?}",
+                coins
+            );
+            // only a preview: nothing is marked as being spent
+            assert_eq!(core.spendable_balance(), coins.iter().sum::<u64>());
+        }
+    }
+
+    #[tokio::test]
+    async fn each_refresh_recomputes_the_balance_from_the_node() {
+        let core = wallet(fixed(1000), &[]);
+        let me = my_key(&core);
+
+        // what the node reports changes under the wallet between refreshes
+        let reported = Arc::new(RwLock::new(vec![(coin(5000, &me), false)]));
+        let node_view = reported.clone();
+        mock_node(&core, move |message| match message {
+            Message::FetchUTXOs(_) => Message::UTXOs(node_view.read().unwrap().clone()),
+            other => panic!("unexpected {:?}", other),
+        })
+        .await;
+
+        core.fetch_utxos().await.unwrap();
+        assert_eq!((core.get_balance(), core.spendable_balance()), (5000, 5000));
+
+        // funds arrive, and a mempool transaction starts spending the first
+        *reported.write().unwrap() = vec![(coin(5000, &me), true), (coin(2500, &me), false)];
+        core.fetch_utxos().await.unwrap();
+        assert_eq!((core.get_balance(), core.spendable_balance()), (7500, 2500));
+
+        // and everything is spent
+        reported.write().unwrap().clear();
+        core.fetch_utxos().await.unwrap();
+        assert_eq!((core.get_balance(), core.spendable_balance()), (0, 0));
     }
 }
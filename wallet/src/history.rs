@@ -0,0 +1,168 @@
+use anyhow::Result;
+use btclib::sha256::Hash;
+use btclib::types::{Transaction, TransactionOutput};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tracing::*;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub direction: Direction,
+    pub amount: u64,
+    // contact name or address for sends, empty for receives
+    pub counterparty: String,
+    pub timestamp: DateTime<Utc>,
+    // every output the entry accounts for, so change coming back to us
+    // is not mistaken for a payment received
+    pub outputs: Vec<Hash>,
+}
+
+pub struct History {
+    path: PathBuf,
+    entries: RwLock<Vec<HistoryEntry>>,
+}
+
+impl History {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let entries = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            vec![]
+        };
+
+        Ok(Self {
+            path,
+            entries: RwLock::new(entries),
+        })
+    }
+
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.read().unwrap().clone()
+    }
+
+    pub fn record_sent(&self, transaction: &Transaction, counterparty: &str, amount: u64) {
+        self.record(vec![HistoryEntry {
+            direction: Direction::Sent,
+            amount,
+            counterparty: counterparty.to_string(),
+            timestamp: Utc::now(),
+            outputs: transaction
+                .outputs
+                .iter()
+                .map(|output| output.hash())
+                .collect(),
+        }]);
+    }
+
+    // outputs already accounted for by an earlier entry are skipped, so the
+    // same UTXO set can be passed in on every refresh
+    pub fn record_received<'a>(&self, outputs: impl IntoIterator<Item = &'a TransactionOutput>) {
+        let known = self
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|entry| entry.outputs.iter().copied())
+            .collect::<HashSet<_>>();
+
+        let now = Utc::now();
+        let received = outputs
+            .into_iter()
+            .filter(|output| !known.contains(&output.hash()))
+            .map(|output| HistoryEntry {
+                direction: Direction::Received,
+                amount: output.value,
+                counterparty: String::new(),
+                timestamp: now,
+                outputs: vec![output.hash()],
+            })
+            .collect::<Vec<_>>();
+
+        self.record(received);
+    }
+
+    fn record(&self, new_entries: Vec<HistoryEntry>) {
+        if new_entries.is_empty() {
+            return;
+        }
+
+        let mut entries = self.entries.write().unwrap();
+        entries.extend(new_entries);
+
+        if let Err(e) = save(&self.path, &entries) {
+            error!("Failed to save history to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+fn save(path: &Path, entries: &[HistoryEntry]) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(entries)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use btclib::crypto::PrivateKey;
+
+    fn coin(value: u64) -> TransactionOutput {
+        TransactionOutput {
+            value,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: PrivateKey::new_key().public_key(),
+        }
+    }
+
+    #[test]
+    fn sends_and_receives_are_recorded_once_and_reloaded() {
+        let dir = std::env::temp_dir().join(format!("rsbtc-wallet-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("history.json");
+        let history = History::load(path.clone()).unwrap();
+
+        let first = coin(5000);
+        history.record_received([&first]);
+
+        // a payment of 3000 with 1500 change coming back
+        let (payment, change) = (coin(3000), coin(1500));
+        let tx = Transaction::new(vec![], vec![payment.clone(), change.clone()]);
+        history.record_sent(&tx, "alice", 3000);
+
+        // the next refresh sees the change and a new coin next to the old
+        // one; only the new coin is news
+        let second = coin(700);
+        history.record_received([&first, &change, &second]);
+
+        let summary = |entries: Vec<HistoryEntry>| {
+            entries
+                .into_iter()
+                .map(|entry| (entry.direction, entry.amount, entry.counterparty))
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            (Direction::Received, 5000, String::new()),
+            (Direction::Sent, 3000, "alice".to_string()),
+            (Direction::Received, 700, String::new()),
+        ];
+        assert_eq!(summary(history.entries()), expected);
+
+        let reloaded = History::load(path).unwrap();
+        assert_eq!(summary(reloaded.entries()), expected);
+        assert_eq!(
+            reloaded.entries()[1].outputs,
+            vec![payment.hash(), change.hash()]
+        );
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
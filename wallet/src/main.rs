@@ -5,12 +5,13 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use tracing::{debug, info};
 mod core;
+mod history;
 mod tasks;
 mod ui;
 mod util;
-use core::Core;
-use tasks::{handle_transactions, ui_task, update_balance, update_utxos};
-use util::{big_mode_btc, generate_dummy_config, setup_panic_hook, setup_tracing};
+use core::{Core, LogConfig};
+use tasks::{handle_transactions, ui_task, update_utxos};
+use util::{balance_text, generate_dummy_config, setup_panic_hook, setup_tracing};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -35,13 +36,13 @@ enum Commands {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    setup_tracing()?;
+    let cli = Cli::parse();
+
+    setup_tracing(&LogConfig::load(&cli.config))?;
     setup_panic_hook();
 
     info!("Starting wallet application");
 
-    let cli = Cli::parse();
-
     match &cli.command {
         Some(Commands::GenerateConfig { output }) => {
             debug!("Generating config file at {:?}", output);
@@ -52,11 +53,7 @@ async fn main() -> Result<()> {
 
     info!("Loading config file at {:?}", cli.config);
 
-    let mut core = Core::load(cli.config.clone()).await?;
-    if let Some(node) = cli.node {
-        info!("Overriding default node with {:?}", node);
-        core.config.default_node = node;
-    }
+    let mut core = Core::load(cli.config.clone(), cli.node).await?;
 
     let (tx_sender, tx_receiver) = kanal::bounded(10);
     core.tx_sender = tx_sender;
@@ -65,13 +62,12 @@ async fn main() -> Result<()> {
 
     info!("Starting background tasks");
 
-    let balance_content = TextContent::new(big_mode_btc(&core));
+    let balance_content = TextContent::new(balance_text(&core));
 
     tokio::select! {
         _ = ui_task(core.clone(), balance_content.clone()).await => (),
-        _ = update_utxos(core.clone()).await => (),
+        _ = update_utxos(core.clone(), balance_content).await => (),
         _ = handle_transactions(tx_receiver.clone_async(), core.clone()).await => (),
-        _ = update_balance(core.clone(), balance_content).await => (),
     }
 
     info!("Shutting down");
@@ -1,6 +1,6 @@
 use crate::core::Core;
 use crate::ui::run_ui;
-use crate::util::big_mode_btc;
+use crate::util::balance_text;
 use btclib::types::Transaction;
 use cursive::views::TextContent;
 use std::sync::Arc;
@@ -8,14 +8,28 @@ use tokio::task::JoinHandle;
 use tokio::time::{self, Duration};
 use tracing::*;
 
-pub async fn update_utxos(core: Arc<Core>) -> JoinHandle<()> {
+pub async fn update_utxos(core: Arc<Core>, balance_content: TextContent) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs(20));
+        let mut interval = time::interval(Duration::from_secs(
+            core.config.refresh_interval_secs.max(1),
+        ));
         loop {
             interval.tick().await;
             if let Err(e) = core.fetch_utxos().await {
                 error!("Error fetching UTXOs: {}", e);
+                continue;
             }
+
+            if let Err(e) = core.fetch_mempool().await {
+                error!("Error fetching mempool: {}", e);
+            }
+
+            if let Err(e) = core.rebroadcast().await {
+                error!("Error rebroadcasting transactions: {}", e);
+            }
+
+            debug!("Updating balance string");
+            balance_content.set_content(balance_text(&core));
         }
     })
 }
@@ -42,13 +56,3 @@ pub async fn ui_task(core: Arc<Core>, balance_content: TextContent) -> JoinHandl
         }
     })
 }
-
-pub async fn update_balance(core: Arc<Core>, balance_content: TextContent) -> JoinHandle<()> {
-    tokio::spawn(async move {
-        loop {
-            tokio::time::sleep(Duration::from_millis(500)).await;
-            info!("Updating balance string");
-            balance_content.set_content(big_mode_btc(&core));
-        }
-    })
-}
@@ -1,27 +1,24 @@
-use crate::core::Core;
+use crate::core::{Core, PreparedTransaction, Recipient};
+use crate::history::Direction;
+use crate::util::{
+    Unit, block_details, block_summary, parse_amount, qr_code, sats_to_btc, transaction_summary,
+};
 use anyhow::Result;
+use btclib::types::Block;
 use cursive::Cursive;
 use cursive::event::{Event, Key};
+use cursive::menu;
 use cursive::views::{
-    Button, Dialog, EditView, LinearLayout, Panel, ResizedView, TextContent, TextView,
+    Button, Dialog, EditView, LinearLayout, Panel, ResizedView, SelectView, TextContent, TextView,
 };
 use cursive::{CursiveExt, traits::*};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use tokio::runtime::Handle;
 use tracing::*;
 
-#[derive(Clone, Copy)]
-enum Unit {
-    Btc,
-    Sats,
-}
-
-fn convert_unit(amount: f64, from: Unit, to: Unit) -> f64 {
-    match (from, to) {
-        (Unit::Btc, Unit::Sats) => amount * 100_000_000.0,
-        (Unit::Sats, Unit::Btc) => amount / 100_000_000.0,
-        _ => amount,
-    }
-}
+// each page costs a request per block
+const BLOCKS_PER_PAGE: u64 = 10;
 
 pub fn run_ui(core: Arc<Core>, balance_content: TextContent) -> Result<()> {
     info!("Initializing UI");
@@ -53,7 +50,35 @@ fn setup_siv(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
 
 fn setup_menubar(siv: &mut Cursive, core: Arc<Core>) {
     siv.menubar()
-        .add_leaf("Send", move |s| show_send_transaction(s, core.clone()))
+        .add_leaf("Send", {
+            let core = core.clone();
+            move |s| show_send_transaction(s, core.clone())
+        })
+        .add_leaf("Receive", {
+            let core = core.clone();
+            move |s| show_receive(s, core.clone())
+        })
+        .add_leaf("History", {
+            let core = core.clone();
+            move |s| show_history(s, core.clone())
+        })
+        .add_leaf("Explorer", {
+            let core = core.clone();
+            move |s| show_explorer(s, core.clone())
+        })
+        .add_subtree(
+            "Contacts",
+            menu::Tree::new()
+                .leaf("Add contact", {
+                    let core = core.clone();
+                    move |s| show_add_contact(s, core.clone())
+                })
+                .leaf("Remove contact", {
+                    let core = core.clone();
+                    move |s| show_remove_contact(s, core.clone())
+                }),
+        )
+        .add_leaf("Import key", move |s| show_import_key(s, core.clone()))
         .add_leaf("Quit", |s| s.quit());
 
     siv.set_autohide_menu(false);
@@ -79,63 +104,383 @@ fn show_send_transaction(s: &mut Cursive, core: Arc<Core>) {
 fn send_transaction(s: &mut Cursive, core: Arc<Core>, unit: Unit) {
     debug!("Send button pressed");
 
-    let recipient = s
-        .call_on_name("recipient", |view: &mut EditView| view.get_content())
-        .unwrap();
+    let mut payments = vec![];
 
-    let amount: f64 = s
-        .call_on_name("amount", |view: &mut EditView| view.get_content())
-        .unwrap()
-        .parse()
-        .unwrap_or(0.0);
+    // rows are numbered from 0; the first missing name is past the last row
+    for row in 0.. {
+        let Some(recipient) = s
+            .call_on_name(&format!("recipient_{}", row), |view: &mut EditView| {
+                view.get_content()
+            })
+        else {
+            break;
+        };
 
-    let amount_sats = convert_unit(amount, unit, Unit::Sats) as u64;
+        if recipient.trim().is_empty() {
+            continue;
+        }
 
-    info!(
-        "Sending transaction to {} with {} sats",
-        recipient, amount_sats
-    );
+        let amount = s
+            .call_on_name(&format!("amount_{}", row), |view: &mut EditView| {
+                view.get_content()
+            })
+            .unwrap();
+
+        let amount_sats = match parse_amount(&amount, unit) {
+            Ok(amount_sats) => amount_sats,
+            Err(e) => {
+                show_error_dialog(s, format!("Amount for {}: {}", recipient.trim(), e));
+                return;
+            }
+        };
+
+        info!("Adding payment to {} with {} sats", recipient, amount_sats);
+        payments.push((recipient.trim().to_string(), amount_sats));
+    }
 
-    match core.send_transaction_async(recipient.as_str(), amount_sats) {
-        Ok(_) => show_success_dialog(s),
-        Err(e) => show_error_dialog(s, e),
+    match core.prepare_transaction(&payments) {
+        Ok(prepared) => show_confirm_transaction(s, core, prepared),
+        Err(e) => show_error_dialog(s, format!("Failed to send transaction: {}", e)),
     }
 }
 
-fn show_success_dialog(s: &mut Cursive) {
-    info!("Transaction sent successfully");
+fn show_confirm_transaction(s: &mut Cursive, core: Arc<Core>, prepared: PreparedTransaction) {
+    info!("Showing transaction summary");
+
+    // a failed check is no reason to stop: sending reports the same problem
+    match Handle::current().block_on(core.test_transaction(&prepared.plan.transaction)) {
+        Ok(None) => (),
+        Ok(Some(reason)) => {
+            show_error_dialog(
+                s,
+                format!("The node would reject this transaction: {}", reason),
+            );
+            return;
+        }
+        Err(e) => warn!("Could not check the transaction with the node: {}", e),
+    }
+
+    let summary = transaction_summary(&prepared, core.get_balance());
+    let prepared = Mutex::new(Some(prepared));
 
     s.add_layer(
-        Dialog::text("Transaction sent successfully")
-            .title("Success")
-            .button("OK", |s| {
-                debug!("Closing success dialog");
+        Dialog::text(summary)
+            .title("Confirm Transaction")
+            .button("Confirm", move |s| {
+                // button callbacks are Fn, so the transaction is taken out
+                // of the Mutex rather than moved
+                let Some(prepared) = prepared.lock().unwrap().take() else {
+                    return;
+                };
                 s.pop_layer();
+
+                match core.send_transaction_async(prepared) {
+                    Ok(()) => show_success_dialog(s, "Transaction sent successfully"),
+                    Err(e) => show_error_dialog(s, format!("Failed to send transaction: {}", e)),
+                }
+            })
+            .button("Back", |s| {
+                info!("Transaction not confirmed");
                 s.pop_layer();
             }),
     );
 }
 
-fn show_error_dialog(s: &mut Cursive, e: impl std::fmt::Display) {
-    error!("Error sending transaction: {}", e);
+fn show_receive(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing receive address");
+
+    let Some(address) = core.receive_address() else {
+        show_error_dialog(s, "The wallet has no keys".to_string());
+        return;
+    };
+
+    let qr = match qr_code(&address) {
+        Ok(qr) => qr,
+        Err(e) => {
+            show_error_dialog(s, format!("Failed to render QR code: {}", e));
+            return;
+        }
+    };
+
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(qr))
+                .child(TextView::new(address)),
+        )
+        .title("Receive")
+        .button("Close", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+fn show_history(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing transaction history");
+
+    let mut entries = core.history.entries();
+    entries.reverse();
+
+    let content = if entries.is_empty() {
+        "No transactions yet".to_string()
+    } else {
+        entries
+            .iter()
+            .map(|entry| {
+                let (sign, direction) = match entry.direction {
+                    Direction::Sent => ("-", format!("sent to {}", entry.counterparty)),
+                    Direction::Received => ("+", "received".to_string()),
+                };
+
+                format!(
+                    "{}  {}{}  {}",
+                    entry.timestamp.format("%Y-%m-%d %H:%M"),
+                    sign,
+                    sats_to_btc(entry.amount),
+                    direction
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
 
     s.add_layer(
-        Dialog::text(format!("Failed to send transaction: {}", e))
-            .title("Error")
-            .button("OK", |s| {
-                debug!("Closing error dialog");
+        Dialog::around(TextView::new(content).scrollable().max_height(20))
+            .title("History")
+            .button("Close", |s| {
                 s.pop_layer();
             }),
     );
 }
 
+// the UI runs on a blocking thread of the runtime, so it can wait on the
+// node directly; the dialogs freeze for at most the request timeout
+fn show_explorer(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing block explorer");
+
+    match Handle::current().block_on(core.fetch_chain_height()) {
+        Ok(0) => show_error_dialog(s, "The chain has no blocks yet".to_string()),
+        Ok(height) => show_blocks_page(s, core, height, height - 1),
+        Err(e) => show_error_dialog(s, format!("Failed to fetch chain info: {}", e)),
+    }
+}
+
+// `newest` is the height of the first block listed; older ones follow
+fn show_blocks_page(s: &mut Cursive, core: Arc<Core>, height: u64, newest: u64) {
+    let oldest = newest.saturating_sub(BLOCKS_PER_PAGE - 1);
+    let mut select = SelectView::<(u64, Option<Block>)>::new();
+
+    for block_height in (oldest..=newest).rev() {
+        match Handle::current().block_on(core.fetch_block(block_height)) {
+            Ok(block) => select.add_item(
+                block_summary(block_height, block.as_ref()),
+                (block_height, block),
+            ),
+            Err(e) => {
+                show_error_dialog(s, format!("Failed to fetch block {}: {}", block_height, e));
+                return;
+            }
+        }
+    }
+
+    select.set_on_submit(
+        |s, (block_height, block): &(u64, Option<Block>)| match block {
+            Some(block) => show_block(s, *block_height, block),
+            None => show_error_dialog(s, format!("The node has pruned block {}", block_height)),
+        },
+    );
+
+    let mut dialog = Dialog::around(select.scrollable().max_height(20))
+        .title(format!("Explorer: {} blocks", height));
+
+    if newest + 1 < height {
+        let core = core.clone();
+        dialog.add_button("Newer", move |s| {
+            s.pop_layer();
+            let newest = (newest + BLOCKS_PER_PAGE).min(height - 1);
+            show_blocks_page(s, core.clone(), height, newest);
+        });
+    }
+
+    if oldest > 0 {
+        dialog.add_button("Older", move |s| {
+            s.pop_layer();
+            show_blocks_page(s, core.clone(), height, oldest - 1);
+        });
+    }
+
+    s.add_layer(dialog.button("Close", |s| {
+        s.pop_layer();
+    }));
+}
+
+fn show_block(s: &mut Cursive, height: u64, block: &Block) {
+    s.add_layer(
+        Dialog::around(
+            TextView::new(block_details(height, block))
+                .scrollable()
+                .max_height(20),
+        )
+        .title(format!("Block {}", height))
+        .button("Back", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+fn show_add_contact(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing add contact dialog");
+
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new("Name:"))
+                .child(EditView::new().with_name("contact_name"))
+                .child(TextView::new("Public key file:"))
+                .child(EditView::new().with_name("contact_key"))
+                .child(TextView::new("Expected address of the key (optional):"))
+                .child(EditView::new().with_name("contact_address")),
+        )
+        .title("Add Contact")
+        .button("Add", move |s| add_contact(s, core.clone()))
+        .button("Cancel", |s| {
+            info!("Adding contact cancelled");
+            s.pop_layer();
+        }),
+    );
+}
+
+fn add_contact(s: &mut Cursive, core: Arc<Core>) {
+    let mut field = |name: &str| {
+        s.call_on_name(name, |view: &mut EditView| view.get_content())
+            .unwrap()
+            .trim()
+            .to_string()
+    };
+
+    let name = field("contact_name");
+    let key = field("contact_key");
+    let address = field("contact_address");
+
+    let contact = Recipient {
+        name: name.clone(),
+        key: PathBuf::from(key),
+        expected_address: (!address.is_empty()).then_some(address),
+    };
+
+    match core.add_contact(contact) {
+        Ok(()) => {
+            refresh_contacts(s, &core);
+            show_success_dialog(s, &format!("Added contact {}", name));
+        }
+        Err(e) => show_error_dialog(s, format!("Failed to add contact: {}", e)),
+    }
+}
+
+fn show_remove_contact(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing remove contact dialog");
+
+    let contacts = core.contacts();
+    if contacts.is_empty() {
+        show_error_dialog(s, "There are no contacts to remove".to_string());
+        return;
+    }
+
+    let select = SelectView::new()
+        .with_all_str(contacts.into_iter().map(|contact| contact.name))
+        .on_submit(move |s, name: &str| {
+            s.pop_layer();
+
+            match core.remove_contact(name) {
+                Ok(()) => {
+                    refresh_contacts(s, &core);
+                    info!("Removed contact {}", name);
+                }
+                Err(e) => show_error_dialog(s, format!("Failed to remove contact: {}", e)),
+            }
+        });
+
+    s.add_layer(
+        Dialog::around(select.scrollable().max_height(10))
+            .title("Remove Contact")
+            .button("Cancel", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
+fn show_import_key(s: &mut Cursive, core: Arc<Core>) {
+    info!("Showing import key dialog");
+
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new("WIF private key:"))
+                .child(EditView::new().secret().with_name("wif")),
+        )
+        .title("Import Key")
+        .button("Import", move |s| import_key(s, core.clone()))
+        .button("Cancel", |s| {
+            info!("Key import cancelled");
+            s.pop_layer();
+        }),
+    );
+}
+
+fn import_key(s: &mut Cursive, core: Arc<Core>) {
+    let wif = s
+        .call_on_name("wif", |view: &mut EditView| view.get_content())
+        .unwrap();
+
+    match core.import_key(&wif) {
+        Ok(public) => show_success_dialog(s, &format!("Imported key {}", public.to_address())),
+        Err(e) => show_error_dialog(s, format!("Failed to import key: {}", e)),
+    }
+}
+
+fn show_success_dialog(s: &mut Cursive, message: &str) {
+    info!("{}", message);
+
+    s.add_layer(Dialog::text(message).title("Success").button("OK", |s| {
+        debug!("Closing success dialog");
+        s.pop_layer();
+        s.pop_layer();
+    }));
+}
+
+fn show_error_dialog(s: &mut Cursive, message: String) {
+    error!("{}", message);
+
+    s.add_layer(Dialog::text(message).title("Error").button("OK", |s| {
+        debug!("Closing error dialog");
+        s.pop_layer();
+    }));
+}
+
 fn create_transaction_layout(unit: Arc<Mutex<Unit>>) -> LinearLayout {
+    LinearLayout::vertical()
+        .child(
+            LinearLayout::vertical()
+                .child(create_recipient_row(0))
+                .with_name("recipients"),
+        )
+        .child(Button::new("Add recipient", add_recipient_row))
+        .child(create_unit_layout(unit))
+}
+
+fn create_recipient_row(row: usize) -> LinearLayout {
     LinearLayout::vertical()
         .child(TextView::new("Recipient:"))
-        .child(EditView::new().with_name("recipient"))
+        .child(EditView::new().with_name(format!("recipient_{}", row)))
         .child(TextView::new("Amount:"))
-        .child(EditView::new().with_name("amount"))
-        .child(create_unit_layout(unit))
+        .child(EditView::new().with_name(format!("amount_{}", row)))
+}
+
+fn add_recipient_row(s: &mut Cursive) {
+    s.call_on_name("recipients", |view: &mut LinearLayout| {
+        let row = view.len();
+        view.add_child(create_recipient_row(row));
+    });
 }
 
 fn create_unit_layout(unit: Arc<Mutex<Unit>>) -> LinearLayout {
@@ -176,6 +521,20 @@ fn setup_layout(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent
     siv.add_layer(layout);
 }
 
+fn contacts_text(core: &Core) -> String {
+    core.contacts()
+        .iter()
+        .map(|contact| contact.name.clone())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn refresh_contacts(s: &mut Cursive, core: &Core) {
+    s.call_on_name("contacts", |view: &mut TextView| {
+        view.set_content(contacts_text(core))
+    });
+}
+
 fn create_info_layout(core: &Core) -> LinearLayout {
     let mut info_layout = LinearLayout::horizontal();
 
@@ -191,16 +550,8 @@ fn create_info_layout(core: &Core) -> LinearLayout {
         Panel::new(TextView::new(keys_content)).title("Your keys"),
     ));
 
-    let contacts_content = core
-        .config
-        .contacts
-        .iter()
-        .map(|contact| contact.name.clone())
-        .collect::<Vec<String>>()
-        .join("\n");
-
     info_layout.add_child(ResizedView::with_full_width(
-        Panel::new(TextView::new(contacts_content)).title("Contacts"),
+        Panel::new(TextView::new(contacts_text(core)).with_name("contacts")).title("Contacts"),
     ));
 
     info_layout
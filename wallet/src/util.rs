@@ -1,21 +1,52 @@
-use crate::core::{Config, Core, FeeConfig, FeeType, Recipient};
+use crate::core::{
+    Config, Core, FeeConfig, FeeType, LogConfig, LogLevel, LogRotation, PreparedTransaction,
+    Recipient,
+};
 use anyhow::Result;
+use btclib::types::Block;
+use qrcode::QrCode;
+use qrcode::render::unicode;
 use std::panic;
 use std::path::PathBuf;
 use tracing::*;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
-pub fn setup_tracing() -> Result<()> {
-    let file_appender = RollingFileAppender::new(Rotation::DAILY, "logs", "wallet.log");
+pub fn setup_tracing(config: &LogConfig) -> Result<()> {
+    let file_appender = RollingFileAppender::builder()
+        .rotation(rotation(config.rotation))
+        .filename_prefix("wallet.log")
+        .max_log_files(config.max_files)
+        .build(&config.dir)?;
+
+    let filter = log_filter(
+        config.level,
+        &std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default(),
+    );
+
     tracing_subscriber::registry()
         .with(fmt::layer().with_writer(file_appender))
-        .with(EnvFilter::from_default_env().add_directive(tracing::Level::TRACE.into()))
+        .with(filter)
         .init();
 
     Ok(())
 }
 
+fn rotation(rotation: LogRotation) -> Rotation {
+    match rotation {
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Never => Rotation::NEVER,
+    }
+}
+
+// RUST_LOG, when set, takes precedence over the configured level
+fn log_filter(level: LogLevel, rust_log: &str) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(Level::from(level).into())
+        .parse_lossy(rust_log)
+}
+
 pub fn setup_panic_hook() {
     panic::set_hook(Box::new(|panic_info| {
         let backtrace = std::backtrace::Backtrace::force_capture();
@@ -32,17 +63,22 @@ pub fn generate_dummy_config(path: &PathBuf) -> Result<()> {
             Recipient {
                 name: "Alice".to_string(),
                 key: PathBuf::from("alice_key"),
+                expected_address: None,
             },
             Recipient {
                 name: "Bob".to_string(),
                 key: PathBuf::from("bob_key"),
+                expected_address: None,
             },
         ],
-        default_node: "127.0.0.1:9000".to_string(),
+        nodes: vec!["127.0.0.1:9000".to_string()],
         fee_config: FeeConfig {
             fee_type: FeeType::Percent,
             value: 0.1,
         },
+        refresh_interval_secs: 20,
+        max_rebroadcasts: 5,
+        log: LogConfig::default(),
     };
 
     let config_str = toml::to_string_pretty(&dummy_config)?;
@@ -59,3 +95,356 @@ pub fn big_mode_btc(core: &Core) -> String {
     text_to_ascii_art::to_art(sats_to_btc(core.get_balance()), "standard", 0, 1, 0)
         .unwrap_or_else(|_| "Error".to_string())
 }
+
+pub fn balance_text(core: &Core) -> String {
+    let mut text = format!(
+        "{}\nSpendable: {}",
+        big_mode_btc(core),
+        sats_to_btc(core.spendable_balance())
+    );
+
+    let pending = core.get_pending_balance();
+
+    if pending != 0 {
+        let sign = if pending < 0 { "-" } else { "+" };
+        text.push_str(&format!(
+            "\nPending: {}{}",
+            sign,
+            sats_to_btc(pending.unsigned_abs())
+        ));
+    }
+
+    text
+}
+
+#[derive(Clone, Copy)]
+pub enum Unit {
+    Btc,
+    Sats,
+}
+
+fn convert_unit(amount: f64, from: Unit, to: Unit) -> f64 {
+    match (from, to) {
+        (Unit::Btc, Unit::Sats) => amount * 100_000_000.0,
+        (Unit::Sats, Unit::Btc) => amount / 100_000_000.0,
+        _ => amount,
+    }
+}
+
+// a zero amount is refused too: it would only pay the fee
+pub fn parse_amount(input: &str, unit: Unit) -> Result<u64> {
+    let input = input.trim();
+
+    if input.is_empty() {
+        return Err(anyhow::anyhow!("no amount given"));
+    }
+
+    let amount: f64 = input
+        .parse()
+        .map_err(|_| anyhow::anyhow!("{:?} is not a number", input))?;
+
+    if !amount.is_finite() || amount < 0.0 {
+        return Err(anyhow::anyhow!("amount must be a positive number"));
+    }
+
+    let sats = convert_unit(amount, unit, Unit::Sats).round();
+
+    if sats >= u64::MAX as f64 {
+        return Err(anyhow::anyhow!("amount is too large"));
+    }
+
+    if sats < 1.0 {
+        return Err(anyhow::anyhow!("amount must be at least one sat"));
+    }
+
+    Ok(sats as u64)
+}
+
+// two rows of modules per line of text, so the code stays roughly square
+pub fn qr_code(text: &str) -> Result<String> {
+    if text.is_empty() {
+        return Err(anyhow::anyhow!("Nothing to encode"));
+    }
+
+    let code = QrCode::new(text.as_bytes())?;
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+pub fn transaction_summary(prepared: &PreparedTransaction, balance: u64) -> String {
+    let amount = |sats: u64| format!("{} ({} sats)", sats_to_btc(sats), sats);
+
+    let mut lines = prepared
+        .payments
+        .iter()
+        .map(|(name, value)| format!("To {}: {}", name, amount(*value)))
+        .collect::<Vec<_>>();
+
+    let plan = &prepared.plan;
+    let spent = prepared.total().saturating_add(plan.fee);
+    lines.push(format!("Fee: {}", amount(plan.fee)));
+
+    let inputs = plan.inputs.iter().map(|utxo| utxo.value).sum::<u64>();
+    lines.push(format!(
+        "Spending {} output(s) worth {}",
+        plan.inputs.len(),
+        amount(inputs)
+    ));
+
+    if plan.change > 0 {
+        lines.push(format!("Change: {}", amount(plan.change)));
+    }
+
+    lines.push(format!("Total: {}", amount(spent)));
+    lines.push(format!(
+        "Balance after: {}",
+        amount(balance.saturating_sub(spent))
+    ));
+
+    lines.join("\n")
+}
+
+// one line per block in the explorer; `block` is None once it is pruned
+pub fn block_summary(height: u64, block: Option<&Block>) -> String {
+    let Some(block) = block else {
+        return format!("#{:<6} (pruned)", height);
+    };
+
+    format!(
+        "#{:<6} {}  {}  {} tx",
+        height,
+        &block.hash().to_string()[..16],
+        block.header.timestamp.format("%Y-%m-%d %H:%M:%S"),
+        block.transactions.len()
+    )
+}
+
+pub fn block_details(height: u64, block: &Block) -> String {
+    let mut lines = vec![
+        format!("Height: {}", height),
+        format!("Hash: {}", block.hash()),
+        format!("Previous: {}", block.header.prev_block_hash),
+        format!(
+            "Time: {}",
+            block.header.timestamp.format("%Y-%m-%d %H:%M:%S")
+        ),
+        format!("Transactions: {}", block.transactions.len()),
+        String::new(),
+    ];
+
+    for (index, tx) in block.transactions.iter().enumerate() {
+        let value = tx.outputs.iter().map(|output| output.value).sum::<u64>();
+        let kind = if index == 0 {
+            "coinbase".to_string()
+        } else {
+            format!("{} in", tx.inputs.len())
+        };
+
+        lines.push(format!(
+            "{}  {}, {} out, {}",
+            &tx.hash().to_string()[..16],
+            kind,
+            tx.outputs.len(),
+            sats_to_btc(value)
+        ));
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::TransactionPlan;
+    use btclib::U256;
+    use btclib::crypto::{PrivateKey, Signature};
+    use btclib::sha256::Hash;
+    use btclib::types::{BlockHeader, Transaction, TransactionInput, TransactionOutput};
+    use btclib::util::MerkleRoot;
+    use chrono::{TimeZone, Utc};
+    use tracing::level_filters::LevelFilter;
+
+    #[test]
+    fn an_address_renders_as_a_qr_code_and_nothing_is_an_error() {
+        let address = PrivateKey::new_key().public_key().to_address();
+        let code = qr_code(&address).unwrap();
+
+        // a square of block characters, a row of text per two of modules
+        let lines = code.lines().collect::<Vec<_>>();
+        assert!(!lines.is_empty());
+        let width = lines[0].chars().count();
+        assert!(lines.iter().all(|line| line.chars().count() == width));
+        assert_eq!(lines.len(), width.div_ceil(2));
+
+        assert!(qr_code("").is_err());
+    }
+
+    fn prepared(
+        payments: &[(&str, u64)],
+        inputs: &[u64],
+        change: u64,
+        fee: u64,
+    ) -> PreparedTransaction {
+        let key = PrivateKey::new_key().public_key();
+        let coin = |value: u64| TransactionOutput {
+            value,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: key.clone(),
+        };
+
+        PreparedTransaction {
+            plan: TransactionPlan {
+                inputs: inputs.iter().map(|&value| coin(value)).collect(),
+                change,
+                fee,
+                transaction: Transaction::new(vec![], vec![]),
+            },
+            payments: payments
+                .iter()
+                .map(|(name, amount)| (name.to_string(), *amount))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn the_summary_lists_each_payment_the_fee_and_what_is_left() {
+        let summary = transaction_summary(
+            &prepared(&[("bob", 50_000_000)], &[100_000_000], 49_990_000, 10_000),
+            150_000_000,
+        );
+        assert_eq!(
+            summary.lines().collect::<Vec<_>>(),
+            vec![
+                "To bob: 0.5 BTC (50000000 sats)",
+                "Fee: 0.0001 BTC (10000 sats)",
+                "Spending 1 output(s) worth 1 BTC (100000000 sats)",
+                "Change: 0.4999 BTC (49990000 sats)",
+                "Total: 0.5001 BTC (50010000 sats)",
+                "Balance after: 0.9999 BTC (99990000 sats)",
+            ]
+        );
+
+        // no change line when everything went out, and never a negative
+        // balance
+        let summary = transaction_summary(
+            &prepared(&[("bob", 6000), ("carol", 3000)], &[5000, 5000], 0, 1000),
+            5000,
+        );
+        assert_eq!(
+            summary.lines().collect::<Vec<_>>(),
+            vec![
+                "To bob: 0.00006 BTC (6000 sats)",
+                "To carol: 0.00003 BTC (3000 sats)",
+                "Fee: 0.00001 BTC (1000 sats)",
+                "Spending 2 output(s) worth 0.0001 BTC (10000 sats)",
+                "Total: 0.0001 BTC (10000 sats)",
+                "Balance after: 0 BTC (0 sats)",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_positive_numbers_parse_as_amounts() {
+        for bad in ["", "  ", "abc", "-1", "0", "NaN", "inf"] {
+            assert!(parse_amount(bad, Unit::Btc).is_err(), "{:?} parsed", bad);
+        }
+
+        assert_eq!(parse_amount("1.5", Unit::Btc).unwrap(), 150_000_000);
+        assert_eq!(parse_amount(" 1.5 ", Unit::Btc).unwrap(), 150_000_000);
+        assert_eq!(parse_amount("1500", Unit::Sats).unwrap(), 1500);
+
+        // less than a sat once converted is as good as zero
+        assert!(parse_amount("0.000000001", Unit::Btc).is_err());
+        assert!(parse_amount("0.4", Unit::Sats).is_err());
+    }
+
+    #[test]
+    fn the_log_table_maps_to_a_rotation_and_filter() {
+        // only what a config needs besides the [log] table under test
+        let required = "my_keys = []\ncontacts = []\nnodes = []\n\n\
+                        [fee_config]\nfee_type = \"Fixed\"\nvalue = 1.0\n";
+        let log = |table: &str| {
+            toml::from_str::<Config>(&format!("{}{}", required, table))
+                .unwrap()
+                .log
+        };
+        let max_level = |config: &LogConfig, rust_log: &str| {
+            log_filter(config.level, rust_log).max_level_hint()
+        };
+
+        // no table at all: daily files, a week of them, at INFO
+        let config = log("");
+        assert_eq!(rotation(config.rotation), Rotation::DAILY);
+        assert_eq!(config.max_files, 7);
+        assert_eq!(max_level(&config, ""), Some(LevelFilter::INFO));
+
+        let config = log(
+            "\n[log]\nlevel = \"warn\"\nrotation = \"hourly\"\ndir = \"/var/log/wallet\"\nmax_files = 24\n",
+        );
+        assert_eq!(rotation(config.rotation), Rotation::HOURLY);
+        assert_eq!(config.dir, PathBuf::from("/var/log/wallet"));
+        assert_eq!(config.max_files, 24);
+        assert_eq!(max_level(&config, ""), Some(LevelFilter::WARN));
+
+        // RUST_LOG wins over the configured level
+        assert_eq!(max_level(&config, "debug"), Some(LevelFilter::DEBUG));
+
+        let config = log("\n[log]\nlevel = \"trace\"\nrotation = \"never\"\n");
+        assert_eq!(rotation(config.rotation), Rotation::NEVER);
+        assert_eq!(max_level(&config, ""), Some(LevelFilter::TRACE));
+    }
+
+    #[test]
+    fn a_block_is_summarized_on_one_line_and_detailed_per_transaction() {
+        let key = PrivateKey::new_key();
+        let output = |value: u64| TransactionOutput {
+            value,
+            unique_id: uuid::Uuid::new_v4(),
+            pubkey: key.public_key(),
+        };
+        let coinbase = Transaction::new(vec![], vec![output(5_000_000_000)]);
+        let spend = Transaction::new(
+            vec![TransactionInput {
+                prev_tx_output_hash: Hash::zero(),
+                signature: Signature::sign_input(&coinbase, 0, &Hash::zero(), &key),
+            }],
+            vec![output(150_000_000), output(50_000_000)],
+        );
+        let transactions = vec![coinbase, spend];
+        let block = Block::new(
+            BlockHeader::new(
+                Utc.with_ymd_and_hms(2024, 3, 9, 14, 5, 0).unwrap(),
+                0,
+                Hash::zero(),
+                MerkleRoot::calculate(&transactions),
+                U256::MAX,
+            ),
+            transactions.clone(),
+        );
+        let short = |hash: Hash| hash.to_string()[..16].to_string();
+
+        assert_eq!(
+            block_summary(42, Some(&block)),
+            format!("#42     {}  2024-03-09 14:05:00  2 tx", short(block.hash()))
+        );
+        assert_eq!(block_summary(7, None), "#7      (pruned)");
+
+        let details = block_details(42, &block);
+        let lines = details.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "Height: 42");
+        assert_eq!(lines[1], format!("Hash: {}", block.hash()));
+        assert_eq!(lines[3], "Time: 2024-03-09 14:05:00");
+        assert_eq!(lines[4], "Transactions: 2");
+        assert_eq!(
+            lines[6],
+            format!("{}  coinbase, 1 out, 50 BTC", short(transactions[0].hash()))
+        );
+        assert_eq!(
+            lines[7],
+            format!("{}  1 in, 2 out, 2 BTC", short(transactions[1].hash()))
+        );
+    }
+}